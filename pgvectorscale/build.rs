@@ -0,0 +1,37 @@
+//! Records the git commit and the pgrx version the extension is built from, for
+//! `vectorscale_version`.
+
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.toml");
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/refs");
+    println!("cargo:rerun-if-env-changed=VECTORSCALE_GIT_COMMIT");
+
+    /* packagers building outside of a git checkout can set the commit themselves */
+    let commit = std::env::var("VECTORSCALE_GIT_COMMIT").ok().or_else(|| {
+        let output = Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout)
+            .ok()
+            .map(|s| s.trim().to_string())
+    });
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=VECTORSCALE_GIT_COMMIT={}", commit);
+    }
+
+    let manifest = std::fs::read_to_string("Cargo.toml").expect("failed to read Cargo.toml");
+    let pgrx_version = manifest
+        .lines()
+        .find(|line| line.trim_start().starts_with("pgrx ="))
+        .and_then(|line| line.split('"').nth(1))
+        .map(|version| version.trim_start_matches('='))
+        .unwrap_or("unknown");
+    println!("cargo:rustc-env=VECTORSCALE_PGRX_VERSION={}", pgrx_version);
+}
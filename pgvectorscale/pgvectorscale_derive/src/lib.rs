@@ -38,6 +38,11 @@ fn impl_readable_macro(ast: &syn::DeriveInput) -> TokenStream {
                 unsafe { rkyv::archived_root::<#name>(self._rb.get_data_slice()) }
             }
 
+            /// The whole item of the node, including the bytes in front of the archived node.
+            pub fn get_data_slice(&self) -> &[u8] {
+                self._rb.get_data_slice()
+            }
+
             pub fn get_owned_page(self) -> crate::util::page::ReadablePage<'a> {
                 self._rb.get_owned_page()
             }
@@ -76,6 +81,11 @@ fn impl_writeable_macro(ast: &syn::DeriveInput) -> TokenStream {
                 #archived_name::with_data(self.wb.get_data_slice())
             }
 
+            /// The whole item of the node, including the bytes in front of the archived node.
+            pub fn get_data_slice_mut(&mut self) -> &mut [u8] {
+                self.wb.get_data_slice()
+            }
+
             pub fn commit(self) {
                 self.wb.commit()
             }
@@ -95,6 +105,21 @@ fn impl_writeable_macro(ast: &syn::DeriveInput) -> TokenStream {
                 unsafe { tape.write(&bytes) }
             }
 
+            /// Writes `prefix` followed by the archived bytes as a single item. The prefix must keep
+            /// the archive aligned, i.e. its length must be a multiple of 8.
+            pub fn write_with_prefix<S: crate::access_method::stats::StatsNodeWrite>(&self, prefix: &[u8], tape: &mut crate::util::tape::Tape, stats: &mut S) -> ItemPointer {
+                if prefix.is_empty() {
+                    return self.write(tape, stats);
+                }
+                assert!(prefix.len() % 8 == 0);
+                let bytes = self.serialize_to_vec();
+                let mut data = Vec::with_capacity(prefix.len() + bytes.len());
+                data.extend_from_slice(prefix);
+                data.extend_from_slice(&bytes);
+                stats.record_write();
+                unsafe { tape.write(&data) }
+            }
+
             pub fn serialize_to_vec(&self) -> rkyv::util::AlignedVec {
                 //TODO 256 probably too small
                 rkyv::to_bytes::<_, 256>(self).unwrap()
@@ -0,0 +1,611 @@
+/*
+Upgrade from 0.2.0 to 0.3.0.
+
+The operator classes are created with the same idempotent SQL as a new installation. Indexes
+built with 0.2.0 keep working: their meta pages are upgraded when they are next written.
+*/
+
+-- src/access_method/mod.rs
+-- the access method handler moves to the library of the new version
+
+CREATE OR REPLACE FUNCTION diskann_amhandler(internal) RETURNS index_am_handler PARALLEL SAFE IMMUTABLE STRICT COST 0.0001 LANGUAGE c AS '$libdir/vectorscale-0.3.0', 'amhandler_wrapper';
+
+-- src/access_method/distance.rs
+
+CREATE OR REPLACE FUNCTION diskann_cosine_distance(vector, vector) RETURNS float8 PARALLEL SAFE IMMUTABLE STRICT LANGUAGE c AS '$libdir/vectorscale-0.3.0', 'diskann_cosine_distance_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_l2_squared_distance(vector, vector) RETURNS float8 PARALLEL SAFE IMMUTABLE STRICT LANGUAGE c AS '$libdir/vectorscale-0.3.0', 'diskann_l2_squared_distance_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_float4_array_cosine_distance(
+	"a" real[],
+	"b" real[]
+) RETURNS double precision
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_float4_array_cosine_distance_wrapper';
+
+-- src/access_method/mod.rs
+-- operator classes for pgvector's halfvec and sparsevec types
+
+DO $$
+DECLARE
+  t text;
+  c int;
+BEGIN
+    FOREACH t IN ARRAY ARRAY['halfvec', 'sparsevec'] LOOP
+        IF to_regtype(t) IS NULL THEN
+            CONTINUE;
+        END IF;
+
+        SELECT count(*)
+        INTO c
+        FROM pg_catalog.pg_opclass c
+        WHERE c.opcname = t || '_cosine_ops'
+        AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+        IF c = 0 THEN
+            EXECUTE format(
+                'CREATE OPERATOR CLASS %I DEFAULT
+                FOR TYPE %s USING diskann AS
+                    OPERATOR 1 <=> (%s, %s) FOR ORDER BY float_ops',
+                t || '_cosine_ops', t, t, t);
+        END IF;
+    END LOOP;
+END;
+$$;
+
+-- operator class for float4[] columns
+
+DO $$
+DECLARE
+  c int;
+BEGIN
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_operator o
+    WHERE o.oprname = '<=>'
+    AND o.oprleft = 'real[]'::regtype
+    AND o.oprright = 'real[]'::regtype;
+
+    IF c = 0 THEN
+        CREATE OPERATOR <=> (
+            LEFTARG = real[],
+            RIGHTARG = real[],
+            FUNCTION = diskann_float4_array_cosine_distance,
+            COMMUTATOR = '<=>'
+        );
+    END IF;
+
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_opclass c
+    WHERE c.opcname = 'float4_array_cosine_ops'
+    AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+    IF c = 0 THEN
+        CREATE OPERATOR CLASS float4_array_cosine_ops DEFAULT
+        FOR TYPE real[] USING diskann AS
+	        OPERATOR 1 <=> (real[], real[]) FOR ORDER BY float_ops;
+    END IF;
+END;
+$$;
+
+-- the weighted cosine distance of the indexes with the weights option
+
+DO $$
+BEGIN
+    IF to_regtype('diskann_weighted_query') IS NULL THEN
+        CREATE TYPE diskann_weighted_query AS (query vector, weights real[]);
+    END IF;
+END;
+$$;
+
+CREATE OR REPLACE FUNCTION diskann_weighted_cosine_distance(vector, diskann_weighted_query) RETURNS float8 PARALLEL SAFE IMMUTABLE STRICT LANGUAGE c AS '$libdir/vectorscale-0.3.0', 'diskann_weighted_cosine_distance_wrapper';
+
+DO $$
+DECLARE
+  c int;
+BEGIN
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_operator o
+    WHERE o.oprname = '<*>'
+    AND o.oprleft = 'vector'::regtype
+    AND o.oprright = 'diskann_weighted_query'::regtype;
+
+    IF c = 0 THEN
+        CREATE OPERATOR <*> (
+            LEFTARG = vector,
+            RIGHTARG = diskann_weighted_query,
+            FUNCTION = diskann_weighted_cosine_distance
+        );
+    END IF;
+
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_opclass c
+    WHERE c.opcname = 'vector_weighted_cosine_ops'
+    AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+    IF c = 0 THEN
+        CREATE OPERATOR CLASS vector_weighted_cosine_ops
+        FOR TYPE vector USING diskann AS
+	        OPERATOR 1 <*> (vector, diskann_weighted_query) FOR ORDER BY float_ops;
+    END IF;
+END;
+$$;
+
+-- operator classes for the filter columns that can follow the vector column of an index
+
+DO $$
+DECLARE
+  t text;
+  c int;
+BEGIN
+    FOREACH t IN ARRAY ARRAY['int2', 'int4', 'int8', 'float4', 'float8', 'bool', 'date', 'timestamp', 'timestamptz'] LOOP
+        SELECT count(*)
+        INTO c
+        FROM pg_catalog.pg_opclass c
+        WHERE c.opcname = t || '_filter_ops'
+        AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+        IF c = 0 THEN
+            EXECUTE format(
+                'CREATE OPERATOR CLASS %I DEFAULT
+                FOR TYPE %s USING diskann AS
+                    OPERATOR 1 <,
+                    OPERATOR 2 <=,
+                    OPERATOR 3 =,
+                    OPERATOR 4 >=,
+                    OPERATOR 5 >',
+                t || '_filter_ops', t);
+        END IF;
+    END LOOP;
+END;
+$$;
+
+-- src/access_method/build.rs
+
+CREATE OR REPLACE VIEW diskann_build_progress AS
+SELECT s.pid, s.datname, s.relid, s.index_relid, s.phase, s.tuples_total, s.tuples_done,
+    p.param18 AS memory_bytes, p.param19 AS wal_bytes
+FROM pg_catalog.pg_stat_progress_create_index s
+JOIN pg_catalog.pg_stat_get_progress_info('CREATE INDEX') p ON p.pid = s.pid
+JOIN pg_catalog.pg_am a ON a.oid = p.param9::oid
+WHERE a.amname = 'diskann';
+
+CREATE OR REPLACE FUNCTION diskann_last_build_stats() RETURNS TABLE (
+	"index" oid,
+	"num_nodes" bigint,
+	"num_restored_nodes" bigint,
+	"avg_neighbors" double precision,
+	"num_prunes" bigint,
+	"avg_neighbors_before_prune" double precision,
+	"avg_neighbors_after_prune" double precision,
+	"distance_comparisons" bigint,
+	"nodes_read" bigint,
+	"nodes_modified" bigint,
+	"nodes_written" bigint,
+	"peak_memory_bytes" bigint,
+	"buffers_hit" bigint,
+	"buffers_read" bigint,
+	"buffers_dirtied" bigint,
+	"wal_records" bigint,
+	"wal_fpi" bigint,
+	"wal_bytes" bigint,
+	"training_ms" double precision,
+	"building_graph_ms" double precision,
+	"finalizing_graph_ms" double precision,
+	"build_ms" double precision
+)
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_last_build_stats_wrapper';
+
+-- src/access_method/search.rs
+
+CREATE OR REPLACE FUNCTION diskann_search(
+	"index" oid,
+	"query" anyelement,
+	"k" INT,
+	"params" jsonb DEFAULT NULL,
+	"after_distance" double precision DEFAULT NULL,
+	"after_ctid" tid DEFAULT NULL
+) RETURNS TABLE (
+	"ctid" tid,
+	"distance" double precision
+)
+VOLATILE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_search_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_quantize(
+	"index" oid,
+	"query" anyelement
+) RETURNS bytea
+STABLE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_quantize_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_quantizer_id(
+	"index" oid
+) RETURNS bigint
+STABLE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_quantizer_id_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_quantizer_codebook(
+	"index" oid
+) RETURNS TABLE (
+	"dimension" INT,
+	"mean" double precision,
+	"std_dev" double precision,
+	"thresholds" double precision[]
+)
+STABLE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_quantizer_codebook_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_search_codes(
+	"index" oid,
+	"codes" bytea,
+	"k" INT,
+	"quantizer_id" bigint DEFAULT NULL,
+	"search_list_size" INT DEFAULT NULL
+) RETURNS TABLE (
+	"ctid" tid,
+	"distance" double precision
+)
+VOLATILE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_search_codes_wrapper';
+
+-- src/access_method/recency.rs
+
+CREATE OR REPLACE FUNCTION diskann_search_recent(
+	"index" oid,
+	"query" anyelement,
+	"k" INT,
+	"half_life" interval,
+	"weight" double precision DEFAULT 0.1,
+	"params" jsonb DEFAULT NULL
+) RETURNS TABLE (
+	"ctid" tid,
+	"score" double precision
+)
+VOLATILE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_search_recent_wrapper';
+
+-- src/access_method/hybrid_search.rs
+
+CREATE OR REPLACE FUNCTION diskann_hybrid_search(
+	"index" oid,
+	"query" anyelement,
+	"candidates" tid[],
+	"k" INT,
+	"method" TEXT DEFAULT 'rrf',
+	"vector_weight" double precision DEFAULT 0.5,
+	"rrf_k" INT DEFAULT 60,
+	"text_scores" double precision[] DEFAULT NULL
+) RETURNS TABLE (
+	"ctid" tid,
+	"score" double precision,
+	"text_rank" INT,
+	"vector_rank" INT,
+	"distance" double precision
+)
+VOLATILE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_hybrid_search_wrapper';
+
+-- src/access_method/knn_join.rs
+
+CREATE OR REPLACE FUNCTION diskann_knn_join(
+	"index" oid,
+	"source" oid,
+	"vector_column" TEXT,
+	"k" INT,
+	"params" jsonb DEFAULT NULL
+) RETURNS TABLE (
+	"source_ctid" tid,
+	"ctid" tid,
+	"distance" double precision,
+	"rank" INT
+)
+VOLATILE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_knn_join_wrapper';
+
+-- src/access_method/query_hints.rs
+
+CREATE OR REPLACE FUNCTION diskann_query_params(
+	"query" anyelement,
+	"search_list_size" INT DEFAULT NULL,
+	"rescore" INT DEFAULT NULL
+) RETURNS anyelement
+STABLE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_query_params_wrapper';
+
+-- src/access_method/guc.rs
+
+CREATE OR REPLACE FUNCTION diskann_set_query_defaults(
+	"index" oid,
+	"search_list_size" INT DEFAULT NULL,
+	"rescore" INT DEFAULT NULL
+) RETURNS void
+VOLATILE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_set_query_defaults_wrapper';
+
+-- src/access_method/entry_points.rs
+
+CREATE OR REPLACE FUNCTION diskann_refresh_entry_points(
+	"index" oid
+) RETURNS INT
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_refresh_entry_points_wrapper';
+
+-- src/access_method/node_placement.rs
+
+CREATE OR REPLACE FUNCTION diskann_reorder_index(
+	"index" oid
+) RETURNS bigint
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_reorder_index_wrapper';
+
+-- src/access_method/graph_import.rs
+
+CREATE OR REPLACE FUNCTION diskann_import_graph(
+	"index_name" TEXT,
+	"table_name" oid,
+	"column_name" TEXT,
+	"key_column" TEXT,
+	"graph_path" TEXT,
+	"options" TEXT DEFAULT NULL
+) RETURNS void
+VOLATILE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_import_graph_wrapper';
+
+-- src/access_method/index_export.rs
+
+CREATE OR REPLACE FUNCTION diskann_export_index(
+	"index" oid,
+	"path" TEXT
+) RETURNS bigint
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_export_index_wrapper';
+
+-- src/access_method/activity.rs
+
+CREATE OR REPLACE FUNCTION diskann_index_activity(
+	"index" oid
+) RETURNS TABLE (
+	"inserts" bigint,
+	"deletes" bigint,
+	"needs_maintenance" bool
+)
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_index_activity_wrapper';
+
+-- src/access_method/introspection.rs
+
+CREATE OR REPLACE FUNCTION vectorscale_version() RETURNS TABLE (
+	"extension_version" TEXT,
+	"format_version" INT,
+	"simd_features" TEXT[],
+	"pgrx_version" TEXT,
+	"pg_version" TEXT,
+	"build_commit" TEXT
+)
+STABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'vectorscale_version_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_index_info(
+	"index" oid
+) RETURNS TABLE (
+	"format_version" INT,
+	"extension_version_when_built" TEXT,
+	"vector_type" TEXT,
+	"distance_type" TEXT,
+	"num_dimensions" INT,
+	"num_dimensions_to_index" INT,
+	"storage_layout" TEXT,
+	"num_bits_per_dimension" INT,
+	"num_neighbors" INT,
+	"search_list_size" INT,
+	"max_alpha" double precision,
+	"pca_dimensions" INT,
+	"weighted" bool,
+	"num_entry_points" INT,
+	"num_nodes" bigint,
+	"num_deleted_nodes" bigint,
+	"num_pages" bigint,
+	"page_counts" TEXT
+)
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_index_info_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_index_bloat(
+	"index" oid
+) RETURNS TABLE (
+	"page_type" TEXT,
+	"in_use" bool,
+	"num_pages" bigint,
+	"num_empty_pages" bigint,
+	"free_bytes" bigint,
+	"num_nodes" bigint,
+	"num_deleted_nodes" bigint,
+	"deleted_fraction" double precision,
+	"reclaimable_bytes" bigint
+)
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_index_bloat_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_page_stats(
+	"index" oid
+) RETURNS TABLE (
+	"page_type" TEXT,
+	"num_pages" bigint,
+	"num_items" bigint,
+	"total_bytes" bigint,
+	"used_bytes" bigint,
+	"free_bytes" bigint,
+	"avg_item_bytes" double precision,
+	"fraction_of_index" double precision
+)
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_page_stats_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_page_ranges(
+	"index" oid
+) RETURNS TABLE (
+	"page_type" TEXT,
+	"first_block" bigint,
+	"last_block" bigint
+)
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_page_ranges_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_node_neighbors(
+	"index" oid,
+	"ctid" tid
+) RETURNS TABLE (
+	"neighbor_index_tid" tid,
+	"neighbor_ctid" tid,
+	"distance" real
+)
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_node_neighbors_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_graph_edges(
+	"index" oid
+) RETURNS TABLE (
+	"source_ctid" tid,
+	"target_ctid" tid,
+	"distance" real
+)
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_graph_edges_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_graph_stats(
+	"index" oid,
+	"sample_size" INT DEFAULT 1000,
+	"min_degree" INT DEFAULT NULL
+) RETURNS TABLE (
+	"num_nodes" bigint,
+	"avg_out_degree" double precision,
+	"min_out_degree" INT,
+	"p50_out_degree" INT,
+	"p90_out_degree" INT,
+	"p99_out_degree" INT,
+	"max_out_degree" INT,
+	"num_nodes_below_min_degree" bigint,
+	"num_reachable_nodes" bigint,
+	"num_sampled_nodes" bigint,
+	"num_unreachable_sampled_nodes" bigint,
+	"num_unreachable_components" bigint
+)
+VOLATILE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_graph_stats_wrapper';
+
+-- src/access_method/size_estimate.rs
+
+CREATE OR REPLACE FUNCTION diskann_estimate_size(
+	"num_rows" bigint,
+	"num_dimensions" INT,
+	"storage_layout" TEXT DEFAULT 'memory_optimized',
+	"num_neighbors" INT DEFAULT -1,
+	"num_bits_per_dimension" INT DEFAULT 0,
+	"num_filter_columns" INT DEFAULT 0
+) RETURNS TABLE (
+	"num_neighbors" INT,
+	"num_bits_per_dimension" INT,
+	"node_size_bytes" bigint,
+	"nodes_per_page" INT,
+	"num_pages" bigint,
+	"index_size_bytes" bigint,
+	"build_memory_bytes" bigint
+)
+IMMUTABLE STRICT PARALLEL SAFE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_estimate_size_wrapper';
+
+-- src/access_method/benchmark.rs
+
+CREATE OR REPLACE FUNCTION diskann_generate_vectors(
+	"num_vectors" INT,
+	"num_dimensions" INT,
+	"num_clusters" INT DEFAULT 10,
+	"stddev" double precision DEFAULT 0.1,
+	"seed" bigint DEFAULT 0
+) RETURNS SETOF real[]
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_generate_vectors_wrapper';
+
+CREATE OR REPLACE FUNCTION diskann_benchmark(
+	"index" oid,
+	"num_queries" INT DEFAULT 100,
+	"k" INT DEFAULT 10
+) RETURNS TABLE (
+	"num_queries" INT,
+	"avg_ms" double precision,
+	"p50_ms" double precision,
+	"p90_ms" double precision,
+	"p99_ms" double precision,
+	"max_ms" double precision,
+	"avg_index_reads" double precision,
+	"avg_heap_reads" double precision,
+	"avg_distance_comparisons" double precision,
+	"avg_quantized_distance_comparisons" double precision,
+	"avg_visited_nodes" double precision
+)
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_benchmark_wrapper';
+
+-- src/access_method/recall.rs
+
+CREATE OR REPLACE FUNCTION diskann_estimate_recall(
+	"index" oid,
+	"sample_queries" INT DEFAULT 100,
+	"k" INT DEFAULT 10
+) RETURNS double precision
+VOLATILE STRICT
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_estimate_recall_wrapper';
+
+-- src/access_method/tune.rs
+
+CREATE OR REPLACE FUNCTION diskann_tune(
+	"index" oid,
+	"target_recall" double precision DEFAULT 0.95,
+	"max_latency_ms" double precision DEFAULT NULL,
+	"num_queries" INT DEFAULT 50,
+	"k" INT DEFAULT 10
+) RETURNS TABLE (
+	"query_search_list_size" INT,
+	"query_rescore" INT,
+	"recall" double precision,
+	"p50_ms" double precision,
+	"p99_ms" double precision,
+	"recommended" bool
+)
+VOLATILE
+LANGUAGE c
+AS '$libdir/vectorscale-0.3.0', 'diskann_tune_wrapper';
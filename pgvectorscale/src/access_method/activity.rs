@@ -0,0 +1,244 @@
+//! Counters of the changes made to each index since it was built, shared by the backends.
+//!
+//! The graph degrades as rows are inserted and deleted: vacuum only removes the deleted nodes,
+//! it does not rebuild the neighborhoods around them. Autovacuum decides from the dead tuples of
+//! the table, which does not reflect how much a graph changed, so the index counts the nodes
+//! inserted and deleted since its build, and `diskann_index_activity` reports whether they
+//! exceed `diskann.maintenance_threshold`, for a scheduled job to rebuild the index.
+//!
+//! Like the result cache, the counters live in shared memory, so they need the library in
+//! `shared_preload_libraries`. They are lost when the server restarts.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use pgrx::{pg_shmem_init, pg_sys, prelude::*, PGRXSharedMemory, PgLwLock, PgRelation};
+
+use super::{
+    guc::TSV_MAINTENANCE_THRESHOLD,
+    storage_common::{open_diskann_index, SharedIndexId},
+};
+
+/// The number of indexes with counters. When more indexes change, the counters of the least
+/// changed index are reused.
+const NUM_INDEXES: usize = 256;
+
+struct IndexCounters {
+    /* changed under the exclusive lock */
+    index: SharedIndexId,
+    /* incremented under the shared lock */
+    inserts: AtomicU64,
+    deletes: AtomicU64,
+}
+
+impl Default for IndexCounters {
+    fn default() -> Self {
+        Self {
+            index: SharedIndexId::INVALID,
+            inserts: AtomicU64::new(0),
+            deletes: AtomicU64::new(0),
+        }
+    }
+}
+
+impl IndexCounters {
+    fn get_changes(&self) -> u64 {
+        self.inserts.load(Ordering::Relaxed) + self.deletes.load(Ordering::Relaxed)
+    }
+}
+
+pub struct IndexActivity {
+    counters: [IndexCounters; NUM_INDEXES],
+}
+
+impl Default for IndexActivity {
+    fn default() -> Self {
+        Self {
+            counters: std::array::from_fn(|_| IndexCounters::default()),
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for IndexActivity {}
+
+impl IndexActivity {
+    fn find(&self, index: SharedIndexId) -> Option<&IndexCounters> {
+        self.counters.iter().find(|c| c.index == index)
+    }
+
+    /// The counters of `index`, taking those of the least changed index if it has none.
+    fn find_or_add(&mut self, index: SharedIndexId) -> &IndexCounters {
+        let position = match self.counters.iter().position(|c| c.index == index) {
+            Some(position) => position,
+            None => {
+                let mut least_changed = 0;
+                for (i, counters) in self.counters.iter().enumerate() {
+                    if counters.get_changes() < self.counters[least_changed].get_changes() {
+                        least_changed = i;
+                    }
+                }
+                let counters = &mut self.counters[least_changed];
+                counters.index = index;
+                counters.inserts.store(0, Ordering::Relaxed);
+                counters.deletes.store(0, Ordering::Relaxed);
+                least_changed
+            }
+        };
+        &self.counters[position]
+    }
+
+    fn reset(&mut self, index: SharedIndexId) {
+        for counters in self.counters.iter_mut() {
+            if counters.index == index {
+                *counters = IndexCounters::default();
+            }
+        }
+    }
+}
+
+static INDEX_ACTIVITY: PgLwLock<IndexActivity> = PgLwLock::new();
+
+/* whether the shared memory of the counters was reserved, i.e. the library is preloaded */
+static INDEX_ACTIVITY_AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// Reserves the shared memory of the counters. Only called while the library is preloaded.
+pub fn init() {
+    pg_shmem_init!(INDEX_ACTIVITY);
+    INDEX_ACTIVITY_AVAILABLE.store(true, Ordering::Relaxed);
+}
+
+fn record(index: &PgRelation, num_inserts: u64, num_deletes: u64) {
+    if !INDEX_ACTIVITY_AVAILABLE.load(Ordering::Relaxed) || num_inserts + num_deletes == 0 {
+        return;
+    }
+    {
+        let activity = INDEX_ACTIVITY.share();
+        if let Some(counters) = activity.find(SharedIndexId::of(index)) {
+            counters.inserts.fetch_add(num_inserts, Ordering::Relaxed);
+            counters.deletes.fetch_add(num_deletes, Ordering::Relaxed);
+            return;
+        }
+    }
+    let mut activity = INDEX_ACTIVITY.exclusive();
+    let counters = activity.find_or_add(SharedIndexId::of(index));
+    counters.inserts.fetch_add(num_inserts, Ordering::Relaxed);
+    counters.deletes.fetch_add(num_deletes, Ordering::Relaxed);
+}
+
+/// Counts nodes inserted into `index`.
+pub fn record_inserts(index: &PgRelation, num_inserts: u64) {
+    record(index, num_inserts, 0);
+}
+
+/// Counts nodes deleted from `index` by vacuum.
+pub fn record_deletes(index: &PgRelation, num_deletes: u64) {
+    record(index, 0, num_deletes);
+}
+
+/// Resets the counters of `index`, which was built again.
+pub fn reset(index: &PgRelation) {
+    if !INDEX_ACTIVITY_AVAILABLE.load(Ordering::Relaxed) {
+        return;
+    }
+    INDEX_ACTIVITY.exclusive().reset(SharedIndexId::of(index));
+}
+
+/// Whether `num_changes` changes to an index of `num_nodes` nodes call for a rebuild.
+fn needs_maintenance(num_changes: u64, num_nodes: f64, threshold: f64) -> bool {
+    num_changes > 0 && num_changes as f64 >= threshold * num_nodes.max(0.0)
+}
+
+/// The number of nodes inserted into and deleted from an index since it was built, and whether
+/// they exceed `diskann.maintenance_threshold` times the number of rows of the index.
+#[pg_extern(volatile)]
+pub fn diskann_index_activity(
+    index: pg_sys::Oid,
+) -> TableIterator<
+    'static,
+    (
+        name!(inserts, i64),
+        name!(deletes, i64),
+        name!(needs_maintenance, bool),
+    ),
+> {
+    if !INDEX_ACTIVITY_AVAILABLE.load(Ordering::Relaxed) {
+        error!("diskann_index_activity requires vectorscale in shared_preload_libraries");
+    }
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let index = SharedIndexId::of(&index_relation);
+    let (inserts, deletes) = match INDEX_ACTIVITY.share().find(index) {
+        Some(counters) => (
+            counters.inserts.load(Ordering::Relaxed),
+            counters.deletes.load(Ordering::Relaxed),
+        ),
+        None => (0, 0),
+    };
+    let num_nodes = unsafe { (*index_relation.rd_rel).reltuples } as f64;
+    TableIterator::once((
+        inserts as i64,
+        deletes as i64,
+        needs_maintenance(
+            inserts + deletes,
+            num_nodes,
+            TSV_MAINTENANCE_THRESHOLD.get(),
+        ),
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use pgrx::*;
+
+    use super::{needs_maintenance, IndexActivity, NUM_INDEXES};
+    use crate::access_method::storage_common::SharedIndexId;
+
+    fn index_id(index: u32) -> SharedIndexId {
+        SharedIndexId {
+            database: pg_sys::Oid::from(5),
+            index: pg_sys::Oid::from(index),
+        }
+    }
+
+    #[pg_test]
+    fn test_index_activity() {
+        let mut activity = Box::<IndexActivity>::default();
+        let index = index_id(16384);
+        assert!(activity.find(index).is_none());
+
+        let counters = activity.find_or_add(index);
+        counters.inserts.fetch_add(10, Ordering::Relaxed);
+        counters.deletes.fetch_add(2, Ordering::Relaxed);
+        assert_eq!(activity.find(index).unwrap().get_changes(), 12);
+        /* an index of another database with the same oid has its own counters */
+        let other_database = SharedIndexId {
+            database: pg_sys::Oid::from(16383),
+            ..index
+        };
+        assert!(activity.find(other_database).is_none());
+
+        /* the counters of the least changed index are reused */
+        for i in 1..NUM_INDEXES as u32 {
+            let counters = activity.find_or_add(index_id(16384 + i));
+            counters.inserts.fetch_add(100, Ordering::Relaxed);
+        }
+        let new_index = index_id(20000);
+        activity.find_or_add(new_index);
+        assert!(activity.find(index).is_none());
+        assert_eq!(activity.find(new_index).unwrap().get_changes(), 0);
+
+        activity.reset(index_id(16385));
+        assert!(activity.find(index_id(16385)).is_none());
+    }
+
+    #[pg_test]
+    fn test_needs_maintenance() {
+        assert!(!needs_maintenance(0, 0.0, 0.2));
+        assert!(needs_maintenance(1, 0.0, 0.2));
+        assert!(!needs_maintenance(199, 1000.0, 0.2));
+        assert!(needs_maintenance(200, 1000.0, 0.2));
+        /* reltuples is -1 before the first vacuum or analyze */
+        assert!(needs_maintenance(1, -1.0, 0.2));
+    }
+}
@@ -0,0 +1,165 @@
+//! Checks `ALTER INDEX ... SET/RESET (...)` on diskann indexes.
+//!
+//! The build options are copied to the meta page when the index is built, so changing them
+//! afterwards would have no effect, or an effect only after a REINDEX. Only the options in
+//! `ALTERABLE_OPTIONS` may be changed; anything else is rejected before the catalog is touched.
+
+use std::ffi::CStr;
+
+use pgrx::{pg_sys, *};
+
+use super::{options::ALTERABLE_OPTIONS, storage_common::is_diskann_index};
+
+static mut PREV_PROCESS_UTILITY_HOOK: pg_sys::ProcessUtility_hook_type = None;
+
+pub unsafe fn init() {
+    PREV_PROCESS_UTILITY_HOOK = pg_sys::ProcessUtility_hook;
+    pg_sys::ProcessUtility_hook = Some(process_utility_hook);
+}
+
+#[pg_guard]
+#[allow(clippy::too_many_arguments)]
+unsafe extern "C" fn process_utility_hook(
+    pstmt: *mut pg_sys::PlannedStmt,
+    query_string: *const std::os::raw::c_char,
+    read_only_tree: bool,
+    context: pg_sys::ProcessUtilityContext,
+    params: pg_sys::ParamListInfo,
+    query_env: *mut pg_sys::QueryEnvironment,
+    dest: *mut pg_sys::DestReceiver,
+    qc: *mut pg_sys::QueryCompletion,
+) {
+    check_alter_index((*pstmt).utilityStmt);
+
+    match PREV_PROCESS_UTILITY_HOOK {
+        Some(prev_hook) => prev_hook(
+            pstmt,
+            query_string,
+            read_only_tree,
+            context,
+            params,
+            query_env,
+            dest,
+            qc,
+        ),
+        None => pg_sys::standard_ProcessUtility(
+            pstmt,
+            query_string,
+            read_only_tree,
+            context,
+            params,
+            query_env,
+            dest,
+            qc,
+        ),
+    }
+}
+
+unsafe fn check_alter_index(stmt: *mut pg_sys::Node) {
+    if !is_a(stmt, pg_sys::NodeTag::T_AlterTableStmt) {
+        return;
+    }
+    let stmt = stmt as *mut pg_sys::AlterTableStmt;
+
+    let cmds = PgList::<pg_sys::AlterTableCmd>::from_pg((*stmt).cmds);
+    for cmd in cmds.iter_ptr() {
+        if (*cmd).subtype != pg_sys::AlterTableType_AT_SetRelOptions
+            && (*cmd).subtype != pg_sys::AlterTableType_AT_ResetRelOptions
+        {
+            continue;
+        }
+
+        let relid = pg_sys::RangeVarGetRelidExtended(
+            (*stmt).relation,
+            pg_sys::NoLock as pg_sys::LOCKMODE,
+            pg_sys::RVROption_RVR_MISSING_OK,
+            None,
+            std::ptr::null_mut(),
+        );
+        if relid == pg_sys::InvalidOid {
+            /* let ALTER report the missing relation */
+            return;
+        }
+        let relation = PgRelation::with_lock(relid, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+        if !is_diskann_index(&relation) {
+            return;
+        }
+
+        let options = PgList::<pg_sys::DefElem>::from_pg((*cmd).def as *mut pg_sys::List);
+        for option in options.iter_ptr() {
+            let name = CStr::from_ptr((*option).defname).to_string_lossy();
+            if !ALTERABLE_OPTIONS.contains(&name.as_ref()) {
+                error!(
+                    "cannot change the \"{}\" option of diskann index \"{}\" without rebuilding it. Only {} can be changed with ALTER INDEX, create a new index to change the other options",
+                    name,
+                    relation.name(),
+                    ALTERABLE_OPTIONS.join(" and "),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use crate::access_method::{
+        guc::{get_query_rescore, get_query_search_list_size},
+        meta_page::MetaPage,
+    };
+
+    #[pg_test]
+    unsafe fn test_alter_index_query_options() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+
+            ALTER INDEX idxtest SET (query_search_list_size = 20, query_rescore = 10);",
+        )?;
+
+        let reloptions: Option<Vec<Option<String>>> =
+            Spi::get_one("SELECT reloptions FROM pg_class WHERE oid = 'idxtest'::regclass")?;
+        assert_eq!(
+            reloptions.unwrap(),
+            vec![
+                Some("query_search_list_size=20".to_string()),
+                Some("query_rescore=10".to_string())
+            ]
+        );
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by embedding <=> '[1,1,1]') SELECT count(*) from cte;",
+        )?;
+        assert_eq!(res, Some(300));
+
+        /* the index options replace the server defaults, a value set in the session wins */
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let meta_page = MetaPage::fetch(&indexrel);
+        assert_eq!(get_query_search_list_size(&indexrel, &meta_page), 20);
+        assert_eq!(get_query_rescore(&indexrel, &meta_page), 10);
+        Spi::run("SET LOCAL diskann.query_search_list_size = 30")?;
+        assert_eq!(get_query_search_list_size(&indexrel, &meta_page), 30);
+        assert_eq!(get_query_rescore(&indexrel, &meta_page), 10);
+
+        Spi::run("ALTER INDEX idxtest RESET (query_rescore)")?;
+        Ok(())
+    }
+
+    #[pg_test(error = "cannot change the \"num_neighbors\" option of diskann index \"idxtest\" without rebuilding it. Only query_search_list_size and query_rescore can be changed with ALTER INDEX, create a new index to change the other options")]
+    unsafe fn test_alter_index_build_option() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            ALTER INDEX idxtest SET (num_neighbors = 20);",
+        )
+    }
+}
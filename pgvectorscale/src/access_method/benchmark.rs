@@ -0,0 +1,204 @@
+//! Functions to benchmark diskann indexes inside the database: a generator of synthetic
+//! datasets and a runner of timed query batches.
+
+use std::time::Instant;
+
+use pgrx::{pg_sys, *};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use super::{
+    guc::{get_query_rescore, get_query_search_list_size},
+    meta_page::MetaPage,
+    plain_storage::PlainStorage,
+    recall::{scan_heap_pointers, VectorReader},
+    sbq::SbqSpeedupStorage,
+    scan::search_heap_pointers,
+    stats::GreedySearchStats,
+    storage::StorageType,
+    storage_common::open_diskann_index,
+};
+
+/// Samples a normal distribution with the Box-Muller transform.
+fn sample_normal(rng: &mut SmallRng, stddev: f64) -> f64 {
+    let u1: f64 = 1.0 - rng.gen::<f64>(); /* in (0, 1] so that the log is finite */
+    let u2: f64 = rng.gen();
+    stddev * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Generates `num_vectors` vectors of `num_dimensions` dimensions, drawn from `num_clusters`
+/// Gaussian clusters with centers uniformly distributed in [-1, 1] in every dimension. The same
+/// `seed` generates the same vectors. The vectors are `real[]` and can be cast to `vector`.
+#[pg_extern(volatile)]
+pub fn diskann_generate_vectors(
+    num_vectors: i32,
+    num_dimensions: i32,
+    num_clusters: default!(i32, 10),
+    stddev: default!(f64, 0.1),
+    seed: default!(i64, 0),
+) -> SetOfIterator<'static, Vec<f32>> {
+    if num_vectors < 0 {
+        error!("num_vectors must not be negative");
+    }
+    if num_dimensions <= 0 {
+        error!("num_dimensions must be positive");
+    }
+    if num_clusters <= 0 {
+        error!("num_clusters must be positive");
+    }
+    if stddev < 0.0 {
+        error!("stddev must not be negative");
+    }
+
+    let mut rng = SmallRng::seed_from_u64(seed as u64);
+    let centers: Vec<Vec<f64>> = (0..num_clusters)
+        .map(|_| {
+            (0..num_dimensions)
+                .map(|_| rng.gen_range(-1.0..=1.0))
+                .collect()
+        })
+        .collect();
+
+    SetOfIterator::new((0..num_vectors).map(move |_| {
+        let center = &centers[rng.gen_range(0..centers.len())];
+        center
+            .iter()
+            .map(|c| (c + sample_normal(&mut rng, stddev)) as f32)
+            .collect()
+    }))
+}
+
+/// The value below which `fraction` of the sorted `values` fall.
+pub fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (sorted.len() as f64 * fraction).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Runs `num_queries` searches for the `k` nearest rows with the current search settings,
+/// using the vectors of sampled rows of the index as queries, and reports the distribution of
+/// their latencies in milliseconds together with the average scan counters per query.
+///
+/// The searches read the index through the buffer cache like regular index scans, but the
+/// latencies don't include the planning and executor overhead of a query.
+#[pg_extern(volatile)]
+pub fn diskann_benchmark(
+    index: pg_sys::Oid,
+    num_queries: default!(i32, 100),
+    k: default!(i32, 10),
+) -> TableIterator<
+    'static,
+    (
+        name!(num_queries, i32),
+        name!(avg_ms, f64),
+        name!(p50_ms, f64),
+        name!(p90_ms, f64),
+        name!(p99_ms, f64),
+        name!(max_ms, f64),
+        name!(avg_index_reads, f64),
+        name!(avg_heap_reads, f64),
+        name!(avg_distance_comparisons, f64),
+        name!(avg_quantized_distance_comparisons, f64),
+        name!(avg_visited_nodes, f64),
+    ),
+> {
+    if num_queries <= 0 {
+        error!("num_queries must be positive");
+    }
+    if k <= 0 {
+        error!("k must be positive");
+    }
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let meta_page = MetaPage::fetch(&index_relation);
+
+    let (_, sample) = match meta_page.get_storage_type() {
+        StorageType::Plain => scan_heap_pointers::<PlainStorage>(&index_relation, num_queries as _),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            scan_heap_pointers::<SbqSpeedupStorage>(&index_relation, num_queries as _)
+        }
+    };
+
+    let search_list_size = get_query_search_list_size(&index_relation, &meta_page);
+    let resort_size = get_query_rescore(&index_relation, &meta_page);
+    let mut reader = VectorReader::new(&index_relation, &heap_relation, &meta_page);
+    let mut stats = GreedySearchStats::new();
+    let mut latencies = Vec::with_capacity(sample.len());
+    for heap_pointer in sample {
+        let Some(query) = reader.read(heap_pointer) else {
+            continue;
+        };
+        let start = Instant::now();
+        search_heap_pointers(
+            &index_relation,
+            &heap_relation,
+            query,
+            k as _,
+            search_list_size,
+            resort_size,
+            &mut stats,
+        );
+        latencies.push(start.elapsed().as_secs_f64() * 1000.0);
+    }
+    if latencies.is_empty() {
+        error!("the index \"{}\" is empty", index_relation.name());
+    }
+
+    latencies.sort_by(|a, b| a.total_cmp(b));
+    let n = latencies.len() as f64;
+    TableIterator::once((
+        latencies.len() as i32,
+        latencies.iter().sum::<f64>() / n,
+        percentile(&latencies, 0.5),
+        percentile(&latencies, 0.9),
+        percentile(&latencies, 0.99),
+        latencies[latencies.len() - 1],
+        stats.get_node_reads() as f64 / n,
+        stats.get_node_heap_reads() as f64 / n,
+        stats.get_total_distance_comparisons() as f64 / n,
+        stats.get_quantized_distance_comparisons() as f64 / n,
+        stats.get_visited_nodes() as f64 / n,
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_benchmark() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(16));
+
+            INSERT INTO test(embedding)
+            SELECT v::vector FROM diskann_generate_vectors(500, 16, num_clusters => 5, seed => 42) v;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);",
+        )?;
+
+        /* the same seed generates the same vectors */
+        let num_different = Spi::get_one::<i64>(
+            "SELECT count(*) FROM (
+                SELECT v FROM diskann_generate_vectors(500, 16, num_clusters => 5, seed => 42) v
+                EXCEPT
+                SELECT embedding::real[] FROM test) d",
+        )?;
+        assert_eq!(num_different, Some(0));
+
+        let (num_queries, p50_ms, p99_ms) = Spi::get_three::<i32, f64, f64>(
+            "SELECT num_queries, p50_ms, p99_ms FROM diskann_benchmark('idxtest'::regclass, num_queries => 20)",
+        )?;
+        assert_eq!(num_queries, Some(20));
+        assert!(p50_ms.unwrap() <= p99_ms.unwrap());
+
+        let avg_index_reads = Spi::get_one::<f64>(
+            "SELECT avg_index_reads FROM diskann_benchmark('idxtest'::regclass, num_queries => 20)",
+        )?;
+        assert!(avg_index_reads.unwrap() > 0.0);
+        Ok(())
+    }
+}
@@ -3,17 +3,34 @@ use std::time::Instant;
 use pgrx::pg_sys::{pgstat_progress_update_param, AsPgCStr};
 use pgrx::*;
 
+use crate::access_method::activity;
+use crate::access_method::build_checkpoint::{row_hash, BuildCheckpoint, RestoredBuild};
+use crate::access_method::entry_points::EntryPointSampler;
+use crate::access_method::full_vectors::{FullVectorBuilder, FullVectorStore, FullVectorType};
 use crate::access_method::graph::Graph;
+use crate::access_method::graph_import::ImportedNodes;
 use crate::access_method::graph_neighbor_store::GraphNeighborStore;
-use crate::access_method::options::TSVIndexOptions;
-use crate::access_method::pg_vector::PgVector;
-use crate::access_method::stats::{InsertStats, WriteStats};
+#[cfg(feature = "build_offload")]
+use crate::access_method::guc::get_build_offload_backend;
+use crate::access_method::guc::TSV_BUILD_FLUSH_AFTER;
+use crate::access_method::options::{TSVIndexOptions, ARRAY_DIMENSIONS_DEFAULT_SENTINEL};
+use crate::access_method::payload::{check_payload_columns, get_num_payload_columns, NodePayload};
+use crate::access_method::pca::PcaTrainer;
+use crate::access_method::pg_vector::{PgVector, VectorType};
+use crate::access_method::result_cache;
+use crate::access_method::stats::{InsertStats, IoUsage, StatsNodeModify, WriteStats};
+use crate::access_method::storage_common::{open_diskann_index, IndexPredicate, IndexedAttribute};
+use crate::access_method::{tenant, time_buckets};
 
 use crate::util::page::PageType;
+use crate::util::table_slot::TableSlot;
 use crate::util::tape::Tape;
 use crate::util::*;
 
-use self::ports::PROGRESS_CREATE_IDX_SUBPHASE;
+use self::ports::{
+    PROGRESS_CREATE_IDX_BUILD_MEMORY, PROGRESS_CREATE_IDX_BUILD_WAL_BYTES,
+    PROGRESS_CREATE_IDX_SUBPHASE,
+};
 
 use super::graph_neighbor_store::BuilderNeighborCache;
 use super::sbq::SbqSpeedupStorage;
@@ -36,6 +53,49 @@ struct BuildState<'a, 'b> {
     graph: Graph<'b>,
     started: Instant,
     stats: InsertStats,
+    num_payload_columns: usize,
+    entry_points: Option<EntryPointSampler>,
+    checkpoint: Option<BuildCheckpoint>,
+    /// Heap pointers and hashes of the rows restored from a checkpoint, sorted by heap pointer.
+    /// The heap scan skips these rows.
+    restored_heap_pointers: Vec<(HeapPointer, u64)>,
+    /// The nodes of a build that imports its graph instead of building it.
+    import: Option<ImportedNodes<'a>>,
+    flusher: BuildFlusher,
+    /* the most memory the in-memory graph and the storage held so far */
+    peak_memory_bytes: usize,
+    /* the usage of the backend when the graph build started */
+    io_started: IoUsage,
+}
+
+/// Writes out the dirty buffers of the index every `diskann.build_flush_after` nodes written
+/// by the build. Otherwise a build dirties a large part of shared buffers, which evicts the
+/// working set of other sessions when the buffers are reused and makes the next checkpoint
+/// write them all at once.
+struct BuildFlusher {
+    flush_after: usize,
+    num_since_flush: usize,
+}
+
+impl BuildFlusher {
+    fn new() -> Self {
+        Self {
+            flush_after: TSV_BUILD_FLUSH_AFTER.get() as usize,
+            num_since_flush: 0,
+        }
+    }
+
+    /// Records that the build wrote `num_nodes` nodes and flushes if it's due.
+    fn record_nodes(&mut self, index: &PgRelation, num_nodes: usize) {
+        if self.flush_after == 0 {
+            return;
+        }
+        self.num_since_flush += num_nodes;
+        if self.num_since_flush >= self.flush_after {
+            unsafe { pg_sys::FlushRelationBuffers(index.as_ptr()) };
+            self.num_since_flush = 0;
+        }
+    }
 }
 
 impl<'a, 'b> BuildState<'a, 'b> {
@@ -45,7 +105,13 @@ impl<'a, 'b> BuildState<'a, 'b> {
         graph: Graph<'b>,
         page_type: PageType,
     ) -> Self {
-        let tape = unsafe { Tape::new(index_relation, page_type) };
+        let tape =
+            unsafe { Tape::with_fillfactor(index_relation, page_type, meta_page.get_fillfactor()) };
+        let entry_points = if meta_page.get_num_entry_points() > 1 {
+            Some(EntryPointSampler::new())
+        } else {
+            None
+        };
 
         BuildState {
             memcxt: PgMemoryContexts::new("diskann build context"),
@@ -55,6 +121,51 @@ impl<'a, 'b> BuildState<'a, 'b> {
             graph: graph,
             started: Instant::now(),
             stats: InsertStats::new(),
+            num_payload_columns: get_num_payload_columns(index_relation),
+            entry_points,
+            checkpoint: None,
+            restored_heap_pointers: Vec::new(),
+            import: None,
+            flusher: BuildFlusher::new(),
+            peak_memory_bytes: 0,
+            io_started: IoUsage::current(),
+        }
+    }
+
+    /// Reports the memory the in-memory graph and `storage` hold and the WAL written since the
+    /// start of the graph build in the progress of the build, so that they can be watched from
+    /// other sessions in `diskann_build_progress`.
+    fn record_progress<S: Storage>(&mut self, storage: &S) {
+        let graph_bytes = match self.graph.get_neighbor_store() {
+            GraphNeighborStore::Builder(neighbors) => neighbors.get_memory_bytes(),
+            GraphNeighborStore::Disk => 0,
+        };
+        let memory_bytes = graph_bytes + storage.get_memory_bytes();
+        self.peak_memory_bytes = self.peak_memory_bytes.max(memory_bytes);
+        let wal_bytes = IoUsage::since(&self.io_started).wal_bytes;
+        unsafe {
+            pgstat_progress_update_param(PROGRESS_CREATE_IDX_BUILD_MEMORY, memory_bytes as i64);
+            pgstat_progress_update_param(PROGRESS_CREATE_IDX_BUILD_WAL_BYTES, wal_bytes);
+        }
+    }
+
+    /// Continues the build from a checkpoint: writes the nodes it saved, whose rows the heap
+    /// scan then skips. Must be called before any other node is written.
+    fn resume(&mut self, index: &PgRelation, mut restored: RestoredBuild) {
+        notice!(
+            "Resuming the index build from a checkpoint of {} tuples",
+            restored.get_num_nodes()
+        );
+        let (first_node, heap_pointers) = restored.write_nodes(&mut self.tape);
+        self.restored_heap_pointers = heap_pointers;
+        self.ntuples = self.restored_heap_pointers.len();
+        if let Some(first_node) = first_node {
+            /* the first node of the build became the entry point when it was inserted */
+            self.graph
+                .set_init_ids(index, vec![first_node], &mut self.stats);
+        }
+        if self.entry_points.is_some() {
+            self.entry_points = restored.take_entry_points();
         }
     }
 }
@@ -77,19 +188,111 @@ pub extern "C" fn ambuild(
         opt.get_storage_type(),
     );
 
-    let dimensions = index_relation.tuple_desc().get(0).unwrap().atttypmod;
-    assert!(dimensions > 0 && dimensions < 2000);
-    let meta_page = unsafe { MetaPage::create(&index_relation, dimensions as _, opt) };
+    check_payload_columns(&index_relation);
+    let dimensions = get_num_dimensions(&heap_relation, &index_relation, &opt);
+    let meta_page = unsafe { MetaPage::create(&index_relation, dimensions, opt) };
 
-    let ntuples = do_heap_scan(index_info, &heap_relation, &index_relation, meta_page);
+    let (heap_tuples, index_tuples) =
+        do_heap_scan(index_info, &heap_relation, &index_relation, meta_page);
+    result_cache::invalidate(&index_relation);
+    activity::reset(&index_relation);
 
     let mut result = unsafe { PgBox::<pg_sys::IndexBuildResult>::alloc0() };
-    result.heap_tuples = ntuples as f64;
-    result.index_tuples = ntuples as f64;
+    result.heap_tuples = heap_tuples;
+    result.index_tuples = index_tuples as f64;
 
     result.into_pg()
 }
 
+/// The number of dimensions comes from the typmod of the indexed column. Expression indexes
+/// only have a typmod if the expression declares one, e.g. through a cast to `vector(n)`.
+///
+/// float4[] columns have no typmod. For those, it's the length of the first array in the table,
+/// or the `array_dimensions` option on an empty table. `num_dimensions` keeps meaning the number
+/// of dimensions to index.
+fn get_num_dimensions(
+    heap_relation: &PgRelation,
+    index_relation: &PgRelation,
+    opt: &PgBox<TSVIndexOptions>,
+) -> u32 {
+    if VectorType::from_index(index_relation) == VectorType::Float4Array {
+        let array_dimensions = opt.array_dimensions;
+        return match unsafe { get_first_value(heap_relation, index_relation) } {
+            Some(datum) => {
+                let dimensions = unsafe { PgVector::get_array_num_dimensions(datum) };
+                if array_dimensions != ARRAY_DIMENSIONS_DEFAULT_SENTINEL
+                    && array_dimensions != dimensions
+                {
+                    error!(
+                        "the arrays of the table have {} elements, but the array_dimensions option is {}",
+                        dimensions,
+                        array_dimensions
+                    );
+                }
+                dimensions
+            }
+            None if array_dimensions != ARRAY_DIMENSIONS_DEFAULT_SENTINEL => array_dimensions,
+            None => error!(
+                "cannot determine the number of dimensions of an empty float4[] column. Set the array_dimensions option of the index"
+            ),
+        };
+    }
+
+    let dimensions = index_relation.tuple_desc().get(0).unwrap().atttypmod;
+    if dimensions < 0 {
+        error!(
+            "cannot determine the number of dimensions of the indexed column. If this is an expression index, cast the expression to vector with explicit dimensions, e.g. ((expr)::vector(768))"
+        );
+    }
+    if dimensions == 0 || dimensions >= 2000 {
+        error!(
+            "the indexed column has {} dimensions, but diskann indexes support 1 to 1999 dimensions",
+            dimensions
+        );
+    }
+    dimensions as _
+}
+
+/// Returns the indexed value of the first row of the table where it is not NULL. For a partial
+/// index, rows that don't match the predicate are skipped.
+unsafe fn get_first_value(
+    heap_relation: &PgRelation,
+    index_relation: &PgRelation,
+) -> Option<pg_sys::Datum> {
+    let table_am = heap_relation.rd_tableam;
+    let scan = (*table_am).scan_begin.unwrap()(
+        heap_relation.as_ptr(),
+        pg_sys::GetTransactionSnapshot(),
+        0,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+        pg_sys::ScanOptions_SO_TYPE_SEQSCAN | pg_sys::ScanOptions_SO_ALLOW_PAGEMODE,
+    );
+    let slot = TableSlot::new(heap_relation);
+    let attribute = IndexedAttribute::new(index_relation);
+    let predicate = IndexPredicate::new(index_relation);
+
+    let mut first_value = None;
+    while (*table_am).scan_getnextslot.unwrap()(
+        scan,
+        pg_sys::ScanDirection_ForwardScanDirection,
+        slot.as_ptr(),
+    ) {
+        if let Some(predicate) = &predicate {
+            if !predicate.matches(&slot) {
+                continue;
+            }
+        }
+        if let Some(datum) = attribute.get_datum(&slot) {
+            /* copy the value out of the slot before it's dropped */
+            first_value = Some(pg_sys::pg_detoast_datum_copy(datum.cast_mut_ptr()).into());
+            break;
+        }
+    }
+    (*table_am).scan_end.unwrap()(scan);
+    first_value
+}
+
 #[pg_guard]
 pub unsafe extern "C" fn aminsert(
     indexrel: pg_sys::Relation,
@@ -104,13 +307,22 @@ pub unsafe extern "C" fn aminsert(
     let index_relation = unsafe { PgRelation::from_pg(indexrel) };
     let heap_relation = unsafe { PgRelation::from_pg(heaprel) };
     let mut meta_page = MetaPage::fetch(&index_relation);
-    let vec = PgVector::from_pg_parts(values, isnull, 0, &meta_page, true, false);
+    let vec = PgVector::from_pg_parts(
+        values,
+        isnull,
+        0,
+        &meta_page,
+        true,
+        meta_page.get_full_vector_type() != FullVectorType::None,
+    );
     if let None = vec {
         //todo handle NULLs?
         return false;
     }
     let vec = vec.unwrap();
     let heap_pointer = ItemPointer::with_item_pointer_data(*heap_tid);
+    let payload =
+        NodePayload::from_pg_parts(values, isnull, get_num_payload_columns(&index_relation));
 
     let mut storage = meta_page.get_storage_type();
     let mut stats = InsertStats::new();
@@ -126,6 +338,7 @@ pub unsafe extern "C" fn aminsert(
                 &index_relation,
                 vec,
                 heap_pointer,
+                &payload,
                 &mut meta_page,
                 &mut stats,
             );
@@ -142,11 +355,14 @@ pub unsafe extern "C" fn aminsert(
                 &index_relation,
                 vec,
                 heap_pointer,
+                &payload,
                 &mut meta_page,
                 &mut stats,
             );
         }
     }
+    result_cache::invalidate_at_commit(&index_relation);
+    activity::record_inserts(&index_relation, 1);
     false
 }
 
@@ -155,22 +371,32 @@ unsafe fn insert_storage<S: Storage>(
     index_relation: &PgRelation,
     vector: PgVector,
     heap_pointer: ItemPointer,
+    payload: &NodePayload,
     meta_page: &mut MetaPage,
     stats: &mut InsertStats,
 ) {
-    let mut tape = Tape::new(&index_relation, S::page_type());
-    let index_pointer = storage.create_node(
-        vector.to_index_slice(),
-        heap_pointer,
-        &meta_page,
-        &mut tape,
-        stats,
-    );
+    let mut tape = Tape::resume(&index_relation, S::page_type());
+    let index_pointer =
+        storage.create_node(&vector, heap_pointer, payload, &meta_page, &mut tape, stats);
 
+    let buckets = get_node_buckets(index_relation, meta_page, payload, stats);
     let mut graph = Graph::new(GraphNeighborStore::Disk, meta_page);
+    graph.set_buckets(buckets);
     graph.insert(&index_relation, index_pointer, vector, storage, stats)
 }
 
+/// The subgraph of a new node, for indexes split into tenant or time buckets.
+fn get_node_buckets<S: StatsNodeModify>(
+    index: &PgRelation,
+    meta_page: &mut MetaPage,
+    payload: &NodePayload,
+    stats: &mut S,
+) -> Option<Vec<u32>> {
+    tenant::get_node_bucket(meta_page, payload)
+        .or_else(|| time_buckets::get_node_bucket(index, meta_page, payload, stats))
+        .map(|bucket| vec![bucket])
+}
+
 #[pg_guard]
 pub extern "C" fn ambuildempty(_index_relation: pg_sys::Relation) {
     panic!("ambuildempty: not yet implemented")
@@ -180,16 +406,35 @@ fn do_heap_scan<'a>(
     index_info: *mut pg_sys::IndexInfo,
     heap_relation: &'a PgRelation,
     index_relation: &'a PgRelation,
-    meta_page: MetaPage,
-) -> usize {
+    mut meta_page: MetaPage,
+) -> (f64, usize) {
     let storage = meta_page.get_storage_type();
+    let mut write_stats = WriteStats::new();
+
+    if meta_page.uses_pca() {
+        meta_page = train_pca(
+            index_info,
+            heap_relation,
+            index_relation,
+            &meta_page,
+            &mut write_stats,
+        );
+    }
+
+    let import = ImportedNodes::for_build(heap_relation, index_relation);
+    /* the checkpoints only store the entry points of a single graph */
+    let mut checkpoint = if import.is_none() && !meta_page.is_partitioned() {
+        BuildCheckpoint::open(index_relation, heap_relation, &meta_page)
+    } else {
+        None
+    };
+    let mut restored = checkpoint.as_mut().and_then(|c| c.restore());
+    let neighbors = restored
+        .as_mut()
+        .map_or_else(BuilderNeighborCache::new, |r| r.take_neighbors());
 
     let mut mp2 = meta_page.clone();
-    let graph = Graph::new(
-        GraphNeighborStore::Builder(BuilderNeighborCache::new()),
-        &mut mp2,
-    );
-    let mut write_stats = WriteStats::new();
+    let graph = Graph::new(GraphNeighborStore::Builder(neighbors), &mut mp2);
     match storage {
         StorageType::Plain => {
             let mut plain = PlainStorage::new_for_build(
@@ -197,84 +442,291 @@ fn do_heap_scan<'a>(
                 heap_relation,
                 meta_page.get_distance_function(),
             );
+            #[cfg(feature = "build_offload")]
+            plain.set_offload(
+                get_build_offload_backend(),
+                meta_page.get_offload_distance(),
+            );
             plain.start_training(&meta_page);
             let page_type = PlainStorage::page_type();
             let mut bs = BuildState::new(index_relation, meta_page, graph, page_type);
+            if let Some(restored) = restored {
+                bs.resume(index_relation, restored);
+            }
+            bs.checkpoint = checkpoint;
+            bs.import = import;
+            enter_build_phase(&mut write_stats, BUILD_PHASE_BUILDING_GRAPH);
             let mut state = StorageBuildState::Plain(&mut plain, &mut bs);
 
-            unsafe {
-                pg_sys::IndexBuildHeapScan(
-                    heap_relation.as_ptr(),
-                    index_relation.as_ptr(),
+            let heap_tuples = unsafe {
+                index_build_heap_scan(
+                    heap_relation,
+                    index_relation,
                     index_info,
                     Some(build_callback),
                     &mut state,
-                );
+                )
+            };
+            if let Some(import) = bs.import.take() {
+                import.set_neighbors(index_relation, &mut bs.graph, &plain, &mut bs.stats);
             }
 
-            finalize_index_build(&mut plain, &mut bs, write_stats)
+            enter_build_phase(&mut write_stats, BUILD_PHASE_FINALIZING_GRAPH);
+            let index_tuples =
+                finalize_index_build(index_relation, &mut plain, &mut bs, write_stats);
+            (heap_tuples, index_tuples)
         }
         StorageType::SbqSpeedup | StorageType::SbqCompression => {
             let mut bq =
                 SbqSpeedupStorage::new_for_build(index_relation, heap_relation, &meta_page);
 
             let page_type = SbqSpeedupStorage::page_type();
-
-            unsafe {
-                pgstat_progress_update_param(PROGRESS_CREATE_IDX_SUBPHASE, BUILD_PHASE_TRAINING);
+            let quantizer_source = TSVIndexOptions::from_relation(index_relation)
+                .get_quantizer_source()
+                .map(|name| open_quantizer_source(&name));
+
+            let mut bs = match quantizer_source {
+                Some(source) => {
+                    bq.copy_quantizer_from(&source, &meta_page, &mut write_stats);
+                    BuildState::new(index_relation, meta_page, graph, page_type)
+                }
+                None => match restored.as_mut().and_then(|r| r.take_quantizer()) {
+                    Some(quantizer) => {
+                        let bs = BuildState::new(index_relation, meta_page, graph, page_type);
+                        bq.restore_quantizer(quantizer, &mut write_stats);
+                        bs
+                    }
+                    None => {
+                        enter_build_phase(&mut write_stats, BUILD_PHASE_TRAINING);
+
+                        bq.start_training(&meta_page);
+
+                        let mut bs = BuildState::new(index_relation, meta_page, graph, page_type);
+                        let mut state = StorageBuildState::SbqSpeedup(&mut bq, &mut bs);
+
+                        unsafe {
+                            pg_sys::IndexBuildHeapScan(
+                                heap_relation.as_ptr(),
+                                index_relation.as_ptr(),
+                                index_info,
+                                Some(build_callback_bq_train),
+                                &mut state,
+                            );
+                        }
+                        bq.finish_training(&mut write_stats);
+                        bs
+                    }
+                },
+            };
+            if let Some(restored) = restored {
+                bs.resume(index_relation, restored);
             }
-
-            bq.start_training(&meta_page);
-
-            let mut bs = BuildState::new(index_relation, meta_page, graph, page_type);
-            let mut state = StorageBuildState::SbqSpeedup(&mut bq, &mut bs);
-
-            unsafe {
-                pg_sys::IndexBuildHeapScan(
-                    heap_relation.as_ptr(),
-                    index_relation.as_ptr(),
-                    index_info,
-                    Some(build_callback_bq_train),
-                    &mut state,
-                );
+            if let Some(checkpoint) = checkpoint.as_mut() {
+                checkpoint.set_quantizer(bq.get_quantizer_state());
             }
-            bq.finish_training(&mut write_stats);
+            bs.checkpoint = checkpoint;
+            bs.import = import;
 
-            unsafe {
-                pgstat_progress_update_param(
-                    PROGRESS_CREATE_IDX_SUBPHASE,
-                    BUILD_PHASE_BUILDING_GRAPH,
-                );
-            }
+            enter_build_phase(&mut write_stats, BUILD_PHASE_BUILDING_GRAPH);
 
             let mut state = StorageBuildState::SbqSpeedup(&mut bq, &mut bs);
 
-            unsafe {
-                pg_sys::IndexBuildHeapScan(
-                    heap_relation.as_ptr(),
-                    index_relation.as_ptr(),
+            let heap_tuples = unsafe {
+                index_build_heap_scan(
+                    heap_relation,
+                    index_relation,
                     index_info,
                     Some(build_callback),
                     &mut state,
-                );
+                )
+            };
+            if let Some(import) = bs.import.take() {
+                import.set_neighbors(index_relation, &mut bs.graph, &bq, &mut bs.stats);
             }
 
-            unsafe {
-                pgstat_progress_update_param(
-                    PROGRESS_CREATE_IDX_SUBPHASE,
-                    BUILD_PHASE_FINALIZING_GRAPH,
+            enter_build_phase(&mut write_stats, BUILD_PHASE_FINALIZING_GRAPH);
+            let index_tuples = finalize_index_build(index_relation, &mut bq, &mut bs, write_stats);
+            if let Some(store) = FullVectorStore::for_index(index_relation, &bs.meta_page) {
+                write_full_vectors(
+                    index_info,
+                    heap_relation,
+                    index_relation,
+                    &bs.meta_page,
+                    store,
                 );
             }
-            finalize_index_build(&mut bq, &mut bs, write_stats)
+            (heap_tuples, index_tuples)
         }
     }
 }
 
+/// Like `pg_sys::IndexBuildHeapScan`, but returns the number of live heap tuples scanned. For a
+/// partial index only the tuples matching the predicate reach the callback, so this is more
+/// than the number of tuples indexed.
+unsafe fn index_build_heap_scan<T>(
+    heap_relation: &PgRelation,
+    index_relation: &PgRelation,
+    index_info: *mut pg_sys::IndexInfo,
+    build_callback: pg_sys::IndexBuildCallback,
+    build_callback_state: *mut T,
+) -> f64 {
+    let table_am = heap_relation.rd_tableam;
+    (*table_am).index_build_range_scan.unwrap()(
+        heap_relation.as_ptr(),
+        index_relation.as_ptr(),
+        index_info,
+        true,
+        false,
+        true,
+        0,
+        pg_sys::InvalidBlockNumber,
+        build_callback,
+        build_callback_state.cast(),
+        std::ptr::null_mut(),
+    )
+}
+
+/// Opens the index named by the quantizer_source option.
+fn open_quantizer_source(name: &str) -> PgRelation {
+    let name = std::ffi::CString::new(name).unwrap();
+    let oid = unsafe {
+        direct_function_call::<pg_sys::Oid>(pg_sys::regclassin, &[name.as_c_str().into_datum()])
+    }
+    .unwrap();
+    open_diskann_index(oid, pg_sys::AccessShareLock as pg_sys::LOCKMODE)
+}
+
+struct FullVectorBuildState<'a, 'b> {
+    meta_page: &'b MetaPage,
+    builder: FullVectorBuilder<'a>,
+    stats: WriteStats,
+}
+
+/// Writes the full vectors of the nodes after the graph, so that the graph and the full vectors
+/// take separate block ranges. The vectors are read from the heap again instead of being kept
+/// during the build.
+fn write_full_vectors(
+    index_info: *mut pg_sys::IndexInfo,
+    heap_relation: &PgRelation,
+    index_relation: &PgRelation,
+    meta_page: &MetaPage,
+    store: FullVectorStore,
+) {
+    unsafe {
+        pgstat_progress_update_param(
+            PROGRESS_CREATE_IDX_SUBPHASE,
+            BUILD_PHASE_WRITING_FULL_VECTORS,
+        );
+    }
+
+    let mut state = FullVectorBuildState {
+        meta_page,
+        builder: FullVectorBuilder::new(index_relation, store),
+        stats: WriteStats::new(),
+    };
+    unsafe {
+        pg_sys::IndexBuildHeapScan(
+            heap_relation.as_ptr(),
+            index_relation.as_ptr(),
+            index_info,
+            Some(build_callback_full_vectors),
+            &mut state,
+        );
+        state.builder.finish(&mut state.stats);
+    }
+    debug1!(
+        "Wrote {} full vectors in {}s",
+        state.stats.nodes_written,
+        Instant::now()
+            .duration_since(state.stats.started)
+            .as_secs_f64()
+    );
+}
+
+#[pg_guard]
+unsafe extern "C" fn build_callback_full_vectors(
+    _index: pg_sys::Relation,
+    ctid: pg_sys::ItemPointer,
+    values: *mut pg_sys::Datum,
+    isnull: *mut bool,
+    _tuple_is_alive: bool,
+    state: *mut std::os::raw::c_void,
+) {
+    let state = (state as *mut FullVectorBuildState).as_mut().unwrap();
+    let vec = PgVector::from_pg_parts(values, isnull, 0, state.meta_page, false, true);
+    if let Some(vec) = vec {
+        let heap_pointer = ItemPointer::with_item_pointer_data(*ctid);
+        state
+            .builder
+            .add(heap_pointer, vec.to_full_slice(), &mut state.stats);
+    }
+}
+
+struct PcaBuildState<'a> {
+    meta_page: &'a MetaPage,
+    trainer: PcaTrainer,
+}
+
+/// Learns the PCA projection from the table and stores it. Returns the meta page with the
+/// projection loaded, it has to be used for the rest of the build.
+fn train_pca(
+    index_info: *mut pg_sys::IndexInfo,
+    heap_relation: &PgRelation,
+    index_relation: &PgRelation,
+    meta_page: &MetaPage,
+    write_stats: &mut WriteStats,
+) -> MetaPage {
+    enter_build_phase(write_stats, BUILD_PHASE_TRAINING_PCA);
+
+    let mut state = PcaBuildState {
+        meta_page,
+        trainer: PcaTrainer::new(meta_page.get_num_dimensions() as usize),
+    };
+    unsafe {
+        pg_sys::IndexBuildHeapScan(
+            heap_relation.as_ptr(),
+            index_relation.as_ptr(),
+            index_info,
+            Some(build_callback_pca_train),
+            &mut state,
+        );
+    }
+
+    let projection = state
+        .trainer
+        .finish(meta_page.get_num_dimensions_to_index() as usize);
+    MetaPage::update_pca_projection(index_relation, projection, write_stats)
+}
+
+#[pg_guard]
+unsafe extern "C" fn build_callback_pca_train(
+    _index: pg_sys::Relation,
+    _ctid: pg_sys::ItemPointer,
+    values: *mut pg_sys::Datum,
+    isnull: *mut bool,
+    _tuple_is_alive: bool,
+    state: *mut std::os::raw::c_void,
+) {
+    let state = (state as *mut PcaBuildState).as_mut().unwrap();
+    let vec = PgVector::from_pg_parts(values, isnull, 0, state.meta_page, false, true);
+    if let Some(vec) = vec {
+        state.trainer.add_sample(vec.to_full_slice());
+    }
+}
+
 fn finalize_index_build<S: Storage>(
+    index: &PgRelation,
     storage: &mut S,
     state: &mut BuildState,
     mut write_stats: WriteStats,
 ) -> usize {
+    state.record_progress(storage);
+    let new_locations = state
+        .graph
+        .place_nodes_breadth_first(index, &mut write_stats);
+    storage.clear_node_cache();
+    state.flusher.record_nodes(index, new_locations.len());
+
     match state.graph.get_neighbor_store() {
         GraphNeighborStore::Builder(builder) => {
             for (&index_pointer, neighbors) in builder.iter() {
@@ -300,6 +752,7 @@ fn finalize_index_build<S: Storage>(
                     neighbors,
                     &mut write_stats,
                 );
+                state.flusher.record_nodes(index, 1);
             }
         }
         GraphNeighborStore::Disk => {
@@ -310,25 +763,56 @@ fn finalize_index_build<S: Storage>(
     debug1!("write done");
     assert_eq!(write_stats.num_nodes, state.ntuples);
 
-    let writing_took = Instant::now()
-        .duration_since(write_stats.started)
-        .as_secs_f64();
-    if write_stats.num_nodes > 0 {
-        debug1!(
-            "Writing took {}s or {}s/tuple.  Avg neighbors: {}",
-            writing_took,
-            writing_took / write_stats.num_nodes as f64,
-            write_stats.num_neighbors / write_stats.num_nodes
-        );
+    /* make the space left by the fillfactor available to inserts */
+    unsafe {
+        state.tape.record_free_space();
+        pg_sys::IndexFreeSpaceMapVacuum(index.as_ptr());
     }
-    if write_stats.prune_stats.calls > 0 {
-        debug1!(
-            "When pruned for cleanup: avg neighbors before/after {}/{} of {} prunes",
-            write_stats.prune_stats.num_neighbors_before_prune / write_stats.prune_stats.calls,
-            write_stats.prune_stats.num_neighbors_after_prune / write_stats.prune_stats.calls,
-            write_stats.prune_stats.calls
+
+    if let Some(entry_points) = state.entry_points.take() {
+        let entry_points: Vec<IndexPointer> = entry_points
+            .choose(
+                state.meta_page.get_num_entry_points() as usize,
+                state.meta_page.get_distance_function(),
+            )
+            .iter()
+            .map(|ip| new_locations[ip])
+            .collect();
+        if !entry_points.is_empty() {
+            MetaPage::update_init_ids(index, entry_points, &mut write_stats);
+        }
+    }
+
+    if let Some(checkpoint) = state.checkpoint.take() {
+        checkpoint.remove();
+    }
+
+    write_stats.phases.finish();
+    let report = BuildReport::new(index, state, &write_stats);
+    if report.num_nodes > 0 {
+        notice!(
+            "Index build done in {:.1}s: {} nodes, avg neighbors {:.1}, {} prunes at the end of the build (avg neighbors before/after {:.1}/{:.1}), {} distance comparisons, nodes read/modified/written {}/{}/{}, buffer usage: {} hits, {} reads, {} dirtied, WAL usage: {} records, {} full page images, {} bytes",
+            report.build_ms / 1000.0,
+            report.num_nodes,
+            report.avg_neighbors,
+            report.num_prunes,
+            report.avg_neighbors_before_prune,
+            report.avg_neighbors_after_prune,
+            report.distance_comparisons,
+            report.nodes_read,
+            report.nodes_modified,
+            report.nodes_written,
+            report.io.buffers_hit,
+            report.io.buffers_read,
+            report.io.buffers_dirtied,
+            report.io.wal_records,
+            report.io.wal_fpi,
+            report.io.wal_bytes,
         );
     }
+    unsafe {
+        LAST_BUILD_REPORT = Some(report);
+    }
     let ntuples = state.ntuples;
 
     warning!("Indexed {} tuples", ntuples);
@@ -336,6 +820,154 @@ fn finalize_index_build<S: Storage>(
     ntuples
 }
 
+/// Summary of an index build, kept for `diskann_last_build_stats`.
+#[derive(Clone, Copy)]
+struct BuildReport {
+    index: pg_sys::Oid,
+    num_nodes: usize,
+    num_restored_nodes: usize,
+    avg_neighbors: f64,
+    num_prunes: usize,
+    avg_neighbors_before_prune: f64,
+    avg_neighbors_after_prune: f64,
+    distance_comparisons: usize,
+    nodes_read: usize,
+    nodes_modified: usize,
+    nodes_written: usize,
+    peak_memory_bytes: usize,
+    io: IoUsage,
+    training_ms: f64,
+    building_graph_ms: f64,
+    finalizing_graph_ms: f64,
+    build_ms: f64,
+}
+
+impl BuildReport {
+    fn new(index: &PgRelation, state: &BuildState, write_stats: &WriteStats) -> Self {
+        let average = |total: usize, count: usize| {
+            if count > 0 {
+                total as f64 / count as f64
+            } else {
+                0.0
+            }
+        };
+        let insert_stats = &state.stats;
+        let prune_stats = &write_stats.prune_stats;
+        BuildReport {
+            index: index.oid(),
+            num_nodes: write_stats.num_nodes,
+            num_restored_nodes: state.restored_heap_pointers.len(),
+            avg_neighbors: average(write_stats.num_neighbors, write_stats.num_nodes),
+            num_prunes: prune_stats.calls,
+            avg_neighbors_before_prune: average(
+                prune_stats.num_neighbors_before_prune,
+                prune_stats.calls,
+            ),
+            avg_neighbors_after_prune: average(
+                prune_stats.num_neighbors_after_prune,
+                prune_stats.calls,
+            ),
+            distance_comparisons: insert_stats
+                .greedy_search_stats
+                .get_total_distance_comparisons()
+                + insert_stats.prune_neighbor_stats.distance_comparisons
+                + prune_stats.distance_comparisons,
+            nodes_read: insert_stats.node_reads + write_stats.nodes_read,
+            nodes_modified: insert_stats.node_modify + write_stats.nodes_modified,
+            nodes_written: insert_stats.node_writes + write_stats.nodes_written,
+            peak_memory_bytes: state.peak_memory_bytes,
+            io: IoUsage::since(&write_stats.io_started),
+            training_ms: write_stats.phases.get_ms(BUILD_PHASE_TRAINING_PCA)
+                + write_stats.phases.get_ms(BUILD_PHASE_TRAINING),
+            building_graph_ms: write_stats.phases.get_ms(BUILD_PHASE_BUILDING_GRAPH),
+            finalizing_graph_ms: write_stats.phases.get_ms(BUILD_PHASE_FINALIZING_GRAPH),
+            build_ms: write_stats.started.elapsed().as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+/// The report of the last index build in this backend. Backends are single threaded.
+static mut LAST_BUILD_REPORT: Option<BuildReport> = None;
+
+/// Returns the statistics of the last diskann index build in the current session, or no row if
+/// the session has not built an index. `build_ms` includes training the quantizer and PCA,
+/// and is split into `training_ms`, for both, `building_graph_ms` and `finalizing_graph_ms`.
+/// `peak_memory_bytes` is the most memory the graph and the quantized vectors held in memory.
+/// The buffer and WAL usage of the build is counted like VACUUM VERBOSE counts it, and shows the
+/// IO cost of options like `storage_layout` and `fillfactor`.
+/// `num_restored_nodes` is the number of nodes restored from a build checkpoint.
+/// Comparing these between versions on the same data shows regressions of the build.
+#[pg_extern(volatile)]
+pub fn diskann_last_build_stats() -> TableIterator<
+    'static,
+    (
+        name!(index, pg_sys::Oid),
+        name!(num_nodes, i64),
+        name!(num_restored_nodes, i64),
+        name!(avg_neighbors, f64),
+        name!(num_prunes, i64),
+        name!(avg_neighbors_before_prune, f64),
+        name!(avg_neighbors_after_prune, f64),
+        name!(distance_comparisons, i64),
+        name!(nodes_read, i64),
+        name!(nodes_modified, i64),
+        name!(nodes_written, i64),
+        name!(peak_memory_bytes, i64),
+        name!(buffers_hit, i64),
+        name!(buffers_read, i64),
+        name!(buffers_dirtied, i64),
+        name!(wal_records, i64),
+        name!(wal_fpi, i64),
+        name!(wal_bytes, i64),
+        name!(training_ms, f64),
+        name!(building_graph_ms, f64),
+        name!(finalizing_graph_ms, f64),
+        name!(build_ms, f64),
+    ),
+> {
+    let report = unsafe { LAST_BUILD_REPORT };
+    TableIterator::new(report.into_iter().map(|r| {
+        (
+            r.index,
+            r.num_nodes as i64,
+            r.num_restored_nodes as i64,
+            r.avg_neighbors,
+            r.num_prunes as i64,
+            r.avg_neighbors_before_prune,
+            r.avg_neighbors_after_prune,
+            r.distance_comparisons as i64,
+            r.nodes_read as i64,
+            r.nodes_modified as i64,
+            r.nodes_written as i64,
+            r.peak_memory_bytes as i64,
+            r.io.buffers_hit,
+            r.io.buffers_read,
+            r.io.buffers_dirtied,
+            r.io.wal_records,
+            r.io.wal_fpi,
+            r.io.wal_bytes,
+            r.training_ms,
+            r.building_graph_ms,
+            r.finalizing_graph_ms,
+            r.build_ms,
+        )
+    }))
+}
+
+/* the builds in progress in all sessions, with the memory and WAL of `BuildState::record_progress` */
+extension_sql!(
+    r#"
+CREATE OR REPLACE VIEW diskann_build_progress AS
+SELECT s.pid, s.datname, s.relid, s.index_relid, s.phase, s.tuples_total, s.tuples_done,
+    p.param18 AS memory_bytes, p.param19 AS wal_bytes
+FROM pg_catalog.pg_stat_progress_create_index s
+JOIN pg_catalog.pg_stat_get_progress_info('CREATE INDEX') p ON p.pid = s.pid
+JOIN pg_catalog.pg_am a ON a.oid = p.param9::oid
+WHERE a.amname = 'diskann';
+"#,
+    name = "diskann_build_progress"
+);
+
 #[pg_guard]
 unsafe extern "C" fn build_callback_bq_train(
     _index: pg_sys::Relation,
@@ -375,14 +1007,32 @@ unsafe extern "C" fn build_callback(
             let vec = PgVector::from_pg_parts(values, isnull, 0, &state.meta_page, true, false);
             if let Some(vec) = vec {
                 let heap_pointer = ItemPointer::with_item_pointer_data(*ctid);
-                build_callback_memory_wrapper(index_relation, heap_pointer, vec, state, *bq);
+                let payload =
+                    NodePayload::from_pg_parts(values, isnull, state.num_payload_columns);
+                build_callback_memory_wrapper(
+                    index_relation,
+                    heap_pointer,
+                    vec,
+                    payload,
+                    state,
+                    *bq,
+                );
             }
         }
         StorageBuildState::Plain(plain, state) => {
             let vec = PgVector::from_pg_parts(values, isnull, 0, &state.meta_page, true, false);
             if let Some(vec) = vec {
                 let heap_pointer = ItemPointer::with_item_pointer_data(*ctid);
-                build_callback_memory_wrapper(index_relation, heap_pointer, vec, state, *plain);
+                let payload =
+                    NodePayload::from_pg_parts(values, isnull, state.num_payload_columns);
+                build_callback_memory_wrapper(
+                    index_relation,
+                    heap_pointer,
+                    vec,
+                    payload,
+                    state,
+                    *plain,
+                );
             }
         }
     }
@@ -393,12 +1043,13 @@ unsafe fn build_callback_memory_wrapper<S: Storage>(
     index: PgRelation,
     heap_pointer: ItemPointer,
     vector: PgVector,
+    payload: NodePayload,
     state: &mut BuildState,
     storage: &mut S,
 ) {
     let mut old_context = state.memcxt.set_as_current();
 
-    build_callback_internal(index, heap_pointer, vector, state, storage);
+    build_callback_internal(index, heap_pointer, vector, payload, state, storage);
 
     old_context.set_as_current();
     state.memcxt.reset();
@@ -409,11 +1060,28 @@ fn build_callback_internal<S: Storage>(
     index: PgRelation,
     heap_pointer: ItemPointer,
     vector: PgVector,
+    payload: NodePayload,
     state: &mut BuildState,
     storage: &mut S,
 ) {
     check_for_interrupts!();
 
+    if let Ok(i) = state
+        .restored_heap_pointers
+        .binary_search_by_key(&heap_pointer, |(hp, _)| *hp)
+    {
+        if state.restored_heap_pointers[i].1
+            != row_hash(vector.to_index_slice(), payload.as_bytes())
+        {
+            state
+                .checkpoint
+                .as_ref()
+                .expect("restored a build without a checkpoint")
+                .discard("has a node of a row that was replaced");
+        }
+        return;
+    }
+
     state.ntuples = state.ntuples + 1;
 
     if state.ntuples % 1000 == 0 {
@@ -429,21 +1097,61 @@ fn build_callback_internal<S: Storage>(
     }
 
     let index_pointer = storage.create_node(
-        vector.to_index_slice(),
+        &vector,
         heap_pointer,
+        &payload,
         &state.meta_page,
         &mut state.tape,
         &mut state.stats,
     );
 
+    state.flusher.record_nodes(&index, 1);
+
+    if let Some(entry_points) = state.entry_points.as_mut() {
+        entry_points.add(index_pointer, vector.to_index_slice());
+    }
+    if let Some(import) = state.import.as_mut() {
+        /* the neighbors are imported after the heap scan */
+        import.add_node(heap_pointer, index_pointer);
+        return;
+    }
+    if let Some(checkpoint) = state.checkpoint.as_mut() {
+        let row_hash = row_hash(vector.to_index_slice(), payload.as_bytes());
+        checkpoint.add_node(heap_pointer, index_pointer, row_hash);
+    }
+
+    let buckets = get_node_buckets(&index, &mut state.meta_page, &payload, &mut state.stats);
+    state.graph.set_buckets(buckets);
     state
         .graph
         .insert(&index, index_pointer, vector, storage, &mut state.stats);
+    if state.ntuples % 1000 == 0 {
+        state.record_progress(storage);
+    }
+
+    if let Some(checkpoint) = state.checkpoint.as_mut() {
+        if checkpoint.is_due(state.ntuples) {
+            let GraphNeighborStore::Builder(neighbors) = state.graph.get_neighbor_store() else {
+                panic!("Should not be using the disk neighbor store during build");
+            };
+            checkpoint.save(&index, neighbors, state.entry_points.as_ref());
+        }
+    }
 }
 
 const BUILD_PHASE_TRAINING: i64 = 0;
 const BUILD_PHASE_BUILDING_GRAPH: i64 = 1;
 const BUILD_PHASE_FINALIZING_GRAPH: i64 = 2;
+const BUILD_PHASE_TRAINING_PCA: i64 = 3;
+const BUILD_PHASE_WRITING_FULL_VECTORS: i64 = 4;
+
+/// Reports `phase` as the subphase of the build and times it.
+fn enter_build_phase(write_stats: &mut WriteStats, phase: i64) {
+    write_stats.phases.enter(phase);
+    unsafe {
+        pgstat_progress_update_param(PROGRESS_CREATE_IDX_SUBPHASE, phase);
+    }
+}
 
 #[pg_guard]
 pub unsafe extern "C" fn ambuildphasename(phasenum: i64) -> *mut ffi::c_char {
@@ -451,6 +1159,8 @@ pub unsafe extern "C" fn ambuildphasename(phasenum: i64) -> *mut ffi::c_char {
         BUILD_PHASE_TRAINING => "training quantizer".as_pg_cstr(),
         BUILD_PHASE_BUILDING_GRAPH => "building graph".as_pg_cstr(),
         BUILD_PHASE_FINALIZING_GRAPH => "finalizing graph".as_pg_cstr(),
+        BUILD_PHASE_TRAINING_PCA => "training PCA projection".as_pg_cstr(),
+        BUILD_PHASE_WRITING_FULL_VECTORS => "writing full vectors".as_pg_cstr(),
         _ => error!("Unknown phase number {}", phasenum),
     }
 }
@@ -735,6 +1445,108 @@ pub mod tests {
         Ok(())
     }
 
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_expression_index_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'), ('[4,5,6]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(((embedding::real[])::vector(3)))
+                WITH ({index_options});
+
+            INSERT INTO test(embedding) VALUES ('[7,8,10]'), (NULL);
+            ",
+        ))?;
+
+        let res: Option<i64> = Spi::get_one(&format!(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by (embedding::real[])::vector(3) <=> '[0,0,1]') SELECT count(*) from cte;",
+        ))?;
+        assert_eq!(3, res.unwrap());
+
+        let first: Option<String> = Spi::get_one(&format!(
+            "   set enable_seqscan = 0;
+                select embedding::text from test order by (embedding::real[])::vector(3) <=> '[7,8,10]' limit 1;",
+        ))?;
+        assert_eq!("[7,8,10]", first.unwrap());
+
+        Spi::run(&format!("drop index idxtest;",))?;
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_partial_index_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3), deleted bool);
+
+            INSERT INTO test(embedding, deleted)
+            SELECT ARRAY[i, i % 5, 1]::vector, i % 3 = 0 FROM generate_series(1, 30) i;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH ({index_options})
+               WHERE NOT deleted;
+
+            INSERT INTO test(embedding, deleted) VALUES ('[1,1,1]', false), ('[1,1,2]', true);
+            ",
+        ))?;
+
+        /* only the rows matching the predicate are indexed, but the table keeps its row count */
+        let index_tuples: Option<f32> =
+            Spi::get_one("SELECT reltuples FROM pg_class WHERE oid = 'idxtest'::regclass")?;
+        assert_eq!(Some(20.0), index_tuples);
+        let heap_tuples: Option<f32> =
+            Spi::get_one("SELECT reltuples FROM pg_class WHERE oid = 'test'::regclass")?;
+        assert_eq!(Some(30.0), heap_tuples);
+
+        let res: Option<i64> = Spi::get_one(&format!(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test where not deleted order by embedding <=> '[0,0,1]') SELECT count(*) from cte;",
+        ))?;
+        assert_eq!(21, res.unwrap());
+
+        Spi::run(&format!("drop index idxtest;",))?;
+
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "pg_test"))]
+    pub unsafe fn test_temp_table_scaffold(index_options: &str) -> spi::Result<()> {
+        /* the pages of a temp table's index live in local buffers */
+        Spi::run(&format!(
+            "CREATE TEMP TABLE test_temp(embedding vector(3));
+
+            INSERT INTO test_temp(embedding)
+            SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest_temp
+                  ON test_temp
+               USING diskann(embedding)
+                WITH ({index_options});
+
+            INSERT INTO test_temp(embedding)
+            SELECT ARRAY[1, i % 5, i]::vector FROM generate_series(1, 300) i;
+
+            DELETE FROM test_temp WHERE embedding = '[1,1,1]';
+            ",
+        ))?;
+
+        let res: Option<i64> = Spi::get_one(&format!(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test_temp order by embedding <=> '[0,0,1]') SELECT count(*) from cte;",
+        ))?;
+        assert_eq!(598, res.unwrap());
+
+        Spi::run(&format!("drop table test_temp;",))?;
+
+        Ok(())
+    }
+
     #[cfg(any(test, feature = "pg_test"))]
     pub unsafe fn test_index_updates(index_options: &str, expected_cnt: i64) -> spi::Result<()> {
         Spi::run(&format!(
@@ -835,4 +1647,182 @@ pub mod tests {
 
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_last_build_stats() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(16));
+
+            INSERT INTO test(embedding)
+            SELECT v::vector FROM diskann_generate_vectors(300, 16, seed => 7) v;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH (num_neighbors = 20);",
+        )?;
+
+        let (is_last_index, num_nodes, avg_neighbors) = Spi::get_three::<bool, i64, f64>(
+            "SELECT index = 'idxtest'::regclass, num_nodes, avg_neighbors FROM diskann_last_build_stats()",
+        )?;
+        assert_eq!(is_last_index, Some(true));
+        assert_eq!(num_nodes, Some(300));
+        assert!(avg_neighbors.unwrap() > 0.0 && avg_neighbors.unwrap() <= 20.0);
+
+        let nodes_written =
+            Spi::get_one::<i64>("SELECT nodes_written FROM diskann_last_build_stats()")?;
+        assert!(nodes_written.unwrap() >= 300);
+
+        /* the phases are part of the build */
+        let (phases_ms, build_ms) = Spi::get_two::<f64, f64>(
+            "SELECT training_ms + building_graph_ms + finalizing_graph_ms, build_ms
+            FROM diskann_last_build_stats()",
+        )?;
+        assert!(phases_ms.unwrap() > 0.0 && phases_ms.unwrap() <= build_ms.unwrap());
+
+        /* at least the neighbor lists of the nodes */
+        let peak_memory_bytes =
+            Spi::get_one::<i64>("SELECT peak_memory_bytes FROM diskann_last_build_stats()")?;
+        assert!(peak_memory_bytes.unwrap() > 300 * 16);
+
+        /* the index is WAL-logged page by page */
+        let (wal_bytes, buffers_dirtied) = Spi::get_two::<i64, i64>(
+            "SELECT wal_bytes, buffers_dirtied FROM diskann_last_build_stats()",
+        )?;
+        assert!(wal_bytes.unwrap() > 8192);
+        assert!(buffers_dirtied.unwrap() > 0);
+
+        /* no build is in progress */
+        let num_builds = Spi::get_one::<i64>("SELECT count(*) FROM diskann_build_progress")?;
+        assert_eq!(num_builds, Some(0));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_build_flush_after() -> spi::Result<()> {
+        Spi::run(
+            "SET diskann.build_flush_after = 50;
+
+            CREATE TABLE test(embedding vector(16));
+
+            INSERT INTO test(embedding)
+            SELECT v::vector FROM diskann_generate_vectors(500, 16, seed => 8) v;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+
+            RESET diskann.build_flush_after;",
+        )?;
+
+        Spi::run("SET enable_seqscan = 0")?;
+        let cnt = Spi::get_one::<i64>(
+            "WITH cte AS (SELECT * FROM test ORDER BY embedding <=> (SELECT embedding FROM test LIMIT 1)) SELECT count(*) FROM cte",
+        )?;
+        assert_eq!(cnt, Some(500));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_fillfactor() -> spi::Result<()> {
+        let mut num_blocks = Vec::new();
+        for fillfactor in [100, 50] {
+            Spi::run(&format!(
+                "CREATE TABLE test(id int, embedding vector(64));
+
+                INSERT INTO test(id, embedding)
+                SELECT i, v::vector FROM diskann_generate_vectors(300, 64, seed => 3) WITH ORDINALITY AS t(v, i);
+
+                CREATE INDEX idxtest ON test USING diskann(embedding) WITH (fillfactor = {fillfactor});",
+            ))?;
+            let blocks = Spi::get_one::<i64>(
+                "SELECT pg_relation_size('idxtest') / current_setting('block_size')::int8",
+            )?
+            .unwrap();
+            num_blocks.push(blocks);
+
+            /* the rows inserted after the build fill the space left on the node pages */
+            Spi::run(
+                "INSERT INTO test(id, embedding)
+                SELECT 300 + i, v::vector FROM diskann_generate_vectors(100, 64, seed => 4) WITH ORDINALITY AS t(v, i);",
+            )?;
+            let blocks_after_insert = Spi::get_one::<i64>(
+                "SELECT pg_relation_size('idxtest') / current_setting('block_size')::int8",
+            )?
+            .unwrap();
+            if fillfactor == 50 {
+                assert_eq!(blocks_after_insert, blocks);
+            }
+
+            Spi::run("SET enable_seqscan = 0")?;
+            let cnt = Spi::get_one::<i64>(
+                "WITH cte AS (SELECT * FROM test ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 350)) SELECT count(*) FROM cte",
+            )?;
+            assert_eq!(cnt, Some(400), "fillfactor {}", fillfactor);
+            Spi::run("RESET enable_seqscan; DROP TABLE test;")?;
+        }
+        assert!(
+            num_blocks[1] as f64 >= num_blocks[0] as f64 * 1.5,
+            "blocks with fillfactor 100/50: {:?}",
+            num_blocks
+        );
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_inserts_fill_new_pages() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(64));
+
+            INSERT INTO test(id, embedding)
+            SELECT i, v::vector FROM diskann_generate_vectors(100, 64, seed => 3) WITH ORDINALITY AS t(v, i);
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);",
+        )?;
+        let blocks = Spi::get_one::<i64>(
+            "SELECT pg_relation_size('idxtest') / current_setting('block_size')::int8",
+        )?
+        .unwrap();
+
+        /* the pages added by inserts are not in the free space map until VACUUM, but the
+        following inserts still fill them */
+        Spi::run(
+            "INSERT INTO test(id, embedding)
+            SELECT 100 + i, v::vector FROM diskann_generate_vectors(200, 64, seed => 4) WITH ORDINALITY AS t(v, i);",
+        )?;
+        let blocks_after_insert = Spi::get_one::<i64>(
+            "SELECT pg_relation_size('idxtest') / current_setting('block_size')::int8",
+        )?
+        .unwrap();
+        assert!(
+            blocks_after_insert - blocks < 100,
+            "blocks before/after the inserts: {}/{}",
+            blocks,
+            blocks_after_insert
+        );
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "the indexed column has 2000 dimensions, but diskann indexes support 1 to 1999 dimensions"
+    )]
+    unsafe fn test_too_many_dimensions() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(2000));
+            CREATE INDEX idxtest ON test USING diskann(embedding);",
+        )?;
+        Ok(())
+    }
+
+    /* no backend for a device is compiled in, so these builds fall back to the CPU backend */
+    #[cfg(feature = "build_offload")]
+    #[pg_test]
+    unsafe fn test_build_offload_plain() -> spi::Result<()> {
+        Spi::run("SET diskann.build_offload = 'gpu'")?;
+        test_index_creation_and_accuracy_scaffold("storage_layout = plain")
+    }
+
+    #[cfg(feature = "build_offload")]
+    #[pg_test]
+    unsafe fn test_build_offload_memory_optimized() -> spi::Result<()> {
+        Spi::run("SET diskann.build_offload = 'gpu'")?;
+        test_index_creation_and_accuracy_scaffold(
+            "storage_layout = memory_optimized, num_bits_per_dimension = 2",
+        )
+    }
 }
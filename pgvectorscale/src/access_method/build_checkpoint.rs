@@ -0,0 +1,562 @@
+//! Checkpoints of index builds.
+//!
+//! Building the graph of a large table can take many hours, and an interrupted CREATE INDEX
+//! normally starts over. With `diskann.build_checkpoint_interval` set, the build periodically
+//! saves its state to files in the `pg_diskann_build` directory of the data directory: the nodes
+//! written so far, the neighbors the build keeps in memory, the trained quantizer and the sample
+//! of entry points. Running the same CREATE INDEX again restores that state and only inserts the
+//! rows that were not processed yet.
+//!
+//! The nodes don't change until the end of the build, so every checkpoint appends the new ones
+//! to a file of nodes, while the neighbors change with every insert and are rewritten in full.
+//! Restoring writes the saved nodes to the new index in the same order, which places them at the
+//! index pointers the saved neighbors refer to.
+//!
+//! A checkpoint belongs to an index name on a table. It is only restored if the index is created
+//! with the same options, and it is removed when the build finishes. Rows inserted or deleted in
+//! between are fine, but if a row of a saved node was replaced by another row at the same heap
+//! pointer, which a vacuum allows, the build stops and the checkpoint is removed.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use pgrx::*;
+
+use crate::util::{tape::Tape, HeapPointer, IndexPointer, ItemPointer};
+
+use super::{
+    entry_points::EntryPointSampler, graph_neighbor_store::BuilderNeighborCache,
+    guc::TSV_BUILD_CHECKPOINT_INTERVAL, meta_page::MetaPage,
+    neighbor_with_distance::NeighborWithDistance,
+};
+
+/// Directory of the checkpoints, relative to the data directory.
+const CHECKPOINT_DIR: &str = "pg_diskann_build";
+const CHECKPOINT_MAGIC: u32 = 0x444b_4243;
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// The state of a trained SBQ quantizer.
+pub struct QuantizerState {
+    pub count: u64,
+    pub mean: Vec<f32>,
+    pub m2: Vec<f32>,
+}
+
+/// Saves the state of a running build.
+pub struct BuildCheckpoint {
+    nodes_path: PathBuf,
+    graph_path: PathBuf,
+    interval: usize,
+    /// Identifies the options of the index. A checkpoint of an index with different options
+    /// is not restored.
+    fingerprint: Vec<u8>,
+    /// Nodes created since the last checkpoint, in the order they were written, with the hash
+    /// of their row.
+    pending_nodes: Vec<(HeapPointer, IndexPointer, u64)>,
+    num_saved_nodes: u64,
+    nodes_file_len: u64,
+    quantizer: Option<QuantizerState>,
+}
+
+/// The state of an interrupted build, read from its checkpoint.
+pub struct RestoredBuild {
+    nodes_path: PathBuf,
+    num_nodes: u64,
+    neighbors: BuilderNeighborCache,
+    entry_points: Option<EntryPointSampler>,
+    quantizer: Option<QuantizerState>,
+}
+
+impl BuildCheckpoint {
+    /// Returns the checkpoint of the build of `index`, or None if checkpoints are disabled.
+    pub fn open(index: &PgRelation, heap: &PgRelation, meta_page: &MetaPage) -> Option<Self> {
+        let interval = TSV_BUILD_CHECKPOINT_INTERVAL.get();
+        if interval <= 0 {
+            return None;
+        }
+        if meta_page.uses_pca() {
+            notice!("Build checkpoints are not supported with pca_dimensions, not saving any");
+            return None;
+        }
+        if let Err(e) = fs::create_dir_all(CHECKPOINT_DIR) {
+            error!(
+                "could not create the directory \"{}\" for build checkpoints: {}",
+                CHECKPOINT_DIR, e
+            );
+        }
+
+        let database = unsafe { pg_sys::MyDatabaseId };
+        let name = format!(
+            "{}_{}_{:016x}",
+            database.as_u32(),
+            heap.oid().as_u32(),
+            fnv1a(index.name().as_bytes())
+        );
+        let path = Path::new(CHECKPOINT_DIR).join(name);
+        Some(Self {
+            nodes_path: path.with_extension("nodes"),
+            graph_path: path.with_extension("graph"),
+            interval: interval as usize,
+            fingerprint: meta_page.serialize_to_vec().to_vec(),
+            pending_nodes: Vec::new(),
+            num_saved_nodes: 0,
+            nodes_file_len: 0,
+            quantizer: None,
+        })
+    }
+
+    /// Reads the last checkpoint of a previous attempt of the build, if there is one that
+    /// matches the options of the index. Otherwise, starts a new checkpoint.
+    pub fn restore(&mut self) -> Option<RestoredBuild> {
+        let restored = match File::open(&self.graph_path) {
+            Ok(file) => match self.read_graph(file) {
+                Ok(restored) => restored,
+                Err(e) => {
+                    notice!(
+                        "Ignoring the build checkpoint \"{}\": {}",
+                        self.graph_path.display(),
+                        e
+                    );
+                    None
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => error!(
+                "could not open the build checkpoint \"{}\": {}",
+                self.graph_path.display(),
+                e
+            ),
+        };
+
+        /* a checkpoint interrupted after writing its nodes may have left more nodes than the
+        graph refers to */
+        let nodes = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&self.nodes_path)
+            .and_then(|file| file.set_len(self.nodes_file_len));
+        if let Err(e) = nodes {
+            error!(
+                "could not open the build checkpoint \"{}\": {}",
+                self.nodes_path.display(),
+                e
+            );
+        }
+        restored
+    }
+
+    fn read_graph(&mut self, file: File) -> io::Result<Option<RestoredBuild>> {
+        let mut reader = BufReader::new(file);
+        if read_u32(&mut reader)? != CHECKPOINT_MAGIC
+            || read_u32(&mut reader)? != CHECKPOINT_VERSION
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a build checkpoint",
+            ));
+        }
+        let fingerprint = read_bytes(&mut reader)?;
+        if fingerprint != self.fingerprint {
+            notice!("Not resuming the index build from a checkpoint of a build with other options");
+            return Ok(None);
+        }
+
+        let num_nodes = read_u64(&mut reader)?;
+        let nodes_file_len = read_u64(&mut reader)?;
+
+        let quantizer = if read_u8(&mut reader)? != 0 {
+            Some(QuantizerState {
+                count: read_u64(&mut reader)?,
+                mean: read_f32s(&mut reader)?,
+                m2: read_f32s(&mut reader)?,
+            })
+        } else {
+            None
+        };
+
+        let entry_points = if read_u8(&mut reader)? != 0 {
+            let num_seen = read_u64(&mut reader)? as usize;
+            let num_samples = read_u64(&mut reader)?;
+            let mut samples = Vec::with_capacity(num_samples as _);
+            for _ in 0..num_samples {
+                samples.push((read_item_pointer(&mut reader)?, read_f32s(&mut reader)?));
+            }
+            Some(EntryPointSampler::with_samples(samples, num_seen))
+        } else {
+            None
+        };
+
+        let mut neighbors = BuilderNeighborCache::new();
+        let num_neighbor_lists = read_u64(&mut reader)?;
+        for _ in 0..num_neighbor_lists {
+            let index_pointer = read_item_pointer(&mut reader)?;
+            let num_neighbors = read_u32(&mut reader)?;
+            let mut list = Vec::with_capacity(num_neighbors as _);
+            for _ in 0..num_neighbors {
+                let neighbor = read_item_pointer(&mut reader)?;
+                let distance = f32::from_le_bytes(read_array(&mut reader)?);
+                list.push(NeighborWithDistance::new(neighbor, distance));
+            }
+            neighbors.set_neighbors(index_pointer, list);
+        }
+
+        self.num_saved_nodes = num_nodes;
+        self.nodes_file_len = nodes_file_len;
+        Ok(Some(RestoredBuild {
+            nodes_path: self.nodes_path.clone(),
+            num_nodes,
+            neighbors,
+            entry_points,
+            quantizer,
+        }))
+    }
+
+    /// Sets the trained quantizer, saved with every checkpoint.
+    pub fn set_quantizer(&mut self, quantizer: QuantizerState) {
+        self.quantizer = Some(quantizer);
+    }
+
+    /// Records a node created by the build. `row_hash` is the `row_hash` of its row.
+    pub fn add_node(
+        &mut self,
+        heap_pointer: HeapPointer,
+        index_pointer: IndexPointer,
+        row_hash: u64,
+    ) {
+        self.pending_nodes
+            .push((heap_pointer, index_pointer, row_hash));
+    }
+
+    /// Whether a checkpoint is due after `ntuples` tuples were inserted.
+    pub fn is_due(&self, ntuples: usize) -> bool {
+        ntuples % self.interval == 0
+    }
+
+    /// Saves the nodes created since the last checkpoint and the current neighbors.
+    pub fn save(
+        &mut self,
+        index: &PgRelation,
+        neighbors: &BuilderNeighborCache,
+        entry_points: Option<&EntryPointSampler>,
+    ) {
+        if let Err(e) = self.append_nodes(index) {
+            error!(
+                "could not write the build checkpoint \"{}\": {}",
+                self.nodes_path.display(),
+                e
+            );
+        }
+        if let Err(e) = self.write_graph(neighbors, entry_points) {
+            error!(
+                "could not write the build checkpoint \"{}\": {}",
+                self.graph_path.display(),
+                e
+            );
+        }
+        debug1!(
+            "Saved a build checkpoint of {} nodes to \"{}\"",
+            self.num_saved_nodes,
+            self.graph_path.display()
+        );
+    }
+
+    fn append_nodes(&mut self, index: &PgRelation) -> io::Result<()> {
+        let file = OpenOptions::new().append(true).open(&self.nodes_path)?;
+        let mut writer = BufWriter::new(file);
+        let mut len = 0;
+        for (heap_pointer, index_pointer, row_hash) in self.pending_nodes.iter() {
+            let node = unsafe { index_pointer.read_bytes(index) };
+            let bytes = node.get_data_slice();
+            write_item_pointer(&mut writer, *heap_pointer)?;
+            write_item_pointer(&mut writer, *index_pointer)?;
+            writer.write_all(&row_hash.to_le_bytes())?;
+            write_bytes(&mut writer, bytes)?;
+            len += 2 * ITEM_POINTER_SIZE + 8 + 4 + bytes.len() as u64;
+        }
+        writer.into_inner()?.sync_all()?;
+
+        self.num_saved_nodes += self.pending_nodes.len() as u64;
+        self.nodes_file_len += len;
+        self.pending_nodes.clear();
+        Ok(())
+    }
+
+    fn write_graph(
+        &self,
+        neighbors: &BuilderNeighborCache,
+        entry_points: Option<&EntryPointSampler>,
+    ) -> io::Result<()> {
+        let temp_path = self.graph_path.with_extension("graph.tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        writer.write_all(&CHECKPOINT_MAGIC.to_le_bytes())?;
+        writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+        write_bytes(&mut writer, &self.fingerprint)?;
+        writer.write_all(&self.num_saved_nodes.to_le_bytes())?;
+        writer.write_all(&self.nodes_file_len.to_le_bytes())?;
+
+        match &self.quantizer {
+            Some(quantizer) => {
+                writer.write_all(&[1])?;
+                writer.write_all(&quantizer.count.to_le_bytes())?;
+                write_f32s(&mut writer, &quantizer.mean)?;
+                write_f32s(&mut writer, &quantizer.m2)?;
+            }
+            None => writer.write_all(&[0])?,
+        }
+
+        match entry_points {
+            Some(entry_points) => {
+                writer.write_all(&[1])?;
+                writer.write_all(&(entry_points.get_num_seen() as u64).to_le_bytes())?;
+                let samples = entry_points.get_samples();
+                writer.write_all(&(samples.len() as u64).to_le_bytes())?;
+                for (index_pointer, vector) in samples {
+                    write_item_pointer(&mut writer, *index_pointer)?;
+                    write_f32s(&mut writer, vector)?;
+                }
+            }
+            None => writer.write_all(&[0])?,
+        }
+
+        let num_neighbor_lists = neighbors.iter().count() as u64;
+        writer.write_all(&num_neighbor_lists.to_le_bytes())?;
+        for (index_pointer, list) in neighbors.iter() {
+            write_item_pointer(&mut writer, *index_pointer)?;
+            writer.write_all(&(list.len() as u32).to_le_bytes())?;
+            for neighbor in list {
+                write_item_pointer(&mut writer, neighbor.get_index_pointer_to_neighbor())?;
+                writer.write_all(&neighbor.get_distance().to_le_bytes())?;
+            }
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp_path, &self.graph_path)
+    }
+
+    /// Removes the checkpoint and stops the build, e.g. because a row of a restored node was
+    /// replaced.
+    pub fn discard(&self, reason: &str) -> ! {
+        discard(&self.nodes_path, reason)
+    }
+
+    /// Removes the checkpoint once the build is done.
+    pub fn remove(self) {
+        for path in [&self.graph_path, &self.nodes_path] {
+            if let Err(e) = fs::remove_file(path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warning!(
+                        "could not remove the build checkpoint \"{}\": {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl RestoredBuild {
+    pub fn get_num_nodes(&self) -> usize {
+        self.num_nodes as usize
+    }
+
+    pub fn take_neighbors(&mut self) -> BuilderNeighborCache {
+        std::mem::replace(&mut self.neighbors, BuilderNeighborCache::new())
+    }
+
+    pub fn take_entry_points(&mut self) -> Option<EntryPointSampler> {
+        self.entry_points.take()
+    }
+
+    pub fn take_quantizer(&mut self) -> Option<QuantizerState> {
+        self.quantizer.take()
+    }
+
+    /// Writes the saved nodes to the index, in the order they were saved. Returns the index
+    /// pointer of the first node, which is the entry point of the graph, and the heap pointers
+    /// and hashes of the rows of the nodes, sorted by heap pointer.
+    pub fn write_nodes(&self, tape: &mut Tape) -> (Option<IndexPointer>, Vec<(HeapPointer, u64)>) {
+        match self.write_nodes_internal(tape) {
+            Ok(heap_pointers) => heap_pointers,
+            Err(e) => error!(
+                "could not read the build checkpoint \"{}\": {}",
+                self.nodes_path.display(),
+                e
+            ),
+        }
+    }
+
+    fn write_nodes_internal(
+        &self,
+        tape: &mut Tape,
+    ) -> io::Result<(Option<IndexPointer>, Vec<(HeapPointer, u64)>)> {
+        let mut reader = BufReader::new(File::open(&self.nodes_path)?);
+        let mut first_index_pointer = None;
+        let mut heap_pointers = Vec::with_capacity(self.num_nodes as _);
+        for _ in 0..self.num_nodes {
+            let heap_pointer = read_item_pointer(&mut reader)?;
+            let saved_index_pointer = read_item_pointer(&mut reader)?;
+            let row_hash = read_u64(&mut reader)?;
+            let bytes = read_bytes(&mut reader)?;
+            let index_pointer = unsafe { tape.write(&bytes) };
+            if index_pointer != saved_index_pointer {
+                discard(&self.nodes_path, "does not match the index");
+            }
+            first_index_pointer.get_or_insert(index_pointer);
+            heap_pointers.push((heap_pointer, row_hash));
+        }
+        heap_pointers.sort_by_key(|(heap_pointer, _)| *heap_pointer);
+        Ok((first_index_pointer, heap_pointers))
+    }
+}
+
+/// Removes a checkpoint and stops the build, which has to start over.
+fn discard(nodes_path: &Path, reason: &str) -> ! {
+    let _ = fs::remove_file(nodes_path);
+    let _ = fs::remove_file(nodes_path.with_extension("graph"));
+    error!(
+        "the build checkpoint \"{}\" {} and was removed, run CREATE INDEX again",
+        nodes_path.display(),
+        reason
+    );
+}
+
+/// Identifies the values a row is indexed with, to detect rows replaced since a checkpoint.
+pub fn row_hash(vector: &[f32], payload: &[u8]) -> u64 {
+    let bytes: Vec<u8> = vector
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .chain(payload.iter().copied())
+        .collect();
+    fnv1a(&bytes)
+}
+
+/// FNV-1a, a hash that stays the same across builds of the extension.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+const ITEM_POINTER_SIZE: u64 = 6;
+
+fn write_item_pointer<W: Write>(writer: &mut W, item_pointer: ItemPointer) -> io::Result<()> {
+    writer.write_all(&item_pointer.block_number.to_le_bytes())?;
+    writer.write_all(&item_pointer.offset.to_le_bytes())
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn write_f32s<W: Write>(writer: &mut W, values: &[f32]) -> io::Result<()> {
+    writer.write_all(&(values.len() as u32).to_le_bytes())?;
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_array<R: Read, const N: usize>(reader: &mut R) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    Ok(read_array::<R, 1>(reader)?[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(read_array(reader)?))
+}
+
+fn read_item_pointer<R: Read>(reader: &mut R) -> io::Result<ItemPointer> {
+    let block_number = u32::from_le_bytes(read_array(reader)?);
+    let offset = u16::from_le_bytes(read_array(reader)?);
+    Ok(ItemPointer::new(block_number, offset))
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_u32(reader)?;
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_f32s<R: Read>(reader: &mut R) -> io::Result<Vec<f32>> {
+    let len = read_u32(reader)?;
+    (0..len)
+        .map(|_| Ok(f32::from_le_bytes(read_array(reader)?)))
+        .collect()
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_resume_build_from_checkpoint() -> spi::Result<()> {
+        /* the last row has the wrong number of dimensions, so the first build fails after
+        inserting the others */
+        Spi::run(
+            "CREATE TABLE test(id int, embedding real[]);
+
+            INSERT INTO test(id, embedding)
+            SELECT i, v FROM diskann_generate_vectors(300, 8, seed => 3) WITH ORDINALITY AS t(v, i);
+
+            INSERT INTO test(id, embedding) VALUES (301, '{1,2,3}');
+
+            SET diskann.build_checkpoint_interval = 100;
+
+            DO $$
+            BEGIN
+                CREATE INDEX idxtest ON test USING diskann(embedding) WITH (storage_layout = plain);
+            EXCEPTION WHEN others THEN
+                NULL;
+            END
+            $$;",
+        )?;
+
+        /* the same index, without the bad row, resumes from the checkpoint */
+        Spi::run(
+            "CREATE INDEX idxtest ON test USING diskann(embedding) WITH (storage_layout = plain)
+                WHERE id <= 300;",
+        )?;
+        let (num_nodes, num_restored_nodes) = Spi::get_two::<i64, i64>(
+            "SELECT num_nodes, num_restored_nodes FROM diskann_last_build_stats()",
+        )?;
+        assert_eq!(num_nodes, Some(300));
+        assert_eq!(num_restored_nodes, Some(300));
+
+        let num_checkpoint_files =
+            Spi::get_one::<i64>("SELECT count(*) FROM pg_ls_dir('pg_diskann_build')")?;
+        assert_eq!(num_checkpoint_files, Some(0));
+
+        Spi::run("SET enable_seqscan = 0")?;
+        let num_found = Spi::get_one::<i64>(
+            "SELECT count(*) FROM (
+                SELECT id FROM test WHERE id <= 300
+                ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 1)
+                LIMIT 10) r",
+        )?;
+        assert_eq!(num_found, Some(10));
+
+        let nearest = Spi::get_one::<i32>(
+            "SELECT id FROM test WHERE id <= 300
+            ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 42)
+            LIMIT 1",
+        )?;
+        assert_eq!(nearest, Some(42));
+        Ok(())
+    }
+}
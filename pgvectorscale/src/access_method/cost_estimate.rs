@@ -36,6 +36,7 @@ pub unsafe extern "C" fn amcostestimate(
     .heap_relation()
     .expect("failed to get heap relation for index");*/
 
+    /* for a partial index, this only counts the rows matching the predicate */
     let total_index_tuples = (*path_ref.indexinfo).tuples;
 
     let mut generic_costs = pg_sys::GenericCosts {
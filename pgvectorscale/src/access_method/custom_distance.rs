@@ -0,0 +1,139 @@
+//! Custom distance functions supplied by operator classes.
+//!
+//! An operator class can register its own distance as support function 1:
+//!
+//! ```sql
+//! CREATE OPERATOR CLASS my_ops FOR TYPE vector USING diskann AS
+//!     OPERATOR 1 <~> (vector, vector) FOR ORDER BY float_ops,
+//!     FUNCTION 1 my_distance(vector, vector);
+//! ```
+//!
+//! The function takes the indexed value and the query and returns a float8 where smaller means
+//! closer. Usually it is the function behind the ORDER BY operator.
+//!
+//! Contract: the graph is still built and traversed with the built-in cosine distance (or its
+//! quantized approximation for the SBQ storage layouts). The custom function is only used to
+//! rescore the candidates produced by the traversal, on the values fetched from the heap. So
+//! results are accurate as long as the custom distance ranks roughly like the cosine distance,
+//! i.e. the true nearest neighbors are among the cosine candidates. Raise
+//! `diskann.query_rescore` to rescore more candidates when the two metrics disagree more. With
+//! `diskann.query_rescore = 0` nothing is rescored and the custom function is not called.
+
+use pgrx::{pg_sys, PgRelation};
+
+use crate::util::{table_slot::TableSlot, HeapPointer};
+
+use super::{stats::StatsHeapNodeRead, storage_common::IndexedAttribute};
+
+/// The support function number of the custom distance in the operator class.
+pub const DISKANN_DISTANCE_PROC: u16 = 1;
+
+pub struct CustomDistance {
+    flinfo: *mut pg_sys::FmgrInfo,
+    collation: pg_sys::Oid,
+    query: pg_sys::Datum,
+    indexed_attr: IndexedAttribute,
+    heap_slot: Option<TableSlot>,
+}
+
+impl CustomDistance {
+    /// Returns the custom distance of the operator class of the index, if it has one. The
+    /// query datum must stay valid for the duration of the scan.
+    pub unsafe fn new(
+        index: &PgRelation,
+        query: pg_sys::Datum,
+        collation: pg_sys::Oid,
+    ) -> Option<Self> {
+        let proc = pg_sys::index_getprocid(index.as_ptr(), 1, DISKANN_DISTANCE_PROC);
+        if proc == pg_sys::InvalidOid {
+            return None;
+        }
+        Some(Self {
+            flinfo: pg_sys::index_getprocinfo(index.as_ptr(), 1, DISKANN_DISTANCE_PROC),
+            collation,
+            query,
+            indexed_attr: IndexedAttribute::new(index),
+            heap_slot: None,
+        })
+    }
+
+    /// Distance between the query and the indexed value of a heap tuple.
+    pub unsafe fn get_distance<S: StatsHeapNodeRead>(
+        &mut self,
+        heap_rel: &PgRelation,
+        heap_pointer: HeapPointer,
+        stats: &mut S,
+    ) -> f32 {
+        let slot = self
+            .heap_slot
+            .get_or_insert_with(|| TableSlot::new(heap_rel));
+        slot.fetch(heap_rel, heap_pointer, stats);
+        let datum = self.indexed_attr.get_datum(slot).unwrap();
+
+        let result = pg_sys::FunctionCall2Coll(self.flinfo, self.collation, datum, self.query);
+        f64::from_bits(result.value() as u64) as f32
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    unsafe fn test_custom_distance_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE OPERATOR <~> (
+                LEFTARG = vector,
+                RIGHTARG = vector,
+                FUNCTION = diskann_l2_squared_distance
+            );
+
+            CREATE OPERATOR CLASS vector_l2_squared_ops FOR TYPE vector USING diskann AS
+                OPERATOR 1 <~> (vector, vector) FOR ORDER BY float_ops,
+                FUNCTION 1 diskann_l2_squared_distance(vector, vector);
+
+            CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[10,0,0]'), ('[1,0.5,0]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding vector_l2_squared_ops)
+                WITH ({index_options});
+
+            INSERT INTO test(embedding) VALUES ('[0,1,0]');
+            ",
+        ))?;
+
+        /* [10,0,0] is the closest by cosine distance, but not by l2 distance */
+        let first: Option<String> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                select embedding::text from test order by embedding <~> '[1,0,0]' limit 1;",
+        )?;
+        assert_eq!("[1,0.5,0]", first.unwrap());
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by embedding <~> '[1,0,0]') SELECT count(*) from cte;",
+        )?;
+        assert_eq!(3, res.unwrap());
+
+        Spi::run(
+            "drop table test;
+            drop operator class vector_l2_squared_ops using diskann;
+            drop operator <~> (vector, vector);",
+        )?;
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_custom_distance_plain() -> spi::Result<()> {
+        test_custom_distance_scaffold("storage_layout = plain")
+    }
+
+    #[pg_test]
+    unsafe fn test_custom_distance_memory_optimized() -> spi::Result<()> {
+        test_custom_distance_scaffold("storage_layout = memory_optimized")
+    }
+}
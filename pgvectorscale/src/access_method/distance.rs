@@ -1,210 +1,141 @@
-/* we use the avx2 version of x86 functions. This verifies that's kosher */
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-#[cfg(not(target_feature = "avx2"))]
-compile_error!(
-    "On x86, the AVX2 feature must be enabled. Set RUSTFLAGS=\"-C target-feature=+avx2,+fma\""
-);
-
-#[inline]
-pub fn distance_l2(a: &[f32], b: &[f32]) -> f32 {
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    //note safety is guraranteed by compile_error above
-    unsafe {
-        return super::distance_x86::distance_l2_x86_avx2(a, b);
-    }
+/* The kernels live in the diskann module, which doesn't depend on Postgres. */
+pub use crate::diskann::distance::*;
+
+use super::{
+    guc::get_distance_impl,
+    pg_vector::{apply_weights, read_weighted_query},
+};
+
+/* The functions below expose the kernels used by the index to SQL, so that exact rescoring done
+by applications produces exactly the same numbers as the index. */
 
-    #[allow(unreachable_code)]
-    {
-        return distance_l2_unoptimized(a, b);
+/// Cosine distance between two normalized copies of the vectors, as computed by the index.
+fn cosine_distance_of_copies(mut a: Vec<f32>, mut b: Vec<f32>) -> f64 {
+    if a.len() != b.len() {
+        pgrx::error!("different vector dimensions {} and {}", a.len(), b.len());
     }
+    preprocess_cosine(&mut a);
+    preprocess_cosine(&mut b);
+    get_distance_impl().get_cosine()(&a, &b) as f64
 }
 
-#[inline(always)]
-pub fn distance_l2_unoptimized(a: &[f32], b: &[f32]) -> f32 {
-    assert_eq!(a.len(), b.len());
-    let norm: f32 = a
-        .iter()
-        .zip(b.iter())
-        .map(|t| (*t.0 as f32 - *t.1 as f32) * (*t.0 as f32 - *t.1 as f32))
-        .sum();
-    assert!(norm >= 0.);
-    //don't sqrt for performance. These are only used for ordering so sqrt not needed
-    norm
+unsafe fn get_vector_arg(fcinfo: pgrx::pg_sys::FunctionCallInfo, num: usize) -> Vec<f32> {
+    get_vector(pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, num))
 }
 
-/* PQ computes distances on subsegments that have few dimensions (e.g. 6). This function optimizes that.
-* We optimize by telling the compiler exactly how long the slices are. This allows the compiler to figure
-* out SIMD optimizations. Look at the benchmark results. */
-#[inline]
-pub fn distance_l2_optimized_for_few_dimensions(a: &[f32], b: &[f32]) -> f32 {
-    let norm: f32 = match a.len() {
-        0 => 0.,
-        1 => a[..1]
-            .iter()
-            .zip(b[..1].iter())
-            .map(|t| (*t.0 as f32 - *t.1 as f32) * (*t.0 as f32 - *t.1 as f32))
-            .sum(),
-        2 => a[..2]
-            .iter()
-            .zip(b[..2].iter())
-            .map(|t| (*t.0 as f32 - *t.1 as f32) * (*t.0 as f32 - *t.1 as f32))
-            .sum(),
-        3 => a[..3]
-            .iter()
-            .zip(b[..3].iter())
-            .map(|t| (*t.0 as f32 - *t.1 as f32) * (*t.0 as f32 - *t.1 as f32))
-            .sum(),
-        4 => a[..4]
-            .iter()
-            .zip(b[..4].iter())
-            .map(|t| (*t.0 as f32 - *t.1 as f32) * (*t.0 as f32 - *t.1 as f32))
-            .sum(),
-        5 => a[..5]
-            .iter()
-            .zip(b[..5].iter())
-            .map(|t| (*t.0 as f32 - *t.1 as f32) * (*t.0 as f32 - *t.1 as f32))
-            .sum(),
-        6 => a[..6]
-            .iter()
-            .zip(b[..6].iter())
-            .map(|t| (*t.0 as f32 - *t.1 as f32) * (*t.0 as f32 - *t.1 as f32))
-            .sum(),
-        7 => a[..7]
-            .iter()
-            .zip(b[..7].iter())
-            .map(|t| (*t.0 as f32 - *t.1 as f32) * (*t.0 as f32 - *t.1 as f32))
-            .sum(),
-        8 => a[..8]
-            .iter()
-            .zip(b[..8].iter())
-            .map(|t| (*t.0 as f32 - *t.1 as f32) * (*t.0 as f32 - *t.1 as f32))
-            .sum(),
-        _ => distance_l2(a, b),
-    };
-    assert!(norm >= 0.);
-    //don't sqrt for performance. These are only used for ordering so sqrt not needed
-    norm
+unsafe fn get_vector(datum: pgrx::pg_sys::Datum) -> Vec<f32> {
+    let vector = pgrx::pg_sys::pg_detoast_datum(datum.cast_mut_ptr())
+        .cast::<super::pg_vector::PgVectorInternal>();
+    (*vector).to_slice().to_vec()
+}
+
+#[pgrx::pg_extern(sql = "
+    CREATE OR REPLACE FUNCTION diskann_cosine_distance(vector, vector) RETURNS float8 PARALLEL SAFE IMMUTABLE STRICT LANGUAGE c AS '@MODULE_PATHNAME@', '@FUNCTION_NAME@';
+", requires = ["diskann_ops_operator"])]
+fn diskann_cosine_distance(fcinfo: pgrx::pg_sys::FunctionCallInfo) -> f64 {
+    unsafe { cosine_distance_of_copies(get_vector_arg(fcinfo, 0), get_vector_arg(fcinfo, 1)) }
 }
 
-#[inline]
-pub fn distance_cosine(a: &[f32], b: &[f32]) -> f32 {
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    //note safety is guraranteed by compile_error above
-    unsafe {
-        return super::distance_x86::distance_cosine_x86_avx2(a, b);
+/// The index never takes the square root of L2 distances, so neither does this function.
+#[pgrx::pg_extern(sql = "
+    CREATE OR REPLACE FUNCTION diskann_l2_squared_distance(vector, vector) RETURNS float8 PARALLEL SAFE IMMUTABLE STRICT LANGUAGE c AS '@MODULE_PATHNAME@', '@FUNCTION_NAME@';
+", requires = ["diskann_ops_operator"])]
+fn diskann_l2_squared_distance(fcinfo: pgrx::pg_sys::FunctionCallInfo) -> f64 {
+    let (a, b) = unsafe { (get_vector_arg(fcinfo, 0), get_vector_arg(fcinfo, 1)) };
+    if a.len() != b.len() {
+        pgrx::error!("different vector dimensions {} and {}", a.len(), b.len());
     }
+    get_distance_impl().get_l2()(&a, &b) as f64
+}
 
-    #[allow(unreachable_code)]
-    {
-        return distance_cosine_unoptimized(a, b);
+/// The weighted cosine distance behind the `<*>` operator of the `vector_weighted_cosine_ops`
+/// operator class, as computed by an index with the weights of the query.
+#[pgrx::pg_extern(sql = "
+    CREATE OR REPLACE FUNCTION diskann_weighted_cosine_distance(vector, diskann_weighted_query) RETURNS float8 PARALLEL SAFE IMMUTABLE STRICT LANGUAGE c AS '@MODULE_PATHNAME@', '@FUNCTION_NAME@';
+", requires = ["diskann_weighted_query_type"])]
+fn diskann_weighted_cosine_distance(fcinfo: pgrx::pg_sys::FunctionCallInfo) -> f64 {
+    let (mut a, mut b, weights) = unsafe {
+        let (query, weights) = read_weighted_query(pgrx::fcinfo::pg_getarg_datum_raw(fcinfo, 1));
+        (get_vector_arg(fcinfo, 0), get_vector(query), weights)
+    };
+    if weights.len() != a.len() {
+        pgrx::error!(
+            "the query has {} weights but the vectors have {} dimensions",
+            weights.len(),
+            a.len()
+        );
     }
+    apply_weights(&mut a, &weights);
+    apply_weights(&mut b, &weights);
+    cosine_distance_of_copies(a, b)
 }
 
-#[inline(always)]
-pub fn distance_cosine_unoptimized(a: &[f32], b: &[f32]) -> f32 {
-    assert_eq!(a.len(), b.len());
-    debug_assert!(preprocess_cosine_get_norm(a).is_none());
-    debug_assert!(preprocess_cosine_get_norm(b).is_none());
-    let res: f32 = a.iter().zip(b).map(|(a, b)| *a * *b).sum();
-    (1.0 - res).max(0.0)
+/// Cosine distance between two float4 arrays. Backs the `<=>` operator on real[] used by the
+/// diskann operator class for float4[] columns.
+#[pgrx::pg_extern(immutable, parallel_safe, strict)]
+pub fn diskann_float4_array_cosine_distance(a: Vec<f32>, b: Vec<f32>) -> f64 {
+    cosine_distance_of_copies(a, b)
 }
 
-pub fn preprocess_cosine_get_norm(a: &[f32]) -> Option<f32> {
-    let norm = a.iter().map(|v| v * v).sum::<f32>();
-    //adjust the epsilon to the length of the vector
-    let adj_epsilon = f32::EPSILON * a.len() as f32;
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
 
-    /* this mainly handles the zero-vector case */
-    if norm < f32::EPSILON {
-        return None;
-    }
-    /* no need to renormalize if norm around 1.0 */
-    if norm >= 1.0 - adj_epsilon && norm <= 1.0 + adj_epsilon {
-        return None;
-    }
-    return Some(norm.sqrt());
-}
+    #[pg_test]
+    fn test_distance_functions() -> spi::Result<()> {
+        let res: Option<f64> =
+            Spi::get_one("SELECT diskann_cosine_distance('[1,0]'::vector, '[0,2]'::vector)")?;
+        assert_eq!(1.0, res.unwrap());
+
+        let res: Option<f64> =
+            Spi::get_one("SELECT diskann_cosine_distance('[1,1]'::vector, '[2,2]'::vector)")?;
+        assert!(res.unwrap() < 1e-6);
+
+        let res: Option<f64> =
+            Spi::get_one("SELECT diskann_l2_squared_distance('[1,2]'::vector, '[4,6]'::vector)")?;
+        assert_eq!(25.0, res.unwrap());
 
-pub fn preprocess_cosine(a: &mut [f32]) {
-    let norm = preprocess_cosine_get_norm(a);
-    match norm {
-        None => (),
-        Some(norm) => {
-            a.iter_mut().for_each(|v| *v /= norm);
-            debug_assert!(
-                preprocess_cosine_get_norm(a).is_none(),
-                "preprocess_cosine isn't idempotent",
-            );
-        }
+        let res: Option<f64> = Spi::get_one(
+            "SELECT diskann_float4_array_cosine_distance('{1,0}'::real[], '{0,2}'::real[])",
+        )?;
+        assert_eq!(1.0, res.unwrap());
+
+        Ok(())
     }
-}
 
-macro_rules! xor_arm {
-    ($a: expr, $b: expr, $sz: expr) => {
-        $a[..$sz]
-            .iter()
-            .zip($b[..$sz].iter())
-            .map(|(&l, &r)| (l ^ r).count_ones() as usize)
-            .sum()
-    };
-}
+    #[pg_test]
+    unsafe fn test_distance_impl() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i % 17, i % 5, i % 11]::vector FROM generate_series(1, 300) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH (storage_layout = plain);
+            SET enable_seqscan = 0;",
+        )?;
+        let query = "SELECT array_agg(id) FROM
+            (SELECT id FROM test ORDER BY embedding <=> '[3, 1, 2]', id LIMIT 10) t";
+        let auto = Spi::get_one::<Vec<i32>>(query)?;
+
+        /* small integers give the same distances in every implementation */
+        let scalar =
+            Spi::get_one::<Vec<i32>>(&format!("SET diskann.distance_impl = 'scalar'; {query}"))?;
+        assert_eq!(scalar, auto);
+        let res = Spi::get_one::<f64>(
+            "SELECT diskann_l2_squared_distance('[1,2]'::vector, '[4,6]'::vector)",
+        )?;
+        assert_eq!(Some(25.0), res);
+        Ok(())
+    }
 
-#[inline(always)]
-pub fn distance_xor_optimized(a: &[u64], b: &[u64]) -> usize {
-    match a.len() {
-        1 => xor_arm!(a, b, 1),
-        2 => xor_arm!(a, b, 2),
-        3 => xor_arm!(a, b, 3),
-        4 => xor_arm!(a, b, 4),
-        5 => xor_arm!(a, b, 5),
-        6 => xor_arm!(a, b, 6),
-        7 => xor_arm!(a, b, 7),
-        8 => xor_arm!(a, b, 8),
-        9 => xor_arm!(a, b, 9),
-        10 => xor_arm!(a, b, 10),
-        11 => xor_arm!(a, b, 11),
-        12 => xor_arm!(a, b, 12),
-        13 => xor_arm!(a, b, 13),
-        14 => xor_arm!(a, b, 14),
-        15 => xor_arm!(a, b, 15),
-        16 => xor_arm!(a, b, 16),
-        17 => xor_arm!(a, b, 17),
-        18 => xor_arm!(a, b, 18),
-        19 => xor_arm!(a, b, 19),
-        20 => xor_arm!(a, b, 20),
-        21 => xor_arm!(a, b, 21),
-        22 => xor_arm!(a, b, 22),
-        23 => xor_arm!(a, b, 23),
-        24 => xor_arm!(a, b, 24),
-        25 => xor_arm!(a, b, 25),
-        26 => xor_arm!(a, b, 26),
-        27 => xor_arm!(a, b, 27),
-        28 => xor_arm!(a, b, 28),
-        29 => xor_arm!(a, b, 29),
-        30 => xor_arm!(a, b, 30),
-        31 => xor_arm!(a, b, 31),
-        32 => xor_arm!(a, b, 32),
-        33 => xor_arm!(a, b, 33),
-        34 => xor_arm!(a, b, 34),
-        35 => xor_arm!(a, b, 35),
-        36 => xor_arm!(a, b, 36),
-        37 => xor_arm!(a, b, 37),
-        38 => xor_arm!(a, b, 38),
-        39 => xor_arm!(a, b, 39),
-        40 => xor_arm!(a, b, 40),
-        41 => xor_arm!(a, b, 41),
-        42 => xor_arm!(a, b, 42),
-        43 => xor_arm!(a, b, 43),
-        44 => xor_arm!(a, b, 44),
-        45 => xor_arm!(a, b, 45),
-        46 => xor_arm!(a, b, 46),
-        47 => xor_arm!(a, b, 47),
-        48 => xor_arm!(a, b, 48),
-        49 => xor_arm!(a, b, 49),
-        _ => a
-            .iter()
-            .zip(b.iter())
-            .map(|(&l, &r)| (l ^ r).count_ones() as usize)
-            .sum(),
+    #[pg_test(
+        error = "invalid value for diskann.distance_impl: \"avx\", expected auto, scalar or simd"
+    )]
+    fn test_distance_impl_invalid() -> spi::Result<()> {
+        Spi::get_one::<f64>(
+            "SET diskann.distance_impl = 'avx';
+            SELECT diskann_l2_squared_distance('[1,2]'::vector, '[4,6]'::vector)",
+        )?;
+        Ok(())
     }
 }
@@ -0,0 +1,376 @@
+//! Entry points of the graph search.
+//!
+//! A single entry point works well on uniform data, but on multi-modal data a search that starts
+//! far away from the query spends many hops just getting to the right region. With
+//! `num_entry_points` > 1, a sample of the nodes is clustered with k-means during build, and the
+//! sampled node closest to each centroid becomes an entry point. A search starts with all the
+//! entry points as candidates, so the traversal begins from the ones closest to the query.
+//!
+//! After heavy churn the entry points chosen at build time may have been deleted or may no
+//! longer represent the data. `diskann_refresh_entry_points('index')` recomputes them from a
+//! sample of the current nodes without rebuilding the graph.
+
+use pgrx::{
+    pg_sys::{self, FirstOffsetNumber},
+    *,
+};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::util::{
+    page::ReadablePage,
+    ports::{PageGetItem, PageGetItemId, PageGetMaxOffsetNumber},
+    table_slot::TableSlot,
+    HeapPointer, IndexPointer, ItemPointer,
+};
+
+use super::{
+    distance::preprocess_cosine,
+    meta_page::MetaPage,
+    pg_vector::PgVector,
+    plain_storage::PlainStorage,
+    sbq::SbqSpeedupStorage,
+    stats::{GreedySearchStats, NoStats},
+    storage::{ArchivedData, Storage, StorageType},
+    storage_common::{open_diskann_index_as_owner, IndexedAttribute},
+};
+
+/// Maximum number of nodes clustered to choose the entry points.
+const ENTRY_POINTS_MAX_SAMPLES: usize = 2000;
+const ENTRY_POINTS_NUM_ITERATIONS: usize = 10;
+const ENTRY_POINTS_SEED: u64 = 0xe7e7e7e7;
+
+/// Reservoir sample of the nodes of a graph, with their index vectors.
+pub struct EntryPointSampler {
+    samples: Vec<(IndexPointer, Vec<f32>)>,
+    num_seen: usize,
+    rng: SmallRng,
+}
+
+impl EntryPointSampler {
+    pub fn new() -> Self {
+        Self {
+            samples: Vec::new(),
+            num_seen: 0,
+            rng: SmallRng::seed_from_u64(ENTRY_POINTS_SEED),
+        }
+    }
+
+    /// Continues a sample of `num_seen` nodes, e.g. one saved by a build checkpoint.
+    pub fn with_samples(samples: Vec<(IndexPointer, Vec<f32>)>, num_seen: usize) -> Self {
+        Self {
+            samples,
+            num_seen,
+            rng: SmallRng::seed_from_u64(ENTRY_POINTS_SEED ^ num_seen as u64),
+        }
+    }
+
+    pub fn get_samples(&self) -> &[(IndexPointer, Vec<f32>)] {
+        &self.samples
+    }
+
+    pub fn get_num_seen(&self) -> usize {
+        self.num_seen
+    }
+
+    /// Adds a node. The vector must be preprocessed, i.e. it must be the vector the index uses
+    /// for distances.
+    pub fn add(&mut self, index_pointer: IndexPointer, vector: &[f32]) {
+        self.num_seen += 1;
+        if self.num_seen <= ENTRY_POINTS_MAX_SAMPLES {
+            self.samples.push((index_pointer, vector.to_vec()));
+        } else {
+            let slot = self.rng.gen_range(0..self.num_seen);
+            if slot < ENTRY_POINTS_MAX_SAMPLES {
+                self.samples[slot] = (index_pointer, vector.to_vec());
+            }
+        }
+    }
+
+    /// Clusters the sample and returns the node closest to every centroid. May return fewer
+    /// than `num_entry_points` nodes if the sample is small or has duplicates.
+    pub fn choose(
+        mut self,
+        num_entry_points: usize,
+        distance_fn: fn(&[f32], &[f32]) -> f32,
+    ) -> Vec<IndexPointer> {
+        if self.samples.len() <= num_entry_points {
+            return self.samples.iter().map(|(ip, _)| *ip).collect();
+        }
+
+        let mut centroids = self.seed_centroids(num_entry_points, distance_fn);
+        let mut assignments = vec![0; self.samples.len()];
+        for _ in 0..ENTRY_POINTS_NUM_ITERATIONS {
+            for (assignment, (_, vector)) in assignments.iter_mut().zip(self.samples.iter()) {
+                *assignment = closest(&centroids, vector, distance_fn);
+            }
+
+            for (c, centroid) in centroids.iter_mut().enumerate() {
+                let mut sum = vec![0.0; centroid.len()];
+                let mut count = 0;
+                for (assignment, (_, vector)) in assignments.iter().zip(self.samples.iter()) {
+                    if *assignment == c {
+                        sum.iter_mut().zip(vector).for_each(|(s, v)| *s += v);
+                        count += 1;
+                    }
+                }
+                /* keep the previous centroid of an empty cluster */
+                if count > 0 {
+                    /* the cosine distance expects normalized vectors */
+                    preprocess_cosine(&mut sum);
+                    *centroid = sum;
+                }
+            }
+        }
+
+        let vectors: Vec<Vec<f32>> = self.samples.iter().map(|(_, v)| v.clone()).collect();
+        let mut entry_points: Vec<IndexPointer> = Vec::with_capacity(num_entry_points);
+        for centroid in centroids.iter() {
+            let index_pointer = self.samples[closest(&vectors, centroid, distance_fn)].0;
+            if !entry_points.contains(&index_pointer) {
+                entry_points.push(index_pointer);
+            }
+        }
+        entry_points
+    }
+
+    /// k-means++ seeding: every new centroid is sampled with a probability proportional to its
+    /// distance to the closest centroid chosen so far.
+    fn seed_centroids(
+        &mut self,
+        num_centroids: usize,
+        distance_fn: fn(&[f32], &[f32]) -> f32,
+    ) -> Vec<Vec<f32>> {
+        let first = self.rng.gen_range(0..self.samples.len());
+        let mut centroids = vec![self.samples[first].1.clone()];
+        let mut min_distances: Vec<f32> = self
+            .samples
+            .iter()
+            .map(|(_, v)| distance_fn(v, &centroids[0]))
+            .collect();
+
+        while centroids.len() < num_centroids {
+            let total: f32 = min_distances.iter().sum();
+            if total <= 0.0 {
+                /* all the remaining samples are duplicates of the centroids */
+                break;
+            }
+            let mut target = self.rng.gen::<f32>() * total;
+            let mut next = min_distances.len() - 1;
+            for (i, d) in min_distances.iter().enumerate() {
+                if target < *d {
+                    next = i;
+                    break;
+                }
+                target -= d;
+            }
+
+            let centroid = self.samples[next].1.clone();
+            for (min_distance, (_, v)) in min_distances.iter_mut().zip(self.samples.iter()) {
+                *min_distance = min_distance.min(distance_fn(v, &centroid));
+            }
+            centroids.push(centroid);
+        }
+        centroids
+    }
+}
+
+fn closest(
+    candidates: &[Vec<f32>],
+    vector: &[f32],
+    distance_fn: fn(&[f32], &[f32]) -> f32,
+) -> usize {
+    let mut best = 0;
+    let mut best_distance = f32::MAX;
+    for (i, candidate) in candidates.iter().enumerate() {
+        let distance = distance_fn(candidate, vector);
+        if distance < best_distance {
+            best = i;
+            best_distance = distance;
+        }
+    }
+    best
+}
+
+/// Recomputes the entry points of a diskann index from a sample of its live nodes and returns
+/// how many were stored. Uses the `num_entry_points` of the index.
+#[pg_extern(volatile)]
+pub fn diskann_refresh_entry_points(index: pg_sys::Oid) -> i32 {
+    let index_relation =
+        open_diskann_index_as_owner(index, pg_sys::ShareUpdateExclusiveLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+
+    let meta_page = MetaPage::fetch(&index_relation);
+    if meta_page.is_partitioned() {
+        error!(
+            "diskann_refresh_entry_points does not support indexes with tenant_buckets or time_buckets"
+        );
+    }
+    let nodes = match meta_page.get_storage_type() {
+        StorageType::Plain => sample_nodes::<PlainStorage>(&index_relation),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            sample_nodes::<SbqSpeedupStorage>(&index_relation)
+        }
+    };
+
+    let indexed_attr = IndexedAttribute::new(&index_relation);
+    let mut slot = unsafe { TableSlot::new(&heap_relation) };
+    let mut stats = GreedySearchStats::new();
+    let mut sampler = EntryPointSampler::new();
+    for (index_pointer, heap_pointer) in nodes {
+        if !unsafe { slot.fetch(&heap_relation, heap_pointer, &mut stats) } {
+            continue;
+        }
+        let Some(datum) = (unsafe { indexed_attr.get_datum(&slot) }) else {
+            continue;
+        };
+        let vector = unsafe { PgVector::from_datum(datum, &meta_page, true, false) };
+        sampler.add(index_pointer, vector.to_index_slice());
+    }
+
+    let entry_points = sampler.choose(
+        meta_page.get_num_entry_points().max(1) as usize,
+        meta_page.get_distance_function(),
+    );
+    if entry_points.is_empty() {
+        /* nothing to choose from, keep the current entry points */
+        return 0;
+    }
+    let num_entry_points = entry_points.len();
+    MetaPage::update_init_ids(&index_relation, entry_points, &mut NoStats);
+    num_entry_points as i32
+}
+
+/// Reservoir sample of the live nodes of an index, as (index pointer, heap pointer) pairs.
+fn sample_nodes<S: Storage>(index: &PgRelation) -> Vec<(IndexPointer, HeapPointer)> {
+    let nblocks = unsafe {
+        pg_sys::RelationGetNumberOfBlocksInFork(index.as_ptr(), pg_sys::ForkNumber_MAIN_FORKNUM)
+    };
+    let mut rng = SmallRng::seed_from_u64(ENTRY_POINTS_SEED);
+    let mut samples = Vec::new();
+    let mut num_seen = 0;
+
+    for block_number in 0..nblocks {
+        let page = unsafe { ReadablePage::read(index, block_number) };
+        if page.is_new() || page.get_type() != S::page_type() {
+            continue;
+        }
+
+        let max_offset = unsafe { PageGetMaxOffsetNumber(*page) };
+        for offset_number in FirstOffsetNumber..(max_offset + 1) as _ {
+            let heap_pointer: ItemPointer = unsafe {
+                let item_id = PageGetItemId(*page, offset_number);
+                let item = PageGetItem(*page, item_id) as *mut u8;
+                let len = (*item_id).lp_len();
+                let data = std::slice::from_raw_parts_mut(item, len as _);
+                let node = S::ArchivedType::with_data(data);
+                if node.is_deleted() {
+                    continue;
+                }
+                node.get_heap_item_pointer()
+            };
+            let index_pointer = ItemPointer::new(block_number, offset_number);
+
+            num_seen += 1;
+            if num_seen <= ENTRY_POINTS_MAX_SAMPLES {
+                samples.push((index_pointer, heap_pointer));
+            } else {
+                let slot = rng.gen_range(0..num_seen);
+                if slot < ENTRY_POINTS_MAX_SAMPLES {
+                    samples[slot] = (index_pointer, heap_pointer);
+                }
+            }
+        }
+    }
+    samples
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use crate::access_method::distance::distance_cosine;
+    use crate::util::ItemPointer;
+
+    use super::EntryPointSampler;
+
+    #[pg_test]
+    fn test_entry_points_one_per_cluster() {
+        let mut sampler = EntryPointSampler::new();
+        for i in 0..30 {
+            /* three well separated clusters around the axes */
+            let mut v = [0.01 * (i % 10) as f32; 3];
+            v[i / 10] = 1.0;
+            let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+            v.iter_mut().for_each(|x| *x /= norm);
+            sampler.add(ItemPointer::new(i as u32, 1), &v);
+        }
+
+        let entry_points = sampler.choose(3, distance_cosine);
+        assert_eq!(entry_points.len(), 3);
+        let mut clusters: Vec<u32> = entry_points.iter().map(|ip| ip.block_number / 10).collect();
+        clusters.sort();
+        assert_eq!(clusters, vec![0, 1, 2]);
+    }
+
+    #[pg_test]
+    unsafe fn test_entry_points_index() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "num_entry_points = 4",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_refresh_entry_points() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[1 + (i % 3 = 0)::int * 10, 1 + (i % 3 = 1)::int * 10, 1 + (i % 3 = 2)::int * 10]::vector
+            FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH (num_entry_points = 3);
+
+            DELETE FROM test WHERE embedding = '[11,1,1]';",
+        )?;
+
+        let num_entry_points: Option<i32> =
+            Spi::get_one("SELECT diskann_refresh_entry_points('idxtest'::regclass)")?;
+        assert!(num_entry_points.unwrap() >= 1);
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by embedding <=> '[1,1,11]') SELECT count(*) from cte;",
+        )?;
+        assert_eq!(200, res.unwrap());
+
+        Spi::run("DROP TABLE test")?;
+        Ok(())
+    }
+
+    #[pg_test(error = "must be owner of index idxtest")]
+    unsafe fn test_refresh_entry_points_not_owner() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test SELECT ARRAY[i, 1, 1]::vector FROM generate_series(1, 10) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            CREATE ROLE diskann_not_owner;
+            GRANT SELECT ON test TO diskann_not_owner;",
+        )?;
+        let index = Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.unwrap();
+
+        /* reading the table is not enough to change the index */
+        Spi::run(&format!(
+            "SET ROLE diskann_not_owner;
+            SELECT diskann_refresh_entry_points('{}'::oid);",
+            index.as_u32()
+        ))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+//! Copies of the full vectors of the nodes, kept in the index for rescoring.
+//!
+//! With the `store_full_vectors` option, the SBQ storage layouts write the full (preprocessed)
+//! vector of every node to pages of their own and keep a pointer to it in the item of the node,
+//! after the neighbor block. Rescoring then reads those pages instead of fetching the rows from
+//! the heap, which is slow when the vectors are TOASTed. The vectors are kept apart from the
+//! nodes so that the graph search still reads as many nodes per page.
+//!
+//! The build writes the full vectors after the graph, so that the graph and the full vectors
+//! take separate block ranges of the index, and prewarming can load the graph alone. The full
+//! vectors of inserted nodes go wherever there is free space.
+//!
+//! With `full_vectors_f16`, the vectors are stored as f16 in half the space. Rescoring then
+//! uses the vectors rounded to f16 precision.
+
+use pgrx::{
+    pg_sys::{BlockNumber, InvalidBlockNumber, InvalidOffsetNumber},
+    PgRelation,
+};
+
+use crate::util::{
+    page::{items_per_page, PageType, WritablePage},
+    ports::{PageGetItem, PageGetItemId},
+    tape::Tape,
+    HeapPointer, IndexPointer, ItemPointer,
+};
+
+use super::{
+    introspection::for_each_live_node,
+    meta_page::MetaPage,
+    neighbor_compression::NeighborBlock,
+    payload::{get_num_payload_columns, get_payload_size},
+    pg_vector::{f16_to_f32, f32_to_f16},
+    sbq::SbqSpeedupStorage,
+    stats::{StatsNodeModify, StatsNodeRead, StatsNodeWrite},
+    storage::ArchivedData,
+};
+
+/// The size of the pointer to the full vector in the item of a node. A multiple of 8 to keep the
+/// archived node that follows it aligned.
+pub const FULL_VECTOR_POINTER_SIZE: usize = 8;
+
+/// Whether and how the full vectors are stored in the index.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FullVectorType {
+    /// not stored, rescoring reads the heap
+    None = 0,
+    /// little-endian f32 values
+    F32 = 1,
+    /// little-endian f16 values, converted to f32 for rescoring
+    F16 = 2,
+}
+
+impl FullVectorType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => FullVectorType::None,
+            1 => FullVectorType::F32,
+            2 => FullVectorType::F16,
+            _ => panic!("Invalid full vector type {}", value),
+        }
+    }
+
+    /// The size of the item of a full vector of `num_dimensions` dimensions.
+    pub fn get_vector_size(&self, num_dimensions: usize) -> usize {
+        match self {
+            FullVectorType::None => 0,
+            FullVectorType::F32 => num_dimensions * std::mem::size_of::<f32>(),
+            FullVectorType::F16 => num_dimensions * std::mem::size_of::<u16>(),
+        }
+    }
+
+    /// Whether a full vector of `num_dimensions` dimensions fits on a page.
+    pub fn fits_on_page(&self, num_dimensions: usize) -> bool {
+        items_per_page(self.get_vector_size(num_dimensions)) > 0
+    }
+
+    fn encode(&self, vector: &[f32]) -> Vec<u8> {
+        match self {
+            FullVectorType::None => panic!("the full vectors are not stored"),
+            FullVectorType::F32 => vector.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            FullVectorType::F16 => vector
+                .iter()
+                .flat_map(|v| f32_to_f16(*v).to_le_bytes())
+                .collect(),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Vec<f32> {
+        match self {
+            FullVectorType::None => panic!("the full vectors are not stored"),
+            FullVectorType::F32 => bytes
+                .chunks_exact(std::mem::size_of::<f32>())
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect(),
+            FullVectorType::F16 => bytes
+                .chunks_exact(std::mem::size_of::<u16>())
+                .map(|b| f16_to_f32(u16::from_le_bytes(b.try_into().unwrap())))
+                .collect(),
+        }
+    }
+}
+
+/// Where the pointer to the full vector is stored in the items of the nodes of an index, and
+/// how the vectors are encoded.
+#[derive(Clone, Copy, Debug)]
+pub struct FullVectorStore {
+    vector_type: FullVectorType,
+    offset: usize,
+}
+
+impl FullVectorStore {
+    /// The store of the nodes of `index`, None if the index does not store the full vectors.
+    pub fn for_index(index: &PgRelation, meta_page: &MetaPage) -> Option<Self> {
+        let vector_type = meta_page.get_full_vector_type();
+        if vector_type == FullVectorType::None {
+            return None;
+        }
+        let neighbor_block_size = NeighborBlock::for_index(index, meta_page)
+            .map_or(0, |neighbor_block| neighbor_block.empty().len());
+        Some(Self {
+            vector_type,
+            offset: get_payload_size(get_num_payload_columns(index)) + neighbor_block_size,
+        })
+    }
+
+    /// Writes `vector` to the full vector pages of `tape` and returns the pointer to it, to store
+    /// in the item of the node.
+    pub fn write<S: StatsNodeWrite>(
+        &self,
+        vector: &[f32],
+        tape: &mut Tape,
+        stats: &mut S,
+    ) -> [u8; FULL_VECTOR_POINTER_SIZE] {
+        let index_pointer = unsafe { tape.write(&self.vector_type.encode(vector)) };
+        stats.record_write();
+        let mut pointer = [0; FULL_VECTOR_POINTER_SIZE];
+        pointer[..4].copy_from_slice(&index_pointer.block_number.to_le_bytes());
+        pointer[4..6].copy_from_slice(&index_pointer.offset.to_le_bytes());
+        pointer
+    }
+
+    /// The pointer to the full vector of a node, from `item`, the index item of the node.
+    pub fn read_pointer(&self, item: &[u8]) -> Option<IndexPointer> {
+        let pointer = &item[self.offset..self.offset + FULL_VECTOR_POINTER_SIZE];
+        let block_number = BlockNumber::from_le_bytes(pointer[..4].try_into().unwrap());
+        let offset = u16::from_le_bytes(pointer[4..6].try_into().unwrap());
+        if block_number == InvalidBlockNumber || offset == InvalidOffsetNumber {
+            return None;
+        }
+        Some(ItemPointer::new(block_number, offset))
+    }
+
+    /// Removes the pointer to the full vector from `item`, the index item of a deleted node.
+    pub fn clear_pointer(&self, item: &mut [u8]) {
+        let pointer = &mut item[self.offset..self.offset + FULL_VECTOR_POINTER_SIZE];
+        pointer.fill(0);
+        pointer[..4].copy_from_slice(&InvalidBlockNumber.to_le_bytes());
+    }
+
+    /// Reads the full vector at `pointer`.
+    pub unsafe fn read<S: StatsNodeRead>(
+        &self,
+        index: &PgRelation,
+        pointer: IndexPointer,
+        stats: &mut S,
+    ) -> Vec<f32> {
+        let bytes = pointer.read_bytes(index);
+        stats.record_read();
+        self.vector_type.decode(bytes.get_data_slice())
+    }
+}
+
+/// Writes the full vectors of the nodes of a new index after its graph. The build writes the
+/// nodes without pointers to their full vectors, then reads the vectors from the heap again and
+/// passes them to `add`, and `finish` stores the pointers in the nodes.
+pub struct FullVectorBuilder<'a> {
+    index: &'a PgRelation,
+    store: FullVectorStore,
+    tape: Option<Tape<'a>>,
+    /* the live nodes, ordered by heap pointer */
+    nodes: Vec<(HeapPointer, IndexPointer)>,
+    /* the pointers to the full vectors written, by node */
+    pointers: Vec<(IndexPointer, [u8; FULL_VECTOR_POINTER_SIZE])>,
+}
+
+impl<'a> FullVectorBuilder<'a> {
+    pub fn new(index: &'a PgRelation, store: FullVectorStore) -> Self {
+        let mut nodes = Vec::new();
+        for_each_live_node::<SbqSpeedupStorage>(index, |index_pointer, node| {
+            nodes.push((node.get_heap_item_pointer(), index_pointer));
+        });
+        nodes.sort();
+        Self {
+            index,
+            store,
+            tape: None,
+            pointers: Vec::with_capacity(nodes.len()),
+            nodes,
+        }
+    }
+
+    /// Writes the full vector of the node of the row at `heap_pointer`, if the row has a node.
+    pub fn add<S: StatsNodeWrite>(
+        &mut self,
+        heap_pointer: HeapPointer,
+        vector: &[f32],
+        stats: &mut S,
+    ) {
+        let Ok(i) = self
+            .nodes
+            .binary_search_by_key(&heap_pointer, |(hp, _)| *hp)
+        else {
+            return;
+        };
+        let index = self.index;
+        let tape = self
+            .tape
+            .get_or_insert_with(|| unsafe { Tape::new(index, PageType::FullVector) });
+        let pointer = self.store.write(vector, tape, stats);
+        self.pointers.push((self.nodes[i].1, pointer));
+    }
+
+    /// Stores the pointers to the full vectors in the nodes, a page of nodes at a time.
+    pub unsafe fn finish<S: StatsNodeModify>(mut self, stats: &mut S) {
+        self.pointers
+            .sort_by_key(|(index_pointer, _)| *index_pointer);
+        let mut start = 0;
+        while start < self.pointers.len() {
+            let block_number = self.pointers[start].0.block_number;
+            let end = start
+                + self.pointers[start..]
+                    .iter()
+                    .take_while(|(index_pointer, _)| index_pointer.block_number == block_number)
+                    .count();
+            let page = WritablePage::modify(self.index, block_number);
+            for (index_pointer, pointer) in &self.pointers[start..end] {
+                let item_id = PageGetItemId(*page, index_pointer.offset);
+                let item = PageGetItem(*page, item_id) as *mut u8;
+                let data = std::slice::from_raw_parts_mut(item, (*item_id).lp_len() as _);
+                data[self.store.offset..self.store.offset + FULL_VECTOR_POINTER_SIZE]
+                    .copy_from_slice(pointer);
+                stats.record_modify();
+            }
+            page.commit();
+            start = end;
+        }
+    }
+}
+
+/// Marks the full vectors of deleted nodes dead, so that the vectors of new nodes take their
+/// space, and records the space in the free space map.
+pub unsafe fn free_full_vectors(index: &PgRelation, mut pointers: Vec<IndexPointer>) {
+    pointers.sort_by_key(|p| (p.block_number, p.offset));
+    let mut start = 0;
+    while start < pointers.len() {
+        let block_number = pointers[start].block_number;
+        let end = start
+            + pointers[start..]
+                .iter()
+                .take_while(|p| p.block_number == block_number)
+                .count();
+        let mut page = WritablePage::modify(index, block_number);
+        if page.get_type() == PageType::FullVector {
+            for pointer in &pointers[start..end] {
+                page.mark_item_dead(pointer.offset);
+            }
+            let reusable_space = page.get_reusable_space();
+            page.commit();
+            pgrx::pg_sys::RecordPageWithFreeSpace(index.as_ptr(), block_number, reusable_space);
+        }
+        start = end;
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_store_full_vectors() -> spi::Result<()> {
+        for storage_layout in ["memory_optimized", "io_optimized"] {
+            Spi::run(&format!(
+                "DROP TABLE IF EXISTS test;
+                CREATE TABLE test(id int, embedding vector(64));
+
+                INSERT INTO test(id, embedding)
+                SELECT i::int, v::vector
+                FROM diskann_generate_vectors(500, 64, num_clusters => 5, seed => 3)
+                    WITH ORDINALITY AS g(v, i);
+
+                CREATE INDEX idxtest ON test USING diskann(embedding)
+                    WITH (storage_layout = {storage_layout}, num_dimensions = 32,
+                        store_full_vectors = true);
+
+                INSERT INTO test(id, embedding)
+                SELECT 500 + i::int, v::vector
+                FROM diskann_generate_vectors(100, 64, num_clusters => 5, seed => 4)
+                    WITH ORDINALITY AS g(v, i);
+
+                SET enable_seqscan = 0;",
+            ))?;
+
+            /* every node of the build and of the inserts has its full vector */
+            let num_vectors = Spi::get_one::<i64>(
+                "SELECT sum(num_items)::int8 FROM diskann_page_stats('idxtest'::regclass)
+                WHERE page_type = 'full_vector'",
+            )?;
+            assert_eq!(num_vectors, Some(600), "{}", storage_layout);
+
+            /* the rescored results are ordered by the full distance */
+            let ordered = Spi::get_one::<bool>(
+                "WITH r AS (
+                    SELECT id, embedding <=> (SELECT embedding FROM test WHERE id = 7) AS d,
+                        row_number() OVER () AS n
+                    FROM (
+                        SELECT id, embedding FROM test
+                        ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 7)
+                        LIMIT 10
+                    ) q
+                ) SELECT bool_and(d >= coalesce((SELECT p.d FROM r p WHERE p.n = r.n - 1), 0))
+                    AND (SELECT id FROM r WHERE n = 1) = 7
+                FROM r",
+            )?;
+            assert_eq!(ordered, Some(true), "{}", storage_layout);
+        }
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_store_full_vectors_f16() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(64));
+
+            INSERT INTO test(id, embedding)
+            SELECT i::int, v::vector
+            FROM diskann_generate_vectors(500, 64, num_clusters => 5, seed => 3)
+                WITH ORDINALITY AS g(v, i);
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (num_dimensions = 32, store_full_vectors = true, full_vectors_f16 = true);
+
+            SET enable_seqscan = 0;",
+        )?;
+
+        /* 2 bytes per dimension */
+        let (num_vectors, avg_item_bytes) = Spi::get_two::<i64, f64>(
+            "SELECT num_items, avg_item_bytes FROM diskann_page_stats('idxtest'::regclass)
+            WHERE page_type = 'full_vector'",
+        )?;
+        assert_eq!(num_vectors, Some(500));
+        assert_eq!(avg_item_bytes, Some(128.0));
+
+        let first = Spi::get_one::<i32>(
+            "SELECT id FROM test
+            ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 7)
+            LIMIT 1",
+        )?;
+        assert_eq!(first, Some(7));
+        Ok(())
+    }
+
+    #[pg_test(error = "full_vectors_f16 requires store_full_vectors")]
+    unsafe fn test_full_vectors_f16_without_store() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (full_vectors_f16 = true);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "store_full_vectors is only supported with the memory_optimized and io_optimized storage layouts"
+    )]
+    unsafe fn test_store_full_vectors_plain() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (storage_layout = plain, store_full_vectors = true);",
+        )?;
+        Ok(())
+    }
+}
@@ -1,17 +1,23 @@
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
-use std::{cmp::Ordering, collections::HashSet};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+};
 
 use pgrx::PgRelation;
 
-use crate::access_method::storage::NodeDistanceMeasure;
-
+use crate::diskann::prune::{robust_prune, PruneDistances};
 use crate::util::{HeapPointer, IndexPointer, ItemPointer};
 
 use super::graph_neighbor_store::GraphNeighborStore;
+use super::node_placement::{breadth_first_locations, move_nodes};
 
 use super::pg_vector::PgVector;
-use super::stats::{GreedySearchStats, InsertStats, PruneNeighborStats, StatsNodeVisit};
+use super::stats::{
+    GreedySearchStats, InsertStats, PruneNeighborStats, StatsNodeModify, StatsNodeRead,
+    StatsNodeVisit,
+};
 use super::storage::Storage;
 use super::{meta_page::MetaPage, neighbor_with_distance::NeighborWithDistance};
 
@@ -142,19 +148,33 @@ impl<QDM, PD> ListSearchResult<QDM, PD> {
     pub fn consume<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
         &mut self,
         storage: &S,
-    ) -> Option<(HeapPointer, IndexPointer)> {
+    ) -> Option<(HeapPointer, IndexPointer, f32)> {
         if self.visited.len() == 0 {
             return None;
         }
         let lsn = self.visited.remove(0);
         let heap_pointer = storage.return_lsn(&lsn, &mut self.stats);
-        return Some((heap_pointer, lsn.index_pointer));
+        return Some((heap_pointer, lsn.index_pointer, lsn.distance));
+    }
+}
+
+/// The distances the pruning needs, computed from the vectors of the storage.
+struct StorageDistances<'a, S: Storage> {
+    storage: &'a S,
+    stats: &'a mut PruneNeighborStats,
+}
+
+impl<'a, S: Storage> PruneDistances<IndexPointer> for StorageDistances<'a, S> {
+    fn get_distances(&mut self, from: IndexPointer, to: &[IndexPointer]) -> Vec<f32> {
+        self.storage.get_node_distances(from, to, self.stats)
     }
 }
 
 pub struct Graph<'a> {
     neighbor_store: GraphNeighborStore,
     meta_page: &'a mut MetaPage,
+    /* the subgraphs searched and inserted into, for indexes split into buckets */
+    buckets: Option<Vec<u32>>,
 }
 
 impl<'a> Graph<'a> {
@@ -162,15 +182,75 @@ impl<'a> Graph<'a> {
         Self {
             neighbor_store,
             meta_page,
+            buckets: None,
         }
     }
 
+    /// Limits the searches to the subgraphs of some tenant or time buckets, see `tenant` and
+    /// `time_buckets`, and the inserts to the subgraph of one. None for the whole graph.
+    pub fn set_buckets(&mut self, buckets: Option<Vec<u32>>) {
+        self.buckets = buckets;
+    }
+
     pub fn get_neighbor_store(&self) -> &GraphNeighborStore {
         &self.neighbor_store
     }
 
+    /// Sets the entry points of the graph, e.g. of a graph restored from a build checkpoint.
+    pub fn set_init_ids<S: StatsNodeModify>(
+        &mut self,
+        index: &PgRelation,
+        init_ids: Vec<IndexPointer>,
+        stats: &mut S,
+    ) {
+        MetaPage::update_init_ids(index, init_ids, stats);
+        *self.meta_page = MetaPage::fetch(index);
+    }
+
+    /// Moves the nodes of a graph being built into breadth-first order from the entry points,
+    /// see `node_placement`. Returns the new location of every node.
+    pub fn place_nodes_breadth_first<S: StatsNodeRead + StatsNodeModify>(
+        &mut self,
+        index: &PgRelation,
+        stats: &mut S,
+    ) -> HashMap<IndexPointer, IndexPointer> {
+        let builder = match &mut self.neighbor_store {
+            GraphNeighborStore::Builder(builder) => builder,
+            GraphNeighborStore::Disk => {
+                panic!("Nodes can only be placed while the graph is being built")
+            }
+        };
+        let locations: Vec<IndexPointer> = builder.iter().map(|(&ip, _)| ip).collect();
+        let init_ids = self.meta_page.get_init_ids().unwrap_or_default();
+        let new_locations =
+            breadth_first_locations(&locations, &init_ids, |ip| builder.get_neighbors(ip));
+
+        unsafe { move_nodes(index, &new_locations, stats) };
+        builder.relocate(&new_locations);
+        if !init_ids.is_empty() {
+            MetaPage::relocate_init_ids(index, |ip| new_locations[&ip], stats);
+            *self.meta_page = MetaPage::fetch(index);
+        }
+        new_locations
+    }
+
+    /// Replaces the neighbors of a node, e.g. with neighbors imported from a graph file.
+    pub fn set_neighbors<S: Storage>(
+        &mut self,
+        storage: &S,
+        neighbors_of: ItemPointer,
+        neighbors: Vec<NeighborWithDistance>,
+        stats: &mut PruneNeighborStats,
+    ) {
+        self.neighbor_store
+            .set_neighbors(storage, self.meta_page, neighbors_of, neighbors, stats);
+    }
+
     fn get_init_ids(&self) -> Option<Vec<ItemPointer>> {
-        self.meta_page.get_init_ids()
+        match &self.buckets {
+            Some(buckets) => self.meta_page.get_bucket_init_ids(buckets),
+            None => self.meta_page.get_init_ids(),
+        }
     }
 
     fn add_neighbors<S: Storage>(
@@ -279,7 +359,7 @@ impl<'a> Graph<'a> {
     /// the next elements.
     pub fn greedy_search_streaming_init<S: Storage>(
         &self,
-        query: PgVector,
+        dm: S::QueryDistanceMeasure,
         search_list_size: usize,
         storage: &S,
     ) -> ListSearchResult<S::QueryDistanceMeasure, S::LSNPrivateData> {
@@ -288,7 +368,6 @@ impl<'a> Graph<'a> {
             //no nodes in the graph
             return ListSearchResult::empty();
         }
-        let dm = storage.get_query_distance_measure(query);
 
         ListSearchResult::new(
             init_ids.unwrap(),
@@ -346,95 +425,19 @@ impl<'a> Graph<'a> {
 
         //sort by distance
         candidates.sort();
-        let mut results = Vec::<NeighborWithDistance>::with_capacity(
+        let points: Vec<(IndexPointer, f32)> = candidates
+            .iter()
+            .map(|c| (c.get_index_pointer_to_neighbor(), c.get_distance()))
+            .collect();
+        let chosen = robust_prune(
+            &points,
             self.get_meta_page().get_num_neighbors() as _,
+            max_alpha,
+            self.get_meta_page().get_num_dimensions() as _,
+            &mut StorageDistances { storage, stats },
         );
-
-        let mut max_factors: Vec<f64> = vec![0.0; candidates.len()];
-
-        let mut alpha = 1.0;
-        let dimension_epsilon = self.get_meta_page().get_num_dimensions() as f32 * f32::EPSILON;
-        //first we add nodes that "pass" a small alpha. Then, if there
-        //is still room we loop again with a larger alpha.
-        while alpha <= max_alpha && results.len() < self.get_meta_page().get_num_neighbors() as _ {
-            for (i, neighbor) in candidates.iter().enumerate() {
-                if results.len() >= self.get_meta_page().get_num_neighbors() as _ {
-                    return results;
-                }
-                if max_factors[i] > alpha {
-                    continue;
-                }
-
-                //don't consider again
-                max_factors[i] = f64::MAX;
-                results.push(neighbor.clone());
-
-                //we've now added this to the results so it's going to be a neighbor
-                //rename for clarity.
-                let existing_neighbor = neighbor;
-
-                let dist_state = unsafe {
-                    storage.get_node_distance_measure(
-                        existing_neighbor.get_index_pointer_to_neighbor(),
-                        stats,
-                    )
-                };
-
-                //go thru the other candidates (tail of the list)
-                for (j, candidate_neighbor) in candidates.iter().enumerate().skip(i + 1) {
-                    //has it been completely excluded?
-                    if max_factors[j] > max_alpha {
-                        continue;
-                    }
-
-                    let mut distance_between_candidate_and_existing_neighbor = unsafe {
-                        dist_state
-                            .get_distance(candidate_neighbor.get_index_pointer_to_neighbor(), stats)
-                    };
-                    let mut distance_between_candidate_and_point =
-                        candidate_neighbor.get_distance();
-
-                    //We need both values to be positive.
-                    //Otherwise, the case where distance_between_candidate_and_point > 0 and distance_between_candidate_and_existing_neighbor < 0 is totally wrong.
-                    //If we implement inner product distance we'll have to figure something else out.
-                    if distance_between_candidate_and_point < 0.0
-                        && distance_between_candidate_and_point >= 0.0 - dimension_epsilon
-                    {
-                        distance_between_candidate_and_point = 0.0;
-                    }
-
-                    if distance_between_candidate_and_existing_neighbor < 0.0
-                        && distance_between_candidate_and_existing_neighbor
-                            >= 0.0 - dimension_epsilon
-                    {
-                        distance_between_candidate_and_existing_neighbor = 0.0;
-                    }
-
-                    debug_assert!(
-                        distance_between_candidate_and_point >= 0.0,
-                        "distance_between_candidate_and_point is negative: {}, {}",
-                        distance_between_candidate_and_point,
-                        f32::EPSILON
-                    );
-                    debug_assert!(distance_between_candidate_and_existing_neighbor >= 0.0);
-
-                    //factor is high if the candidate is closer to an existing neighbor than the point it's being considered for
-                    let factor =
-                        if distance_between_candidate_and_existing_neighbor < 0.0 + f32::EPSILON {
-                            if distance_between_candidate_and_point < 0.0 + f32::EPSILON {
-                                1.0
-                            } else {
-                                f64::MAX
-                            }
-                        } else {
-                            distance_between_candidate_and_point as f64
-                                / distance_between_candidate_and_existing_neighbor as f64
-                        };
-                    max_factors[j] = max_factors[j].max(factor)
-                }
-            }
-            alpha = alpha * 1.2
-        }
+        let results: Vec<NeighborWithDistance> =
+            chosen.into_iter().map(|i| candidates[i].clone()).collect();
         stats.num_neighbors_after_prune += results.len();
         results
     }
@@ -447,9 +450,15 @@ impl<'a> Graph<'a> {
         storage: &S,
         stats: &mut InsertStats,
     ) {
-        if self.meta_page.get_init_ids().is_none() {
+        if self.get_init_ids().is_none() {
             //TODO probably better set off of centeroids
-            MetaPage::update_init_ids(index, vec![index_pointer], stats);
+            match &self.buckets {
+                Some(buckets) => {
+                    assert!(buckets.len() == 1);
+                    MetaPage::update_bucket_init_id(index, buckets[0], index_pointer, stats)
+                }
+                None => MetaPage::set_first_init_id(index, index_pointer, stats),
+            }
             *self.meta_page = MetaPage::fetch(index);
 
             self.neighbor_store.set_neighbors(
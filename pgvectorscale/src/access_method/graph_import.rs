@@ -0,0 +1,405 @@
+//! Imports graphs built outside the database by Microsoft DiskANN.
+//!
+//! `diskann_import_graph` creates a diskann index whose graph comes from the graph file of an
+//! in-memory DiskANN index instead of being built. The build still scans the table to create a
+//! node for every row, but instead of searching the graph to insert a node, it takes the
+//! neighbors of the node from the file. The neighbors are then pruned to `num_neighbors` and
+//! written like at the end of a regular build.
+//!
+//! DiskANN identifies the points of a graph by their position in the file of vectors the graph
+//! was built from. A key column of the table holds that position for every row. The vectors
+//! themselves are read from the table, so the file of vectors isn't needed.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, Read},
+    ptr::addr_of_mut,
+};
+
+use pgrx::{pg_sys, *};
+
+use crate::util::{table_slot::TableSlot, HeapPointer, IndexPointer};
+
+use super::{
+    graph::Graph,
+    neighbor_with_distance::NeighborWithDistance,
+    stats::{GreedySearchStats, InsertStats},
+    storage::{NodeDistanceMeasure, Storage},
+};
+
+/// The size of the header of a graph file: the file size, the maximum degree, the entry point
+/// and the number of frozen points.
+const GRAPH_HEADER_SIZE: u64 = 8 + 4 + 4 + 8;
+
+/// A graph to import into the next index built with the given name on the given table. Set by
+/// `diskann_import_graph` only while it runs CREATE INDEX.
+struct PendingImport {
+    heap: pg_sys::Oid,
+    index_name: String,
+    graph_path: String,
+    key_column: String,
+}
+
+/// Backends are single threaded.
+static mut PENDING_IMPORT: Option<PendingImport> = None;
+
+/// The nodes created by a build that imports a graph, by their DiskANN id.
+pub struct ImportedNodes<'a> {
+    graph_path: String,
+    heap: &'a PgRelation,
+    key_attribute: pg_sys::AttrNumber,
+    key_type: pg_sys::Oid,
+    slot: TableSlot,
+    index_pointers: Vec<Option<IndexPointer>>,
+    first_index_pointer: Option<IndexPointer>,
+    num_nodes: usize,
+    stats: GreedySearchStats,
+}
+
+impl<'a> ImportedNodes<'a> {
+    /// Returns the graph to import into `index`, if its build was started by
+    /// `diskann_import_graph`.
+    pub fn for_build(heap: &'a PgRelation, index: &PgRelation) -> Option<Self> {
+        let import = unsafe { addr_of_mut!(PENDING_IMPORT).replace(None) }?;
+        if import.heap != heap.oid() || import.index_name != index.name() {
+            return None;
+        }
+
+        let tuple_desc = heap.tuple_desc();
+        let Some(key) = tuple_desc
+            .iter()
+            .find(|a| !a.is_dropped() && a.name() == import.key_column)
+        else {
+            error!(
+                "column \"{}\" of relation \"{}\" does not exist",
+                import.key_column,
+                heap.name()
+            );
+        };
+        let key_type = key.atttypid;
+        if ![pg_sys::INT2OID, pg_sys::INT4OID, pg_sys::INT8OID].contains(&key_type) {
+            error!(
+                "the key column \"{}\" must be of type smallint, integer or bigint",
+                import.key_column
+            );
+        }
+
+        Some(Self {
+            graph_path: import.graph_path,
+            heap,
+            key_attribute: key.attnum,
+            key_type,
+            slot: unsafe { TableSlot::new(heap) },
+            index_pointers: Vec::new(),
+            first_index_pointer: None,
+            num_nodes: 0,
+            stats: GreedySearchStats::new(),
+        })
+    }
+
+    /// Records the node created for the row at `heap_pointer`.
+    pub fn add_node(&mut self, heap_pointer: HeapPointer, index_pointer: IndexPointer) {
+        let key = unsafe {
+            if !self.slot.fetch(self.heap, heap_pointer, &mut self.stats) {
+                error!(
+                    "could not fetch the row at ({}, {})",
+                    heap_pointer.block_number, heap_pointer.offset
+                );
+            }
+            self.slot
+                .get_attribute(self.key_attribute)
+                .map(|datum| match self.key_type {
+                    pg_sys::INT2OID => i16::from_datum(datum, false).unwrap() as i64,
+                    pg_sys::INT4OID => i32::from_datum(datum, false).unwrap() as i64,
+                    _ => i64::from_datum(datum, false).unwrap(),
+                })
+        };
+        let Some(key) = key.filter(|key| (0..=u32::MAX as i64).contains(key)) else {
+            error!(
+                "the row at ({}, {}) has no valid key, keys must be between 0 and {}",
+                heap_pointer.block_number,
+                heap_pointer.offset,
+                u32::MAX
+            );
+        };
+
+        let id = key as usize;
+        if id >= self.index_pointers.len() {
+            self.index_pointers.resize(id + 1, None);
+        }
+        if self.index_pointers[id].is_some() {
+            error!("more than one row has the key {}", key);
+        }
+        self.index_pointers[id] = Some(index_pointer);
+        self.first_index_pointer.get_or_insert(index_pointer);
+        self.num_nodes += 1;
+    }
+
+    /// Sets the neighbors of every node to the neighbors of its point in the graph file, and
+    /// makes the entry point of the file the entry point of the index. Neighbors without a row
+    /// are dropped.
+    pub fn set_neighbors<S: Storage>(
+        self,
+        index: &PgRelation,
+        graph: &mut Graph,
+        storage: &S,
+        stats: &mut InsertStats,
+    ) {
+        let mut reader = GraphFileReader::open(&self.graph_path);
+        let mut num_imported = 0;
+        let mut id = 0;
+        while let Some(neighbor_ids) = reader.next_neighbors() {
+            check_for_interrupts!();
+            if let Some(index_pointer) = self.get_index_pointer(id) {
+                let neighbors = unsafe {
+                    let distance_measure = storage.get_node_distance_measure(index_pointer, stats);
+                    neighbor_ids
+                        .into_iter()
+                        .filter(|neighbor_id| *neighbor_id != id)
+                        .filter_map(|neighbor_id| self.get_index_pointer(neighbor_id))
+                        .map(|neighbor| {
+                            let distance = distance_measure
+                                .get_distance(neighbor, &mut stats.prune_neighbor_stats);
+                            NeighborWithDistance::new(neighbor, distance)
+                        })
+                        .collect()
+                };
+                graph.set_neighbors(
+                    storage,
+                    index_pointer,
+                    neighbors,
+                    &mut stats.prune_neighbor_stats,
+                );
+                num_imported += 1;
+            }
+            id += 1;
+        }
+
+        if num_imported < self.num_nodes {
+            error!(
+                "{} rows have a key that is not a point of the graph, which has {} points",
+                self.num_nodes - num_imported,
+                id
+            );
+        }
+
+        let entry_point = self
+            .get_index_pointer(reader.get_entry_point())
+            .or(self.first_index_pointer);
+        if let Some(entry_point) = entry_point {
+            graph.set_init_ids(index, vec![entry_point], stats);
+        }
+    }
+
+    /// The node of the point `id` of the graph, if a row has that key.
+    fn get_index_pointer(&self, id: u32) -> Option<IndexPointer> {
+        self.index_pointers.get(id as usize).copied().flatten()
+    }
+}
+
+/// Reads the graph file of an in-memory DiskANN index: a header with the size of the file, the
+/// maximum degree, the entry point and the number of frozen points, followed by the number of
+/// neighbors and the neighbors of every point, all little-endian.
+struct GraphFileReader {
+    path: String,
+    reader: BufReader<File>,
+    remaining: u64,
+    entry_point: u32,
+}
+
+impl GraphFileReader {
+    fn open(path: &str) -> Self {
+        Self::open_internal(path)
+            .unwrap_or_else(|e| error!("could not read the graph file \"{}\": {}", path, e))
+    }
+
+    fn open_internal(path: &str) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let expected_len = read_u64(&mut reader)?;
+        let _max_degree = read_u32(&mut reader)?;
+        let entry_point = read_u32(&mut reader)?;
+        let _num_frozen_points = read_u64(&mut reader)?;
+        if expected_len != file_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a DiskANN graph file, its size does not match its header",
+            ));
+        }
+        Ok(Self {
+            path: path.to_string(),
+            reader,
+            remaining: file_len - GRAPH_HEADER_SIZE,
+            entry_point,
+        })
+    }
+
+    /// The point the searches of DiskANN start from.
+    fn get_entry_point(&self) -> u32 {
+        self.entry_point
+    }
+
+    /// The neighbors of the next point, or None after the last one.
+    fn next_neighbors(&mut self) -> Option<Vec<u32>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let neighbors = self
+            .read_neighbors()
+            .unwrap_or_else(|e| error!("could not read the graph file \"{}\": {}", self.path, e));
+        Some(neighbors)
+    }
+
+    fn read_neighbors(&mut self) -> io::Result<Vec<u32>> {
+        let num_neighbors = read_u32(&mut self.reader)?;
+        let size = 4 * (1 + num_neighbors as u64);
+        if size > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the neighbors of the last point are cut off",
+            ));
+        }
+        self.remaining -= size;
+        (0..num_neighbors)
+            .map(|_| read_u32(&mut self.reader))
+            .collect()
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Creates the diskann index `index_name` on `column_name` of `table_name` from the graph file
+/// of an in-memory DiskANN index, instead of building the graph. `key_column` holds the
+/// position of the vector of every row in the file the graph was built from, i.e. the id of
+/// its point in the graph. Every row must have a distinct key that is a point of the graph.
+/// Points without a row are left out of the index. `options` are the options of the index, as
+/// in the WITH clause of CREATE INDEX.
+///
+/// The graph file is read by the server, so this requires the privileges of the
+/// pg_read_server_files role.
+#[pg_extern(volatile)]
+pub fn diskann_import_graph(
+    index_name: &str,
+    table_name: pg_sys::Oid,
+    column_name: &str,
+    key_column: &str,
+    graph_path: &str,
+    options: default!(Option<&str>, "NULL"),
+) {
+    let can_read_files =
+        Spi::get_one::<bool>("SELECT pg_has_role('pg_read_server_files', 'USAGE')")
+            .expect("failed to check the privileges of the user");
+    if can_read_files != Some(true) {
+        error!("must have the privileges of the pg_read_server_files role to import a graph file");
+    }
+
+    let mut statement = Spi::get_one_with_args::<String>(
+        "SELECT format('CREATE INDEX %I ON %s USING diskann (%I)', $1, $2::regclass, $3)",
+        vec![
+            (PgBuiltInOids::TEXTOID.oid(), index_name.into_datum()),
+            (PgBuiltInOids::OIDOID.oid(), table_name.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), column_name.into_datum()),
+        ],
+    )
+    .expect("failed to format the CREATE INDEX statement")
+    .expect("the CREATE INDEX statement was null");
+    if let Some(options) = options.filter(|o| !o.trim().is_empty()) {
+        statement.push_str(&format!(" WITH ({})", options));
+    }
+
+    unsafe {
+        PENDING_IMPORT = Some(PendingImport {
+            heap: table_name,
+            index_name: index_name.to_string(),
+            graph_path: graph_path.to_string(),
+            key_column: key_column.to_string(),
+        });
+    }
+    PgTryBuilder::new(|| Spi::run(&statement).expect("failed to create the index"))
+        .finally(|| unsafe { PENDING_IMPORT = None })
+        .execute();
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use std::io::Write;
+
+    use pgrx::*;
+
+    /// Writes a DiskANN graph file where every point is connected to the `degree` points
+    /// before and after it on a ring of `num_points` points.
+    fn write_ring_graph(path: &std::path::Path, num_points: u32, degree: u32) {
+        let mut body = Vec::new();
+        for i in 0..num_points {
+            body.extend_from_slice(&(2 * degree).to_le_bytes());
+            for d in 1..=degree {
+                body.extend_from_slice(&((i + d) % num_points).to_le_bytes());
+                body.extend_from_slice(&((i + num_points - d) % num_points).to_le_bytes());
+            }
+        }
+        let mut file = std::fs::File::create(path).unwrap();
+        file.write_all(&(24 + body.len() as u64).to_le_bytes())
+            .unwrap();
+        file.write_all(&(2 * degree).to_le_bytes()).unwrap();
+        file.write_all(&0u32.to_le_bytes()).unwrap();
+        file.write_all(&0u64.to_le_bytes()).unwrap();
+        file.write_all(&body).unwrap();
+    }
+
+    #[pg_test]
+    unsafe fn test_import_graph() -> spi::Result<()> {
+        let path = std::env::temp_dir().join("diskann_test_import.graph");
+        write_ring_graph(&path, 200, 5);
+
+        /* points on a circle, so that the ring is the graph of the nearest neighbors */
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[cos(2 * pi() * i / 200), sin(2 * pi() * i / 200), 1]::vector
+            FROM generate_series(0, 199) i;",
+        )?;
+        Spi::run(&format!(
+            "SELECT diskann_import_graph('idxtest', 'test'::regclass, 'embedding', 'id', '{}',
+                'storage_layout = plain, num_neighbors = 20')",
+            path.display()
+        ))?;
+
+        let num_nodes = Spi::get_one::<i64>("SELECT num_nodes FROM diskann_last_build_stats()")?;
+        assert_eq!(num_nodes, Some(200));
+
+        let num_edges =
+            Spi::get_one::<i64>("SELECT count(*) FROM diskann_graph_edges('idxtest'::regclass)")?;
+        assert_eq!(num_edges, Some(2000));
+
+        let neighbors = Spi::get_one::<String>(
+            "SELECT string_agg(n.id::text, ',' ORDER BY n.id)
+            FROM test t, diskann_node_neighbors('idxtest'::regclass, t.ctid) e
+            JOIN test n ON n.ctid = e.neighbor_ctid
+            WHERE t.id = 0",
+        )?;
+        assert_eq!(neighbors.as_deref(), Some("1,2,3,4,5,195,196,197,198,199"));
+
+        Spi::run("SET enable_seqscan = 0")?;
+        let nearest = Spi::get_one::<i32>(
+            "SELECT id FROM test ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 100)
+            LIMIT 1",
+        )?;
+        assert_eq!(nearest, Some(100));
+
+        std::fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+}
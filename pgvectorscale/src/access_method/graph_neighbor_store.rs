@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 
 use crate::util::{IndexPointer, ItemPointer};
 
@@ -18,14 +18,29 @@ pub struct BuilderNeighborCache {
     //use a btree to provide ordering on the item pointers in iter().
     //this ensures the write in finalize_node_at_end_of_build() is ordered, not random.
     neighbor_map: BTreeMap<ItemPointer, Vec<NeighborWithDistance>>,
+    /* an estimate of the memory held by the neighbor lists */
+    memory_bytes: usize,
+}
+
+/// An estimate of the memory held by the entry of a node in the map.
+fn entry_bytes(neighbors: &Vec<NeighborWithDistance>) -> usize {
+    std::mem::size_of::<ItemPointer>()
+        + std::mem::size_of::<Vec<NeighborWithDistance>>()
+        + neighbors.capacity() * std::mem::size_of::<NeighborWithDistance>()
 }
 
 impl BuilderNeighborCache {
     pub fn new() -> Self {
         Self {
             neighbor_map: BTreeMap::new(),
+            memory_bytes: 0,
         }
     }
+
+    /// An estimate of the memory held by the neighbor lists, for the progress of the build.
+    pub fn get_memory_bytes(&self) -> usize {
+        self.memory_bytes
+    }
     pub fn iter(&self) -> impl Iterator<Item = (&ItemPointer, &Vec<NeighborWithDistance>)> {
         self.neighbor_map.iter()
     }
@@ -62,12 +77,36 @@ impl BuilderNeighborCache {
         neighbors_of: ItemPointer,
         new_neighbors: Vec<NeighborWithDistance>,
     ) {
-        self.neighbor_map.insert(neighbors_of, new_neighbors);
+        self.memory_bytes += entry_bytes(&new_neighbors);
+        if let Some(old_neighbors) = self.neighbor_map.insert(neighbors_of, new_neighbors) {
+            self.memory_bytes -= entry_bytes(&old_neighbors);
+        }
     }
 
     pub fn max_neighbors(&self, meta_page: &MetaPage) -> usize {
         meta_page.get_max_neighbors_during_build()
     }
+
+    /// Updates the nodes and their neighbors after the nodes were moved to `new_locations`.
+    pub fn relocate(&mut self, new_locations: &HashMap<ItemPointer, ItemPointer>) {
+        let neighbor_map = std::mem::take(&mut self.neighbor_map);
+        self.neighbor_map = neighbor_map
+            .into_iter()
+            .map(|(index_pointer, neighbors)| {
+                let neighbors = neighbors
+                    .into_iter()
+                    .map(|n| {
+                        NeighborWithDistance::new(
+                            new_locations[&n.get_index_pointer_to_neighbor()],
+                            n.get_distance(),
+                        )
+                    })
+                    .collect();
+                (new_locations[&index_pointer], neighbors)
+            })
+            .collect();
+        self.memory_bytes = self.neighbor_map.values().map(entry_bytes).sum();
+    }
 }
 
 pub enum GraphNeighborStore {
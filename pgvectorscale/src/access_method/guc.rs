@@ -1,7 +1,34 @@
-use pgrx::*;
+use std::ffi::CStr;
+
+use pgrx::{pg_sys::AsPgCStr, *};
+
+#[cfg(feature = "build_offload")]
+use crate::diskann::offload::{select_backend, OffloadBackend};
+
+use super::{
+    distance::DistanceImpl, meta_page::MetaPage, options::TSVIndexOptions, stats::NoStats,
+    storage::DEFAULT_STORAGE_TYPE_STR, storage_common::open_diskann_index_as_owner,
+};
 
 pub static TSV_QUERY_SEARCH_LIST_SIZE: GucSetting<i32> = GucSetting::<i32>::new(100);
 pub static TSV_RESORT_SIZE: GucSetting<i32> = GucSetting::<i32>::new(50);
+pub static TSV_BUILD_CHECKPOINT_INTERVAL: GucSetting<i32> = GucSetting::<i32>::new(0);
+pub static TSV_BUILD_FLUSH_AFTER: GucSetting<i32> = GucSetting::<i32>::new(10000);
+pub static TSV_RESULT_CACHE: GucSetting<bool> = GucSetting::<bool>::new(false);
+pub static TSV_LOG_MIN_DURATION: GucSetting<i32> = GucSetting::<i32>::new(-1);
+pub static TSV_MAINTENANCE_THRESHOLD: GucSetting<f64> = GucSetting::<f64>::new(0.2);
+pub static TSV_DEFAULT_NUM_NEIGHBORS: GucSetting<i32> = GucSetting::<i32>::new(-1);
+pub static TSV_DEFAULT_STORAGE_LAYOUT: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(None);
+pub static TSV_DISTANCE_IMPL: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
+        CStr::from_bytes_with_nul_unchecked(b"auto\0")
+    }));
+#[cfg(feature = "build_offload")]
+pub static TSV_BUILD_OFFLOAD: GucSetting<Option<&'static CStr>> =
+    GucSetting::<Option<&'static CStr>>::new(Some(unsafe {
+        CStr::from_bytes_with_nul_unchecked(b"cpu\0")
+    }));
 
 pub fn init() {
     GucRegistry::define_int_guc(
@@ -25,4 +52,271 @@ pub fn init() {
         GucContext::Userset,
         GucFlags::default(),
     );
+
+    GucRegistry::define_int_guc(
+        "diskann.build_checkpoint_interval",
+        "The number of tuples between checkpoints of an index build (0 to disable checkpoints)",
+        "An index build that is interrupted resumes from its last checkpoint when the same CREATE INDEX is run again.",
+        &TSV_BUILD_CHECKPOINT_INTERVAL,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.build_flush_after",
+        "The number of nodes an index build writes between flushes of its dirty buffers (0 to disable flushing)",
+        "Writing the buffers of the index out during the build keeps it from leaving a large part of shared buffers dirty, which the next checkpoint would have to write at once.",
+        &TSV_BUILD_FLUSH_AFTER,
+        0,
+        i32::MAX,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.default_num_neighbors",
+        "The num_neighbors of the indexes created without the option (-1 for the built-in default)",
+        "Lets administrators set the defaults of new indexes for all the users of a server or database.",
+        &TSV_DEFAULT_NUM_NEIGHBORS,
+        -1,
+        1000,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "diskann.default_storage_layout",
+        "The storage_layout of the indexes created without the option (empty for the built-in default)",
+        "Lets administrators set the defaults of new indexes for all the users of a server or database.",
+        &TSV_DEFAULT_STORAGE_LAYOUT,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_string_guc(
+        "diskann.distance_impl",
+        "The implementation of the distance functions: auto, scalar or simd",
+        "auto uses the SIMD kernels on the platforms that have them. scalar gives the same distances, and so the same order of results, on every machine, which helps to compare results between machines and to keep tests deterministic.",
+        &TSV_DISTANCE_IMPL,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    #[cfg(feature = "build_offload")]
+    GucRegistry::define_string_guc(
+        "diskann.build_offload",
+        "The backend the builds offload the quantizer training and the pruning distances to",
+        "The builds fall back to the CPU when the backend is not compiled in or finds no device.",
+        &TSV_BUILD_OFFLOAD,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_int_guc(
+        "diskann.log_min_duration",
+        "Logs the index scans that take at least this long, with their counters (-1 to disable)",
+        "The time is the time spent in the index, and the log shows the search parameters the scan used, the nodes it visited, the index pages and heap rows it read and the number of rows it rescored.",
+        &TSV_LOG_MIN_DURATION,
+        -1,
+        i32::MAX,
+        GucContext::Suset,
+        GucFlags::UNIT_MS,
+    );
+
+    GucRegistry::define_float_guc(
+        "diskann.maintenance_threshold",
+        "The fraction of the rows of an index inserted or deleted after which diskann_index_activity reports that it needs maintenance",
+        "The graph of an index degrades as nodes are inserted and deleted, rebuilding it restores its recall.",
+        &TSV_MAINTENANCE_THRESHOLD,
+        0.0,
+        100.0,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        "diskann.result_cache",
+        "Whether diskann_search caches its results in shared memory",
+        "Repeated searches then read the rows from the cache instead of searching the index. Requires vectorscale in shared_preload_libraries.",
+        &TSV_RESULT_CACHE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+}
+
+/// The search list size of a query on `index`. In order of precedence, it comes from a value
+/// set in the session, the `query_search_list_size` option of the index, the default stored
+/// with `diskann_set_query_defaults`, and the server-wide value of the GUC.
+pub fn get_query_search_list_size(index: &PgRelation, meta_page: &MetaPage) -> usize {
+    let value = TSV_QUERY_SEARCH_LIST_SIZE.get();
+    if is_set_in_session("diskann.query_search_list_size", value) {
+        return value as usize;
+    }
+    TSVIndexOptions::from_relation(index)
+        .get_query_search_list_size()
+        .or(meta_page.get_query_search_list_size())
+        .map_or(value as usize, |v| v as usize)
+}
+
+/// The number of candidates a query on `index` rescores, resolved like
+/// `get_query_search_list_size`.
+pub fn get_query_rescore(index: &PgRelation, meta_page: &MetaPage) -> usize {
+    let value = TSV_RESORT_SIZE.get();
+    if is_set_in_session("diskann.query_rescore", value) {
+        return value as usize;
+    }
+    TSVIndexOptions::from_relation(index)
+        .get_query_rescore()
+        .or(meta_page.get_query_rescore())
+        .map_or(value as usize, |v| v as usize)
+}
+
+/// Stores default query parameters in the meta page of a diskann index, so that sessions
+/// don't need to set the GUCs. NULL removes a default. Like the query options of `ALTER INDEX`,
+/// only the owner of the index can set them.
+#[pg_extern(volatile)]
+pub fn diskann_set_query_defaults(
+    index: pg_sys::Oid,
+    search_list_size: default!(Option<i32>, "NULL"),
+    rescore: default!(Option<i32>, "NULL"),
+) {
+    if matches!(search_list_size, Some(v) if !(1..=10000).contains(&v)) {
+        error!("search_list_size must be between 1 and 10000");
+    }
+    if matches!(rescore, Some(v) if !(0..=1000).contains(&v)) {
+        error!("rescore must be between 0 and 1000");
+    }
+
+    let index_relation =
+        open_diskann_index_as_owner(index, pg_sys::ShareUpdateExclusiveLock as pg_sys::LOCKMODE);
+    MetaPage::update_query_defaults(
+        &index_relation,
+        search_list_size.map(|v| v as u32),
+        rescore.map(|v| v as u32),
+        &mut NoStats,
+    );
+}
+
+/// The storage layout of new indexes created without the `storage_layout` option.
+pub fn get_default_storage_layout() -> String {
+    TSV_DEFAULT_STORAGE_LAYOUT
+        .get()
+        .map(|value| value.to_string_lossy().into_owned())
+        .filter(|value| !value.is_empty())
+        .unwrap_or_else(|| DEFAULT_STORAGE_TYPE_STR.to_owned())
+}
+
+/// The implementation of the distance functions selected by `diskann.distance_impl`.
+pub fn get_distance_impl() -> DistanceImpl {
+    let value = TSV_DISTANCE_IMPL
+        .get()
+        .map(|value| value.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let distance_impl = match value.as_str() {
+        "" | "auto" => DistanceImpl::Auto,
+        "scalar" => DistanceImpl::Scalar,
+        "simd" => DistanceImpl::Simd,
+        _ => error!(
+            "invalid value for diskann.distance_impl: \"{}\", expected auto, scalar or simd",
+            value
+        ),
+    };
+    if !distance_impl.is_available() {
+        error!(
+            "diskann.distance_impl = {} is not available on this platform",
+            value
+        );
+    }
+    distance_impl
+}
+
+/// The backend selected by `diskann.build_offload`, or the CPU backend with a warning if it's
+/// not available.
+#[cfg(feature = "build_offload")]
+pub fn get_build_offload_backend() -> Box<dyn OffloadBackend> {
+    let value = TSV_BUILD_OFFLOAD
+        .get()
+        .map(|value| value.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let backend = select_backend(&value, get_distance_impl());
+    if !value.is_empty() && backend.name() != value {
+        warning!(
+            "the build_offload backend \"{}\" is not available, the build runs on the {}",
+            value,
+            backend.name()
+        );
+    }
+    backend
+}
+
+/// Whether an int GUC differs from its reset value, i.e. the default or the value from the
+/// server configuration, which means that it was SET in the session or transaction.
+fn is_set_in_session(name: &str, value: i32) -> bool {
+    let reset_value = unsafe { pg_sys::GetConfigOptionResetString(name.as_pg_cstr()) };
+    if reset_value.is_null() {
+        return false;
+    }
+    let reset_value = unsafe { CStr::from_ptr(reset_value) };
+    reset_value
+        .to_str()
+        .ok()
+        .and_then(|v| v.parse::<i32>().ok())
+        != Some(value)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::{get_query_rescore, get_query_search_list_size};
+    use crate::access_method::meta_page::MetaPage;
+
+    #[pg_test]
+    unsafe fn test_query_defaults_in_meta_page() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH (query_rescore = 5);
+            SELECT diskann_set_query_defaults('idxtest'::regclass, search_list_size => 40, rescore => 20);",
+        )?;
+
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let meta_page = MetaPage::fetch(&indexrel);
+        assert_eq!(meta_page.get_query_search_list_size(), Some(40));
+        assert_eq!(meta_page.get_query_rescore(), Some(20));
+
+        /* the option of the index takes precedence over the meta page */
+        assert_eq!(get_query_search_list_size(&indexrel, &meta_page), 40);
+        assert_eq!(get_query_rescore(&indexrel, &meta_page), 5);
+
+        Spi::run("SELECT diskann_set_query_defaults('idxtest'::regclass)")?;
+        let meta_page = MetaPage::fetch(&indexrel);
+        assert_eq!(meta_page.get_query_search_list_size(), None);
+        assert_eq!(get_query_search_list_size(&indexrel, &meta_page), 100);
+        Ok(())
+    }
+
+    #[pg_test(error = "must be owner of index idxtest")]
+    unsafe fn test_query_defaults_not_owner() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            CREATE ROLE diskann_not_owner;
+            GRANT SELECT ON test TO diskann_not_owner;",
+        )?;
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.expect("oid was null");
+
+        /* the defaults apply to the queries of all users */
+        Spi::run(&format!(
+            "SET ROLE diskann_not_owner;
+            SELECT diskann_set_query_defaults('{}'::oid, search_list_size => 1);",
+            index_oid.as_u32()
+        ))?;
+        Ok(())
+    }
 }
@@ -0,0 +1,292 @@
+//! Fuses the results of a text search with the distances of the index.
+//!
+//! `diskann_hybrid_search` takes the rows found by a text search, best first, and ranks the same
+//! rows by the distance of their vectors to a query vector, as the index computes it. The vector
+//! side is constrained to the text candidates, so it reads one vector per candidate instead of
+//! running a separate top-k search whose results mostly don't match the text. The two rankings
+//! are then fused by reciprocal rank fusion or by a weighted sum of normalized scores.
+
+use pgrx::{pg_sys, *};
+
+use crate::util::{HeapPointer, ItemPointer};
+
+use super::{
+    meta_page::MetaPage,
+    pg_vector::PgVector,
+    recall::VectorReader,
+    storage_common::{check_query_type, open_diskann_index},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FusionMethod {
+    /// the sum of 1 / (rrf_k + rank) over both rankings
+    Rrf { k: f64 },
+    /// vector_weight * vector score + (1 - vector_weight) * text score, both scaled to [0, 1]
+    Weighted { vector_weight: f64 },
+}
+
+struct Candidate {
+    heap_pointer: HeapPointer,
+    text_rank: usize,
+    text_score: f64,
+    vector_rank: usize,
+    distance: f64,
+}
+
+/// Scales `values` to [0, 1], 1 for the largest. All 1 if they are all equal.
+fn normalize(values: &[f64]) -> Vec<f64> {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    values
+        .iter()
+        .map(|v| {
+            if max > min {
+                (v - min) / (max - min)
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+/// Ranks the candidates by distance and returns their fused scores, in the order of `candidates`.
+fn fuse(candidates: &mut [Candidate], method: FusionMethod) -> Vec<f64> {
+    let mut by_distance: Vec<usize> = (0..candidates.len()).collect();
+    by_distance.sort_by(|a, b| {
+        candidates[*a]
+            .distance
+            .total_cmp(&candidates[*b].distance)
+            .then_with(|| {
+                candidates[*a]
+                    .heap_pointer
+                    .cmp(&candidates[*b].heap_pointer)
+            })
+    });
+    for (rank, i) in by_distance.into_iter().enumerate() {
+        candidates[i].vector_rank = rank + 1;
+    }
+
+    match method {
+        FusionMethod::Rrf { k } => candidates
+            .iter()
+            .map(|c| 1.0 / (k + c.text_rank as f64) + 1.0 / (k + c.vector_rank as f64))
+            .collect(),
+        FusionMethod::Weighted { vector_weight } => {
+            let text_scores =
+                normalize(&candidates.iter().map(|c| c.text_score).collect::<Vec<_>>());
+            /* the closest vector scores 1 */
+            let vector_scores =
+                normalize(&candidates.iter().map(|c| -c.distance).collect::<Vec<_>>());
+            text_scores
+                .iter()
+                .zip(vector_scores.iter())
+                .map(|(text, vector)| vector_weight * vector + (1.0 - vector_weight) * text)
+                .collect()
+        }
+    }
+}
+
+/// Returns the `k` best rows among `candidates`, the ctids of the rows found by a text search,
+/// best first, after fusing their text ranking with their ranking by the distance to `query`:
+///
+/// ```sql
+/// SELECT d.*, h.score
+/// FROM diskann_hybrid_search('idx'::regclass, $1, ARRAY(
+///     SELECT ctid FROM docs WHERE tsv @@ $2 ORDER BY ts_rank(tsv, $2) DESC LIMIT 100), 10) h
+/// JOIN docs d ON d.ctid = h.ctid
+/// ORDER BY h.score DESC;
+/// ```
+///
+/// `method` is `rrf`, reciprocal rank fusion with the constant `rrf_k`, which dampens the weight
+/// of the top ranks, or `weighted`, which adds the distances and the text scores scaled to
+/// [0, 1] with `vector_weight` and `1 - vector_weight`. The text scores are `text_scores`, e.g. the `ts_rank` of the candidates,
+/// or their ranks if NULL. The distance is the one `diskann_search` returns.
+#[allow(clippy::too_many_arguments)]
+#[pg_extern(volatile)]
+pub fn diskann_hybrid_search(
+    index: pg_sys::Oid,
+    query: AnyElement,
+    candidates: Vec<pg_sys::ItemPointerData>,
+    k: i32,
+    method: default!(&str, "'rrf'"),
+    vector_weight: default!(f64, 0.5),
+    rrf_k: default!(i32, 60),
+    text_scores: default!(Option<Vec<f64>>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(score, f64),
+        name!(text_rank, i32),
+        name!(vector_rank, i32),
+        name!(distance, f64),
+    ),
+> {
+    if k <= 0 {
+        error!("k must be positive");
+    }
+    let method = match method {
+        "rrf" if rrf_k >= 0 => FusionMethod::Rrf { k: rrf_k as f64 },
+        "rrf" => error!("rrf_k must not be negative"),
+        "weighted" if (0.0..=1.0).contains(&vector_weight) => {
+            FusionMethod::Weighted { vector_weight }
+        }
+        "weighted" => error!("vector_weight must be between 0 and 1"),
+        _ => error!(
+            "unknown fusion method \"{}\", expected rrf or weighted",
+            method
+        ),
+    };
+    if matches!(&text_scores, Some(scores) if scores.len() != candidates.len()) {
+        error!("text_scores must have one score per candidate");
+    }
+
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let meta_page = MetaPage::fetch(&index_relation);
+    check_query_type(&index_relation, &query);
+    let query = unsafe { PgVector::from_datum(query.datum(), &meta_page, true, true) };
+    let distance_fn = meta_page.get_distance_function();
+    let mut reader = VectorReader::new(&index_relation, &heap_relation, &meta_page);
+
+    let mut seen = std::collections::HashSet::new();
+    let mut rows = Vec::with_capacity(candidates.len());
+    for (i, ctid) in candidates.into_iter().enumerate() {
+        let heap_pointer = unsafe { ItemPointer::with_item_pointer_data(ctid) };
+        if !seen.insert(heap_pointer) {
+            continue;
+        }
+        /* the rows that no longer exist are dropped */
+        let Some(vector) = reader.read(heap_pointer) else {
+            continue;
+        };
+        let text_rank = rows.len() + 1;
+        rows.push(Candidate {
+            heap_pointer,
+            text_rank,
+            text_score: text_scores.as_ref().map_or(-(text_rank as f64), |s| s[i]),
+            vector_rank: 0,
+            distance: distance_fn(query.to_full_slice(), vector.to_full_slice()) as f64,
+        });
+    }
+
+    let scores = fuse(&mut rows, method);
+    let mut order: Vec<usize> = (0..rows.len()).collect();
+    order.sort_by(|a, b| {
+        scores[*b]
+            .total_cmp(&scores[*a])
+            .then_with(|| rows[*a].heap_pointer.cmp(&rows[*b].heap_pointer))
+    });
+    let results: Vec<_> = order
+        .into_iter()
+        .take(k as usize)
+        .map(|i| {
+            let row = &rows[i];
+            let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
+                ..Default::default()
+            };
+            row.heap_pointer.to_item_pointer_data(&mut ctid);
+            (
+                ctid,
+                scores[i],
+                row.text_rank as i32,
+                row.vector_rank as i32,
+                row.distance,
+            )
+        })
+        .collect();
+    TableIterator::new(results)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::{fuse, normalize, Candidate, FusionMethod};
+    use crate::util::ItemPointer;
+
+    fn candidate(offset: u16, distance: f64) -> Candidate {
+        Candidate {
+            heap_pointer: ItemPointer::new(0, offset),
+            text_rank: offset as usize,
+            text_score: -(offset as f64),
+            vector_rank: 0,
+            distance,
+        }
+    }
+
+    #[pg_test]
+    fn test_fuse() {
+        assert_eq!(normalize(&[1.0, 3.0, 2.0]), vec![0.0, 1.0, 0.5]);
+        assert_eq!(normalize(&[2.0, 2.0]), vec![1.0, 1.0]);
+
+        /* the text ranking is reversed by the distances */
+        let mut candidates = vec![candidate(1, 3.0), candidate(2, 2.0), candidate(3, 1.0)];
+        let scores = fuse(&mut candidates, FusionMethod::Rrf { k: 60.0 });
+        let ranks: Vec<usize> = candidates.iter().map(|c| c.vector_rank).collect();
+        assert_eq!(ranks, vec![3, 2, 1]);
+        assert!((scores[0] - scores[2]).abs() < 1e-12);
+        assert!(scores[1] < scores[0]);
+
+        let scores = fuse(
+            &mut candidates,
+            FusionMethod::Weighted { vector_weight: 1.0 },
+        );
+        assert_eq!(scores, vec![0.0, 0.5, 1.0]);
+        let scores = fuse(
+            &mut candidates,
+            FusionMethod::Weighted { vector_weight: 0.0 },
+        );
+        assert_eq!(scores, vec![1.0, 0.5, 0.0]);
+    }
+
+    #[pg_test]
+    unsafe fn test_hybrid_search() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, body text, embedding vector(3));
+
+            INSERT INTO test(id, body, embedding)
+            SELECT i, CASE WHEN i % 10 = 0 THEN 'red apple' ELSE 'green pear' END,
+                ARRAY[i, i % 5, 1]::vector
+            FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);",
+        )?;
+
+        /* only the text matches are returned, the closest first with the vector only */
+        let (count, all_match, first) = Spi::get_three::<i64, bool, i32>(
+            "SELECT count(*), bool_and(t.body = 'red apple'),
+                (array_agg(t.id ORDER BY h.score DESC))[1]
+            FROM diskann_hybrid_search('idxtest'::regclass, '[100, 0, 1]'::vector,
+                ARRAY(SELECT ctid FROM test WHERE body = 'red apple' ORDER BY id), 10,
+                method => 'weighted', vector_weight => 1.0) h
+            JOIN test t ON t.ctid = h.ctid",
+        )?;
+        assert_eq!(count, Some(10));
+        assert_eq!(all_match, Some(true));
+        assert_eq!(first, Some(100));
+
+        /* with rrf, both rankings count */
+        let (text_rank, vector_rank) = Spi::get_two::<i32, i32>(
+            "SELECT text_rank, vector_rank
+            FROM diskann_hybrid_search('idxtest'::regclass, '[100, 0, 1]'::vector,
+                ARRAY(SELECT ctid FROM test WHERE body = 'red apple' ORDER BY id), 1)",
+        )?;
+        assert!(text_rank.unwrap() >= 1 && vector_rank.unwrap() >= 1);
+        Ok(())
+    }
+
+    #[pg_test(error = "unknown fusion method \"max\", expected rrf or weighted")]
+    unsafe fn test_hybrid_search_unknown_method() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            SELECT * FROM diskann_hybrid_search('idxtest'::regclass, '[1, 1, 1]'::vector,
+                '{}'::tid[], 5, method => 'max');",
+        )?;
+        Ok(())
+    }
+}
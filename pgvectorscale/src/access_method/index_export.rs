@@ -0,0 +1,265 @@
+//! Exports a diskann index to a self-contained file.
+//!
+//! The file holds the graph, the quantizer and the vector stored in every node, independent
+//! of the page format and the layout of the nodes on the pages, so that it can be served or
+//! archived outside of Postgres. All numbers are little-endian:
+//!
+//! - the magic `DISKANNX` and the format version, u32
+//! - the storage layout and the distance type, each a u32 length followed by UTF-8
+//! - the number of dimensions, the number of SBQ bits per dimension and `num_neighbors`, u32
+//! - the number of nodes, u64, and the size of the vector of every node in bytes, u32
+//! - the number of entry points, u32, and their node ids, u32
+//! - the number of weights, u32, and the weights, f32
+//! - the number of SBQ means, u32, and the means, f32
+//! - for every node: the heap TID as a u32 block number and a u16 offset, the vector, and the
+//!   number of neighbors, u32, followed by their node ids, u32
+//!
+//! Node ids are the positions of the nodes in the file. The vector of a node is the full
+//! vector as f32 for the plain storage layout and the quantized vector as u64 words for SBQ.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use pgrx::{pg_sys, *};
+
+use crate::util::IndexPointer;
+
+use super::{
+    introspection::{for_each_live_node, for_each_live_node_with_neighbors},
+    meta_page::MetaPage,
+    plain_storage::PlainStorage,
+    sbq::{SbqMeans, SbqSpeedupStorage},
+    stats::NoStats,
+    storage::{ArchivedData, Storage, StorageType},
+    storage_common::open_diskann_index,
+};
+
+const EXPORT_MAGIC: &[u8; 8] = b"DISKANNX";
+const EXPORT_FORMAT_VERSION: u32 = 1;
+
+fn write_u16<W: Write>(writer: &mut W, value: u16) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_str<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    write_u32(writer, value.len() as u32)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn write_f32s<W: Write>(writer: &mut W, values: &[f32]) -> io::Result<()> {
+    write_u32(writer, values.len() as u32)?;
+    values
+        .iter()
+        .try_for_each(|v| writer.write_all(&v.to_le_bytes()))
+}
+
+/// Writes the live nodes of the index and returns their number.
+fn export_nodes<S: Storage, W: Write>(
+    index: &PgRelation,
+    meta_page: &MetaPage,
+    means: &[f32],
+    writer: &mut W,
+) -> io::Result<usize> {
+    let mut ids: HashMap<IndexPointer, u32> = HashMap::new();
+    let mut code_size = 0;
+    for_each_live_node::<S>(index, |index_pointer, node| {
+        ids.insert(index_pointer, ids.len() as u32);
+        if code_size == 0 {
+            code_size = node.get_vector_code().len();
+        }
+    });
+    let entry_points: Vec<u32> = meta_page
+        .get_init_ids()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|index_pointer| ids.get(index_pointer).copied())
+        .collect();
+
+    writer.write_all(EXPORT_MAGIC)?;
+    write_u32(writer, EXPORT_FORMAT_VERSION)?;
+    write_str(writer, meta_page.get_storage_type().as_str())?;
+    write_str(writer, meta_page.get_distance_type_name())?;
+    write_u32(writer, meta_page.get_num_dimensions_to_index())?;
+    write_u32(
+        writer,
+        if means.is_empty() {
+            0
+        } else {
+            meta_page.get_bq_num_bits_per_dimension() as u32
+        },
+    )?;
+    write_u32(writer, meta_page.get_num_neighbors())?;
+    write_u64(writer, ids.len() as u64)?;
+    write_u32(writer, code_size as u32)?;
+    write_u32(writer, entry_points.len() as u32)?;
+    for id in entry_points {
+        write_u32(writer, id)?;
+    }
+    write_f32s(writer, meta_page.get_weights())?;
+    write_f32s(writer, means)?;
+
+    let mut result = Ok(());
+    for_each_live_node_with_neighbors::<S>(index, meta_page, |_, node, neighbors| {
+        if result.is_err() {
+            return;
+        }
+        check_for_interrupts!();
+        let heap_pointer = node.get_heap_item_pointer();
+        let neighbors: Vec<u32> = neighbors
+            .iter()
+            .filter_map(|neighbor| ids.get(neighbor).copied())
+            .collect();
+        result = (|| {
+            write_u32(writer, heap_pointer.block_number)?;
+            write_u16(writer, heap_pointer.offset)?;
+            writer.write_all(&node.get_vector_code())?;
+            write_u32(writer, neighbors.len() as u32)?;
+            neighbors.iter().try_for_each(|id| write_u32(writer, *id))
+        })();
+    });
+    result?;
+    writer.flush()?;
+    Ok(ids.len())
+}
+
+/// Exports the graph, the quantizer and the vectors of the nodes of a diskann index to a
+/// self-contained file at `path` on the server, for serving or archiving the index outside of
+/// Postgres. The format of the file doesn't depend on the page format of the index; it is
+/// described at the top of `index_export.rs`. Returns the number of exported nodes.
+///
+/// Writes to the table are blocked while the index is exported. Indexes with
+/// `pca_dimensions` can't be exported. Writing files on the server requires the privileges of
+/// the pg_write_server_files role.
+#[pg_extern(volatile)]
+pub fn diskann_export_index(index: pg_sys::Oid, path: &str) -> i64 {
+    let can_write_files =
+        Spi::get_one::<bool>("SELECT pg_has_role('pg_write_server_files', 'USAGE')")
+            .expect("failed to check the privileges of the user");
+    if can_write_files != Some(true) {
+        error!("must have the privileges of the pg_write_server_files role to export an index");
+    }
+
+    /* keep the graph from changing between the passes over the nodes */
+    let index_relation = open_diskann_index(index, pg_sys::ShareLock as pg_sys::LOCKMODE);
+    let meta_page = MetaPage::fetch(&index_relation);
+    if meta_page.uses_pca() {
+        error!("indexes with pca_dimensions can't be exported");
+    }
+
+    let file = File::create(path)
+        .unwrap_or_else(|e| error!("could not create the file \"{}\": {}", path, e));
+    let mut writer = BufWriter::new(file);
+    let num_nodes = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            export_nodes::<PlainStorage, _>(&index_relation, &meta_page, &[], &mut writer)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let quantizer = unsafe { SbqMeans::load(&index_relation, &meta_page, &mut NoStats) };
+            export_nodes::<SbqSpeedupStorage, _>(
+                &index_relation,
+                &meta_page,
+                &quantizer.mean,
+                &mut writer,
+            )
+        }
+    }
+    .unwrap_or_else(|e| error!("could not write the file \"{}\": {}", path, e));
+    num_nodes as i64
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    fn read_u32(data: &[u8], pos: &mut usize) -> u32 {
+        let value = u32::from_le_bytes(data[*pos..*pos + 4].try_into().unwrap());
+        *pos += 4;
+        value
+    }
+
+    fn read_str(data: &[u8], pos: &mut usize) -> String {
+        let len = read_u32(data, pos) as usize;
+        let value = String::from_utf8(data[*pos..*pos + len].to_vec()).unwrap();
+        *pos += len;
+        value
+    }
+
+    #[pg_test]
+    unsafe fn test_export_index() -> spi::Result<()> {
+        let path = std::env::temp_dir().join("diskann_test_export.bin");
+        for storage_layout in ["plain", "memory_optimized"] {
+            Spi::run(&format!(
+                "CREATE TABLE test(embedding vector(64));
+
+                INSERT INTO test(embedding)
+                SELECT v::vector FROM diskann_generate_vectors(300, 64, seed => 1) v;
+
+                CREATE INDEX idxtest ON test USING diskann(embedding)
+                    WITH (storage_layout = {storage_layout});",
+            ))?;
+
+            let num_nodes = Spi::get_one::<i64>(&format!(
+                "SELECT diskann_export_index('idxtest'::regclass, '{}')",
+                path.display()
+            ))?;
+            assert_eq!(num_nodes, Some(300), "{}", storage_layout);
+            let num_edges = Spi::get_one::<i64>(
+                "SELECT count(*) FROM diskann_graph_edges('idxtest'::regclass)",
+            )?
+            .unwrap();
+
+            let data = std::fs::read(&path).unwrap();
+            assert_eq!(&data[..8], b"DISKANNX");
+            let mut pos = 12;
+            assert_eq!(read_str(&data, &mut pos), storage_layout);
+            assert_eq!(read_str(&data, &mut pos), "cosine");
+            assert_eq!(read_u32(&data, &mut pos), 64);
+            let num_bits = read_u32(&data, &mut pos);
+            let _num_neighbors = read_u32(&data, &mut pos);
+            let num_nodes = u64::from_le_bytes(data[pos..pos + 8].try_into().unwrap());
+            pos += 8;
+            assert_eq!(num_nodes, 300);
+            let code_size = read_u32(&data, &mut pos) as usize;
+            let num_entry_points = read_u32(&data, &mut pos);
+            assert!(num_entry_points >= 1);
+            pos += 4 * num_entry_points as usize;
+            let num_weights = read_u32(&data, &mut pos);
+            pos += 4 * num_weights as usize;
+            let num_means = read_u32(&data, &mut pos);
+            pos += 4 * num_means as usize;
+            if storage_layout == "plain" {
+                assert_eq!((num_bits, code_size, num_means), (0, 64 * 4, 0));
+            } else {
+                assert_eq!((num_bits, code_size, num_means), (2, 64 * 2 / 8, 64));
+            }
+
+            let mut exported_edges = 0;
+            for _ in 0..num_nodes {
+                pos += 6 + code_size;
+                let num_neighbors = read_u32(&data, &mut pos);
+                for _ in 0..num_neighbors {
+                    assert!((read_u32(&data, &mut pos) as u64) < num_nodes);
+                }
+                exported_edges += num_neighbors as i64;
+            }
+            assert_eq!(pos, data.len(), "{}", storage_layout);
+            assert_eq!(exported_edges, num_edges, "{}", storage_layout);
+
+            Spi::run("DROP TABLE test")?;
+        }
+        std::fs::remove_file(&path).unwrap();
+        Ok(())
+    }
+}
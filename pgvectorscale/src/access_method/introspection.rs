@@ -0,0 +1,1259 @@
+//! SQL functions to inspect diskann indexes.
+
+use std::collections::{HashSet, VecDeque};
+
+use pgrx::{
+    pg_sys::{self, FirstOffsetNumber},
+    *,
+};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::util::{
+    page::{PageType, ReadablePage},
+    ports::{PageGetItem, PageGetItemId, PageGetMaxOffsetNumber},
+    HeapPointer, IndexPointer, ItemPointer,
+};
+
+use super::{
+    full_vectors::FullVectorType,
+    meta_page::{MetaPage, TSV_VERSION},
+    neighbor_compression::NeighborBlock,
+    neighbor_with_distance::NeighborWithDistance,
+    plain_storage::PlainStorage,
+    sbq::SbqSpeedupStorage,
+    stats::GreedySearchStats,
+    storage::{ArchivedData, Storage, StorageType},
+    storage_common::open_diskann_index,
+};
+
+/// Counts of the pages of one type.
+pub struct PageTypeSummary {
+    pub page_type: PageType,
+    pub num_pages: i64,
+    pub free_bytes: i64,
+    pub num_items: i64,
+    pub item_bytes: i64,
+    /// node pages without live nodes
+    pub num_empty_pages: i64,
+    pub num_nodes: i64,
+    pub num_deleted_nodes: i64,
+    pub deleted_bytes: i64,
+}
+
+impl PageTypeSummary {
+    fn new(page_type: PageType) -> Self {
+        Self {
+            page_type,
+            num_pages: 0,
+            free_bytes: 0,
+            num_items: 0,
+            item_bytes: 0,
+            num_empty_pages: 0,
+            num_nodes: 0,
+            num_deleted_nodes: 0,
+            deleted_bytes: 0,
+        }
+    }
+}
+
+/// Page and node counts of an index, from a scan of all its pages.
+pub struct IndexPageSummary {
+    pub num_pages: i64,
+    /// in the order the page types are first seen
+    pub pages_by_type: Vec<PageTypeSummary>,
+    pub num_nodes: i64,
+    pub num_deleted_nodes: i64,
+}
+
+impl IndexPageSummary {
+    pub fn scan(index: &PgRelation, meta_page: &MetaPage) -> Self {
+        match meta_page.get_storage_type() {
+            StorageType::Plain => Self::scan_storage::<PlainStorage>(index),
+            StorageType::SbqSpeedup | StorageType::SbqCompression => {
+                Self::scan_storage::<SbqSpeedupStorage>(index)
+            }
+        }
+    }
+
+    fn scan_storage<S: Storage>(index: &PgRelation) -> Self {
+        let nblocks = unsafe {
+            pg_sys::RelationGetNumberOfBlocksInFork(index.as_ptr(), pg_sys::ForkNumber_MAIN_FORKNUM)
+        };
+        let mut summary = Self {
+            num_pages: nblocks as i64,
+            pages_by_type: Vec::new(),
+            num_nodes: 0,
+            num_deleted_nodes: 0,
+        };
+
+        for block_number in 0..nblocks {
+            let page = unsafe { ReadablePage::read(index, block_number) };
+            if page.is_new() {
+                continue;
+            }
+            let page_type = page.get_type();
+            let position = match summary
+                .pages_by_type
+                .iter()
+                .position(|t| t.page_type == page_type)
+            {
+                Some(position) => position,
+                None => {
+                    summary.pages_by_type.push(PageTypeSummary::new(page_type));
+                    summary.pages_by_type.len() - 1
+                }
+            };
+            let type_summary = &mut summary.pages_by_type[position];
+            type_summary.num_pages += 1;
+            type_summary.free_bytes += page.get_free_space() as i64;
+            let max_offset = unsafe { PageGetMaxOffsetNumber(*page) };
+            for offset_number in FirstOffsetNumber..(max_offset + 1) as _ {
+                let len = unsafe { (*PageGetItemId(*page, offset_number)).lp_len() };
+                type_summary.num_items += 1;
+                type_summary.item_bytes += len as i64;
+            }
+            if page_type != S::page_type() {
+                continue;
+            }
+
+            let mut num_live = 0;
+            for offset_number in FirstOffsetNumber..(max_offset + 1) as _ {
+                let (deleted, len) = unsafe {
+                    let item_id = PageGetItemId(*page, offset_number);
+                    let item = PageGetItem(*page, item_id) as *mut u8;
+                    let len = (*item_id).lp_len();
+                    let data = std::slice::from_raw_parts_mut(item, len as _);
+                    (S::ArchivedType::with_data(data).is_deleted(), len)
+                };
+                if deleted {
+                    type_summary.num_deleted_nodes += 1;
+                    type_summary.deleted_bytes += len as i64;
+                } else {
+                    type_summary.num_nodes += 1;
+                    num_live += 1;
+                }
+            }
+            if num_live == 0 {
+                type_summary.num_empty_pages += 1;
+            }
+        }
+
+        for type_summary in summary.pages_by_type.iter() {
+            summary.num_nodes += type_summary.num_nodes;
+            summary.num_deleted_nodes += type_summary.num_deleted_nodes;
+        }
+        summary
+    }
+}
+
+/// The SIMD instruction sets the distance functions are compiled for.
+fn get_simd_features() -> Vec<String> {
+    let mut features = Vec::new();
+    if cfg!(target_feature = "avx2") {
+        features.push("avx2");
+    }
+    if cfg!(target_feature = "fma") {
+        features.push("fma");
+    }
+    if cfg!(target_feature = "avx512f") {
+        features.push("avx512f");
+    }
+    if cfg!(target_feature = "neon") {
+        features.push("neon");
+    }
+    features.into_iter().map(String::from).collect()
+}
+
+/// Describes the build of the extension loaded in the session: its version, the format version
+/// of the indexes it builds, the SIMD instruction sets its distance functions use, the versions
+/// of pgrx and Postgres it was compiled with and the git commit it was built from, NULL if
+/// unknown. Indexes with a later format version than `format_version` can't be read by it, see
+/// `diskann_index_info`.
+#[pg_extern(stable, parallel_safe)]
+pub fn vectorscale_version() -> TableIterator<
+    'static,
+    (
+        name!(extension_version, String),
+        name!(format_version, i32),
+        name!(simd_features, Vec<String>),
+        name!(pgrx_version, String),
+        name!(pg_version, String),
+        name!(build_commit, Option<String>),
+    ),
+> {
+    let pg_version = std::ffi::CStr::from_bytes_with_nul(pg_sys::PG_VERSION_STR)
+        .unwrap()
+        .to_string_lossy()
+        .into_owned();
+    TableIterator::once((
+        env!("CARGO_PKG_VERSION").to_string(),
+        TSV_VERSION as i32,
+        get_simd_features(),
+        env!("VECTORSCALE_PGRX_VERSION").to_string(),
+        pg_version,
+        option_env!("VECTORSCALE_GIT_COMMIT").map(String::from),
+    ))
+}
+
+/// Describes a diskann index: its build parameters from the meta page and counts from a scan
+/// of all its pages. `page_counts` lists the number of pages of every page type.
+#[pg_extern(volatile)]
+pub fn diskann_index_info(
+    index: pg_sys::Oid,
+) -> TableIterator<
+    'static,
+    (
+        name!(format_version, i32),
+        name!(extension_version_when_built, String),
+        name!(vector_type, String),
+        name!(distance_type, String),
+        name!(num_dimensions, i32),
+        name!(num_dimensions_to_index, i32),
+        name!(storage_layout, String),
+        name!(num_bits_per_dimension, i32),
+        name!(num_neighbors, i32),
+        name!(search_list_size, i32),
+        name!(max_alpha, f64),
+        name!(pca_dimensions, i32),
+        name!(weighted, bool),
+        name!(num_entry_points, i32),
+        name!(num_nodes, i64),
+        name!(num_deleted_nodes, i64),
+        name!(num_pages, i64),
+        name!(page_counts, String),
+    ),
+> {
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let meta_page = MetaPage::fetch(&index_relation);
+    let summary = IndexPageSummary::scan(&index_relation, &meta_page);
+
+    let storage_type = meta_page.get_storage_type();
+    let num_bits_per_dimension = match storage_type {
+        StorageType::Plain => 0,
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            meta_page.get_bq_num_bits_per_dimension() as i32
+        }
+    };
+    let pca_dimensions = if meta_page.uses_pca() {
+        meta_page.get_num_dimensions_to_index() as i32
+    } else {
+        0
+    };
+    let page_counts = summary
+        .pages_by_type
+        .iter()
+        .map(|t| format!("{}: {}", t.page_type.as_str(), t.num_pages))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    TableIterator::once((
+        meta_page.get_version() as i32,
+        meta_page.get_extension_version_when_built().to_string(),
+        meta_page.get_vector_type().as_str().to_string(),
+        meta_page.get_distance_type_name().to_string(),
+        meta_page.get_num_dimensions() as i32,
+        meta_page.get_num_dimensions_to_index() as i32,
+        storage_type.as_str().to_string(),
+        num_bits_per_dimension,
+        meta_page.get_num_neighbors() as i32,
+        meta_page.get_search_list_size_for_build() as i32,
+        meta_page.get_max_alpha(),
+        pca_dimensions,
+        !meta_page.get_weights().is_empty(),
+        meta_page.get_init_ids().map_or(0, |ids| ids.len() as i32),
+        summary.num_nodes,
+        summary.num_deleted_nodes,
+        summary.num_pages,
+        page_counts,
+    ))
+}
+
+/// Whether an index with this meta page uses pages of `page_type`. Other pages are left over,
+/// e.g. from a storage layout of an older version, and only a REINDEX removes them.
+fn is_page_type_in_use(page_type: PageType, meta_page: &MetaPage) -> bool {
+    match page_type {
+        PageType::MetaV1 | PageType::Meta => true,
+        PageType::Node => meta_page.get_storage_type() == StorageType::Plain,
+        PageType::SbqNode | PageType::SbqMeans => {
+            meta_page.get_storage_type() != StorageType::Plain
+        }
+        PageType::Pca => meta_page.uses_pca(),
+        PageType::FullVector => meta_page.get_full_vector_type() != FullVectorType::None,
+        PageType::PqQuantizerDef | PageType::PqQuantizerVector => false,
+    }
+}
+
+/// Reports the space that a REINDEX would reclaim, per page type: the free space on the pages,
+/// the nodes deleted by VACUUM, whose space is never reused, node pages without live nodes,
+/// and whether the index still uses pages of the type at all. Rows deleted since the last
+/// VACUUM still have live nodes.
+#[pg_extern(volatile)]
+pub fn diskann_index_bloat(
+    index: pg_sys::Oid,
+) -> TableIterator<
+    'static,
+    (
+        name!(page_type, String),
+        name!(in_use, bool),
+        name!(num_pages, i64),
+        name!(num_empty_pages, i64),
+        name!(free_bytes, i64),
+        name!(num_nodes, i64),
+        name!(num_deleted_nodes, i64),
+        name!(deleted_fraction, f64),
+        name!(reclaimable_bytes, i64),
+    ),
+> {
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let meta_page = MetaPage::fetch(&index_relation);
+    let summary = IndexPageSummary::scan(&index_relation, &meta_page);
+
+    let rows: Vec<_> = summary
+        .pages_by_type
+        .iter()
+        .map(|t| {
+            let in_use = is_page_type_in_use(t.page_type, &meta_page);
+            let num_all_nodes = t.num_nodes + t.num_deleted_nodes;
+            let deleted_fraction = if num_all_nodes == 0 {
+                0.0
+            } else {
+                t.num_deleted_nodes as f64 / num_all_nodes as f64
+            };
+            let reclaimable_bytes = if in_use {
+                t.free_bytes + t.deleted_bytes
+            } else {
+                t.num_pages * pg_sys::BLCKSZ as i64
+            };
+            (
+                t.page_type.as_str().to_string(),
+                in_use,
+                t.num_pages,
+                t.num_empty_pages,
+                t.free_bytes,
+                t.num_nodes,
+                t.num_deleted_nodes,
+                deleted_fraction,
+                reclaimable_bytes,
+            )
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+/// Shows where the bytes of an index go: for every page type, the number of pages, the number
+/// of items on them and the bytes the items use, and the share of the index the pages take.
+#[pg_extern(volatile)]
+pub fn diskann_page_stats(
+    index: pg_sys::Oid,
+) -> TableIterator<
+    'static,
+    (
+        name!(page_type, String),
+        name!(num_pages, i64),
+        name!(num_items, i64),
+        name!(total_bytes, i64),
+        name!(used_bytes, i64),
+        name!(free_bytes, i64),
+        name!(avg_item_bytes, f64),
+        name!(fraction_of_index, f64),
+    ),
+> {
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let meta_page = MetaPage::fetch(&index_relation);
+    let summary = IndexPageSummary::scan(&index_relation, &meta_page);
+
+    let rows: Vec<_> = summary
+        .pages_by_type
+        .iter()
+        .map(|t| {
+            let avg_item_bytes = if t.num_items == 0 {
+                0.0
+            } else {
+                t.item_bytes as f64 / t.num_items as f64
+            };
+            (
+                t.page_type.as_str().to_string(),
+                t.num_pages,
+                t.num_items,
+                t.num_pages * pg_sys::BLCKSZ as i64,
+                t.item_bytes,
+                t.free_bytes,
+                avg_item_bytes,
+                t.num_pages as f64 / summary.num_pages.max(1) as f64,
+            )
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+/// Lists the runs of consecutive pages of the same type, so that a part of the index can be
+/// loaded on its own, e.g. the graph without the full vectors:
+///
+/// `SELECT pg_prewarm('idx', 'buffer', 'main', first_block, last_block) FROM diskann_page_ranges('idx') WHERE page_type = 'sbq_node';`
+#[pg_extern(volatile)]
+pub fn diskann_page_ranges(
+    index: pg_sys::Oid,
+) -> TableIterator<
+    'static,
+    (
+        name!(page_type, String),
+        name!(first_block, i64),
+        name!(last_block, i64),
+    ),
+> {
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let nblocks = unsafe {
+        pg_sys::RelationGetNumberOfBlocksInFork(
+            index_relation.as_ptr(),
+            pg_sys::ForkNumber_MAIN_FORKNUM,
+        )
+    };
+
+    let mut ranges: Vec<(PageType, i64, i64)> = Vec::new();
+    for block_number in 0..nblocks {
+        let page = unsafe { ReadablePage::read(&index_relation, block_number) };
+        if page.is_new() {
+            continue;
+        }
+        let page_type = page.get_type();
+        match ranges.last_mut() {
+            Some((last_type, _, last_block)) if *last_type == page_type => {
+                *last_block = block_number as i64
+            }
+            _ => ranges.push((page_type, block_number as i64, block_number as i64)),
+        }
+    }
+    TableIterator::new(
+        ranges
+            .into_iter()
+            .map(|(page_type, first, last)| (page_type.as_str().to_string(), first, last)),
+    )
+}
+
+/// Calls `f` with the node at `index_pointer`, or returns None if the node is deleted.
+unsafe fn with_live_node<S: Storage, T>(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+    f: impl FnOnce(&S::ArchivedType) -> T,
+) -> Option<T> {
+    with_live_node_neighbors::<S, _>(index, None, index_pointer, |node, _| f(node))
+}
+
+/// Calls `f` with the node at `index_pointer` and its neighbors, or returns None if the node
+/// is deleted.
+unsafe fn with_live_node_neighbors<S: Storage, T>(
+    index: &PgRelation,
+    neighbor_block: Option<NeighborBlock>,
+    index_pointer: IndexPointer,
+    f: impl FnOnce(&S::ArchivedType, Vec<ItemPointer>) -> T,
+) -> Option<T> {
+    let page = ReadablePage::read(index, index_pointer.block_number);
+    let item_id = PageGetItemId(*page, index_pointer.offset);
+    let item = PageGetItem(*page, item_id) as *mut u8;
+    let len = (*item_id).lp_len();
+    let data = std::slice::from_raw_parts_mut(item, len as _);
+    let compressed_neighbors = neighbor_block.map(|neighbor_block| neighbor_block.read(data));
+    let node = S::ArchivedType::with_data(data);
+    if node.is_deleted() {
+        None
+    } else {
+        let neighbors =
+            compressed_neighbors.unwrap_or_else(|| node.get_index_pointer_to_neighbors());
+        Some(f(&*node, neighbors))
+    }
+}
+
+/// Returns the heap pointer of the node at `index_pointer`, or None if the node is deleted.
+unsafe fn read_heap_pointer<S: Storage>(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+) -> Option<HeapPointer> {
+    with_live_node::<S, _>(index, index_pointer, |node| node.get_heap_item_pointer())
+}
+
+/// Finds the live node of the row at `heap_pointer` by scanning all the pages of the index.
+fn find_node<S: Storage>(index: &PgRelation, heap_pointer: HeapPointer) -> Option<IndexPointer> {
+    let nblocks = unsafe {
+        pg_sys::RelationGetNumberOfBlocksInFork(index.as_ptr(), pg_sys::ForkNumber_MAIN_FORKNUM)
+    };
+    for block_number in 0..nblocks {
+        let page = unsafe { ReadablePage::read(index, block_number) };
+        if page.is_new() || page.get_type() != S::page_type() {
+            continue;
+        }
+
+        let max_offset = unsafe { PageGetMaxOffsetNumber(*page) };
+        for offset_number in FirstOffsetNumber..(max_offset + 1) as _ {
+            let found = unsafe {
+                let item_id = PageGetItemId(*page, offset_number);
+                let item = PageGetItem(*page, item_id) as *mut u8;
+                let len = (*item_id).lp_len();
+                let data = std::slice::from_raw_parts_mut(item, len as _);
+                let node = S::ArchivedType::with_data(data);
+                !node.is_deleted() && node.get_heap_item_pointer() == heap_pointer
+            };
+            if found {
+                return Some(ItemPointer::new(block_number, offset_number));
+            }
+        }
+    }
+    None
+}
+
+fn to_ctid(item_pointer: ItemPointer) -> pg_sys::ItemPointerData {
+    let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
+        ..Default::default()
+    };
+    item_pointer.to_item_pointer_data(&mut ctid);
+    ctid
+}
+
+/// Returns the neighbors of the node of the row at `ctid`, for debugging the graph around a row
+/// that is missing from search results. `neighbor_index_tid` is the position of the neighbor in
+/// the index and `neighbor_ctid` the row it belongs to, NULL if the neighbor was deleted.
+/// `distance` is the distance the graph was pruned with: between the full vectors for the plain
+/// storage layout and between the quantized vectors for the other layouts.
+#[pg_extern(volatile)]
+pub fn diskann_node_neighbors(
+    index: pg_sys::Oid,
+    ctid: pg_sys::ItemPointerData,
+) -> TableIterator<
+    'static,
+    (
+        name!(neighbor_index_tid, pg_sys::ItemPointerData),
+        name!(neighbor_ctid, Option<pg_sys::ItemPointerData>),
+        name!(distance, f32),
+    ),
+> {
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let meta_page = MetaPage::fetch(&index_relation);
+    let heap_pointer = unsafe { ItemPointer::with_item_pointer_data(ctid) };
+
+    let mut stats = GreedySearchStats::new();
+    let mut neighbors: Vec<NeighborWithDistance> = Vec::new();
+    let rows = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage = PlainStorage::load_for_search(
+                &index_relation,
+                &heap_relation,
+                meta_page.get_distance_function(),
+            );
+            let node = find_node::<PlainStorage>(&index_relation, heap_pointer);
+            if let Some(node) = node {
+                storage.get_neighbors_with_distances_from_disk(node, &mut neighbors, &mut stats);
+            }
+            node.map(|_| neighbor_rows::<PlainStorage>(&index_relation, &neighbors))
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let storage = SbqSpeedupStorage::load_for_insert(
+                &heap_relation,
+                &index_relation,
+                &meta_page,
+                &mut stats,
+            );
+            let node = find_node::<SbqSpeedupStorage>(&index_relation, heap_pointer);
+            if let Some(node) = node {
+                storage.get_neighbors_with_distances_from_disk(node, &mut neighbors, &mut stats);
+            }
+            node.map(|_| neighbor_rows::<SbqSpeedupStorage>(&index_relation, &neighbors))
+        }
+    };
+
+    let Some(rows) = rows else {
+        error!(
+            "the row at ({},{}) is not in the diskann index \"{}\"",
+            heap_pointer.block_number,
+            heap_pointer.offset,
+            index_relation.name()
+        );
+    };
+    TableIterator::new(rows)
+}
+
+fn neighbor_rows<S: Storage>(
+    index: &PgRelation,
+    neighbors: &[NeighborWithDistance],
+) -> Vec<(pg_sys::ItemPointerData, Option<pg_sys::ItemPointerData>, f32)> {
+    neighbors
+        .iter()
+        .map(|neighbor| {
+            let index_pointer = neighbor.get_index_pointer_to_neighbor();
+            let heap_pointer = unsafe { read_heap_pointer::<S>(index, index_pointer) };
+            (
+                to_ctid(index_pointer),
+                heap_pointer.map(to_ctid),
+                neighbor.get_distance(),
+            )
+        })
+        .collect()
+}
+
+/// Returns all the edges of the graph of an index, for analyzing its structure with external
+/// tools. Edges to deleted nodes are left out, see `diskann_node_neighbors` for the columns.
+/// The edges are read one index page at a time, so the function streams large indexes.
+#[pg_extern(volatile)]
+pub fn diskann_graph_edges(
+    index: pg_sys::Oid,
+) -> TableIterator<
+    'static,
+    (
+        name!(source_ctid, pg_sys::ItemPointerData),
+        name!(target_ctid, pg_sys::ItemPointerData),
+        name!(distance, f32),
+    ),
+> {
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let nblocks = unsafe {
+        pg_sys::RelationGetNumberOfBlocksInFork(
+            index_relation.as_ptr(),
+            pg_sys::ForkNumber_MAIN_FORKNUM,
+        )
+    };
+    TableIterator::new(GraphEdges {
+        index,
+        meta_page: MetaPage::fetch(&index_relation),
+        block_number: 0,
+        nblocks,
+        edges: Vec::new().into_iter(),
+    })
+}
+
+struct GraphEdges {
+    index: pg_sys::Oid,
+    meta_page: MetaPage,
+    block_number: pg_sys::BlockNumber,
+    nblocks: pg_sys::BlockNumber,
+    edges: std::vec::IntoIter<(pg_sys::ItemPointerData, pg_sys::ItemPointerData, f32)>,
+}
+
+impl Iterator for GraphEdges {
+    type Item = (pg_sys::ItemPointerData, pg_sys::ItemPointerData, f32);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(edge) = self.edges.next() {
+                return Some(edge);
+            }
+            if self.block_number >= self.nblocks {
+                return None;
+            }
+            self.edges = self.read_page_edges().into_iter();
+            self.block_number += 1;
+        }
+    }
+}
+
+impl GraphEdges {
+    fn read_page_edges(&self) -> Vec<(pg_sys::ItemPointerData, pg_sys::ItemPointerData, f32)> {
+        /* the relation is opened again for every page because the iterator outlives the call */
+        let index_relation =
+            unsafe { PgRelation::with_lock(self.index, pg_sys::AccessShareLock as pg_sys::LOCKMODE) };
+        let heap_relation = index_relation
+            .heap_relation()
+            .expect("failed to get heap relation for index");
+
+        let mut stats = GreedySearchStats::new();
+        match self.meta_page.get_storage_type() {
+            StorageType::Plain => {
+                let storage = PlainStorage::load_for_search(
+                    &index_relation,
+                    &heap_relation,
+                    self.meta_page.get_distance_function(),
+                );
+                page_edges(&storage, &index_relation, self.block_number, &mut stats)
+            }
+            StorageType::SbqSpeedup | StorageType::SbqCompression => {
+                let storage = SbqSpeedupStorage::load_for_insert(
+                    &heap_relation,
+                    &index_relation,
+                    &self.meta_page,
+                    &mut stats,
+                );
+                page_edges(&storage, &index_relation, self.block_number, &mut stats)
+            }
+        }
+    }
+}
+
+/// The edges from the live nodes of one index page.
+fn page_edges<S: Storage>(
+    storage: &S,
+    index: &PgRelation,
+    block_number: pg_sys::BlockNumber,
+    stats: &mut GreedySearchStats,
+) -> Vec<(pg_sys::ItemPointerData, pg_sys::ItemPointerData, f32)> {
+    let mut nodes = Vec::new();
+    {
+        let page = unsafe { ReadablePage::read(index, block_number) };
+        if page.is_new() || page.get_type() != S::page_type() {
+            return Vec::new();
+        }
+        let max_offset = unsafe { PageGetMaxOffsetNumber(*page) };
+        for offset_number in FirstOffsetNumber..(max_offset + 1) as _ {
+            nodes.push(ItemPointer::new(block_number, offset_number));
+        }
+    }
+
+    let mut edges = Vec::new();
+    let mut neighbors: Vec<NeighborWithDistance> = Vec::new();
+    for index_pointer in nodes {
+        let Some(heap_pointer) = (unsafe { read_heap_pointer::<S>(index, index_pointer) }) else {
+            continue;
+        };
+        neighbors.clear();
+        storage.get_neighbors_with_distances_from_disk(index_pointer, &mut neighbors, stats);
+        let source_ctid = to_ctid(heap_pointer);
+        edges.extend(
+            neighbor_rows::<S>(index, &neighbors)
+                .into_iter()
+                .filter_map(|(_, target_ctid, distance)| {
+                    target_ctid.map(|target_ctid| (source_ctid, target_ctid, distance))
+                }),
+        );
+    }
+    edges
+}
+
+const GRAPH_STATS_SEED: u64 = 0x57a757a7;
+
+/// Calls `f` with every live node of the index, in physical order.
+pub fn for_each_live_node<S: Storage>(
+    index: &PgRelation,
+    mut f: impl FnMut(IndexPointer, &S::ArchivedType),
+) {
+    for_each_live_item::<S>(index, |index_pointer, data| {
+        let node = S::ArchivedType::with_data(data);
+        if !node.is_deleted() {
+            f(index_pointer, &*node);
+        }
+    });
+}
+
+/// Calls `f` with every live node of the index and its neighbors, in physical order.
+pub fn for_each_live_node_with_neighbors<S: Storage>(
+    index: &PgRelation,
+    meta_page: &MetaPage,
+    mut f: impl FnMut(IndexPointer, &S::ArchivedType, Vec<ItemPointer>),
+) {
+    let neighbor_block = NeighborBlock::for_index(index, meta_page);
+    for_each_live_item::<S>(index, |index_pointer, data| {
+        let compressed_neighbors = neighbor_block.map(|neighbor_block| neighbor_block.read(data));
+        let node = S::ArchivedType::with_data(data);
+        if !node.is_deleted() {
+            let neighbors =
+                compressed_neighbors.unwrap_or_else(|| node.get_index_pointer_to_neighbors());
+            f(index_pointer, &*node, neighbors);
+        }
+    });
+}
+
+/// Calls `f` with the item of every node of the index, deleted or not, in physical order.
+fn for_each_live_item<S: Storage>(index: &PgRelation, mut f: impl FnMut(IndexPointer, &mut [u8])) {
+    let nblocks = unsafe {
+        pg_sys::RelationGetNumberOfBlocksInFork(index.as_ptr(), pg_sys::ForkNumber_MAIN_FORKNUM)
+    };
+    for block_number in 0..nblocks {
+        let page = unsafe { ReadablePage::read(index, block_number) };
+        if page.is_new() || page.get_type() != S::page_type() {
+            continue;
+        }
+
+        let max_offset = unsafe { PageGetMaxOffsetNumber(*page) };
+        for offset_number in FirstOffsetNumber..(max_offset + 1) as _ {
+            unsafe {
+                let item_id = PageGetItemId(*page, offset_number);
+                let item = PageGetItem(*page, item_id) as *mut u8;
+                let len = (*item_id).lp_len();
+                let data = std::slice::from_raw_parts_mut(item, len as _);
+                f(ItemPointer::new(block_number, offset_number), data);
+            }
+        }
+    }
+}
+
+/// The out-degree of every live node and a reservoir sample of the live nodes.
+fn scan_degrees<S: Storage>(
+    index: &PgRelation,
+    meta_page: &MetaPage,
+    sample_size: usize,
+) -> (Vec<i64>, Vec<IndexPointer>) {
+    let mut rng = SmallRng::seed_from_u64(GRAPH_STATS_SEED);
+    /* nodes by out-degree */
+    let mut degree_histogram: Vec<i64> = Vec::new();
+    let mut sample = Vec::new();
+    let mut num_seen = 0;
+
+    for_each_live_node_with_neighbors::<S>(index, meta_page, |index_pointer, _, neighbors| {
+        let degree = neighbors.len();
+        if degree_histogram.len() <= degree {
+            degree_histogram.resize(degree + 1, 0);
+        }
+        degree_histogram[degree] += 1;
+
+        num_seen += 1;
+        if num_seen <= sample_size {
+            sample.push(index_pointer);
+        } else {
+            let slot = rng.gen_range(0..num_seen);
+            if slot < sample_size {
+                sample[slot] = index_pointer;
+            }
+        }
+    });
+    (degree_histogram, sample)
+}
+
+/// Marks all the live nodes reachable from `start` as visited.
+fn visit_reachable<S: Storage>(
+    index: &PgRelation,
+    neighbor_block: Option<NeighborBlock>,
+    start: &[IndexPointer],
+    visited: &mut HashSet<IndexPointer>,
+) {
+    let mut queue: VecDeque<IndexPointer> = start
+        .iter()
+        .filter(|index_pointer| visited.insert(**index_pointer))
+        .copied()
+        .collect();
+    while let Some(index_pointer) = queue.pop_front() {
+        let neighbors = unsafe {
+            with_live_node_neighbors::<S, _>(
+                index,
+                neighbor_block,
+                index_pointer,
+                |_, neighbors| neighbors,
+            )
+        };
+        for neighbor in neighbors.unwrap_or_default() {
+            if visited.insert(neighbor) {
+                queue.push_back(neighbor);
+            }
+        }
+    }
+}
+
+/// Graph statistics of the index, see `diskann_graph_stats`.
+struct GraphStats {
+    degree_histogram: Vec<i64>,
+    num_reachable_nodes: i64,
+    num_sampled_nodes: i64,
+    num_unreachable_sampled_nodes: i64,
+    num_unreachable_components: i64,
+}
+
+impl GraphStats {
+    fn compute<S: Storage>(index: &PgRelation, meta_page: &MetaPage, sample_size: usize) -> Self {
+        let (degree_histogram, sample) = scan_degrees::<S>(index, meta_page, sample_size);
+        let neighbor_block = NeighborBlock::for_index(index, meta_page);
+
+        let mut visited = HashSet::new();
+        let entry_points = meta_page.get_init_ids().unwrap_or_default();
+        visit_reachable::<S>(index, neighbor_block, &entry_points, &mut visited);
+        let num_reachable_nodes = visited
+            .iter()
+            .filter(|index_pointer| unsafe {
+                with_live_node::<S, _>(index, **index_pointer, |_| ()).is_some()
+            })
+            .count() as i64;
+
+        /* every sampled node that is still unvisited starts a part of the graph that cannot be
+        reached from the entry points, nor from the parts found before it */
+        let unreachable: Vec<_> = sample
+            .iter()
+            .filter(|index_pointer| !visited.contains(*index_pointer))
+            .copied()
+            .collect();
+        let mut num_unreachable_components = 0;
+        for index_pointer in unreachable.iter() {
+            if !visited.contains(index_pointer) {
+                num_unreachable_components += 1;
+                visit_reachable::<S>(index, neighbor_block, &[*index_pointer], &mut visited);
+            }
+        }
+
+        Self {
+            degree_histogram,
+            num_reachable_nodes,
+            num_sampled_nodes: sample.len() as i64,
+            num_unreachable_sampled_nodes: unreachable.len() as i64,
+            num_unreachable_components,
+        }
+    }
+
+    fn num_nodes(&self) -> i64 {
+        self.degree_histogram.iter().sum()
+    }
+
+    fn avg_degree(&self) -> f64 {
+        let num_nodes = self.num_nodes();
+        if num_nodes == 0 {
+            return 0.0;
+        }
+        let num_edges: i64 = self
+            .degree_histogram
+            .iter()
+            .enumerate()
+            .map(|(degree, count)| degree as i64 * count)
+            .sum();
+        num_edges as f64 / num_nodes as f64
+    }
+
+    /// The smallest degree such that at least `fraction` of the nodes have at most that degree.
+    fn degree_percentile(&self, fraction: f64) -> i32 {
+        let target = (self.num_nodes() as f64 * fraction).ceil() as i64;
+        let mut seen = 0;
+        for (degree, count) in self.degree_histogram.iter().enumerate() {
+            seen += count;
+            if seen >= target.max(1) {
+                return degree as i32;
+            }
+        }
+        0
+    }
+
+    fn num_nodes_below_degree(&self, min_degree: usize) -> i64 {
+        self.degree_histogram.iter().take(min_degree).sum()
+    }
+}
+
+/// Measures the quality of the graph of an index: the distribution of the out-degrees of the
+/// live nodes, the number of nodes with fewer than `min_degree` neighbors (by default half of
+/// `num_neighbors`), and the number of live nodes a search can reach from the entry points.
+/// Nodes that cannot be reached are never returned by a search. They are grouped into parts of
+/// the graph that are disconnected from the entry points, estimated from a sample of
+/// `sample_size` nodes.
+///
+/// Reads every page of the index and keeps the set of reached nodes in memory.
+#[pg_extern(volatile)]
+pub fn diskann_graph_stats(
+    index: pg_sys::Oid,
+    sample_size: default!(i32, 1000),
+    min_degree: default!(Option<i32>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(num_nodes, i64),
+        name!(avg_out_degree, f64),
+        name!(min_out_degree, i32),
+        name!(p50_out_degree, i32),
+        name!(p90_out_degree, i32),
+        name!(p99_out_degree, i32),
+        name!(max_out_degree, i32),
+        name!(num_nodes_below_min_degree, i64),
+        name!(num_reachable_nodes, i64),
+        name!(num_sampled_nodes, i64),
+        name!(num_unreachable_sampled_nodes, i64),
+        name!(num_unreachable_components, i64),
+    ),
+> {
+    if sample_size < 0 {
+        error!("sample_size must not be negative");
+    }
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let meta_page = MetaPage::fetch(&index_relation);
+    let min_degree = match min_degree {
+        Some(min_degree) if min_degree < 0 => error!("min_degree must not be negative"),
+        Some(min_degree) => min_degree as usize,
+        None => meta_page.get_num_neighbors() as usize / 2,
+    };
+
+    let stats = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            GraphStats::compute::<PlainStorage>(&index_relation, &meta_page, sample_size as _)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            GraphStats::compute::<SbqSpeedupStorage>(
+                &index_relation,
+                &meta_page,
+                sample_size as _,
+            )
+        }
+    };
+
+    let min_out_degree = stats
+        .degree_histogram
+        .iter()
+        .position(|count| *count > 0)
+        .unwrap_or(0);
+    TableIterator::once((
+        stats.num_nodes(),
+        stats.avg_degree(),
+        min_out_degree as i32,
+        stats.degree_percentile(0.5),
+        stats.degree_percentile(0.9),
+        stats.degree_percentile(0.99),
+        stats.degree_histogram.len().saturating_sub(1) as i32,
+        stats.num_nodes_below_degree(min_degree),
+        stats.num_reachable_nodes,
+        stats.num_sampled_nodes,
+        stats.num_unreachable_sampled_nodes,
+        stats.num_unreachable_components,
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_vectorscale_version() -> spi::Result<()> {
+        let (extension_version, format_version) = Spi::get_two::<String, i32>(
+            "SELECT extension_version, format_version FROM vectorscale_version()",
+        )?;
+        assert_eq!(extension_version.unwrap(), env!("CARGO_PKG_VERSION"));
+
+        /* the format version of the indexes it builds */
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test(embedding) SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 10) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding);",
+        )?;
+        let index_format_version = Spi::get_one::<i32>(
+            "SELECT format_version FROM diskann_index_info('idxtest'::regclass)",
+        )?;
+        assert_eq!(format_version, index_format_version);
+
+        let (has_avx2, pg_version) = Spi::get_two::<bool, String>(
+            "SELECT 'avx2' = ANY(simd_features), pg_version FROM vectorscale_version()",
+        )?;
+        if cfg!(any(target_arch = "x86", target_arch = "x86_64")) {
+            assert_eq!(has_avx2, Some(true));
+        }
+        assert!(pg_version.unwrap().starts_with("PostgreSQL "));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_index_info() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (num_neighbors = 20, storage_layout = memory_optimized);
+
+            DELETE FROM test WHERE embedding = '[1,1,1]';",
+        )?;
+
+        let (storage_layout, num_neighbors, num_nodes) = Spi::get_three::<String, i32, i64>(
+            "SELECT storage_layout, num_neighbors, num_nodes FROM diskann_index_info('idxtest'::regclass)",
+        )?;
+        assert_eq!(storage_layout.unwrap(), "memory_optimized");
+        assert_eq!(num_neighbors, Some(20));
+        /* deleted rows stay in the graph until vacuum */
+        assert_eq!(num_nodes, Some(300));
+
+        let (vector_type, num_dimensions, page_counts) = Spi::get_three::<String, i32, String>(
+            "SELECT vector_type, num_dimensions, page_counts FROM diskann_index_info('idxtest'::regclass)",
+        )?;
+        assert_eq!(vector_type.unwrap(), "vector");
+        assert_eq!(num_dimensions, Some(3));
+        let page_counts = page_counts.unwrap();
+        assert!(page_counts.starts_with("meta: 1, "));
+        assert!(page_counts.contains("sbq_means: 1"));
+        assert!(page_counts.contains("sbq_node: "));
+
+        let num_entry_points = Spi::get_one::<i32>(
+            "SELECT num_entry_points FROM diskann_index_info('idxtest'::regclass)",
+        )?;
+        assert_eq!(num_entry_points, Some(1));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_index_bloat() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (storage_layout = plain);",
+        )?;
+
+        let (num_deleted_nodes, in_use) = Spi::get_two::<i64, bool>(
+            "SELECT num_deleted_nodes, in_use FROM diskann_index_bloat('idxtest'::regclass) WHERE page_type = 'node'",
+        )?;
+        assert_eq!(num_deleted_nodes, Some(0));
+        assert_eq!(in_use, Some(true));
+
+        let num_unused_types = Spi::get_one::<i64>(
+            "SELECT count(*) FROM diskann_index_bloat('idxtest'::regclass) WHERE NOT in_use",
+        )?;
+        assert_eq!(num_unused_types, Some(0));
+
+        let (num_pages, reclaimable_bytes) = Spi::get_two::<i64, i64>(
+            "SELECT sum(num_pages)::int8, sum(reclaimable_bytes)::int8 FROM diskann_index_bloat('idxtest'::regclass)",
+        )?;
+        assert!(reclaimable_bytes.unwrap() < num_pages.unwrap() * 8192);
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_page_stats() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (storage_layout = memory_optimized);",
+        )?;
+
+        let (num_items, fraction_of_index) = Spi::get_two::<i64, f64>(
+            "SELECT num_items, fraction_of_index FROM diskann_page_stats('idxtest'::regclass) WHERE page_type = 'sbq_node'",
+        )?;
+        assert_eq!(num_items, Some(300));
+        assert!(fraction_of_index.unwrap() > 0.0);
+
+        let (num_pages, total_fraction) = Spi::get_two::<i64, f64>(
+            "SELECT sum(num_pages)::int8, sum(fraction_of_index) FROM diskann_page_stats('idxtest'::regclass)",
+        )?;
+        let relation_pages = Spi::get_one::<i64>(
+            "SELECT pg_relation_size('idxtest') / current_setting('block_size')::int8",
+        )?;
+        assert_eq!(num_pages, relation_pages);
+        assert!((total_fraction.unwrap() - 1.0).abs() < 1e-9);
+
+        let (used_bytes, free_bytes, total_bytes) = Spi::get_three::<i64, i64, i64>(
+            "SELECT used_bytes, free_bytes, total_bytes FROM diskann_page_stats('idxtest'::regclass) WHERE page_type = 'meta'",
+        )?;
+        assert!(used_bytes.unwrap() + free_bytes.unwrap() < total_bytes.unwrap());
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_page_ranges() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(64));
+
+            INSERT INTO test(id, embedding)
+            SELECT i::int, v::vector
+            FROM diskann_generate_vectors(1000, 64, num_clusters => 5, seed => 3)
+                WITH ORDINALITY AS g(v, i);
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (storage_layout = memory_optimized, store_full_vectors = true);",
+        )?;
+
+        /* the ranges cover the index */
+        let (num_pages, relation_pages) = Spi::get_two::<i64, i64>(
+            "SELECT sum(last_block - first_block + 1)::int8,
+                pg_relation_size('idxtest') / current_setting('block_size')::int8
+            FROM diskann_page_ranges('idxtest'::regclass)",
+        )?;
+        assert_eq!(num_pages, relation_pages);
+
+        /* the build writes the full vectors after the graph, in one range */
+        let (num_ranges, after_graph) = Spi::get_two::<i64, bool>(
+            "SELECT count(*), min(first_block) > (
+                SELECT max(last_block) FROM diskann_page_ranges('idxtest'::regclass)
+                WHERE page_type = 'sbq_node')
+            FROM diskann_page_ranges('idxtest'::regclass)
+            WHERE page_type = 'full_vector'",
+        )?;
+        assert_eq!(num_ranges, Some(1));
+        assert_eq!(after_graph, Some(true));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_node_neighbors() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (num_neighbors = 20, storage_layout = plain);",
+        )?;
+
+        let (num_neighbors, num_rows) = Spi::get_two::<i64, i64>(
+            "SELECT count(*), count(DISTINCT t.ctid)
+            FROM diskann_node_neighbors('idxtest'::regclass, (SELECT ctid FROM test WHERE embedding = '[1,1,1]')) n
+            JOIN test t ON (t.ctid = n.neighbor_ctid)
+            WHERE n.distance >= 0",
+        )?;
+        assert!(num_neighbors.unwrap() > 0);
+        assert!(num_neighbors.unwrap() <= 20);
+        /* neighbors are distinct live rows */
+        assert_eq!(num_neighbors, num_rows);
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_graph_edges() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (num_neighbors = 20);
+
+            DELETE FROM test WHERE embedding = '[1,1,1]';",
+        )?;
+
+        let (num_sources, max_out_degree) = Spi::get_two::<i64, i64>(
+            "SELECT count(*), max(out_degree) FROM (
+                SELECT source_ctid, count(*) AS out_degree
+                FROM diskann_graph_edges('idxtest'::regclass)
+                GROUP BY source_ctid) s",
+        )?;
+        /* deleted rows keep their node until vacuum */
+        assert_eq!(num_sources, Some(300));
+        assert!(max_out_degree.unwrap() <= 20);
+
+        let num_self_edges = Spi::get_one::<i64>(
+            "SELECT count(*) FROM diskann_graph_edges('idxtest'::regclass) WHERE source_ctid = target_ctid",
+        )?;
+        assert_eq!(num_self_edges, Some(0));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_graph_stats() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (num_neighbors = 20);",
+        )?;
+
+        let (num_nodes, num_reachable_nodes, num_unreachable_components) =
+            Spi::get_three::<i64, i64, i64>(
+                "SELECT num_nodes, num_reachable_nodes, num_unreachable_components
+                FROM diskann_graph_stats('idxtest'::regclass)",
+            )?;
+        assert_eq!(num_nodes, Some(300));
+        assert_eq!(num_reachable_nodes, Some(300));
+        assert_eq!(num_unreachable_components, Some(0));
+
+        let (min_out_degree, max_out_degree, num_sampled_nodes) = Spi::get_three::<i32, i32, i64>(
+            "SELECT min_out_degree, max_out_degree, num_sampled_nodes
+            FROM diskann_graph_stats('idxtest'::regclass, sample_size => 100)",
+        )?;
+        assert!(min_out_degree.unwrap() > 0);
+        assert!(max_out_degree.unwrap() <= 20);
+        assert_eq!(num_sampled_nodes, Some(100));
+
+        let num_nodes_below_min_degree = Spi::get_one::<i64>(
+            "SELECT num_nodes_below_min_degree FROM diskann_graph_stats('idxtest'::regclass, min_degree => 0)",
+        )?;
+        assert_eq!(num_nodes_below_min_degree, Some(0));
+        Ok(())
+    }
+}
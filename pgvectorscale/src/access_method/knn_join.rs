@@ -0,0 +1,253 @@
+//! Matches every row of a table against an index, for many-to-many joins such as entity
+//! resolution.
+//!
+//! A LATERAL join over an index scan or `diskann_search` runs one search per row of the outer
+//! table, and every search plans the subquery, opens the index and loads its storage, e.g. the
+//! quantizer, again. `diskann_knn_join` reads the vectors of the source table in one sequential
+//! scan and searches them in batches, each against the storage of the index loaded once.
+
+use pgrx::{pg_sys, *};
+
+use crate::util::{table_slot::TableSlot, HeapPointer, ItemPointer};
+
+use super::{
+    meta_page::MetaPage,
+    pg_vector::PgVector,
+    scan::search_heap_pointers_batch,
+    search::SearchParams,
+    stats::GreedySearchStats,
+    storage_common::{check_select_privilege, open_diskann_index},
+};
+
+/// The number of source rows searched with the same storage.
+const BATCH_SIZE: usize = 1000;
+
+/// A sequential scan of the vectors of a column of the source table.
+struct SourceScan<'a> {
+    relation: &'a PgRelation,
+    scan: pg_sys::TableScanDesc,
+    slot: TableSlot,
+    attribute: pg_sys::AttrNumber,
+    finished: bool,
+}
+
+impl<'a> SourceScan<'a> {
+    unsafe fn new(relation: &'a PgRelation, attribute: pg_sys::AttrNumber) -> Self {
+        let scan = (*relation.rd_tableam).scan_begin.unwrap()(
+            relation.as_ptr(),
+            pg_sys::GetActiveSnapshot(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            pg_sys::ScanOptions_SO_TYPE_SEQSCAN | pg_sys::ScanOptions_SO_ALLOW_PAGEMODE,
+        );
+        Self {
+            relation,
+            scan,
+            slot: TableSlot::new(relation),
+            attribute,
+            finished: false,
+        }
+    }
+
+    /// The ctid and the vector of the next row whose vector is not NULL.
+    unsafe fn next(&mut self, meta_page: &MetaPage) -> Option<(HeapPointer, PgVector)> {
+        /* a table scan that reached the end starts over if called again */
+        while !self.finished {
+            if !(*self.relation.rd_tableam).scan_getnextslot.unwrap()(
+                self.scan,
+                pg_sys::ScanDirection_ForwardScanDirection,
+                self.slot.as_ptr(),
+            ) {
+                self.finished = true;
+                break;
+            }
+            let Some(datum) = self.slot.get_attribute(self.attribute) else {
+                continue;
+            };
+            let heap_pointer = ItemPointer::with_item_pointer_data((*self.slot.as_ptr()).tts_tid);
+            /* the vector is copied out of the slot */
+            return Some((
+                heap_pointer,
+                PgVector::from_datum(datum, meta_page, true, true),
+            ));
+        }
+        None
+    }
+}
+
+impl<'a> Drop for SourceScan<'a> {
+    fn drop(&mut self) {
+        unsafe { (*self.relation.rd_tableam).scan_end.unwrap()(self.scan) };
+    }
+}
+
+fn to_ctid(heap_pointer: HeapPointer) -> pg_sys::ItemPointerData {
+    let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
+        ..Default::default()
+    };
+    heap_pointer.to_item_pointer_data(&mut ctid);
+    ctid
+}
+
+/// Returns the `k` rows of the table of `index` nearest to the vector of every row of `source`
+/// found by the index, with their rank, closest first. `vector_column` is the column of `source`
+/// holding the vectors, of the type of the indexed column, and its rows with a NULL vector have
+/// no matches. The rows of both tables are identified by their `ctid`:
+///
+/// ```sql
+/// SELECT c.id, p.id, j.distance
+/// FROM diskann_knn_join('products_embedding_idx'::regclass, 'candidates'::regclass, 'embedding', 5) j
+/// JOIN candidates c ON c.ctid = j.source_ctid
+/// JOIN products p ON p.ctid = j.ctid;
+/// ```
+///
+/// The searches use the `search_list_size` and `rescore` of `params` if set, like
+/// `diskann_search`, and the distances are the ones the index orders the rows by.
+#[pg_extern(volatile)]
+pub fn diskann_knn_join(
+    index: pg_sys::Oid,
+    source: pg_sys::Oid,
+    vector_column: &str,
+    k: i32,
+    params: default!(Option<JsonB>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(source_ctid, pg_sys::ItemPointerData),
+        name!(ctid, pg_sys::ItemPointerData),
+        name!(distance, f64),
+        name!(rank, i32),
+    ),
+> {
+    if k <= 0 {
+        error!("k must be positive");
+    }
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let source_relation =
+        unsafe { PgRelation::with_lock(source, pg_sys::AccessShareLock as pg_sys::LOCKMODE) };
+    if source_relation.rd_tableam.is_null() {
+        error!("\"{}\" is not a table", source_relation.name());
+    }
+    check_select_privilege(source);
+
+    let tuple_desc = source_relation.tuple_desc();
+    let Some(column) = tuple_desc
+        .iter()
+        .find(|a| !a.is_dropped() && a.name() == vector_column)
+    else {
+        error!(
+            "column \"{}\" of relation \"{}\" does not exist",
+            vector_column,
+            source_relation.name()
+        );
+    };
+    if column.atttypid != index_relation.tuple_desc().get(0).unwrap().atttypid {
+        error!(
+            "column \"{}\" must have the type of the indexed column",
+            vector_column
+        );
+    }
+    let attribute = column.attnum;
+
+    let meta_page = MetaPage::fetch(&index_relation);
+    let params = SearchParams::new(&index_relation, &meta_page, params);
+    let mut scan = unsafe { SourceScan::new(&source_relation, attribute) };
+    let mut stats = GreedySearchStats::new();
+    let mut rows = Vec::new();
+    loop {
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        while batch.len() < BATCH_SIZE {
+            match unsafe { scan.next(&meta_page) } {
+                Some(query) => batch.push(query),
+                None => break,
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+
+        let results = search_heap_pointers_batch(
+            &index_relation,
+            &heap_relation,
+            batch.into_iter(),
+            k as _,
+            params.search_list_size,
+            params.rescore,
+            &mut stats,
+        );
+        for (source_pointer, matches) in results {
+            for (rank, (heap_pointer, distance)) in matches.into_iter().enumerate() {
+                rows.push((
+                    to_ctid(source_pointer),
+                    to_ctid(heap_pointer),
+                    distance as f64,
+                    rank as i32 + 1,
+                ));
+            }
+        }
+        check_for_interrupts!();
+    }
+    TableIterator::new(rows)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_knn_join() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 7, 1]::vector
+            FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+
+            CREATE TABLE queries(id int, embedding vector(3));
+            INSERT INTO queries(id, embedding) VALUES (1, '[10, 3, 1]'), (2, NULL), (3, '[200, 4, 1]');",
+        )?;
+
+        /* k matches for every row with a vector */
+        let (count, num_sources, ranks) = Spi::get_three::<i64, i64, bool>(
+            "SELECT count(*), count(DISTINCT source_ctid), bool_and(rank BETWEEN 1 AND 5)
+            FROM diskann_knn_join('idxtest'::regclass, 'queries'::regclass, 'embedding', 5)",
+        )?;
+        assert_eq!(count, Some(10));
+        assert_eq!(num_sources, Some(2));
+        assert_eq!(ranks, Some(true));
+
+        /* the queries are vectors of the table */
+        let nearest = Spi::get_one::<Vec<i32>>(
+            "SELECT array_agg(t.id ORDER BY q.id)
+            FROM diskann_knn_join('idxtest'::regclass, 'queries'::regclass, 'embedding', 1) j
+            JOIN queries q ON q.ctid = j.source_ctid
+            JOIN test t ON t.ctid = j.ctid",
+        )?;
+        assert_eq!(nearest, Some(vec![10, 200]));
+
+        /* every row of the table finds itself */
+        let found_self = Spi::get_one::<i64>(
+            "SELECT count(*) FROM diskann_knn_join('idxtest'::regclass, 'test'::regclass, 'embedding', 1)
+            WHERE source_ctid = ctid",
+        )?;
+        assert_eq!(found_self, Some(300));
+        Ok(())
+    }
+
+    #[pg_test(error = "column \"id\" must have the type of the indexed column")]
+    unsafe fn test_knn_join_wrong_column() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            SELECT * FROM diskann_knn_join('idxtest'::regclass, 'test'::regclass, 'id', 5);",
+        )?;
+        Ok(())
+    }
+}
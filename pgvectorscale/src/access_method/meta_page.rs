@@ -8,22 +8,40 @@ use crate::access_method::options::TSVIndexOptions;
 use crate::util::page;
 use crate::util::*;
 
-use super::distance;
+use super::full_vectors::{FullVectorType, FULL_VECTOR_POINTER_SIZE};
+use super::guc::get_distance_impl;
+use super::neighbor_compression::NeighborEncoding;
 use super::options::{
-    NUM_DIMENSIONS_DEFAULT_SENTINEL, NUM_NEIGHBORS_DEFAULT_SENTINEL,
+    DEFAULT_FILLFACTOR, NUM_DIMENSIONS_DEFAULT_SENTINEL, NUM_NEIGHBORS_DEFAULT_SENTINEL,
+    QUERY_RESCORE_DEFAULT_SENTINEL, QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL,
     SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL,
 };
+use super::payload::{find_timestamp_column, get_num_payload_columns, get_payload_size};
+use super::pca::PcaProjection;
+use super::pg_vector::VectorType;
 use super::sbq::SbqNode;
-use super::stats::StatsNodeModify;
+use super::stats::{StatsNodeModify, StatsNodeWrite};
 use super::storage::StorageType;
+use super::storage_common::orders_by_weighted_query;
 
 const TSV_MAGIC_NUMBER: u32 = 768756476; //Magic number, random
-const TSV_VERSION: u32 = 2;
-const GRAPH_SLACK_FACTOR: f64 = 1.3_f64;
+pub const TSV_VERSION: u32 = 3;
+pub const GRAPH_SLACK_FACTOR: f64 = 1.3_f64;
+
+const MICROSECONDS_PER_SECOND: i64 = 1_000_000;
 
 const META_BLOCK_NUMBER: pg_sys::BlockNumber = 0;
 const META_HEADER_OFFSET: pgrx::pg_sys::OffsetNumber = 1;
 const META_OFFSET: pgrx::pg_sys::OffsetNumber = 2;
+/// Older meta pages stored a single entry point, invalid if the graph was empty.
+fn init_ids_from(init_id: ItemPointer) -> Vec<ItemPointer> {
+    if init_id.is_valid() {
+        vec![init_id]
+    } else {
+        Vec::new()
+    }
+}
+
 /// This is old metadata version for extension versions <=0.0.2.
 /// Note it is NOT repr(C)
 #[derive(Clone)]
@@ -74,8 +92,86 @@ impl MetaPageV1 {
             storage_type: StorageType::Plain as u8,
             search_list_size: self.search_list_size,
             max_alpha: self.max_alpha,
-            init_ids: ItemPointer::new(self.init_ids_block_number, self.init_ids_offset),
+            init_ids: init_ids_from(ItemPointer::new(
+                self.init_ids_block_number,
+                self.init_ids_offset,
+            )),
             quantizer_metadata: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            vector_type: VectorType::Vector as u8,
+            weights: Vec::new(),
+            pca_dimensions: 0,
+            pca_projection: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            pca: None,
+            num_entry_points: 1,
+            query_search_list_size: QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL,
+            query_rescore: QUERY_RESCORE_DEFAULT_SENTINEL,
+            neighbor_encoding: NeighborEncoding::Inline as u8,
+            fillfactor: DEFAULT_FILLFACTOR as u8,
+            full_vector_type: FullVectorType::None as u8,
+            tenant_buckets: 0,
+            bucket_init_ids: Vec::new(),
+            time_buckets: 0,
+            time_bucket_width: 0,
+            first_time_bucket: i64::MAX,
+            last_time_bucket: i64::MIN,
+        }
+    }
+}
+
+/// This is the metadata format of version 2, written by the extension versions after 0.0.2 up
+/// to 0.2.0. Since 0.3.0 it is upgraded in memory when read; the new format is written on the
+/// next update of the meta page.
+#[derive(Clone, Archive, Deserialize, Serialize, Readable)]
+#[archive(check_bytes)]
+pub struct MetaPageV2 {
+    magic_number: u32,
+    version: u32,
+    extension_version_when_built: String,
+    distance_type: u16,
+    num_dimensions: u32,
+    num_dimensions_to_index: u32,
+    bq_num_bits_per_dimension: u8,
+    storage_type: u8,
+    num_neighbors: u32,
+    search_list_size: u32,
+    max_alpha: f64,
+    init_ids: ItemPointer,
+    quantizer_metadata: ItemPointer,
+}
+
+impl MetaPageV2 {
+    pub fn get_new_meta(&self) -> MetaPage {
+        MetaPage {
+            magic_number: self.magic_number,
+            version: TSV_VERSION,
+            extension_version_when_built: self.extension_version_when_built.clone(),
+            distance_type: self.distance_type,
+            num_dimensions: self.num_dimensions,
+            num_dimensions_to_index: self.num_dimensions_to_index,
+            bq_num_bits_per_dimension: self.bq_num_bits_per_dimension,
+            storage_type: self.storage_type,
+            num_neighbors: self.num_neighbors,
+            search_list_size: self.search_list_size,
+            max_alpha: self.max_alpha,
+            init_ids: init_ids_from(self.init_ids),
+            quantizer_metadata: self.quantizer_metadata,
+            vector_type: VectorType::Vector as u8,
+            weights: Vec::new(),
+            pca_dimensions: 0,
+            pca_projection: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            pca: None,
+            num_entry_points: 1,
+            query_search_list_size: QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL,
+            query_rescore: QUERY_RESCORE_DEFAULT_SENTINEL,
+            neighbor_encoding: NeighborEncoding::Inline as u8,
+            fillfactor: DEFAULT_FILLFACTOR as u8,
+            full_vector_type: FullVectorType::None as u8,
+            tenant_buckets: 0,
+            bucket_init_ids: Vec::new(),
+            time_buckets: 0,
+            time_bucket_width: 0,
+            first_time_bucket: i64::MAX,
+            last_time_bucket: i64::MIN,
         }
     }
 }
@@ -99,6 +195,13 @@ enum DistanceType {
 }
 
 impl DistanceType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            DistanceType::Cosine => "cosine",
+            DistanceType::L2 => "l2",
+        }
+    }
+
     fn from_u16(value: u16) -> Self {
         match value {
             0 => DistanceType::Cosine,
@@ -130,8 +233,42 @@ pub struct MetaPage {
     num_neighbors: u32,
     search_list_size: u32,
     max_alpha: f64,
-    init_ids: ItemPointer,
+    /// entry points of the graph search, empty if the graph is empty
+    init_ids: Vec<ItemPointer>,
     quantizer_metadata: ItemPointer,
+    /// The value of the VectorType enum: the type of the indexed column
+    vector_type: u8,
+    /// per-dimension weights of the distance, empty if the distance is unweighted
+    weights: Vec<f32>,
+    /// number of principal components the vectors are projected on, 0 if PCA is not used
+    pca_dimensions: u32,
+    pca_projection: ItemPointer,
+    /// the projection stored at pca_projection, its components are read on first use
+    #[with(rkyv::with::Skip)]
+    pca: Option<PcaProjection>,
+    /// number of entry points chosen for the graph search
+    num_entry_points: u32,
+    /// default search list size of queries, QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL if unset
+    query_search_list_size: u32,
+    /// default number of rescored candidates of queries, QUERY_RESCORE_DEFAULT_SENTINEL if unset
+    query_rescore: i32,
+    /// The value of the NeighborEncoding enum: how the neighbor lists of the nodes are stored
+    neighbor_encoding: u8,
+    /// percentage to which the build fills the node pages
+    fillfactor: u8,
+    /// The value of the FullVectorType enum: whether the full vectors are stored for rescoring
+    full_vector_type: u8,
+    /// number of separate subgraphs of the tenants, 0 if the index has a single graph
+    tenant_buckets: u32,
+    /// entry point of the subgraph of every tenant or time bucket, invalid if the bucket is empty
+    bucket_init_ids: Vec<ItemPointer>,
+    /// number of separate subgraphs of the time buckets, 0 if the graph isn't split by time
+    time_buckets: u32,
+    /// width of a time bucket, in microseconds
+    time_bucket_width: i64,
+    /// the first and the last time buckets that have nodes, first > last if none has
+    first_time_bucket: i64,
+    last_time_bucket: i64,
 }
 
 impl MetaPage {
@@ -173,27 +310,161 @@ impl MetaPage {
         self.max_alpha
     }
 
+    /// The distance function of the index, in the implementation selected by
+    /// `diskann.distance_impl`.
     pub fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32 {
+        let distance_impl = get_distance_impl();
         match DistanceType::from_u16(self.distance_type) {
-            DistanceType::Cosine => distance::distance_cosine,
-            DistanceType::L2 => distance::distance_l2,
+            DistanceType::Cosine => distance_impl.get_cosine(),
+            DistanceType::L2 => distance_impl.get_l2(),
         }
     }
 
+    /// The distance of the index, as computed by the backends of `diskann.build_offload`.
+    #[cfg(feature = "build_offload")]
+    pub fn get_offload_distance(&self) -> crate::diskann::offload::OffloadDistance {
+        match DistanceType::from_u16(self.distance_type) {
+            DistanceType::Cosine => crate::diskann::offload::OffloadDistance::Cosine,
+            DistanceType::L2 => crate::diskann::offload::OffloadDistance::L2,
+        }
+    }
+
+    pub fn get_distance_type_name(&self) -> &'static str {
+        DistanceType::from_u16(self.distance_type).as_str()
+    }
+
+    /// The on-disk format version of the meta page.
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_extension_version_when_built(&self) -> &str {
+        &self.extension_version_when_built
+    }
+
     pub fn get_storage_type(&self) -> StorageType {
         StorageType::from_u8(self.storage_type)
     }
 
+    pub fn get_vector_type(&self) -> VectorType {
+        VectorType::from_u8(self.vector_type)
+    }
+
+    /// The per-dimension weights of the distance. Empty if the distance is unweighted.
+    pub fn get_weights(&self) -> &[f32] {
+        &self.weights
+    }
+
+    pub fn uses_pca(&self) -> bool {
+        self.pca_dimensions > 0
+    }
+
+    /// The PCA projection of the vectors. None if PCA is not used, or during build before the
+    /// projection is learned.
+    pub fn get_pca_projection(&self) -> Option<&PcaProjection> {
+        self.pca.as_ref()
+    }
+
     pub fn get_max_neighbors_during_build(&self) -> usize {
         return ((self.get_num_neighbors() as f64) * GRAPH_SLACK_FACTOR).ceil() as usize;
     }
 
+    /// The entry points of the graph. For an index split into buckets, the entry points of all
+    /// the subgraphs.
     pub fn get_init_ids(&self) -> Option<Vec<IndexPointer>> {
-        if !self.init_ids.is_valid() {
+        let init_ids: Vec<IndexPointer> = if self.is_partitioned() {
+            self.bucket_init_ids
+                .iter()
+                .filter(|ip| ip.is_valid())
+                .copied()
+                .collect()
+        } else {
+            self.init_ids.clone()
+        };
+        if init_ids.is_empty() {
+            return None;
+        }
+
+        Some(init_ids)
+    }
+
+    /// Whether the graph is split into the subgraphs of tenant or time buckets.
+    pub fn is_partitioned(&self) -> bool {
+        !self.bucket_init_ids.is_empty()
+    }
+
+    /// The number of tenant buckets of the index, 0 if it isn't partitioned by tenant.
+    pub fn get_tenant_buckets(&self) -> u32 {
+        self.tenant_buckets
+    }
+
+    /// The number of time buckets of the index, 0 if it isn't split by time.
+    pub fn get_time_buckets(&self) -> u32 {
+        self.time_buckets
+    }
+
+    /// The width of a time bucket, in microseconds.
+    pub fn get_time_bucket_width(&self) -> i64 {
+        self.time_bucket_width
+    }
+
+    /// The first and the last time buckets that have nodes, None if none has.
+    pub fn get_time_bucket_range(&self) -> Option<(i64, i64)> {
+        if self.first_time_bucket > self.last_time_bucket {
             return None;
         }
+        Some((self.first_time_bucket, self.last_time_bucket))
+    }
+
+    /// The entry points of the subgraphs of some buckets, None if they are all empty.
+    pub fn get_bucket_init_ids(&self, buckets: &[u32]) -> Option<Vec<IndexPointer>> {
+        let init_ids: Vec<IndexPointer> = buckets
+            .iter()
+            .map(|&bucket| self.bucket_init_ids[bucket as usize])
+            .filter(|ip| ip.is_valid())
+            .collect();
+        if init_ids.is_empty() {
+            return None;
+        }
+        Some(init_ids)
+    }
+
+    pub fn get_num_entry_points(&self) -> u32 {
+        self.num_entry_points
+    }
 
-        Some(vec![self.init_ids])
+    /// The default search list size of queries stored with `diskann_set_query_defaults`.
+    pub fn get_query_search_list_size(&self) -> Option<u32> {
+        if self.query_search_list_size == QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL {
+            None
+        } else {
+            Some(self.query_search_list_size)
+        }
+    }
+
+    /// The default number of rescored candidates stored with `diskann_set_query_defaults`.
+    pub fn get_query_rescore(&self) -> Option<u32> {
+        if self.query_rescore == QUERY_RESCORE_DEFAULT_SENTINEL {
+            None
+        } else {
+            Some(self.query_rescore as u32)
+        }
+    }
+
+    pub fn get_neighbor_encoding(&self) -> NeighborEncoding {
+        NeighborEncoding::from_u8(self.neighbor_encoding)
+    }
+
+    /// The percentage to which the build fills the node pages. The rest of every page is left
+    /// free for the nodes inserted after the build.
+    pub fn get_fillfactor(&self) -> u8 {
+        self.fillfactor
+    }
+
+    /// How the index stores the full vectors of the nodes, FullVectorType::None if rescoring
+    /// reads them from the heap.
+    pub fn get_full_vector_type(&self) -> FullVectorType {
+        FullVectorType::from_u8(self.full_vector_type)
     }
 
     pub fn get_quantizer_metadata_pointer(&self) -> Option<IndexPointer> {
@@ -210,24 +481,53 @@ impl MetaPage {
     fn calculate_num_neighbors(
         num_dimensions: u32,
         num_bits_per_dimension: u8,
+        payload_size: usize,
         opt: &PgBox<TSVIndexOptions>,
     ) -> u32 {
         let num_neighbors = (*opt).get_num_neighbors();
         if num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
-            match (*opt).get_storage_type() {
-                StorageType::Plain => 50,
-                StorageType::SbqSpeedup => SbqNode::get_default_num_neighbors(
-                    num_dimensions as usize,
-                    num_dimensions as usize,
-                    num_bits_per_dimension,
-                ) as u32,
-                StorageType::SbqCompression => 50,
-            }
+            Self::default_num_neighbors(
+                (*opt).get_storage_type(),
+                num_dimensions,
+                num_bits_per_dimension,
+                payload_size,
+            )
         } else {
             num_neighbors as u32
         }
     }
 
+    /// The num_neighbors of an index built without the option.
+    pub fn default_num_neighbors(
+        storage_type: StorageType,
+        num_dimensions: u32,
+        num_bits_per_dimension: u8,
+        payload_size: usize,
+    ) -> u32 {
+        match storage_type {
+            StorageType::Plain => 50,
+            StorageType::SbqSpeedup => SbqNode::get_default_num_neighbors(
+                num_dimensions as usize,
+                num_dimensions as usize,
+                num_bits_per_dimension,
+                payload_size,
+            ) as u32,
+            StorageType::SbqCompression => 50,
+        }
+    }
+
+    /// The num_bits_per_dimension of an index built without the option.
+    pub fn default_bq_num_bits_per_dimension(
+        storage_type: StorageType,
+        num_dimensions_to_index: u32,
+    ) -> u8 {
+        if storage_type == StorageType::SbqCompression && num_dimensions_to_index < 900 {
+            2
+        } else {
+            1
+        }
+    }
+
     /// Write out a new meta page.
     /// Has to be done as the first write to a new relation.
     pub unsafe fn create(
@@ -237,7 +537,22 @@ impl MetaPage {
     ) -> MetaPage {
         let version = Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
 
-        let num_dimensions_to_index = if (*opt).num_dimensions == NUM_DIMENSIONS_DEFAULT_SENTINEL {
+        let pca_dimensions = (*opt).pca_dimensions;
+        if pca_dimensions > 0 {
+            if (*opt).num_dimensions != NUM_DIMENSIONS_DEFAULT_SENTINEL {
+                pgrx::error!("the num_dimensions and pca_dimensions options cannot be used together");
+            }
+            if pca_dimensions >= num_dimensions {
+                pgrx::error!(
+                    "pca_dimensions must be less than the number of dimensions of the vectors ({})",
+                    num_dimensions
+                );
+            }
+        }
+
+        let num_dimensions_to_index = if pca_dimensions > 0 {
+            pca_dimensions
+        } else if (*opt).num_dimensions == NUM_DIMENSIONS_DEFAULT_SENTINEL {
             num_dimensions
         } else {
             (*opt).num_dimensions
@@ -245,13 +560,10 @@ impl MetaPage {
 
         let bq_num_bits_per_dimension =
             if (*opt).bq_num_bits_per_dimension == SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL {
-                if (*opt).get_storage_type() == StorageType::SbqCompression
-                    && num_dimensions_to_index < 900
-                {
-                    2
-                } else {
-                    1
-                }
+                Self::default_bq_num_bits_per_dimension(
+                    (*opt).get_storage_type(),
+                    num_dimensions_to_index,
+                )
             } else {
                 (*opt).bq_num_bits_per_dimension as u8
             };
@@ -260,13 +572,105 @@ impl MetaPage {
             //limited by SbqMeans fitting on a page
             pgrx::error!("SBQ with more than 1 bit per dimension is not supported for more than 900 dimensions");
         }
-        if bq_num_bits_per_dimension > 1 && (*opt).get_storage_type() != StorageType::SbqCompression
-        {
+        if bq_num_bits_per_dimension > 1 && (*opt).get_storage_type() == StorageType::Plain {
+            pgrx::error!(
+                "SBQ with more than 1 bit per dimension is only supported with the memory_optimized and io_optimized storage layouts"
+            );
+        }
+
+        let weights = (*opt).get_weights();
+        if !weights.is_empty() && weights.len() != num_dimensions as usize {
+            pgrx::error!(
+                "the weights option has {} values but the vectors have {} dimensions",
+                weights.len(),
+                num_dimensions
+            );
+        }
+        /* the operator has to order the rows by the same distance as the index */
+        match (weights.is_empty(), orders_by_weighted_query(index)) {
+            (false, false) => pgrx::error!(
+                "indexes with the weights option must use the vector_weighted_cosine_ops operator class"
+            ),
+            (true, true) => pgrx::error!(
+                "the vector_weighted_cosine_ops operator class requires the weights option"
+            ),
+            _ => {}
+        }
+
+        let full_vector_type = match ((*opt).get_storage_type(), (*opt).store_full_vectors) {
+            (_, false) if (*opt).full_vectors_f16 => {
+                pgrx::error!("full_vectors_f16 requires store_full_vectors")
+            }
+            (_, false) => FullVectorType::None,
+            (StorageType::Plain, true) => pgrx::error!(
+                "store_full_vectors is only supported with the memory_optimized and io_optimized storage layouts"
+            ),
+            (_, true) if (*opt).full_vectors_f16 => FullVectorType::F16,
+            (_, true) => FullVectorType::F32,
+        };
+        if !full_vector_type.fits_on_page(num_dimensions as usize) {
             pgrx::error!(
-                "SBQ with more than 1 bit per dimension is only supported with the memory_optimized storage layout"
+                "store_full_vectors does not support vectors with {} dimensions",
+                num_dimensions
             );
         }
 
+        /* the pointer to the full vector is stored like the payload, in the item of the node */
+        let mut payload_size = get_payload_size(get_num_payload_columns(index));
+        if full_vector_type != FullVectorType::None {
+            payload_size += FULL_VECTOR_POINTER_SIZE;
+        }
+        let num_neighbors = Self::calculate_num_neighbors(
+            num_dimensions,
+            bq_num_bits_per_dimension,
+            payload_size,
+            &opt,
+        );
+        let neighbor_encoding = match ((*opt).get_storage_type(), (*opt).compress_neighbors) {
+            (StorageType::Plain, false) => NeighborEncoding::Inline,
+            (StorageType::Plain, true) => pgrx::error!(
+                "compress_neighbors is only supported with the memory_optimized and io_optimized storage layouts"
+            ),
+            (_, false) => NeighborEncoding::Packed,
+            (_, true) => NeighborEncoding::Varint,
+        };
+
+        let tenant_buckets = (*opt).tenant_buckets;
+        if tenant_buckets > 0 {
+            let num_payload_columns = get_num_payload_columns(index);
+            if num_payload_columns == 0 {
+                pgrx::error!("tenant_buckets requires a filter column after the vector column");
+            }
+            let tenant_column = index.tuple_desc().get(1).unwrap();
+            if ![pg_sys::INT2OID, pg_sys::INT4OID, pg_sys::INT8OID]
+                .contains(&tenant_column.atttypid)
+            {
+                pgrx::error!(
+                    "the tenant column \"{}\" must be of type smallint, integer or bigint",
+                    tenant_column.name()
+                );
+            }
+            if (*opt).num_entry_points > 1 {
+                pgrx::error!(
+                    "the tenant_buckets and num_entry_points options cannot be used together"
+                );
+            }
+        }
+        let time_buckets = (*opt).time_buckets;
+        if time_buckets > 0 {
+            if find_timestamp_column(index).is_none() {
+                pgrx::error!("time_buckets requires a timestamp or timestamptz filter column");
+            }
+            if tenant_buckets > 0 {
+                pgrx::error!("the tenant_buckets and time_buckets options cannot be used together");
+            }
+            if (*opt).num_entry_points > 1 {
+                pgrx::error!(
+                    "the time_buckets and num_entry_points options cannot be used together"
+                );
+            }
+        }
+
         let meta = MetaPage {
             magic_number: TSV_MAGIC_NUMBER,
             version: TSV_VERSION,
@@ -275,16 +679,32 @@ impl MetaPage {
             num_dimensions,
             num_dimensions_to_index,
             storage_type: (*opt).get_storage_type() as u8,
-            num_neighbors: Self::calculate_num_neighbors(
-                num_dimensions,
-                bq_num_bits_per_dimension,
-                &opt,
-            ),
+            num_neighbors,
             bq_num_bits_per_dimension,
             search_list_size: (*opt).search_list_size,
             max_alpha: (*opt).max_alpha,
-            init_ids: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            init_ids: Vec::new(),
             quantizer_metadata: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            vector_type: VectorType::from_index(index) as u8,
+            weights,
+            pca_dimensions,
+            pca_projection: ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+            pca: None,
+            num_entry_points: (*opt).num_entry_points,
+            query_search_list_size: QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL,
+            query_rescore: QUERY_RESCORE_DEFAULT_SENTINEL,
+            neighbor_encoding: neighbor_encoding as u8,
+            fillfactor: (*opt).fillfactor as u8,
+            full_vector_type: full_vector_type as u8,
+            tenant_buckets,
+            bucket_init_ids: vec![
+                ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber);
+                tenant_buckets.max(time_buckets) as usize
+            ],
+            time_buckets,
+            time_bucket_width: (*opt).time_bucket_width as i64 * MICROSECONDS_PER_SECOND,
+            first_time_bucket: i64::MAX,
+            last_time_bucket: i64::MIN,
         };
         let page = page::WritablePage::new(index, crate::util::page::PageType::Meta);
         meta.write_to_page(page);
@@ -307,84 +727,227 @@ impl MetaPage {
 
         //serialize the meta
         let bytes = self.serialize_to_vec();
+        /* the weights are the only unbounded part of the meta */
+        if pg_sys::MAXALIGN(bytes.len()) > page.get_free_space() {
+            pgrx::error!(
+                "the weights option has {} values, more than the meta page of the index has room for",
+                self.weights.len()
+            );
+        }
         let off = page.add_item(&bytes);
         assert!(off == META_OFFSET);
 
         page.commit();
     }
 
-    unsafe fn overwrite(index: &PgRelation, new_meta: &MetaPage) {
-        let mut page = page::WritablePage::modify(index, META_BLOCK_NUMBER);
-        page.reinit(crate::util::page::PageType::Meta);
-        new_meta.write_to_page(page);
-
-        let page = page::ReadablePage::read(index, META_BLOCK_NUMBER);
-        let page_type = page.get_type();
-        if page_type != crate::util::page::PageType::Meta {
-            pgrx::error!(
-                "Problem upgrading meta page: wrong page type: {:?}",
-                page_type
-            );
-        }
-        let meta = Self::get_meta_from_page(page);
-        if meta != *new_meta {
-            pgrx::error!("Problem upgrading meta page: meta mismatch");
-        }
-    }
-
     /// Read the meta page for an index
     pub fn fetch(index: &PgRelation) -> MetaPage {
         unsafe {
             let page = page::ReadablePage::read(index, META_BLOCK_NUMBER);
             let page_type = page.get_type();
             if page_type == crate::util::page::PageType::MetaV1 {
-                let old_meta = MetaPageV1::page_get_meta(*page, *(*(page.get_buffer())));
-                let new_meta = (*old_meta).get_new_meta();
-
                 //release the page
                 std::mem::drop(page);
 
-                Self::overwrite(index, &new_meta);
-                return new_meta;
+                return Self::modify(index, |_| true);
+            }
+            let mut meta = Self::get_meta_from_page(*page);
+            std::mem::drop(page);
+            if meta.pca_projection.is_valid() {
+                meta.pca = Some(PcaProjection::stored(
+                    index,
+                    meta.pca_projection,
+                    meta.num_dimensions as usize,
+                    meta.pca_dimensions as usize,
+                ));
             }
-            Self::get_meta_from_page(page)
+            meta
         }
     }
 
-    unsafe fn get_meta_from_page(page: page::ReadablePage) -> MetaPage {
+    /// The meta stored on the meta page `page`, whose buffer the caller keeps locked.
+    unsafe fn get_meta_from_page(page: pg_sys::Page) -> MetaPage {
         //check the header. In the future, we can use this to check the version
-        let rb = page.get_item_unchecked(META_HEADER_OFFSET);
-        let meta = ReadableMetaPageHeader::with_readable_buffer(rb);
-        let archived = meta.get_archived_node();
-        assert!(archived.magic_number == TSV_MAGIC_NUMBER);
-        assert!(archived.version == TSV_VERSION);
+        let header =
+            rkyv::archived_root::<MetaPageHeader>(Self::get_item(page, META_HEADER_OFFSET));
+        assert!(header.magic_number == TSV_MAGIC_NUMBER);
+        let version = header.version;
+        assert!(version == 2 || version == TSV_VERSION);
 
-        let page = meta.get_owned_page();
+        if version == 2 {
+            let archived = rkyv::archived_root::<MetaPageV2>(Self::get_item(page, META_OFFSET));
+            assert!(archived.magic_number == TSV_MAGIC_NUMBER);
+            assert!(archived.version == 2);
+
+            let old_meta: MetaPageV2 = archived.deserialize(&mut rkyv::Infallible).unwrap();
+            return old_meta.get_new_meta();
+        }
 
         //retrieve the MetaPage itself and deserialize it
-        let rb = page.get_item_unchecked(META_OFFSET);
-        let meta = ReadableMetaPage::with_readable_buffer(rb);
-        let archived = meta.get_archived_node();
+        let archived = rkyv::archived_root::<MetaPage>(Self::get_item(page, META_OFFSET));
         assert!(archived.magic_number == TSV_MAGIC_NUMBER);
         assert!(archived.version == TSV_VERSION);
 
         archived.deserialize(&mut rkyv::Infallible).unwrap()
     }
 
+    unsafe fn get_item<'a>(page: pg_sys::Page, offset: pg_sys::OffsetNumber) -> &'a [u8] {
+        let item_id = ports::PageGetItemId(page, offset);
+        let item = ports::PageGetItem(page, item_id) as *const u8;
+        std::slice::from_raw_parts(item, (*item_id).lp_len() as usize)
+    }
+
+    /// Changes the meta page of an index. `change` is applied to the meta stored on the page
+    /// while its buffer is locked exclusively, so that concurrent changes are not lost, and
+    /// returns false if it leaves the meta as it is. Returns the meta after the change.
+    unsafe fn modify<F: FnOnce(&mut MetaPage) -> bool>(index: &PgRelation, change: F) -> MetaPage {
+        let mut page = page::WritablePage::modify(index, META_BLOCK_NUMBER);
+        let mut meta = match page.get_type() {
+            crate::util::page::PageType::MetaV1 => {
+                (*MetaPageV1::page_get_meta(*page, **page.get_buffer())).get_new_meta()
+            }
+            crate::util::page::PageType::Meta => Self::get_meta_from_page(*page),
+            page_type => pgrx::error!(
+                "Problem reading meta page: wrong page type: {:?}",
+                page_type
+            ),
+        };
+        if !change(&mut meta) {
+            return meta;
+        }
+        page.reinit(crate::util::page::PageType::Meta);
+        meta.write_to_page(page);
+        meta
+    }
+
     /// Change the init ids for an index.
     pub fn update_init_ids<S: StatsNodeModify>(
         index: &PgRelation,
         init_ids: Vec<IndexPointer>,
         stats: &mut S,
     ) {
-        assert_eq!(init_ids.len(), 1); //change this if we support multiple
-        let id = init_ids[0];
+        assert!(!init_ids.is_empty());
+
+        unsafe {
+            Self::modify(index, |meta| {
+                meta.init_ids = init_ids;
+                true
+            });
+            stats.record_modify();
+        };
+    }
 
-        let mut meta = Self::fetch(index);
-        meta.init_ids = id;
+    /// Sets the entry point of an empty graph, unless a concurrent insert set one first.
+    pub fn set_first_init_id<S: StatsNodeModify>(
+        index: &PgRelation,
+        init_id: IndexPointer,
+        stats: &mut S,
+    ) {
+        unsafe {
+            Self::modify(index, |meta| {
+                if !meta.init_ids.is_empty() {
+                    return false;
+                }
+                meta.init_ids = vec![init_id];
+                true
+            });
+            stats.record_modify();
+        };
+    }
 
+    /// Sets the entry point of the subgraph of an empty bucket, unless a concurrent insert set
+    /// one first.
+    pub fn update_bucket_init_id<S: StatsNodeModify>(
+        index: &PgRelation,
+        bucket: u32,
+        init_id: IndexPointer,
+        stats: &mut S,
+    ) {
         unsafe {
-            Self::overwrite(index, &meta);
+            Self::modify(index, |meta| {
+                if meta.bucket_init_ids[bucket as usize].is_valid() {
+                    return false;
+                }
+                meta.bucket_init_ids[bucket as usize] = init_id;
+                true
+            });
+            stats.record_modify();
+        };
+    }
+
+    /// Extends the range of the time buckets that have nodes to `bucket`.
+    pub fn extend_time_bucket_range<S: StatsNodeModify>(
+        index: &PgRelation,
+        bucket: i64,
+        stats: &mut S,
+    ) {
+        unsafe {
+            Self::modify(index, |meta| {
+                if (meta.first_time_bucket..=meta.last_time_bucket).contains(&bucket) {
+                    return false;
+                }
+                meta.first_time_bucket = meta.first_time_bucket.min(bucket);
+                meta.last_time_bucket = meta.last_time_bucket.max(bucket);
+                true
+            });
+            stats.record_modify();
+        };
+    }
+
+    /// Points the entry points of the graph, and of the subgraphs of the buckets, to the new
+    /// locations of their nodes.
+    pub fn relocate_init_ids<S: StatsNodeModify, F: Fn(IndexPointer) -> IndexPointer>(
+        index: &PgRelation,
+        relocate: F,
+        stats: &mut S,
+    ) {
+        unsafe {
+            Self::modify(index, |meta| {
+                meta.init_ids = meta.init_ids.iter().map(|&ip| relocate(ip)).collect();
+                for init_id in meta.bucket_init_ids.iter_mut() {
+                    if init_id.is_valid() {
+                        *init_id = relocate(*init_id);
+                    }
+                }
+                true
+            });
+            stats.record_modify();
+        };
+    }
+
+    /// Stores the PCA projection learned during build.
+    pub fn update_pca_projection<S: StatsNodeWrite + StatsNodeModify>(
+        index: &PgRelation,
+        projection: PcaProjection,
+        stats: &mut S,
+    ) -> MetaPage {
+        let pca_projection = unsafe { projection.store(index, stats) };
+        let mut meta = unsafe {
+            Self::modify(index, |meta| {
+                assert!(meta.uses_pca());
+                meta.pca_projection = pca_projection;
+                true
+            })
+        };
+        stats.record_modify();
+        meta.pca = Some(projection);
+        meta
+    }
+
+    /// Changes the default query parameters of an index. None unsets a default.
+    pub fn update_query_defaults<S: StatsNodeModify>(
+        index: &PgRelation,
+        search_list_size: Option<u32>,
+        rescore: Option<u32>,
+        stats: &mut S,
+    ) {
+        unsafe {
+            Self::modify(index, |meta| {
+                meta.query_search_list_size =
+                    search_list_size.unwrap_or(QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL);
+                meta.query_rescore = rescore.map_or(QUERY_RESCORE_DEFAULT_SENTINEL, |r| r as i32);
+                true
+            });
             stats.record_modify();
         };
     }
@@ -394,11 +957,11 @@ impl MetaPage {
         quantizer_pointer: IndexPointer,
         stats: &mut S,
     ) {
-        let mut meta = Self::fetch(index);
-        meta.quantizer_metadata = quantizer_pointer;
-
         unsafe {
-            Self::overwrite(index, &meta);
+            Self::modify(index, |meta| {
+                meta.quantizer_metadata = quantizer_pointer;
+                true
+            });
             stats.record_modify();
         };
     }
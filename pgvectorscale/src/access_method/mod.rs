@@ -1,28 +1,51 @@
 use pgrx::*;
+pub mod activity;
+mod alter_index;
+mod benchmark;
 mod build;
+mod build_checkpoint;
 mod cost_estimate;
+mod custom_distance;
 mod debugging;
+mod entry_points;
+mod full_vectors;
 mod graph;
+mod graph_import;
 mod graph_neighbor_store;
+mod hybrid_search;
+mod index_export;
+mod introspection;
+mod knn_join;
 pub mod guc;
 mod meta_page;
+mod neighbor_compression;
 mod neighbor_with_distance;
+mod node_placement;
 pub mod options;
+mod payload;
+mod pca;
 pub mod pg_vector;
 mod plain_node;
 mod plain_storage;
+mod query_hints;
+mod recall;
+mod recency;
+pub mod result_cache;
 mod scan;
+mod search;
+mod size_estimate;
 pub mod stats;
 mod storage;
 mod storage_common;
+mod tenant;
+mod time_buckets;
+mod tune;
 mod upgrade_test;
 mod vacuum;
 
 extern crate blas_src;
 
 pub mod distance;
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-mod distance_x86;
 mod sbq;
 
 #[pg_extern(sql = "
@@ -48,14 +71,14 @@ fn amhandler(_fcinfo: pg_sys::FunctionCallInfo) -> PgBox<pg_sys::IndexAmRoutine>
         unsafe { PgBox::<pg_sys::IndexAmRoutine>::alloc_node(pg_sys::NodeTag::T_IndexAmRoutine) };
 
     amroutine.amstrategies = 0;
-    amroutine.amsupport = 0; //TODO
+    amroutine.amsupport = 1; /* optional custom distance, see custom_distance.rs */
     amroutine.amoptsprocnum = 0;
 
     amroutine.amcanorder = false;
     amroutine.amcanorderbyop = true;
     amroutine.amcanbackward = false; /* can change direction mid-scan */
     amroutine.amcanunique = false;
-    amroutine.amcanmulticol = false;
+    amroutine.amcanmulticol = true; /* columns after the vector are filter columns */
     amroutine.amoptionalkey = true;
     amroutine.amsearcharray = false;
     amroutine.amsearchnulls = false;
@@ -112,6 +135,179 @@ $$;
     name = "diskann_ops_operator"
 );
 
+// Operator classes for pgvector's halfvec and sparsevec types, which only exist in pgvector 0.7.0 and later.
+// Like the SQL above, this is idempotent.
+extension_sql!(
+    r#"
+DO $$
+DECLARE
+  t text;
+  c int;
+BEGIN
+    FOREACH t IN ARRAY ARRAY['halfvec', 'sparsevec'] LOOP
+        IF to_regtype(t) IS NULL THEN
+            CONTINUE;
+        END IF;
+
+        SELECT count(*)
+        INTO c
+        FROM pg_catalog.pg_opclass c
+        WHERE c.opcname = t || '_cosine_ops'
+        AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+        IF c = 0 THEN
+            EXECUTE format(
+                'CREATE OPERATOR CLASS %I DEFAULT
+                FOR TYPE %s USING diskann AS
+                    OPERATOR 1 <=> (%s, %s) FOR ORDER BY float_ops',
+                t || '_cosine_ops', t, t, t);
+        END IF;
+    END LOOP;
+END;
+$$;
+"#,
+    name = "diskann_pgvector_types_operator",
+    requires = ["diskann_ops_operator"]
+);
+
+// Operator class for float4[] columns. Like the SQL above, this is idempotent.
+extension_sql!(
+    r#"
+DO $$
+DECLARE
+  c int;
+BEGIN
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_operator o
+    WHERE o.oprname = '<=>'
+    AND o.oprleft = 'real[]'::regtype
+    AND o.oprright = 'real[]'::regtype;
+
+    IF c = 0 THEN
+        CREATE OPERATOR <=> (
+            LEFTARG = real[],
+            RIGHTARG = real[],
+            FUNCTION = diskann_float4_array_cosine_distance,
+            COMMUTATOR = '<=>'
+        );
+    END IF;
+
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_opclass c
+    WHERE c.opcname = 'float4_array_cosine_ops'
+    AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+    IF c = 0 THEN
+        CREATE OPERATOR CLASS float4_array_cosine_ops DEFAULT
+        FOR TYPE real[] USING diskann AS
+	        OPERATOR 1 <=> (real[], real[]) FOR ORDER BY float_ops;
+    END IF;
+END;
+$$;
+"#,
+    name = "diskann_float4_array_operator",
+    requires = [
+        "diskann_ops_operator",
+        distance::diskann_float4_array_cosine_distance
+    ]
+);
+
+// The weighted cosine distance of the indexes with the weights option. The operator takes the
+// weights along with the query, so that it orders the rows like such an index does, while <=>
+// keeps the unweighted distance. Like the SQL above, this is idempotent.
+extension_sql!(
+    r#"
+DO $$
+BEGIN
+    IF to_regtype('diskann_weighted_query') IS NULL THEN
+        CREATE TYPE diskann_weighted_query AS (query vector, weights real[]);
+    END IF;
+END;
+$$;
+"#,
+    name = "diskann_weighted_query_type",
+    requires = ["diskann_ops_operator"]
+);
+
+extension_sql!(
+    r#"
+DO $$
+DECLARE
+  c int;
+BEGIN
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_operator o
+    WHERE o.oprname = '<*>'
+    AND o.oprleft = 'vector'::regtype
+    AND o.oprright = 'diskann_weighted_query'::regtype;
+
+    IF c = 0 THEN
+        CREATE OPERATOR <*> (
+            LEFTARG = vector,
+            RIGHTARG = diskann_weighted_query,
+            FUNCTION = diskann_weighted_cosine_distance
+        );
+    END IF;
+
+    SELECT count(*)
+    INTO c
+    FROM pg_catalog.pg_opclass c
+    WHERE c.opcname = 'vector_weighted_cosine_ops'
+    AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+    IF c = 0 THEN
+        CREATE OPERATOR CLASS vector_weighted_cosine_ops
+        FOR TYPE vector USING diskann AS
+	        OPERATOR 1 <*> (vector, diskann_weighted_query) FOR ORDER BY float_ops;
+    END IF;
+END;
+$$;
+"#,
+    name = "diskann_weighted_operator",
+    requires = [
+        "diskann_weighted_query_type",
+        distance::diskann_weighted_cosine_distance
+    ]
+);
+
+// Operator classes for the filter columns that can follow the vector column of an index.
+// Like the SQL above, this is idempotent.
+extension_sql!(
+    r#"
+DO $$
+DECLARE
+  t text;
+  c int;
+BEGIN
+    FOREACH t IN ARRAY ARRAY['int2', 'int4', 'int8', 'float4', 'float8', 'bool', 'date', 'timestamp', 'timestamptz'] LOOP
+        SELECT count(*)
+        INTO c
+        FROM pg_catalog.pg_opclass c
+        WHERE c.opcname = t || '_filter_ops'
+        AND c.opcmethod = (SELECT oid FROM pg_catalog.pg_am am  WHERE am.amname = 'diskann');
+
+        IF c = 0 THEN
+            EXECUTE format(
+                'CREATE OPERATOR CLASS %I DEFAULT
+                FOR TYPE %s USING diskann AS
+                    OPERATOR 1 <,
+                    OPERATOR 2 <=,
+                    OPERATOR 3 =,
+                    OPERATOR 4 >=,
+                    OPERATOR 5 >',
+                t || '_filter_ops', t);
+        END IF;
+    END LOOP;
+END;
+$$;
+"#,
+    name = "diskann_filter_ops_operator",
+    requires = ["diskann_ops_operator"]
+);
+
 #[pg_guard]
 pub extern "C" fn amvalidate(_opclassoid: pg_sys::Oid) -> bool {
     true
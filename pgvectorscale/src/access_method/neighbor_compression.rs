@@ -0,0 +1,367 @@
+//! Neighbor lists stored in front of the archived nodes of SBQ indexes.
+//!
+//! Nodes never change size, so the neighbor list of a node is stored in a block of a fixed size
+//! in front of the archived node, after the filter payload, and the archived node has no room
+//! for neighbors. The block starts with the number of neighbors, and an all-zero block is an
+//! empty list. Indexes built before neighbor blocks, and plain indexes, store the neighbors as
+//! ItemPointers in the archived node, 8 bytes each.
+//!
+//! By default every neighbor is a packed 6-byte TID: the block number as a u32 followed by the
+//! offset as a u16, both little-endian, after the number of neighbors as a u16.
+//!
+//! With the `compress_neighbors` option the neighbors are sorted by their position in the
+//! index, and every neighbor is stored as the difference to the block number of the previous
+//! one, followed by its offset, or by the difference to the offset of the previous one if both
+//! are on the same block. All numbers are varints, preceded by the number of neighbors.
+//! Neighbors are usually spread over few blocks, so most of them take 2 to 4 bytes. The block
+//! is sized for `COMPRESSED_BYTES_PER_NEIGHBOR` bytes per neighbor; a list that doesn't fit
+//! keeps only its closest neighbors.
+
+use pgrx::PgRelation;
+
+use crate::util::ItemPointer;
+
+use super::{
+    meta_page::MetaPage,
+    neighbor_with_distance::NeighborWithDistance,
+    payload::{get_num_payload_columns, get_payload_size},
+};
+
+/// The size of a neighbor stored as a packed TID.
+pub const PACKED_BYTES_PER_NEIGHBOR: usize = 6;
+
+/// The space reserved for every neighbor in the block of a node with `compress_neighbors`.
+pub const COMPRESSED_BYTES_PER_NEIGHBOR: usize = 4;
+
+/// How the neighbors of the nodes of an index are stored.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NeighborEncoding {
+    /// ItemPointers in the archived node
+    Inline = 0,
+    /// packed TIDs in the neighbor block
+    Packed = 1,
+    /// delta- and varint-encoded TIDs in the neighbor block
+    Varint = 2,
+}
+
+impl NeighborEncoding {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => NeighborEncoding::Inline,
+            1 => NeighborEncoding::Packed,
+            2 => NeighborEncoding::Varint,
+            _ => panic!("Invalid neighbor encoding {}", value),
+        }
+    }
+
+    /// The size of the block of a node with room for `num_neighbors` neighbors, 0 if the
+    /// neighbors are stored in the archived node. A multiple of 8 to keep the archived node
+    /// that follows it aligned.
+    pub fn get_block_size(&self, num_neighbors: usize) -> usize {
+        /* the number of neighbors takes at most 2 bytes */
+        let size = match self {
+            NeighborEncoding::Inline => return 0,
+            NeighborEncoding::Packed => 2 + num_neighbors * PACKED_BYTES_PER_NEIGHBOR,
+            NeighborEncoding::Varint => 2 + num_neighbors * COMPRESSED_BYTES_PER_NEIGHBOR,
+        };
+        (size + 7) / 8 * 8
+    }
+
+    fn encode(&self, neighbors: &[ItemPointer]) -> Vec<u8> {
+        match self {
+            NeighborEncoding::Inline => panic!("inline neighbors are not encoded"),
+            NeighborEncoding::Packed => encode_packed(neighbors),
+            NeighborEncoding::Varint => encode_varint(neighbors),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Vec<ItemPointer> {
+        match self {
+            NeighborEncoding::Inline => panic!("inline neighbors are not encoded"),
+            NeighborEncoding::Packed => decode_packed(bytes),
+            NeighborEncoding::Varint => decode_varint(bytes),
+        }
+    }
+}
+
+fn encode_packed(neighbors: &[ItemPointer]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(2 + neighbors.len() * PACKED_BYTES_PER_NEIGHBOR);
+    out.extend_from_slice(&(neighbors.len() as u16).to_le_bytes());
+    for neighbor in neighbors {
+        out.extend_from_slice(&neighbor.block_number.to_le_bytes());
+        out.extend_from_slice(&neighbor.offset.to_le_bytes());
+    }
+    out
+}
+
+fn decode_packed(bytes: &[u8]) -> Vec<ItemPointer> {
+    let num_neighbors = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+    bytes[2..2 + num_neighbors * PACKED_BYTES_PER_NEIGHBOR]
+        .chunks_exact(PACKED_BYTES_PER_NEIGHBOR)
+        .map(|tid| {
+            ItemPointer::new(
+                u32::from_le_bytes([tid[0], tid[1], tid[2], tid[3]]),
+                u16::from_le_bytes([tid[4], tid[5]]),
+            )
+        })
+        .collect()
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> u32 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Encodes neighbors sorted by block number and offset.
+fn encode_varint(neighbors: &[ItemPointer]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(2 + neighbors.len() * COMPRESSED_BYTES_PER_NEIGHBOR);
+    write_varint(&mut out, neighbors.len() as u32);
+    let mut previous = ItemPointer::new(0, 0);
+    for neighbor in neighbors {
+        let block_delta = neighbor.block_number - previous.block_number;
+        write_varint(&mut out, block_delta);
+        if block_delta == 0 {
+            write_varint(&mut out, (neighbor.offset - previous.offset) as u32);
+        } else {
+            write_varint(&mut out, neighbor.offset as u32);
+        }
+        previous = *neighbor;
+    }
+    out
+}
+
+fn decode_varint(bytes: &[u8]) -> Vec<ItemPointer> {
+    let mut pos = 0;
+    let num_neighbors = read_varint(bytes, &mut pos) as usize;
+    let mut neighbors = Vec::with_capacity(num_neighbors);
+    let mut previous = ItemPointer::new(0, 0);
+    for _ in 0..num_neighbors {
+        let block_delta = read_varint(bytes, &mut pos);
+        let offset = read_varint(bytes, &mut pos) as u16;
+        let neighbor = if block_delta == 0 {
+            ItemPointer::new(previous.block_number, previous.offset + offset)
+        } else {
+            ItemPointer::new(previous.block_number + block_delta, offset)
+        };
+        neighbors.push(neighbor);
+        previous = neighbor;
+    }
+    neighbors
+}
+
+/// Where and how the neighbor list is stored in the items of the nodes of an index.
+#[derive(Clone, Copy, Debug)]
+pub struct NeighborBlock {
+    encoding: NeighborEncoding,
+    offset: usize,
+    size: usize,
+}
+
+impl NeighborBlock {
+    /// The block of the nodes of `index`, None if their neighbors are stored in the archived
+    /// nodes.
+    pub fn for_index(index: &PgRelation, meta_page: &MetaPage) -> Option<Self> {
+        let encoding = meta_page.get_neighbor_encoding();
+        if encoding == NeighborEncoding::Inline {
+            return None;
+        }
+        Some(Self {
+            encoding,
+            offset: get_payload_size(get_num_payload_columns(index)),
+            size: encoding.get_block_size(meta_page.get_num_neighbors() as usize),
+        })
+    }
+
+    /// The block of a new node, without neighbors.
+    pub fn empty(&self) -> Vec<u8> {
+        vec![0; self.size]
+    }
+
+    /// The neighbors stored in `item`, the index item of a node.
+    pub fn read(&self, item: &[u8]) -> Vec<ItemPointer> {
+        self.encoding
+            .decode(&item[self.offset..self.offset + self.size])
+    }
+
+    /// Stores as many of the closest `neighbors` as fit in `item`, the index item of a node,
+    /// and returns them in the order they are stored in.
+    pub fn write(
+        &self,
+        item: &mut [u8],
+        neighbors: &[NeighborWithDistance],
+    ) -> Vec<NeighborWithDistance> {
+        let mut by_distance = neighbors.to_vec();
+        by_distance.sort();
+
+        let mut num_kept = by_distance.len();
+        loop {
+            let mut kept = by_distance[..num_kept].to_vec();
+            if self.encoding == NeighborEncoding::Varint {
+                kept.sort_by_key(|n| {
+                    let index_pointer = n.get_index_pointer_to_neighbor();
+                    (index_pointer.block_number, index_pointer.offset)
+                });
+            }
+            let index_pointers: Vec<_> = kept
+                .iter()
+                .map(|n| n.get_index_pointer_to_neighbor())
+                .collect();
+            let bytes = self.encoding.encode(&index_pointers);
+            if bytes.len() <= self.size {
+                let block = &mut item[self.offset..self.offset + self.size];
+                block[..bytes.len()].copy_from_slice(&bytes);
+                block[bytes.len()..].fill(0);
+                return kept;
+            }
+            num_kept -= 1;
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use crate::util::ItemPointer;
+
+    use super::{decode_varint, encode_varint, NeighborEncoding};
+
+    #[pg_test]
+    fn test_encode_neighbors() {
+        let neighbors = vec![
+            ItemPointer::new(1, 3),
+            ItemPointer::new(1, 7),
+            ItemPointer::new(2, 1),
+            ItemPointer::new(300, 2),
+            ItemPointer::new(300_000, 65),
+        ];
+        let bytes = encode_varint(&neighbors);
+        /* the count, 2 bytes per neighbor except 3 for block 300 and 4 for block 300000 */
+        assert_eq!(bytes.len(), 1 + 2 + 2 + 2 + 3 + 4);
+        assert_eq!(decode_varint(&bytes), neighbors);
+        assert_eq!(decode_varint(&[0; 8]), vec![]);
+
+        let bytes = NeighborEncoding::Packed.encode(&neighbors);
+        assert_eq!(bytes.len(), 2 + 5 * 6);
+        assert_eq!(NeighborEncoding::Packed.decode(&bytes), neighbors);
+        assert_eq!(NeighborEncoding::Packed.decode(&[0; 8]), vec![]);
+        assert_eq!(NeighborEncoding::Packed.get_block_size(50), 304);
+        assert_eq!(NeighborEncoding::Inline.get_block_size(50), 0);
+    }
+
+    #[pg_test]
+    unsafe fn test_packed_neighbors() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(64));
+
+            INSERT INTO test(embedding)
+            SELECT v::vector FROM diskann_generate_vectors(300, 64, seed => 1) v;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (storage_layout = memory_optimized, num_neighbors = 50);
+
+            INSERT INTO test(embedding)
+            SELECT v::vector FROM diskann_generate_vectors(50, 64, seed => 2) v;",
+        )?;
+
+        /* the nodes are as large as estimated, with 6 bytes per neighbor */
+        let (item_bytes, node_size) = Spi::get_two::<f64, i64>(
+            "SELECT (SELECT avg_item_bytes FROM diskann_page_stats('idxtest'::regclass)
+                    WHERE page_type = 'sbq_node'),
+                (SELECT node_size_bytes FROM diskann_estimate_size(300, 64, num_neighbors => 50))",
+        )?;
+        assert_eq!(item_bytes.unwrap() as i64, node_size.unwrap());
+
+        let (num_nodes, max_out_degree, num_reachable_nodes) = Spi::get_three::<i64, i32, i64>(
+            "SELECT num_nodes, max_out_degree, num_reachable_nodes
+            FROM diskann_graph_stats('idxtest'::regclass, sample_size => 0)",
+        )?;
+        assert_eq!(num_nodes, Some(350));
+        assert!(max_out_degree.unwrap() <= 50);
+        assert!(num_reachable_nodes.unwrap() > 300);
+
+        Spi::run("SET enable_seqscan = 0")?;
+        let num_results = Spi::get_one::<i64>(
+            "SELECT count(*) FROM (SELECT * FROM test
+                ORDER BY embedding <=> (SELECT embedding FROM test LIMIT 1) LIMIT 10) r",
+        )?;
+        assert_eq!(num_results, Some(10));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_compress_neighbors() -> spi::Result<()> {
+        for storage_layout in ["memory_optimized", "io_optimized"] {
+            Spi::run(&format!(
+                "CREATE TABLE test(embedding vector(64));
+
+                INSERT INTO test(embedding)
+                SELECT v::vector FROM diskann_generate_vectors(1000, 64, seed => 1) v;
+
+                CREATE INDEX idxtest ON test USING diskann(embedding)
+                    WITH (storage_layout = {storage_layout}, compress_neighbors = true);
+
+                CREATE INDEX idxtest_uncompressed ON test USING diskann(embedding)
+                    WITH (storage_layout = {storage_layout});
+
+                INSERT INTO test(embedding)
+                SELECT v::vector FROM diskann_generate_vectors(100, 64, seed => 2) v;",
+            ))?;
+
+            let (compressed_pages, uncompressed_pages) = Spi::get_two::<i64, i64>(
+                "SELECT (SELECT num_pages FROM diskann_index_info('idxtest'::regclass)),
+                    (SELECT num_pages FROM diskann_index_info('idxtest_uncompressed'::regclass))",
+            )?;
+            let (compressed_pages, uncompressed_pages) =
+                (compressed_pages.unwrap(), uncompressed_pages.unwrap());
+            if storage_layout == "memory_optimized" {
+                assert!(compressed_pages < uncompressed_pages);
+            } else {
+                /* io_optimized nodes fill a page either way */
+                assert!(compressed_pages <= uncompressed_pages);
+            }
+
+            let (num_nodes, num_reachable_nodes) = Spi::get_two::<i64, i64>(
+                "SELECT num_nodes, num_reachable_nodes
+                FROM diskann_graph_stats('idxtest'::regclass, sample_size => 0)",
+            )?;
+            assert_eq!(num_nodes, Some(1100), "{}", storage_layout);
+            assert!(num_reachable_nodes.unwrap() > 1000, "{}", storage_layout);
+
+            /* keeping only the closest neighbors that fit barely changes the graph */
+            let (recall, uncompressed_recall) = Spi::get_two::<f64, f64>(
+                "SELECT diskann_estimate_recall('idxtest'::regclass, sample_queries => 20),
+                    diskann_estimate_recall('idxtest_uncompressed'::regclass, sample_queries => 20)",
+            )?;
+            let (recall, uncompressed_recall) = (recall.unwrap(), uncompressed_recall.unwrap());
+            assert!(
+                recall > uncompressed_recall - 0.05,
+                "{}: recall was {}, {} without compression",
+                storage_layout,
+                recall,
+                uncompressed_recall
+            );
+
+            Spi::run("DROP TABLE test")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,365 @@
+//! Placement of the nodes of a graph on the pages of the index.
+//!
+//! The build writes the nodes in the order of the heap scan, so the neighbors of a node are
+//! spread over the whole index. At the end of the build the nodes are moved so that they are
+//! in the order of a breadth-first traversal of the graph from its entry points: the unvisited
+//! neighbors of a node are placed next to each other, close to the node, and a search reads
+//! fewer distinct pages. The nodes keep the set of locations they occupy, so the moves don't
+//! change the size of the index or the space left by the fillfactor.
+//!
+//! `diskann_reorder_index` does the same for an existing index, whose inserted nodes are
+//! placed wherever there is free space.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use pgrx::{pg_sys, *};
+
+use crate::util::IndexPointer;
+
+use super::{
+    introspection::for_each_live_node_with_neighbors,
+    meta_page::MetaPage,
+    neighbor_with_distance::NeighborWithDistance,
+    plain_storage::PlainStorage,
+    sbq::SbqSpeedupStorage,
+    stats::{StatsNodeModify, StatsNodeRead, WriteStats},
+    storage::{Storage, StorageType},
+    storage_common::open_diskann_index_as_owner,
+};
+
+/// Returns the new location of every node, for placing the nodes in breadth-first order from
+/// `entry_points`. `locations` are the current locations of the nodes in page order; the nodes
+/// not reachable from the entry points are traversed from the first of them not yet placed.
+pub fn breadth_first_locations<F: Fn(IndexPointer) -> Vec<IndexPointer>>(
+    locations: &[IndexPointer],
+    entry_points: &[IndexPointer],
+    get_neighbors: F,
+) -> HashMap<IndexPointer, IndexPointer> {
+    let known: HashSet<IndexPointer> = locations.iter().copied().collect();
+    let mut visited: HashSet<IndexPointer> = HashSet::with_capacity(locations.len());
+    let mut order: Vec<IndexPointer> = Vec::with_capacity(locations.len());
+    let mut queue = VecDeque::new();
+
+    let starts = entry_points.iter().chain(locations.iter());
+    for &start in starts {
+        if !known.contains(&start) || !visited.insert(start) {
+            continue;
+        }
+        queue.push_back(start);
+        while let Some(index_pointer) = queue.pop_front() {
+            order.push(index_pointer);
+            for neighbor in get_neighbors(index_pointer) {
+                if known.contains(&neighbor) && visited.insert(neighbor) {
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+
+    order.into_iter().zip(locations.iter().copied()).collect()
+}
+
+/// Moves the nodes to their new locations by copying their bytes. The nodes must all have the
+/// same size and `new_locations` must be a permutation of their locations. Every node is read
+/// and written once, following the cycles of the permutation.
+pub unsafe fn move_nodes<S: StatsNodeRead + StatsNodeModify>(
+    index: &PgRelation,
+    new_locations: &HashMap<IndexPointer, IndexPointer>,
+    stats: &mut S,
+) {
+    let mut starts: Vec<IndexPointer> = new_locations
+        .iter()
+        .filter(|(from, to)| from != to)
+        .map(|(from, _)| *from)
+        .collect();
+    starts.sort();
+
+    let mut moved: HashSet<IndexPointer> = HashSet::with_capacity(starts.len());
+    for start in starts {
+        if moved.contains(&start) {
+            continue;
+        }
+        let mut data = read_node_bytes(index, start, stats);
+        let mut from = start;
+        loop {
+            moved.insert(from);
+            let to = new_locations[&from];
+            let next_data = if to == start {
+                None
+            } else {
+                Some(read_node_bytes(index, to, stats))
+            };
+
+            let buffer = to.modify_bytes(index);
+            assert_eq!(buffer.get_data_slice().len(), data.len());
+            buffer.get_data_slice().copy_from_slice(&data);
+            buffer.commit();
+            stats.record_modify();
+
+            match next_data {
+                Some(next_data) => {
+                    data = next_data;
+                    from = to;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+unsafe fn read_node_bytes<S: StatsNodeRead>(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+    stats: &mut S,
+) -> Vec<u8> {
+    let buffer = index_pointer.read_bytes(index);
+    stats.record_read();
+    buffer.get_data_slice().to_vec()
+}
+
+/// Moves the live nodes of an existing index into breadth-first order from the entry points
+/// and points their neighbor lists to the new locations. Returns the number of moved nodes.
+fn reorder_nodes<S: Storage>(
+    storage: &S,
+    index: &PgRelation,
+    meta_page: &MetaPage,
+    stats: &mut WriteStats,
+) -> usize {
+    let mut neighbors: BTreeMap<IndexPointer, Vec<IndexPointer>> = BTreeMap::new();
+    for_each_live_node_with_neighbors::<S>(index, meta_page, |index_pointer, _, node_neighbors| {
+        neighbors.insert(index_pointer, node_neighbors);
+    });
+    let locations: Vec<IndexPointer> = neighbors.keys().copied().collect();
+    let init_ids = meta_page.get_init_ids().unwrap_or_default();
+    let new_locations = breadth_first_locations(&locations, &init_ids, |index_pointer| {
+        neighbors.get(&index_pointer).cloned().unwrap_or_default()
+    });
+
+    unsafe { move_nodes(index, &new_locations, stats) };
+
+    /* deleted nodes stay where they are */
+    let relocate = |index_pointer: IndexPointer| {
+        new_locations
+            .get(&index_pointer)
+            .copied()
+            .unwrap_or(index_pointer)
+    };
+    for (&index_pointer, node_neighbors) in &neighbors {
+        check_for_interrupts!();
+        let new_index_pointer = relocate(index_pointer);
+        let new_neighbors: Vec<IndexPointer> =
+            node_neighbors.iter().map(|&n| relocate(n)).collect();
+        if new_index_pointer == index_pointer && new_neighbors == *node_neighbors {
+            continue;
+        }
+        /* the distances aren't stored; the lists keep their order */
+        let new_neighbors: Vec<NeighborWithDistance> = new_neighbors
+            .into_iter()
+            .map(|n| NeighborWithDistance::new(n, 0.0))
+            .collect();
+        storage.set_neighbors_on_disk(meta_page, new_index_pointer, &new_neighbors, stats);
+    }
+
+    if !init_ids.is_empty() {
+        MetaPage::relocate_init_ids(index, relocate, stats);
+    }
+    new_locations.iter().filter(|(from, to)| from != to).count()
+}
+
+/// Rewrites a diskann index with its nodes in breadth-first order of the graph from the entry
+/// points, so that nodes that are traversed together are on the same or adjacent pages and
+/// searches of an index that doesn't fit in memory read fewer pages. The build places the
+/// nodes this way; the nodes inserted later go wherever there is free space. Returns the
+/// number of moved nodes.
+///
+/// Blocks all reads and writes of the index while it runs. The index doesn't grow. Only the owner
+/// of the index can reorder it, and its ownership is checked before the index is locked.
+#[pg_extern(volatile)]
+pub fn diskann_reorder_index(index: pg_sys::Oid) -> i64 {
+    let index_relation =
+        open_diskann_index_as_owner(index, pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let meta_page = MetaPage::fetch(&index_relation);
+    let mut stats = WriteStats::new();
+
+    let num_moved = match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage = PlainStorage::load_for_insert(
+                &index_relation,
+                &heap_relation,
+                meta_page.get_distance_function(),
+            );
+            reorder_nodes(&storage, &index_relation, &meta_page, &mut stats)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let storage = SbqSpeedupStorage::load_for_insert(
+                &heap_relation,
+                &index_relation,
+                &meta_page,
+                &mut stats,
+            );
+            reorder_nodes(&storage, &index_relation, &meta_page, &mut stats)
+        }
+    };
+    num_moved as i64
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use crate::util::ItemPointer;
+
+    use super::breadth_first_locations;
+
+    #[pg_test]
+    fn test_breadth_first_locations() {
+        let locations: Vec<ItemPointer> = (1..=5).map(|i| ItemPointer::new(1, i)).collect();
+        /* 5 -> 2 -> 4, 5 -> 1; 3 is unreachable */
+        let neighbors = |ip: ItemPointer| match ip.offset {
+            5 => vec![ItemPointer::new(1, 2), ItemPointer::new(1, 1)],
+            2 => vec![ItemPointer::new(1, 4), ItemPointer::new(1, 5)],
+            _ => vec![],
+        };
+        let new_locations = breadth_first_locations(&locations, &[locations[4]], neighbors);
+
+        let order: Vec<u16> = [5, 2, 1, 4, 3].to_vec();
+        for (position, offset) in order.into_iter().enumerate() {
+            assert_eq!(
+                new_locations[&ItemPointer::new(1, offset)],
+                locations[position],
+                "node {}",
+                offset
+            );
+        }
+    }
+
+    #[pg_test]
+    unsafe fn test_breadth_first_placement() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized"] {
+            Spi::run(&format!(
+                "CREATE TABLE test(embedding vector(16));
+
+                INSERT INTO test(embedding)
+                SELECT v::vector FROM diskann_generate_vectors(2000, 16, num_clusters => 8, seed => 5) v;
+
+                CREATE INDEX idxtest ON test USING diskann(embedding)
+                    WITH (storage_layout = {storage_layout}, num_neighbors = 20);",
+            ))?;
+
+            /* the neighbors of a node are much closer to it than a random node */
+            let (edge_distance, random_distance) = Spi::get_two::<f64, f64>(
+                "WITH edges AS (
+                    SELECT (source_ctid::text::point)[0] AS source, (target_ctid::text::point)[0] AS target
+                    FROM diskann_graph_edges('idxtest'::regclass)
+                ), nodes AS (SELECT source AS block FROM edges)
+                SELECT (SELECT avg(abs(source - target)) FROM edges),
+                       (SELECT avg(abs(a.block - b.block)) FROM (SELECT * FROM nodes ORDER BY random() LIMIT 500) a,
+                                                               (SELECT * FROM nodes ORDER BY random() LIMIT 500) b)",
+            )?;
+            assert!(
+                edge_distance.unwrap() < random_distance.unwrap() * 0.5,
+                "{}: average block distance of edges {:?}, of random nodes {:?}",
+                storage_layout,
+                edge_distance,
+                random_distance
+            );
+
+            Spi::run("SET enable_seqscan = 0")?;
+            let cnt = Spi::get_one::<i64>(
+                "WITH cte AS (SELECT * FROM test ORDER BY embedding <=> (SELECT embedding FROM test LIMIT 1)) SELECT count(*) FROM cte",
+            )?;
+            assert_eq!(cnt, Some(2000), "{}", storage_layout);
+            Spi::run("RESET enable_seqscan; DROP TABLE test;")?;
+        }
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_reorder_index() -> spi::Result<()> {
+        for storage_layout in ["plain", "memory_optimized", "io_optimized"] {
+            Spi::run(&format!(
+                "CREATE TABLE test(id int, embedding vector(16));
+
+                INSERT INTO test(id, embedding)
+                SELECT i, v::vector FROM diskann_generate_vectors(1000, 16, num_clusters => 8, seed => 6) WITH ORDINALITY AS t(v, i);
+
+                CREATE INDEX idxtest ON test USING diskann(embedding)
+                    WITH (storage_layout = {storage_layout}, num_neighbors = 20);
+
+                INSERT INTO test(id, embedding)
+                SELECT 1000 + i, v::vector FROM diskann_generate_vectors(1000, 16, num_clusters => 8, seed => 7) WITH ORDINALITY AS t(v, i);
+
+                DELETE FROM test WHERE id % 10 = 0;",
+            ))?;
+
+            let query = "SELECT array_agg(id) FROM (SELECT id FROM test ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 1501) LIMIT 10) t";
+            Spi::run("SET enable_seqscan = 0")?;
+            let before = Spi::get_one::<Vec<i32>>(query)?;
+            let num_edges = Spi::get_one::<i64>(
+                "SELECT count(*) FROM diskann_graph_edges('idxtest'::regclass)",
+            )?;
+            let size = Spi::get_one::<i64>("SELECT pg_relation_size('idxtest')")?;
+
+            let num_moved =
+                Spi::get_one::<i64>("SELECT diskann_reorder_index('idxtest'::regclass)")?;
+            assert!(num_moved.unwrap() > 0, "{}", storage_layout);
+
+            /* the same graph at other locations */
+            assert_eq!(
+                Spi::get_one::<Vec<i32>>(query)?,
+                before,
+                "{}",
+                storage_layout
+            );
+            assert_eq!(
+                Spi::get_one::<i64>(
+                    "SELECT count(*) FROM diskann_graph_edges('idxtest'::regclass)"
+                )?,
+                num_edges,
+                "{}",
+                storage_layout
+            );
+            assert_eq!(
+                Spi::get_one::<i64>("SELECT pg_relation_size('idxtest')")?,
+                size,
+                "{}",
+                storage_layout
+            );
+            let cnt = Spi::get_one::<i64>(
+                "WITH cte AS (SELECT * FROM test ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 1501)) SELECT count(*) FROM cte",
+            )?;
+            assert_eq!(cnt, Some(1800), "{}", storage_layout);
+
+            /* the nodes are in place, the second run doesn't move them */
+            let num_moved =
+                Spi::get_one::<i64>("SELECT diskann_reorder_index('idxtest'::regclass)")?;
+            assert_eq!(num_moved, Some(0), "{}", storage_layout);
+            Spi::run("RESET enable_seqscan; DROP TABLE test;")?;
+        }
+        Ok(())
+    }
+
+    #[pg_test(error = "must be owner of index idxtest")]
+    unsafe fn test_reorder_not_owner() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test SELECT ARRAY[i, 1, 1]::vector FROM generate_series(1, 10) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            CREATE ROLE diskann_not_owner;
+            GRANT SELECT ON test TO diskann_not_owner;",
+        )?;
+        let index = Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.unwrap();
+
+        /* other users cannot lock the index out of all queries */
+        Spi::run(&format!(
+            "SET ROLE diskann_not_owner;
+            SELECT diskann_reorder_index('{}'::oid);",
+            index.as_u32()
+        ))?;
+        Ok(())
+    }
+}
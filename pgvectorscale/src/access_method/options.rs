@@ -2,7 +2,10 @@ use memoffset::*;
 use pgrx::{pg_sys::AsPgCStr, prelude::*, set_varsize, void_ptr, PgRelation};
 use std::{ffi::CStr, fmt::Debug};
 
-use super::storage::StorageType;
+use super::{
+    guc::{get_default_storage_layout, TSV_DEFAULT_NUM_NEIGHBORS},
+    storage::StorageType,
+};
 
 //DO NOT derive Clone for this struct. The storage layout string comes at the end and wouldn't be copied properly.
 #[derive(Debug, PartialEq)]
@@ -13,17 +16,43 @@ pub struct TSVIndexOptions {
     vl_len_: i32,
 
     pub storage_layout_offset: i32,
+    pub weights_offset: i32,
+    pub quantizer_source_offset: i32,
     num_neighbors: i32,
     pub search_list_size: u32,
     pub num_dimensions: u32,
     pub max_alpha: f64,
     pub bq_num_bits_per_dimension: u32,
+    pub pca_dimensions: u32,
+    pub num_entry_points: u32,
+    pub query_search_list_size: u32,
+    pub query_rescore: i32,
+    pub compress_neighbors: bool,
+    pub fillfactor: i32,
+    pub store_full_vectors: bool,
+    pub full_vectors_f16: bool,
+    pub tenant_buckets: u32,
+    pub time_buckets: u32,
+    pub time_bucket_width: i32,
+    pub array_dimensions: u32,
 }
 
 pub const NUM_NEIGHBORS_DEFAULT_SENTINEL: i32 = -1;
 pub const NUM_DIMENSIONS_DEFAULT_SENTINEL: u32 = 0;
+pub const ARRAY_DIMENSIONS_DEFAULT_SENTINEL: u32 = 0;
 pub const SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL: u32 = 0;
 const DEFAULT_MAX_ALPHA: f64 = 1.2;
+const DEFAULT_NUM_ENTRY_POINTS: u32 = 1;
+pub const QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL: u32 = 0;
+pub const QUERY_RESCORE_DEFAULT_SENTINEL: i32 = -1;
+pub const DEFAULT_FILLFACTOR: i32 = 100;
+pub const MAX_TENANT_BUCKETS: u32 = 256;
+pub const MAX_TIME_BUCKETS: u32 = 256;
+const DEFAULT_TIME_BUCKET_WIDTH: i32 = 86400;
+
+/// Options that only affect queries, so they can be changed with ALTER INDEX ... SET without
+/// rebuilding the index. All the other options are copied to the meta page during the build.
+pub const ALTERABLE_OPTIONS: [&str; 2] = ["query_search_list_size", "query_rescore"];
 
 impl TSVIndexOptions {
     //note: this should only be used when building a new index. The options aren't really versioned.
@@ -35,11 +64,25 @@ impl TSVIndexOptions {
             // use defaults
             let mut ops = unsafe { PgBox::<TSVIndexOptions>::alloc0() };
             ops.storage_layout_offset = 0;
+            ops.weights_offset = 0;
+            ops.quantizer_source_offset = 0;
             ops.num_neighbors = NUM_NEIGHBORS_DEFAULT_SENTINEL;
             ops.search_list_size = 100;
             ops.max_alpha = DEFAULT_MAX_ALPHA;
             ops.num_dimensions = NUM_DIMENSIONS_DEFAULT_SENTINEL;
             ops.bq_num_bits_per_dimension = SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL;
+            ops.pca_dimensions = 0;
+            ops.num_entry_points = DEFAULT_NUM_ENTRY_POINTS;
+            ops.query_search_list_size = QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL;
+            ops.query_rescore = QUERY_RESCORE_DEFAULT_SENTINEL;
+            ops.compress_neighbors = false;
+            ops.fillfactor = DEFAULT_FILLFACTOR;
+            ops.store_full_vectors = false;
+            ops.full_vectors_f16 = false;
+            ops.tenant_buckets = 0;
+            ops.time_buckets = 0;
+            ops.time_bucket_width = DEFAULT_TIME_BUCKET_WIDTH;
+            ops.array_dimensions = ARRAY_DIMENSIONS_DEFAULT_SENTINEL;
             unsafe {
                 set_varsize(
                     ops.as_ptr().cast(),
@@ -53,26 +96,65 @@ impl TSVIndexOptions {
     }
 
     pub fn get_num_neighbors(&self) -> i32 {
-        if self.num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
+        /* indexes created without the option take diskann.default_num_neighbors */
+        let num_neighbors = if self.num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
+            TSV_DEFAULT_NUM_NEIGHBORS.get()
+        } else {
+            self.num_neighbors
+        };
+        if num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
             //specify to use the default value here
             //we can't derive the default at this point in the code because the default is based on the number of dimensions in the vector in the io_optimized case.
             NUM_NEIGHBORS_DEFAULT_SENTINEL
         } else {
-            if self.num_neighbors < 10 {
+            if num_neighbors < 10 {
                 panic!("num_neighbors must be greater than 10, or -1 for default")
             }
-            self.num_neighbors
+            num_neighbors
         }
     }
 
+    /// The storage layout of the index, `diskann.default_storage_layout` if the option is not set.
     pub fn get_storage_type(&self) -> StorageType {
-        let s = self.get_str(self.storage_layout_offset, || {
-            super::storage::DEFAULT_STORAGE_TYPE_STR.to_owned()
-        });
+        let s = self.get_str(self.storage_layout_offset, get_default_storage_layout);
 
         StorageType::from_str(s.as_str())
     }
 
+    /// The per-dimension weights of the distance, or an empty vector if the distance is unweighted.
+    pub fn get_weights(&self) -> Vec<f32> {
+        let s = self.get_str(self.weights_offset, String::new);
+        parse_weights(s.as_str())
+    }
+
+    /// The default search list size of queries on this index, if set.
+    pub fn get_query_search_list_size(&self) -> Option<u32> {
+        if self.query_search_list_size == QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL {
+            None
+        } else {
+            Some(self.query_search_list_size)
+        }
+    }
+
+    /// The default number of rescored candidates of queries on this index, if set.
+    pub fn get_query_rescore(&self) -> Option<u32> {
+        if self.query_rescore == QUERY_RESCORE_DEFAULT_SENTINEL {
+            None
+        } else {
+            Some(self.query_rescore as u32)
+        }
+    }
+
+    /// The name of the index whose SBQ quantizer is reused instead of training a new one.
+    pub fn get_quantizer_source(&self) -> Option<String> {
+        let s = self.get_str(self.quantizer_source_offset, String::new);
+        if s.is_empty() {
+            None
+        } else {
+            Some(s)
+        }
+    }
+
     fn get_str<F: FnOnce() -> String>(&self, offset: i32, default: F) -> String {
         if offset == 0 {
             default()
@@ -86,7 +168,7 @@ impl TSVIndexOptions {
     }
 }
 
-const NUM_REL_OPTS: usize = 6;
+const NUM_REL_OPTS: usize = 20;
 static mut RELOPT_KIND_TSV: pg_sys::relopt_kind = 0;
 
 // amoptions is a function that gets a datum of text[] data from pg_class.reloptions (which contains text in the format "key=value") and returns a bytea for the struct for the parsed options.
@@ -111,6 +193,16 @@ pub unsafe extern "C" fn amoptions(
             opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
             offset: offset_of!(TSVIndexOptions, storage_layout_offset) as i32,
         },
+        pg_sys::relopt_parse_elt {
+            optname: "weights".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(TSVIndexOptions, weights_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "quantizer_source".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(TSVIndexOptions, quantizer_source_offset) as i32,
+        },
         pg_sys::relopt_parse_elt {
             optname: "num_neighbors".as_pg_cstr(),
             opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
@@ -136,6 +228,66 @@ pub unsafe extern "C" fn amoptions(
             opttype: pg_sys::relopt_type_RELOPT_TYPE_REAL,
             offset: offset_of!(TSVIndexOptions, max_alpha) as i32,
         },
+        pg_sys::relopt_parse_elt {
+            optname: "pca_dimensions".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, pca_dimensions) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "num_entry_points".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, num_entry_points) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "query_search_list_size".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, query_search_list_size) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "query_rescore".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, query_rescore) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "compress_neighbors".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, compress_neighbors) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "fillfactor".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, fillfactor) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "store_full_vectors".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, store_full_vectors) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "full_vectors_f16".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(TSVIndexOptions, full_vectors_f16) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "tenant_buckets".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, tenant_buckets) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "time_buckets".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, time_buckets) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "time_bucket_width".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, time_bucket_width) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "array_dimensions".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(TSVIndexOptions, array_dimensions) as i32,
+        },
     ];
 
     build_relopts(reloptions, validate, tab)
@@ -175,6 +327,40 @@ extern "C" fn validate_storage_layout(value: *const std::os::raw::c_char) {
     _ = StorageType::from_str(value);
 }
 
+/// Parses a list of weights such as '[1, 0.5, 2]'. An empty string means no weights.
+fn parse_weights(value: &str) -> Vec<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(value);
+    if value.trim().is_empty() {
+        return Vec::new();
+    }
+    value
+        .split(',')
+        .map(|w| match w.trim().parse::<f32>() {
+            Ok(w) if w.is_finite() && w >= 0.0 => w,
+            _ => panic!(
+                "Invalid weight '{}'. Weights must be non-negative numbers, e.g. '[1, 0.5, 2]'",
+                w.trim()
+            ),
+        })
+        .collect()
+}
+
+#[pg_guard]
+extern "C" fn validate_weights(value: *const std::os::raw::c_char) {
+    if value.is_null() {
+        return;
+    }
+
+    let value = unsafe { CStr::from_ptr(value) }
+        .to_str()
+        .expect("failed to parse weights value");
+    _ = parse_weights(value);
+}
+
 pub unsafe fn init() {
     RELOPT_KIND_TSV = pg_sys::add_reloption_kind();
 
@@ -182,11 +368,31 @@ pub unsafe fn init() {
         RELOPT_KIND_TSV,
         "storage_layout".as_pg_cstr(),
         "Storage layout: either memory_optimized, io_optimized, or plain".as_pg_cstr(),
-        super::storage::DEFAULT_STORAGE_TYPE_STR.as_pg_cstr(),
+        /* no default, so that indexes created without the option take the GUC default */
+        std::ptr::null(),
         Some(validate_storage_layout),
         pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
     );
 
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_TSV,
+        "weights".as_pg_cstr(),
+        "Per-dimension weights of the distance, e.g. '[1, 0.5, 2]'".as_pg_cstr(),
+        std::ptr::null(),
+        Some(validate_weights),
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_TSV,
+        "quantizer_source".as_pg_cstr(),
+        "An existing diskann index whose SBQ quantizer is reused instead of training one"
+            .as_pg_cstr(),
+        std::ptr::null(),
+        None,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
     pg_sys::add_int_reloption(
         RELOPT_KIND_TSV,
         "num_neighbors".as_pg_cstr(),
@@ -236,6 +442,131 @@ pub unsafe fn init() {
         32,
         pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
     );
+
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "pca_dimensions".as_pg_cstr(),
+        "The number of principal components to project the vectors on (0 to disable PCA)"
+            .as_pg_cstr(),
+        0,
+        0,
+        5000,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "num_entry_points".as_pg_cstr(),
+        "The number of entry points of the graph search, chosen by clustering the data"
+            .as_pg_cstr(),
+        DEFAULT_NUM_ENTRY_POINTS as _,
+        1,
+        64,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "compress_neighbors".as_pg_cstr(),
+        "Store the neighbor lists delta and varint encoded to make the nodes smaller".as_pg_cstr(),
+        false,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "fillfactor".as_pg_cstr(),
+        "Packs the node pages of the index to this percentage during the build".as_pg_cstr(),
+        DEFAULT_FILLFACTOR,
+        10,
+        100,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "store_full_vectors".as_pg_cstr(),
+        "Store a copy of the full vectors in the index so that rescoring does not read the heap"
+            .as_pg_cstr(),
+        false,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_TSV,
+        "full_vectors_f16".as_pg_cstr(),
+        "Store the full vectors as f16 to halve their size".as_pg_cstr(),
+        false,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "tenant_buckets".as_pg_cstr(),
+        "The number of separate subgraphs the rows are spread over by the first filter column (0 for one graph)"
+            .as_pg_cstr(),
+        0,
+        0,
+        MAX_TENANT_BUCKETS as _,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "time_buckets".as_pg_cstr(),
+        "The number of separate subgraphs the rows are spread over by their timestamp filter column (0 for one graph)"
+            .as_pg_cstr(),
+        0,
+        0,
+        MAX_TIME_BUCKETS as _,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "time_bucket_width".as_pg_cstr(),
+        "The width of the time buckets, in seconds".as_pg_cstr(),
+        DEFAULT_TIME_BUCKET_WIDTH,
+        1,
+        i32::MAX,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "array_dimensions".as_pg_cstr(),
+        "The number of elements of the arrays of a real[] column (0 to take it from the first row)"
+            .as_pg_cstr(),
+        ARRAY_DIMENSIONS_DEFAULT_SENTINEL as _,
+        0,
+        5000,
+        pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    /* the query options can be changed without blocking queries */
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "query_search_list_size".as_pg_cstr(),
+        "The default search list size of queries on this index (0 to use diskann.query_search_list_size)"
+            .as_pg_cstr(),
+        QUERY_SEARCH_LIST_SIZE_DEFAULT_SENTINEL as _,
+        0,
+        10000,
+        pg_sys::ShareUpdateExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_TSV,
+        "query_rescore".as_pg_cstr(),
+        "The default number of elements rescored by queries on this index (-1 to use diskann.query_rescore)"
+            .as_pg_cstr(),
+        QUERY_RESCORE_DEFAULT_SENTINEL,
+        -1,
+        1000,
+        pg_sys::ShareUpdateExclusiveLock as pg_sys::LOCKMODE,
+    );
+
+    super::alter_index::init();
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -389,4 +720,118 @@ mod tests {
         assert_eq!(options.bq_num_bits_per_dimension, 5);
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_index_options_guc_defaults() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(encoding vector(3));
+            SET diskann.default_num_neighbors = 30;
+            SET diskann.default_storage_layout = 'plain';
+            CREATE INDEX idxtest ON test USING diskann(encoding);
+            CREATE INDEX idxtest_options ON test USING diskann(encoding)
+                WITH (num_neighbors = 40, storage_layout = io_optimized);",
+        )?;
+
+        let options_of = |name: &str| -> spi::Result<PgBox<TSVIndexOptions>> {
+            let index_oid =
+                Spi::get_one::<pg_sys::Oid>(&format!("SELECT '{name}'::regclass::oid"))?
+                    .expect("oid was null");
+            let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+            Ok(TSVIndexOptions::from_relation(&indexrel))
+        };
+
+        /* the GUCs apply to the options that are not set */
+        let options = options_of("idxtest")?;
+        assert_eq!(options.get_num_neighbors(), 30);
+        assert_eq!(options.get_storage_type(), StorageType::Plain);
+
+        let options = options_of("idxtest_options")?;
+        assert_eq!(options.get_num_neighbors(), 40);
+        assert_eq!(options.get_storage_type(), StorageType::SbqSpeedup);
+
+        /* the index keeps the options it was built with */
+        let (num_neighbors, storage_layout) = Spi::get_two::<i32, String>(
+            "SELECT num_neighbors, storage_layout FROM diskann_index_info('idxtest'::regclass)",
+        )?;
+        assert_eq!(num_neighbors, Some(30));
+        assert_eq!(storage_layout.as_deref(), Some("plain"));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_index_options_weights() -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(encoding vector(3));
+        CREATE INDEX idxtest
+                  ON test
+               USING diskann(encoding vector_weighted_cosine_ops)
+               WITH (weights = '[1, 0.5, 2]');",
+        ))?;
+
+        let index_oid =
+            Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.expect("oid was null");
+        let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+        let options = TSVIndexOptions::from_relation(&indexrel);
+        assert_eq!(options.get_weights(), vec![1.0, 0.5, 2.0]);
+        assert_eq!(options.get_storage_type(), StorageType::SbqCompression);
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_index_options_partitions() -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(id int, encoding vector(3)) PARTITION BY RANGE (id);
+        CREATE TABLE test_old PARTITION OF test FOR VALUES FROM (0) TO (1000);
+        CREATE TABLE test_recent PARTITION OF test FOR VALUES FROM (1000) TO (2000);
+
+        -- a lighter index for the recent partition, attached by the index on the parent
+        CREATE INDEX idxtest_recent
+                  ON test_recent
+               USING diskann(encoding)
+                WITH (num_neighbors = 10, storage_layout = plain);
+
+        CREATE INDEX idxtest
+                  ON test
+               USING diskann(encoding)
+                WITH (num_neighbors = 60);
+
+        -- new partitions inherit the options of the index on the parent
+        CREATE TABLE test_new PARTITION OF test FOR VALUES FROM (2000) TO (3000);
+
+        INSERT INTO test(id, encoding)
+        SELECT i, ARRAY[i, i % 7, 1]::vector FROM generate_series(0, 2999) i;",
+        ))?;
+
+        let options_of = |partition: &str| -> spi::Result<PgBox<TSVIndexOptions>> {
+            let index_oid = Spi::get_one::<pg_sys::Oid>(&format!(
+                "SELECT i.indexrelid FROM pg_index i WHERE i.indrelid = '{partition}'::regclass"
+            ))?
+            .expect("oid was null");
+            let indexrel = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+            Ok(TSVIndexOptions::from_relation(&indexrel))
+        };
+
+        let old = options_of("test_old")?;
+        assert_eq!(old.num_neighbors, 60);
+        assert_eq!(old.get_storage_type(), StorageType::SbqCompression);
+
+        let recent = options_of("test_recent")?;
+        assert_eq!(recent.num_neighbors, 10);
+        assert_eq!(recent.get_storage_type(), StorageType::Plain);
+
+        let new = options_of("test_new")?;
+        assert_eq!(new.num_neighbors, 60);
+
+        let attached = Spi::get_one::<i64>(
+            "SELECT count(*) FROM pg_inherits WHERE inhparent = 'idxtest'::regclass",
+        )?;
+        assert_eq!(attached, Some(3));
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by encoding <=> '[1500,1,1]') SELECT count(*) from cte;",
+        )?;
+        assert_eq!(res, Some(3000));
+        Ok(())
+    }
 }
@@ -0,0 +1,313 @@
+//! Filter payloads: the values of the index columns that follow the vector column.
+//!
+//! The values are stored in front of the archived node, in the same index item, so that a scan
+//! can evaluate quals on them without fetching the heap tuple. rkyv locates the archived root at
+//! the end of the buffer, so the prefix does not change how nodes are read. Indexes with only a
+//! vector column have an empty payload and keep exactly the same on-disk layout.
+//!
+//! Layout: a u64 null bitmap followed by one u64 per column holding the pass-by-value datum.
+
+use pgrx::{pg_sys, PgRelation};
+
+use crate::util::IndexPointer;
+
+use super::stats::StatsNodeRead;
+
+const PAYLOAD_WORD_SIZE: usize = std::mem::size_of::<u64>();
+const MAX_PAYLOAD_COLUMNS: usize = u64::BITS as usize;
+
+/// Number of payload columns of an index, i.e. all the key columns except the vector.
+pub fn get_num_payload_columns(index: &PgRelation) -> usize {
+    unsafe {
+        let nkeyatts = (*index.rd_index).indnkeyatts as usize;
+        assert!(nkeyatts >= 1);
+        nkeyatts - 1
+    }
+}
+
+/// Size in bytes of the payload stored in front of every node. Always a multiple of 8 to keep
+/// the archived node that follows it aligned.
+pub fn get_payload_size(num_payload_columns: usize) -> usize {
+    if num_payload_columns == 0 {
+        0
+    } else {
+        (num_payload_columns + 1) * PAYLOAD_WORD_SIZE
+    }
+}
+
+/// The first payload column of type timestamp or timestamptz, with its type.
+pub fn find_timestamp_column(index: &PgRelation) -> Option<(usize, pg_sys::Oid)> {
+    let tuple_desc = index.tuple_desc();
+    (1..=get_num_payload_columns(index)).find_map(|i| {
+        let type_oid = tuple_desc.get(i).unwrap().atttypid;
+        [pg_sys::TIMESTAMPOID, pg_sys::TIMESTAMPTZOID]
+            .contains(&type_oid)
+            .then_some((i - 1, type_oid))
+    })
+}
+
+/// Payload columns are stored as a single datum, so only fixed-size, pass-by-value types are supported.
+pub fn check_payload_columns(index: &PgRelation) {
+    let num_payload_columns = get_num_payload_columns(index);
+    if num_payload_columns > MAX_PAYLOAD_COLUMNS {
+        pgrx::error!(
+            "diskann indexes support at most {} columns besides the vector",
+            MAX_PAYLOAD_COLUMNS
+        );
+    }
+
+    let tuple_desc = index.tuple_desc();
+    if tuple_desc.get(0).unwrap().attbyval {
+        pgrx::error!("the first column of a diskann index must be the vector column");
+    }
+    for i in 1..=num_payload_columns {
+        let att = tuple_desc.get(i).unwrap();
+        if !att.attbyval || att.attlen <= 0 {
+            pgrx::error!(
+                "column \"{}\" cannot be used as a filter column in a diskann index: only fixed-size, pass-by-value types are supported",
+                att.name()
+            );
+        }
+    }
+}
+
+pub struct NodePayload {
+    bytes: Vec<u8>,
+}
+
+impl NodePayload {
+    pub fn empty() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    /// Encodes the payload columns of an index tuple. The vector is at position 0 and is skipped.
+    pub unsafe fn from_pg_parts(
+        values: *const pg_sys::Datum,
+        isnull: *const bool,
+        num_payload_columns: usize,
+    ) -> Self {
+        if num_payload_columns == 0 {
+            return Self::empty();
+        }
+
+        let values = std::slice::from_raw_parts(values, num_payload_columns + 1);
+        let isnull = std::slice::from_raw_parts(isnull, num_payload_columns + 1);
+
+        let mut nulls: u64 = 0;
+        let mut bytes = Vec::with_capacity(get_payload_size(num_payload_columns));
+        bytes.extend_from_slice(&[0; PAYLOAD_WORD_SIZE]);
+        for i in 0..num_payload_columns {
+            let value = if isnull[i + 1] {
+                nulls |= 1 << i;
+                0
+            } else {
+                values[i + 1].value() as u64
+            };
+            bytes.extend_from_slice(&value.to_ne_bytes());
+        }
+        bytes[..PAYLOAD_WORD_SIZE].copy_from_slice(&nulls.to_ne_bytes());
+        Self { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The value of the payload column `column`, None if it is NULL.
+    pub fn get_column(&self, column: usize) -> Option<u64> {
+        let nulls = u64::from_ne_bytes(self.bytes[..PAYLOAD_WORD_SIZE].try_into().unwrap());
+        if nulls & (1 << column) != 0 {
+            return None;
+        }
+        let start = (column + 1) * PAYLOAD_WORD_SIZE;
+        Some(u64::from_ne_bytes(
+            self.bytes[start..start + PAYLOAD_WORD_SIZE]
+                .try_into()
+                .unwrap(),
+        ))
+    }
+}
+
+/// Reads the value of the payload column `column` of a node, None if it is NULL.
+pub unsafe fn read_payload_column<S: StatsNodeRead>(
+    index: &PgRelation,
+    index_pointer: IndexPointer,
+    column: usize,
+    stats: &mut S,
+) -> Option<u64> {
+    let rb = index_pointer.read_bytes(index);
+    stats.record_read();
+    let payload = rb.get_data_slice();
+    let nulls = u64::from_ne_bytes(payload[..PAYLOAD_WORD_SIZE].try_into().unwrap());
+    if nulls & (1 << column) != 0 {
+        return None;
+    }
+    let start = (column + 1) * PAYLOAD_WORD_SIZE;
+    Some(u64::from_ne_bytes(
+        payload[start..start + PAYLOAD_WORD_SIZE]
+            .try_into()
+            .unwrap(),
+    ))
+}
+
+/// Evaluates the scan keys on the payload columns of a node.
+pub struct PayloadFilter {
+    keys: Vec<pg_sys::ScanKeyData>,
+    num_payload_columns: usize,
+}
+
+impl PayloadFilter {
+    pub unsafe fn new(index: &PgRelation, keys: pg_sys::ScanKey, nkeys: usize) -> Self {
+        let keys = if nkeys > 0 {
+            std::slice::from_raw_parts(keys as *const pg_sys::ScanKeyData, nkeys).to_vec()
+        } else {
+            Vec::new()
+        };
+        Self {
+            keys,
+            num_payload_columns: get_num_payload_columns(index),
+        }
+    }
+
+    /// The value that the payload column `column` must be equal to, if a scan key says so.
+    pub fn get_equal_value(&self, column: usize) -> Option<u64> {
+        self.keys
+            .iter()
+            .find(|key| {
+                key.sk_attno as usize == column + 2
+                    && key.sk_strategy == pg_sys::BTEqualStrategyNumber as u16
+                    && key.sk_flags & pg_sys::SK_ISNULL as i32 == 0
+            })
+            .map(|key| key.sk_argument.value() as u64)
+    }
+
+    /// The smallest and the largest values that the payload column `column` can have according
+    /// to the scan keys, for the columns whose datums are signed integers, e.g. timestamps.
+    pub fn get_bounds(&self, column: usize) -> (Option<i64>, Option<i64>) {
+        let mut lower: Option<i64> = None;
+        let mut upper: Option<i64> = None;
+        for key in self.keys.iter() {
+            if key.sk_attno as usize != column + 2 || key.sk_flags & pg_sys::SK_ISNULL as i32 != 0 {
+                continue;
+            }
+            let value = key.sk_argument.value() as i64;
+            let strategy = key.sk_strategy as u32;
+            if strategy <= pg_sys::BTEqualStrategyNumber {
+                upper = Some(upper.map_or(value, |u| u.min(value)));
+            }
+            if strategy >= pg_sys::BTEqualStrategyNumber {
+                lower = Some(lower.map_or(value, |l| l.max(value)));
+            }
+        }
+        (lower, upper)
+    }
+
+    /// Returns true if the node passes all the scan keys.
+    pub unsafe fn matches<S: StatsNodeRead>(
+        &mut self,
+        index: &PgRelation,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> bool {
+        if self.keys.is_empty() {
+            return true;
+        }
+
+        let rb = index_pointer.read_bytes(index);
+        stats.record_read();
+        let payload = &rb.get_data_slice()[..get_payload_size(self.num_payload_columns)];
+        let nulls = u64::from_ne_bytes(payload[..PAYLOAD_WORD_SIZE].try_into().unwrap());
+
+        for key in self.keys.iter_mut() {
+            /* all our operators are strict, so a NULL on either side never matches */
+            if key.sk_flags & pg_sys::SK_ISNULL as i32 != 0 {
+                return false;
+            }
+            let column = key.sk_attno as usize - 2;
+            assert!(column < self.num_payload_columns);
+            if nulls & (1 << column) != 0 {
+                return false;
+            }
+            let start = (column + 1) * PAYLOAD_WORD_SIZE;
+            let value = u64::from_ne_bytes(
+                payload[start..start + PAYLOAD_WORD_SIZE]
+                    .try_into()
+                    .unwrap(),
+            );
+            let result = pg_sys::FunctionCall2Coll(
+                &mut key.sk_func,
+                key.sk_collation,
+                pg_sys::Datum::from(value as usize),
+                key.sk_argument,
+            );
+            if result.value() == 0 {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    unsafe fn test_filter_columns_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3), category int4, created_at timestamptz);
+
+            INSERT INTO test(embedding, category, created_at) VALUES
+                ('[1,2,3]', 1, '2024-01-01'),
+                ('[4,5,6]', 2, '2024-01-02'),
+                ('[7,8,10]', 1, NULL);
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding, category, created_at)
+                WITH ({index_options});
+
+            INSERT INTO test(embedding, category, created_at) VALUES
+                ('[1,2,4]', 2, '2024-01-03'),
+                ('[4,5,7]', NULL, '2024-01-04');
+            ",
+        ))?;
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test where category = 1 order by embedding <=> '[0,0,1]') SELECT count(*) from cte;",
+        )?;
+        assert_eq!(2, res.unwrap());
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test where category = 2 and created_at > '2024-01-02' order by embedding <=> '[0,0,1]') SELECT count(*) from cte;",
+        )?;
+        assert_eq!(1, res.unwrap());
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by embedding <=> '[0,0,1]') SELECT count(*) from cte;",
+        )?;
+        assert_eq!(5, res.unwrap());
+
+        Spi::run("drop index idxtest;")?;
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_filter_columns_plain() -> spi::Result<()> {
+        test_filter_columns_scaffold("num_neighbors=38, storage_layout = plain")
+    }
+
+    #[pg_test]
+    unsafe fn test_filter_columns_io_optimized() -> spi::Result<()> {
+        test_filter_columns_scaffold("num_neighbors=38, storage_layout = io_optimized")
+    }
+
+    #[pg_test]
+    unsafe fn test_filter_columns_memory_optimized() -> spi::Result<()> {
+        test_filter_columns_scaffold("num_neighbors=38, storage_layout = memory_optimized")
+    }
+}
@@ -0,0 +1,350 @@
+//! PCA projection of the indexed vectors.
+//!
+//! With the `pca_dimensions` option, a projection onto the top principal components is learned
+//! from a sample of the table during build. The graph and the quantizer work on the projected
+//! vectors, and candidates are rescored with the full vectors from the heap, the same way as
+//! when indexing fewer dimensions than the vectors have.
+//!
+//! Vectors are normalized before being projected, so the cosine similarity is an inner product
+//! and the projection is computed from the uncentered second moment matrix. The projection
+//! usually doesn't fit on a page, so it is stored in chunks referenced from a header item.
+
+use std::{cell::RefCell, rc::Rc};
+
+use ndarray::{Array2, ArrayView2};
+use pgrx::{pg_sys, PgRelation};
+use pgvectorscale_derive::{Readable, Writeable};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::util::{
+    page::PageType, tape::Tape, ArchivedItemPointer, IndexPointer, ItemPointer, ReadableBuffer,
+    WritableBuffer,
+};
+
+use super::stats::{NoStats, StatsNodeRead, StatsNodeWrite};
+
+/// Maximum number of vectors the projection is learned from.
+const PCA_MAX_SAMPLES: usize = 5000;
+/// Number of floats stored per chunk item.
+const PCA_CHUNK_SIZE: usize = 1024;
+const PCA_NUM_ITERATIONS: usize = 30;
+const PCA_SEED: u64 = 0x5ca1ab1e;
+
+#[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
+#[archive(check_bytes)]
+pub struct PcaHeader {
+    num_dimensions: u32,
+    num_components: u32,
+    chunks: Vec<ItemPointer>,
+}
+
+#[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
+#[archive(check_bytes)]
+pub struct PcaChunk {
+    values: Vec<f32>,
+}
+
+/// A projection onto `num_components` principal components.
+#[derive(Clone, PartialEq, Debug)]
+pub struct PcaProjection {
+    num_dimensions: usize,
+    num_components: usize,
+    /// row-major, one row of `num_dimensions` values per component, None until a stored
+    /// projection is first used
+    components: RefCell<Option<Rc<Vec<f32>>>>,
+    /// the index the components are read from and their location, None for a projection
+    /// learned in this backend. The projection is only used while the index is open.
+    stored: Option<(pg_sys::Relation, IndexPointer)>,
+}
+
+impl PcaProjection {
+    fn learned(num_dimensions: usize, num_components: usize, components: Vec<f32>) -> Self {
+        assert_eq!(components.len(), num_dimensions * num_components);
+        Self {
+            num_dimensions,
+            num_components,
+            components: RefCell::new(Some(Rc::new(components))),
+            stored: None,
+        }
+    }
+
+    /// The projection stored at `index_pointer`. Its components are only read on first use,
+    /// so that fetching the meta page of an index doesn't read them for operations that don't
+    /// project any vector.
+    pub fn stored(
+        index: &PgRelation,
+        index_pointer: IndexPointer,
+        num_dimensions: usize,
+        num_components: usize,
+    ) -> Self {
+        Self {
+            num_dimensions,
+            num_components,
+            components: RefCell::new(None),
+            stored: Some((index.as_ptr(), index_pointer)),
+        }
+    }
+
+    pub fn get_num_components(&self) -> usize {
+        self.num_components
+    }
+
+    pub fn project(&self, vector: &[f32], result: &mut [f32]) {
+        assert_eq!(vector.len(), self.num_dimensions);
+        assert_eq!(result.len(), self.num_components);
+        let components = self.get_components();
+        for (r, component) in result
+            .iter_mut()
+            .zip(components.chunks_exact(self.num_dimensions))
+        {
+            *r = component.iter().zip(vector).map(|(c, v)| c * v).sum();
+        }
+    }
+
+    fn get_components(&self) -> Rc<Vec<f32>> {
+        if let Some(components) = self.components.borrow().as_ref() {
+            return components.clone();
+        }
+        let (relation, index_pointer) = self
+            .stored
+            .expect("a PCA projection without components must be stored");
+        let components = unsafe {
+            let index = PgRelation::from_pg(relation);
+            Rc::new(Self::load(&index, index_pointer, &mut NoStats))
+        };
+        assert_eq!(components.len(), self.num_dimensions * self.num_components);
+        *self.components.borrow_mut() = Some(components.clone());
+        components
+    }
+
+    pub unsafe fn store<S: StatsNodeWrite>(
+        &self,
+        index: &PgRelation,
+        stats: &mut S,
+    ) -> ItemPointer {
+        let mut tape = Tape::new(index, PageType::Pca);
+        let chunks = self
+            .get_components()
+            .chunks(PCA_CHUNK_SIZE)
+            .map(|values| {
+                let chunk = PcaChunk {
+                    values: values.to_vec(),
+                };
+                chunk.write(&mut tape, stats)
+            })
+            .collect();
+        let header = PcaHeader {
+            num_dimensions: self.num_dimensions as u32,
+            num_components: self.num_components as u32,
+            chunks,
+        };
+        let index_pointer = header.write(&mut tape, stats);
+        tape.close();
+        index_pointer
+    }
+
+    /// Loads the components stored at `index_pointer`. They never change once written, so
+    /// they're cached in the relcache entry of the index and read from disk once per backend.
+    unsafe fn load<S: StatsNodeRead>(
+        index: &PgRelation,
+        index_pointer: IndexPointer,
+        stats: &mut S,
+    ) -> Vec<f32> {
+        if let Some(components) = Self::load_from_cache(index, index_pointer) {
+            return components;
+        }
+
+        let header = PcaHeader::read(index, index_pointer, stats);
+        let archived = header.get_archived_node();
+        let num_values = archived.num_dimensions as usize * archived.num_components as usize;
+        let chunks: Vec<ItemPointer> = archived
+            .chunks
+            .iter()
+            .map(|p| p.deserialize_item_pointer())
+            .collect();
+        std::mem::drop(header);
+
+        let mut components = Vec::with_capacity(num_values);
+        for chunk_pointer in chunks {
+            let chunk = PcaChunk::read(index, chunk_pointer, stats);
+            components.extend_from_slice(chunk.get_archived_node().values.as_slice());
+        }
+        assert_eq!(components.len(), num_values);
+
+        Self::store_in_cache(index, index_pointer, &components);
+        components
+    }
+
+    unsafe fn load_from_cache(index: &PgRelation, index_pointer: IndexPointer) -> Option<Vec<f32>> {
+        let cache = (*index.as_ptr()).rd_amcache as *const PcaCacheHeader;
+        if cache.is_null() || (*cache).index_pointer != index_pointer {
+            return None;
+        }
+        /* copied, the cache is freed on relcache invalidation */
+        let values = std::slice::from_raw_parts(cache.add(1).cast::<f32>(), (*cache).num_values);
+        Some(values.to_vec())
+    }
+
+    unsafe fn store_in_cache(index: &PgRelation, index_pointer: IndexPointer, components: &[f32]) {
+        let relation = index.as_ptr();
+        if !(*relation).rd_amcache.is_null() {
+            pg_sys::pfree((*relation).rd_amcache);
+            (*relation).rd_amcache = std::ptr::null_mut();
+        }
+        /* rd_amcache must be a single chunk in rd_indexcxt, it's freed on relcache invalidation */
+        let size =
+            std::mem::size_of::<PcaCacheHeader>() + components.len() * std::mem::size_of::<f32>();
+        let cache =
+            pg_sys::MemoryContextAlloc((*relation).rd_indexcxt, size) as *mut PcaCacheHeader;
+        cache.write(PcaCacheHeader {
+            index_pointer,
+            num_values: components.len(),
+        });
+        std::ptr::copy_nonoverlapping(
+            components.as_ptr(),
+            cache.add(1).cast::<f32>(),
+            components.len(),
+        );
+        (*relation).rd_amcache = cache.cast();
+    }
+}
+
+#[repr(C)]
+struct PcaCacheHeader {
+    index_pointer: IndexPointer,
+    num_values: usize,
+}
+
+/// Learns a projection from a reservoir sample of the (normalized) vectors.
+pub struct PcaTrainer {
+    num_dimensions: usize,
+    samples: Vec<f32>,
+    num_seen: usize,
+    rng: SmallRng,
+}
+
+impl PcaTrainer {
+    pub fn new(num_dimensions: usize) -> Self {
+        Self {
+            num_dimensions,
+            samples: Vec::new(),
+            num_seen: 0,
+            rng: SmallRng::seed_from_u64(PCA_SEED),
+        }
+    }
+
+    pub fn add_sample(&mut self, sample: &[f32]) {
+        assert_eq!(sample.len(), self.num_dimensions);
+        self.num_seen += 1;
+        if self.num_seen <= PCA_MAX_SAMPLES {
+            self.samples.extend_from_slice(sample);
+        } else {
+            let slot = self.rng.gen_range(0..self.num_seen);
+            if slot < PCA_MAX_SAMPLES {
+                let start = slot * self.num_dimensions;
+                self.samples[start..start + self.num_dimensions].copy_from_slice(sample);
+            }
+        }
+    }
+
+    pub fn finish(mut self, num_components: usize) -> PcaProjection {
+        let d = self.num_dimensions;
+        let n = self.samples.len() / d;
+        assert!(num_components < d);
+
+        if n == 0 {
+            /* nothing to learn from: keep the first dimensions, like a num_dimensions index */
+            pgrx::warning!(
+                "the table is empty, the PCA projection keeps the first {} dimensions",
+                num_components
+            );
+            let mut components = vec![0.0; num_components * d];
+            for i in 0..num_components {
+                components[i * d + i] = 1.0;
+            }
+            return PcaProjection::learned(d, num_components, components);
+        }
+
+        let x = ArrayView2::from_shape((n, d), &self.samples).unwrap();
+        let second_moment = x.t().dot(&x);
+
+        /* subspace iteration converges to the eigenvectors of the largest eigenvalues */
+        let mut q = Array2::from_shape_fn((d, num_components), |_| self.rng.gen::<f32>() - 0.5);
+        orthonormalize_columns(&mut q);
+        for _ in 0..PCA_NUM_ITERATIONS {
+            q = second_moment.dot(&q);
+            orthonormalize_columns(&mut q);
+        }
+
+        PcaProjection::learned(d, num_components, q.t().iter().cloned().collect())
+    }
+}
+
+/// Modified Gram-Schmidt. Columns that are linearly dependent on the previous ones become zero.
+fn orthonormalize_columns(q: &mut Array2<f32>) {
+    for j in 0..q.ncols() {
+        for i in 0..j {
+            let previous = q.column(i).to_owned();
+            let dot = previous.dot(&q.column(j));
+            q.column_mut(j).scaled_add(-dot, &previous);
+        }
+        let norm = q.column(j).dot(&q.column(j)).sqrt();
+        if norm > f32::EPSILON {
+            q.column_mut(j).mapv_inplace(|v| v / norm);
+        } else {
+            q.column_mut(j).fill(0.0);
+        }
+    }
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::PcaTrainer;
+
+    #[pg_test]
+    fn test_pca_finds_principal_components() {
+        /* all the variance is along the first two axes */
+        let mut trainer = PcaTrainer::new(3);
+        for i in 0..100 {
+            let a = (i as f32).sin();
+            let b = (i as f32).cos() * 0.5;
+            trainer.add_sample(&[a, b, 0.0]);
+        }
+        let projection = trainer.finish(2);
+
+        let mut result = [0.0; 2];
+        projection.project(&[0.0, 0.0, 1.0], &mut result);
+        assert!(result.iter().all(|v| v.abs() < 1e-4));
+
+        projection.project(&[0.6, 0.8, 0.0], &mut result);
+        let norm: f32 = result.iter().map(|v| v * v).sum();
+        assert!((norm - 1.0).abs() < 1e-4);
+    }
+
+    #[pg_test]
+    unsafe fn test_pca_index_plain() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = plain, pca_dimensions = 100",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_pca_index_memory_optimized() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = memory_optimized, pca_dimensions = 100",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_pca_index_empty_table() -> spi::Result<()> {
+        crate::access_method::build::tests::test_empty_table_insert_scaffold(
+            "storage_layout = memory_optimized, pca_dimensions = 2",
+        )
+    }
+}
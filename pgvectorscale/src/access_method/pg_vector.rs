@@ -1,3 +1,5 @@
+use memoffset::*;
+use pgrx::array::RawArray;
 use pgrx::*;
 
 use super::{distance::preprocess_cosine, meta_page};
@@ -20,6 +22,172 @@ impl PgVectorInternal {
     }
 }
 
+//Ported from pg_vector code
+#[repr(C)]
+#[derive(Debug)]
+struct PgHalfVectorInternal {
+    vl_len_: i32, /* varlena header (do not touch directly!) */
+    dim: i16,     /* number of dimensions */
+    unused: i16,
+    x: pg_sys::__IncompleteArrayField<u16>, /* IEEE 754 half precision */
+}
+
+//Ported from pg_vector code
+#[repr(C)]
+#[derive(Debug)]
+struct PgSparseVectorInternal {
+    vl_len_: i32, /* varlena header (do not touch directly!) */
+    dim: i32,     /* number of dimensions */
+    nnz: i32,     /* number of non-zero elements */
+    unused: i32,
+    indices: pg_sys::__IncompleteArrayField<i32>, /* followed by nnz float values */
+}
+
+/// The type of the indexed column. All the vectors are converted to f32 when read.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum VectorType {
+    Vector = 0,
+    HalfVec = 1,
+    SparseVec = 2,
+    Float4Array = 3,
+}
+
+impl VectorType {
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0 => VectorType::Vector,
+            1 => VectorType::HalfVec,
+            2 => VectorType::SparseVec,
+            3 => VectorType::Float4Array,
+            _ => panic!("Invalid vector type"),
+        }
+    }
+
+    /// The SQL name of the type.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VectorType::Vector => "vector",
+            VectorType::HalfVec => "halfvec",
+            VectorType::SparseVec => "sparsevec",
+            VectorType::Float4Array => "real[]",
+        }
+    }
+
+    pub fn from_index(index: &PgRelation) -> Self {
+        let type_oid = index.tuple_desc().get(0).unwrap().atttypid;
+        if type_oid == pg_sys::FLOAT4ARRAYOID {
+            return VectorType::Float4Array;
+        }
+        let type_name = unsafe {
+            let name = pg_sys::format_type_be(type_oid);
+            let type_name = std::ffi::CStr::from_ptr(name).to_string_lossy().to_string();
+            pg_sys::pfree(name.cast());
+            type_name
+        };
+        /* the type is schema qualified if it's not on the search path */
+        match type_name.rsplit('.').next().unwrap() {
+            "vector" => VectorType::Vector,
+            "halfvec" => VectorType::HalfVec,
+            "sparsevec" => VectorType::SparseVec,
+            _ => error!("diskann indexes do not support the type {}", type_name),
+        }
+    }
+}
+
+pub fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    let bits = match exponent {
+        0 if mantissa == 0 => sign,
+        0 => {
+            /* subnormal, normalize it */
+            let mut exponent = 127 - 15 + 1;
+            let mut mantissa = mantissa;
+            while mantissa & 0x400 == 0 {
+                mantissa <<= 1;
+                exponent -= 1;
+            }
+            sign | (exponent << 23) | ((mantissa & 0x3ff) << 13)
+        }
+        0x1f => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+/// Converts to the nearest f16, rounding ties to even like the halfvec type.
+pub fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        /* infinity, or NaN that stays NaN */
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+    let round = |half: u32, remainder: u32, halfway: u32| {
+        if remainder > halfway || (remainder == halfway && half & 1 == 1) {
+            half + 1
+        } else {
+            half
+        }
+    };
+    if exponent <= 0 {
+        /* subnormal, or too small for an f16 */
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let half = round(
+            mantissa >> shift,
+            mantissa & ((1 << shift) - 1),
+            1 << (shift - 1),
+        );
+        return sign | half as u16;
+    }
+    /* a carry out of the mantissa rounds up the exponent, up to infinity */
+    let half = round(
+        ((exponent as u32) << 10) | (mantissa >> 13),
+        mantissa & 0x1fff,
+        0x1000,
+    );
+    sign | half as u16
+}
+
+/// Scales every dimension of `vector` by the square root of its weight, which turns the cosine
+/// distance of the scaled vectors into the weighted cosine distance. Does nothing without
+/// weights.
+pub fn apply_weights(vector: &mut [f32], weights: &[f32]) {
+    for (v, w) in vector.iter_mut().zip(weights.iter()) {
+        *v *= w.sqrt();
+    }
+}
+
+/// Reads a `diskann_weighted_query`, the query of the `<*>` operator of the
+/// `vector_weighted_cosine_ops` operator class: the datum of its vector and its weights.
+pub unsafe fn read_weighted_query(datum: pg_sys::Datum) -> (pg_sys::Datum, Vec<f32>) {
+    let tuple = pg_sys::pg_detoast_datum(datum.cast_mut_ptr()) as pg_sys::HeapTupleHeader;
+    let mut is_null = false;
+    let query = pg_sys::GetAttributeByNum(tuple, 1, &mut is_null);
+    if is_null {
+        error!("the query of a diskann_weighted_query must not be NULL");
+    }
+    let weights = pg_sys::GetAttributeByNum(tuple, 2, &mut is_null);
+    let Some(weights) = Vec::<f32>::from_datum(weights, is_null) else {
+        error!("the weights of a diskann_weighted_query must not be NULL");
+    };
+    if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+        error!("the weights of a diskann_weighted_query must be non-negative numbers");
+    }
+    (query, weights)
+}
+
 pub struct PgVector {
     index_distance: Option<*mut PgVectorInternal>,
     index_distance_needs_pfree: bool,
@@ -68,15 +236,24 @@ impl PgVector {
         ))
     }
 
-    unsafe fn create_inner(
+    /// Detoasts the datum into a palloc'd copy.
+    ///
+    /// If `num_dimensions` is given, only the prefix of the vector holding that many elements
+    /// is fetched. For externally stored vectors this avoids reading the entire TOAST chain
+    /// when only the indexed dimensions are needed.
+    unsafe fn detoast_copy(
         datum: pg_sys::Datum,
-        meta_page: &meta_page::MetaPage,
-        is_index_distance: bool,
+        num_dimensions: Option<u32>,
     ) -> *mut PgVectorInternal {
-        //TODO: we are using a copy here to avoid lifetime issues and because in some cases we have to
-        //modify the datum in preprocess_cosine. We should find a way to avoid the copy if the vector is
-        //normalized and preprocess_cosine is a noop;
-        let detoasted = pg_sys::pg_detoast_datum_copy(datum.cast_mut_ptr());
+        let detoasted = match num_dimensions {
+            Some(num_dimensions) => {
+                /* slice offsets are relative to the data, i.e. they exclude the varlena header */
+                let slice_size =
+                    Self::size_of_internal(num_dimensions) - offset_of!(PgVectorInternal, dim);
+                pg_sys::pg_detoast_datum_slice(datum.cast_mut_ptr(), 0, slice_size as _)
+            }
+            None => pg_sys::pg_detoast_datum_copy(datum.cast_mut_ptr()),
+        };
         let is_copy = !std::ptr::eq(
             detoasted.cast::<PgVectorInternal>(),
             datum.cast_mut_ptr::<PgVectorInternal>(),
@@ -86,18 +263,129 @@ impl PgVector {
         assert!(is_copy, "Datum should be a copy");
         let casted = detoasted.cast::<PgVectorInternal>();
 
-        if is_index_distance
-            && meta_page.get_num_dimensions() != meta_page.get_num_dimensions_to_index()
-        {
-            assert!((*casted).dim > meta_page.get_num_dimensions_to_index() as _);
-            (*casted).dim = meta_page.get_num_dimensions_to_index() as _;
+        if let Some(num_dimensions) = num_dimensions {
+            assert!((*casted).dim >= num_dimensions as _);
+            (*casted).dim = num_dimensions as _;
         }
+        casted
+    }
+
+    fn size_of_internal(num_dimensions: u32) -> usize {
+        offset_of!(PgVectorInternal, x) + num_dimensions as usize * std::mem::size_of::<f32>()
+    }
+
+    /// Copies the first `num_dimensions` elements of an already detoasted vector into a new palloc'd vector.
+    unsafe fn copy_prefix(
+        source: *const PgVectorInternal,
+        num_dimensions: u32,
+    ) -> *mut PgVectorInternal {
+        assert!((*source).dim >= num_dimensions as _);
+        let size = Self::size_of_internal(num_dimensions);
+        let copy = pg_sys::palloc(size).cast::<PgVectorInternal>();
+        std::ptr::copy_nonoverlapping(source.cast::<u8>(), copy.cast::<u8>(), size);
+        set_varsize(copy.cast(), size as i32);
+        (*copy).dim = num_dimensions as _;
+        copy
+    }
+
+    /// Creates a new palloc'd vector with `num_dimensions` zeroed elements.
+    unsafe fn alloc(num_dimensions: u32) -> *mut PgVectorInternal {
+        let size = Self::size_of_internal(num_dimensions);
+        let vector = pg_sys::palloc0(size).cast::<PgVectorInternal>();
+        set_varsize(vector.cast(), size as i32);
+        (*vector).dim = num_dimensions as _;
+        vector
+    }
+
+    /// Returns the number of elements of a float4[] datum.
+    pub unsafe fn get_array_num_dimensions(datum: pg_sys::Datum) -> u32 {
+        let detoasted = pg_sys::pg_detoast_datum(datum.cast_mut_ptr());
+        let array = Self::check_array(detoasted.cast());
+        let len = array.len() as u32;
+        if !std::ptr::eq(detoasted.cast::<u8>(), datum.cast_mut_ptr::<u8>()) {
+            pg_sys::pfree(detoasted.cast());
+        }
+        len
+    }
 
-        let dim = (*casted).dim;
-        let raw_slice = unsafe { (*casted).x.as_mut_slice(dim as _) };
+    unsafe fn check_array(array: *mut pg_sys::ArrayType) -> RawArray {
+        let array = RawArray::from_ptr(std::ptr::NonNull::new(array).unwrap());
+        if array.dims().len() != 1 {
+            error!("vector arrays must have exactly one dimension");
+        }
+        if array.nulls().is_some() {
+            error!("vector arrays must not contain nulls");
+        }
+        array
+    }
 
+    /// Converts a halfvec, sparsevec or float4[] datum into a palloc'd vector.
+    unsafe fn convert_to_vector(
+        datum: pg_sys::Datum,
+        vector_type: VectorType,
+    ) -> *mut PgVectorInternal {
+        let detoasted = pg_sys::pg_detoast_datum(datum.cast_mut_ptr());
+        let vector = match vector_type {
+            VectorType::Vector => panic!("vectors do not need a conversion"),
+            VectorType::HalfVec => {
+                let half = detoasted.cast::<PgHalfVectorInternal>();
+                let dim = (*half).dim as u32;
+                let vector = Self::alloc(dim);
+                let source = (*half).x.as_slice(dim as _);
+                let target = (*vector).x.as_mut_slice(dim as _);
+                for (t, s) in target.iter_mut().zip(source.iter()) {
+                    *t = f16_to_f32(*s);
+                }
+                vector
+            }
+            VectorType::SparseVec => {
+                let sparse = detoasted.cast::<PgSparseVectorInternal>();
+                let nnz = (*sparse).nnz as usize;
+                let vector = Self::alloc((*sparse).dim as u32);
+                let indices = (*sparse).indices.as_slice(nnz);
+                let values = std::slice::from_raw_parts(
+                    (*sparse).indices.as_ptr().add(nnz).cast::<f32>(),
+                    nnz,
+                );
+                let target = (*vector).x.as_mut_slice((*sparse).dim as _);
+                for (&i, &v) in indices.iter().zip(values.iter()) {
+                    target[i as usize] = v;
+                }
+                vector
+            }
+            VectorType::Float4Array => {
+                let array = Self::check_array(detoasted.cast());
+                let len = array.len();
+                let vector = Self::alloc(len as u32);
+                let source = array.data::<f32>();
+                (*vector)
+                    .x
+                    .as_mut_slice(len)
+                    .copy_from_slice(source.as_ref());
+                vector
+            }
+        };
+        if !std::ptr::eq(detoasted.cast::<u8>(), datum.cast_mut_ptr::<u8>()) {
+            pg_sys::pfree(detoasted.cast());
+        }
+        vector
+    }
+
+    unsafe fn preprocess(
+        vector: *mut PgVectorInternal,
+        meta_page: &meta_page::MetaPage,
+    ) -> *mut PgVectorInternal {
+        let dim = (*vector).dim;
+        let raw_slice = unsafe { (*vector).x.as_mut_slice(dim as _) };
+
+        /* scaling every dimension by the square root of its weight before normalizing turns the
+        cosine distance into the weighted cosine distance. Both the nodes and the queries go
+        through here, so the quantizer is trained on the weighted vectors too */
+        apply_weights(raw_slice, meta_page.get_weights());
+
+        /* always normalize, so that the cosine distance reduces to an inner product */
         preprocess_cosine(raw_slice);
-        casted
+        vector
     }
 
     pub unsafe fn from_datum(
@@ -106,9 +394,30 @@ impl PgVector {
         index_distance: bool,
         full_distance: bool,
     ) -> PgVector {
+        //TODO: we are using a copy here to avoid lifetime issues and because in some cases we have to
+        //modify the datum in preprocess_cosine. We should find a way to avoid the copy if the vector is
+        //normalized and preprocess_cosine is a noop;
+        let vector_type = meta_page.get_vector_type();
+        if vector_type != VectorType::Vector {
+            let full = Self::convert_to_vector(datum, vector_type);
+            if (*full).dim as u32 != meta_page.get_num_dimensions() {
+                error!(
+                    "expected {} dimensions, not {}",
+                    meta_page.get_num_dimensions(),
+                    (*full).dim
+                );
+            }
+            return Self::from_full_copy(full, meta_page, index_distance, full_distance);
+        }
+
+        if meta_page.uses_pca() {
+            let full = Self::detoast_copy(datum, None);
+            return Self::from_full_copy(full, meta_page, index_distance, full_distance);
+        }
+
         if meta_page.get_num_dimensions() == meta_page.get_num_dimensions_to_index() {
             /* optimization if the num dimensions are the same */
-            let inner = Self::create_inner(datum, meta_page, true);
+            let inner = Self::preprocess(Self::detoast_copy(datum, None), meta_page);
             return PgVector {
                 index_distance: Some(inner),
                 index_distance_needs_pfree: true,
@@ -117,15 +426,102 @@ impl PgVector {
             };
         }
 
+        let num_dimensions_to_index = meta_page.get_num_dimensions_to_index();
+        let (idx, full) = match (index_distance, full_distance) {
+            (true, true) => {
+                /* detoast once and derive the index vector from the full one */
+                let full = Self::detoast_copy(datum, None);
+                return Self::from_full_copy(full, meta_page, index_distance, full_distance);
+            }
+            (true, false) => {
+                let idx = Self::detoast_copy(datum, Some(num_dimensions_to_index));
+                (Some(Self::preprocess(idx, meta_page)), None)
+            }
+            (false, true) => (
+                None,
+                Some(Self::preprocess(Self::detoast_copy(datum, None), meta_page)),
+            ),
+            (false, false) => (None, None),
+        };
+
+        PgVector {
+            index_distance: idx,
+            index_distance_needs_pfree: true,
+            full_distance: full,
+            full_distance_needs_pfree: true,
+        }
+    }
+
+    /// Builds the vectors from a palloc'd copy of the full vector, taking ownership of it.
+    unsafe fn from_full_copy(
+        full: *mut PgVectorInternal,
+        meta_page: &meta_page::MetaPage,
+        index_distance: bool,
+        full_distance: bool,
+    ) -> PgVector {
+        if meta_page.uses_pca() {
+            return Self::from_full_copy_projected(full, meta_page, index_distance, full_distance);
+        }
+
+        if meta_page.get_num_dimensions() == meta_page.get_num_dimensions_to_index() {
+            let inner = Self::preprocess(full, meta_page);
+            return PgVector {
+                index_distance: Some(inner),
+                index_distance_needs_pfree: true,
+                full_distance: Some(inner),
+                full_distance_needs_pfree: false,
+            };
+        }
+
+        /* the prefix has to be copied before preprocessing because the full vector is normalized
+        over all of its dimensions */
         let idx = if index_distance {
-            Some(Self::create_inner(datum, meta_page, true))
+            let idx = Self::copy_prefix(full, meta_page.get_num_dimensions_to_index());
+            Some(Self::preprocess(idx, meta_page))
+        } else {
+            None
+        };
+        let full = if full_distance {
+            Some(Self::preprocess(full, meta_page))
         } else {
+            pg_sys::pfree(full.cast());
             None
         };
 
+        PgVector {
+            index_distance: idx,
+            index_distance_needs_pfree: true,
+            full_distance: full,
+            full_distance_needs_pfree: true,
+        }
+    }
+
+    /// Like `from_full_copy`, but the index vector is the PCA projection of the full vector.
+    unsafe fn from_full_copy_projected(
+        full: *mut PgVectorInternal,
+        meta_page: &meta_page::MetaPage,
+        index_distance: bool,
+        full_distance: bool,
+    ) -> PgVector {
+        /* the projection is learned on normalized vectors, so project after preprocessing */
+        let full = Self::preprocess(full, meta_page);
+        let idx = if index_distance {
+            let projection = meta_page
+                .get_pca_projection()
+                .expect("the PCA projection is not loaded");
+            let num_components = projection.get_num_components();
+            let idx = Self::alloc(num_components as u32);
+            let raw_slice = (*idx).x.as_mut_slice(num_components);
+            projection.project((*full).to_slice(), raw_slice);
+            preprocess_cosine(raw_slice);
+            Some(idx)
+        } else {
+            None
+        };
         let full = if full_distance {
-            Some(Self::create_inner(datum, meta_page, false))
+            Some(full)
         } else {
+            pg_sys::pfree(full.cast());
             None
         };
 
@@ -145,3 +541,280 @@ impl PgVector {
         unsafe { (*self.full_distance.unwrap()).to_slice() }
     }
 }
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::{f16_to_f32, f32_to_f16};
+
+    #[pg_test]
+    fn test_f16_to_f32() {
+        assert_eq!(f16_to_f32(0x0000), 0.0);
+        assert_eq!(f16_to_f32(0x3c00), 1.0);
+        assert_eq!(f16_to_f32(0xc000), -2.0);
+        assert_eq!(f16_to_f32(0x3555), 0.333251953125);
+        assert_eq!(f16_to_f32(0x7bff), 65504.0);
+        assert_eq!(f16_to_f32(0x0001), 2.0_f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7c00), f32::INFINITY);
+    }
+
+    #[pg_test]
+    fn test_f32_to_f16() {
+        assert_eq!(f32_to_f16(0.0), 0x0000);
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        assert_eq!(f32_to_f16(1.0 / 3.0), 0x3555);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(65536.0), 0x7c00);
+        assert_eq!(f32_to_f16(2.0_f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(2.0_f32.powi(-26)), 0x0000);
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+        /* ties round to even */
+        assert_eq!(f32_to_f16(1.0 + 2.0_f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 3.0 * 2.0_f32.powi(-11)), 0x3c02);
+        for half in (0..0x7c00).step_by(7) {
+            assert_eq!(f32_to_f16(f16_to_f32(half)), half);
+        }
+    }
+
+    unsafe fn test_vector_type_scaffold(vector_type: &str, index_options: &str) -> spi::Result<()> {
+        let supported: Option<bool> =
+            Spi::get_one(&format!("SELECT to_regtype('{vector_type}') IS NOT NULL"))?;
+        if !supported.unwrap() {
+            /* older pgvector versions don't have this type */
+            return Ok(());
+        }
+
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding {vector_type}(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,2,3]'::vector), ('[4,5,6]'::vector);
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH ({index_options});
+
+            INSERT INTO test(embedding) VALUES ('[7,8,10]'::vector), ('[0,0,1]'::vector);
+            ",
+        ))?;
+
+        let res: Option<i64> = Spi::get_one(&format!(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by embedding <=> '[0,0,1]'::vector::{vector_type}) SELECT count(*) from cte;",
+        ))?;
+        assert_eq!(4, res.unwrap());
+
+        let first: Option<String> = Spi::get_one(&format!(
+            "   set enable_seqscan = 0;
+                select embedding::vector::text from test order by embedding <=> '[0,0,1]'::vector::{vector_type} limit 1;",
+        ))?;
+        assert_eq!("[0,0,1]", first.unwrap());
+
+        Spi::run("drop table test;")?;
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_halfvec_index() -> spi::Result<()> {
+        test_vector_type_scaffold("halfvec", "storage_layout = plain")?;
+        test_vector_type_scaffold("halfvec", "storage_layout = memory_optimized")
+    }
+
+    #[pg_test]
+    unsafe fn test_sparsevec_index() -> spi::Result<()> {
+        test_vector_type_scaffold("sparsevec", "storage_layout = plain")?;
+        test_vector_type_scaffold("sparsevec", "storage_layout = memory_optimized")
+    }
+
+    unsafe fn test_float4_array_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding real[]);
+
+            INSERT INTO test(embedding) VALUES ('{{1,2,3}}'), ('{{4,5,6}}');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH ({index_options});
+
+            INSERT INTO test(embedding) VALUES ('{{7,8,10}}'), ('{{0,0,1}}');
+            ",
+        ))?;
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test order by embedding <=> '{0,0,1}'::real[]) SELECT count(*) from cte;",
+        )?;
+        assert_eq!(4, res.unwrap());
+
+        let first: Option<String> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                select embedding::text from test order by embedding <=> '{0,0,1}'::real[] limit 1;",
+        )?;
+        assert_eq!("{0,0,1}", first.unwrap());
+
+        Spi::run("drop table test;")?;
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_float4_array_index() -> spi::Result<()> {
+        test_float4_array_scaffold("storage_layout = plain")?;
+        test_float4_array_scaffold("storage_layout = memory_optimized")?;
+        test_float4_array_scaffold("storage_layout = memory_optimized, num_dimensions = 2")
+    }
+
+    #[pg_test]
+    unsafe fn test_float4_array_empty_table() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding real[]);
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (array_dimensions = 3, num_dimensions = 2);
+            INSERT INTO test(embedding) VALUES ('{1,2,3}'), ('{4,5,6}'), ('{0,0,1}');",
+        )?;
+
+        /* all the elements are kept, only the first two are indexed */
+        let first: Option<String> = Spi::get_one(
+            "set enable_seqscan = 0;
+            select embedding::text from test order by embedding <=> '{0,0,1}'::real[] limit 1;",
+        )?;
+        assert_eq!("{0,0,1}", first.unwrap());
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "cannot determine the number of dimensions of an empty float4[] column. Set the array_dimensions option of the index"
+    )]
+    unsafe fn test_float4_array_empty_table_no_dimensions() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding real[]);
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH (num_dimensions = 2);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "the arrays of the table have 3 elements, but the array_dimensions option is 2"
+    )]
+    unsafe fn test_float4_array_dimensions_mismatch() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding real[]);
+            INSERT INTO test(embedding) VALUES ('{1,2,3}');
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH (array_dimensions = 2);",
+        )?;
+        Ok(())
+    }
+
+    unsafe fn test_weights_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding) VALUES ('[1,1,0]'), ('[0.1,0,10]');
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding vector_weighted_cosine_ops)
+                WITH ({index_options});
+
+            INSERT INTO test(embedding) VALUES ('[0,1,0]');
+            ",
+        ))?;
+
+        /* without weights [1,1,0] is the closest, but the third dimension is ignored */
+        let weighted_query =
+            "select embedding::text from test order by embedding <*> ('[1,0,0]', '{1,1,0}')::diskann_weighted_query limit 1;";
+        let first: Option<String> =
+            Spi::get_one(&format!("set enable_seqscan = 0; {weighted_query}"))?;
+        assert_eq!("[0.1,0,10]", first.unwrap());
+
+        /* the operator orders the rows like the index */
+        let first: Option<String> = Spi::get_one(&format!(
+            "set enable_seqscan = 1; set enable_indexscan = 0; {weighted_query}"
+        ))?;
+        assert_eq!("[0.1,0,10]", first.unwrap());
+
+        /* and <=> keeps the unweighted distance, the index cannot order by it */
+        let first: Option<String> = Spi::get_one(
+            "   set enable_indexscan = 1;
+                select embedding::text from test order by embedding <=> '[1,0,0]' limit 1;",
+        )?;
+        assert_eq!("[1,1,0]", first.unwrap());
+
+        Spi::run("drop table test;")?;
+
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_weighted_index() -> spi::Result<()> {
+        test_weights_scaffold("storage_layout = plain, weights = '[1, 1, 0]'")?;
+        test_weights_scaffold("storage_layout = memory_optimized, weights = '[1, 1, 0]'")
+    }
+
+    #[pg_test(
+        error = "indexes with the weights option must use the vector_weighted_cosine_ops operator class"
+    )]
+    unsafe fn test_weights_default_opclass() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH (weights = '[1, 1, 0]');",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "the weights option has 1999 values, more than the meta page of the index has room for"
+    )]
+    unsafe fn test_weights_too_many() -> spi::Result<()> {
+        let weights = vec!["1"; 1999].join(",");
+        Spi::run(&format!(
+            "CREATE TABLE test(embedding vector(1999));
+            CREATE INDEX idxtest ON test USING diskann(embedding vector_weighted_cosine_ops)
+                WITH (weights = '[{weights}]');",
+        ))?;
+        Ok(())
+    }
+
+    #[pg_test(error = "the weights of the query differ from the weights of the index")]
+    unsafe fn test_weighted_query_other_weights() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test(embedding) VALUES ('[1,1,0]'), ('[0.1,0,10]');
+            CREATE INDEX idxtest ON test USING diskann(embedding vector_weighted_cosine_ops)
+                WITH (weights = '[1, 1, 0]');
+            SET enable_seqscan = 0;
+            SELECT * FROM test ORDER BY embedding <*> ('[1,0,0]', '{1,1,1}')::diskann_weighted_query;",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_float4_array_index_empty_table() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding real[]);
+
+            CREATE INDEX idxtest
+                  ON test
+               USING diskann(embedding)
+                WITH (array_dimensions = 3);
+
+            INSERT INTO test(embedding) VALUES ('{1,2,3}'), ('{4,5,6}'), ('{0,0,1}');
+            ",
+        )?;
+
+        let first: Option<String> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                select embedding::text from test order by embedding <=> '{0,0,1}'::real[] limit 1;",
+        )?;
+        assert_eq!("{0,0,1}", first.unwrap());
+
+        Spi::run("drop table test;")?;
+
+        Ok(())
+    }
+}
@@ -49,6 +49,19 @@ impl Node {
         let pq_vector = Vec::with_capacity(0);
         Self::new_internal(vector, pq_vector, heap_item_pointer, meta_page)
     }
+
+    /// The serialized size of a node, without the payload.
+    pub fn test_size(num_neighbors: usize, num_dimensions: usize) -> usize {
+        let node = Self {
+            vector: vec![0.0; num_dimensions],
+            pq_vector: Vec::with_capacity(0),
+            neighbor_index_pointers: (0..num_neighbors)
+                .map(|_| ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber))
+                .collect(),
+            heap_item_pointer: HeapPointer::new(InvalidBlockNumber, InvalidOffsetNumber),
+        };
+        node.serialize_to_vec().len()
+    }
 }
 
 /// contains helpers for mutate-in-place. See struct_mutable_refs in test_alloc.rs in rkyv
@@ -129,4 +142,8 @@ impl ArchivedData for ArchivedNode {
     fn get_heap_item_pointer(&self) -> HeapPointer {
         self.heap_item_pointer.deserialize_item_pointer()
     }
+
+    fn get_vector_code(&self) -> Vec<u8> {
+        self.vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
 }
@@ -1,6 +1,7 @@
 use super::{
     graph::{ListSearchNeighbor, ListSearchResult},
     graph_neighbor_store::GraphNeighborStore,
+    payload::NodePayload,
     pg_vector::PgVector,
     plain_node::{ArchivedNode, Node, ReadableNode},
     stats::{
@@ -8,9 +9,11 @@ use super::{
         StatsNodeRead, StatsNodeWrite, WriteStats,
     },
     storage::{ArchivedData, NodeDistanceMeasure, Storage},
-    storage_common::get_attribute_number_from_index,
+    storage_common::IndexedAttribute,
 };
 
+use std::cell::RefCell;
+
 use pgrx::PgRelation;
 
 use crate::util::{
@@ -19,11 +22,21 @@ use crate::util::{
 
 use super::{meta_page::MetaPage, neighbor_with_distance::NeighborWithDistance};
 
+#[cfg(feature = "build_offload")]
+use super::storage::get_node_distances_one_by_one;
+#[cfg(feature = "build_offload")]
+use crate::diskann::offload::{OffloadBackend, OffloadDistance};
+
 pub struct PlainStorage<'a> {
     pub index: &'a PgRelation,
     pub distance_fn: fn(&[f32], &[f32]) -> f32,
     heap_rel: &'a PgRelation,
-    heap_attr: pgrx::pg_sys::AttrNumber,
+    indexed_attr: IndexedAttribute,
+    /* reused across heap fetches to avoid creating a slot for every rescored candidate */
+    heap_slot: RefCell<Option<TableSlot>>,
+    /* the backend the pruning distances of a build are computed by */
+    #[cfg(feature = "build_offload")]
+    offload: Option<(Box<dyn OffloadBackend>, OffloadDistance)>,
 }
 
 impl<'a> PlainStorage<'a> {
@@ -36,10 +49,19 @@ impl<'a> PlainStorage<'a> {
             index: index,
             distance_fn: distance_fn,
             heap_rel: heap_rel,
-            heap_attr: get_attribute_number_from_index(index),
+            indexed_attr: IndexedAttribute::new(index),
+            heap_slot: RefCell::new(None),
+            #[cfg(feature = "build_offload")]
+            offload: None,
         }
     }
 
+    /// Computes the distances of the pruning of the build with `backend`.
+    #[cfg(feature = "build_offload")]
+    pub fn set_offload(&mut self, backend: Box<dyn OffloadBackend>, distance: OffloadDistance) {
+        self.offload = Some((backend, distance));
+    }
+
     pub fn load_for_insert(
         index_relation: &'a PgRelation,
         heap_rel: &'a PgRelation,
@@ -49,7 +71,10 @@ impl<'a> PlainStorage<'a> {
             index: index_relation,
             distance_fn: distance_fn,
             heap_rel: heap_rel,
-            heap_attr: get_attribute_number_from_index(&index_relation),
+            indexed_attr: IndexedAttribute::new(&index_relation),
+            heap_slot: RefCell::new(None),
+            #[cfg(feature = "build_offload")]
+            offload: None,
         }
     }
 
@@ -62,7 +87,10 @@ impl<'a> PlainStorage<'a> {
             index: index_relation,
             distance_fn: distance_fn,
             heap_rel: heap_rel,
-            heap_attr: get_attribute_number_from_index(&index_relation),
+            indexed_attr: IndexedAttribute::new(&index_relation),
+            heap_slot: RefCell::new(None),
+            #[cfg(feature = "build_offload")]
+            offload: None,
         }
     }
 }
@@ -169,15 +197,17 @@ impl<'a> Storage for PlainStorage<'a> {
 
     fn create_node<S: StatsNodeWrite>(
         &self,
-        full_vector: &[f32],
+        vector: &PgVector,
         heap_pointer: HeapPointer,
+        payload: &NodePayload,
         meta_page: &MetaPage,
         tape: &mut Tape,
         stats: &mut S,
     ) -> ItemPointer {
         //OPT: avoid the clone?
-        let node = Node::new_for_full_vector(full_vector.to_vec(), heap_pointer, meta_page);
-        let index_pointer: IndexPointer = node.write(tape, stats);
+        let node =
+            Node::new_for_full_vector(vector.to_index_slice().to_vec(), heap_pointer, meta_page);
+        let index_pointer: IndexPointer = node.write_with_prefix(payload.as_bytes(), tape, stats);
         index_pointer
     }
 
@@ -200,6 +230,12 @@ impl<'a> Storage for PlainStorage<'a> {
         node.commit();
     }
 
+    fn clear_node_cache(&mut self) {}
+
+    fn get_memory_bytes(&self) -> usize {
+        0
+    }
+
     unsafe fn get_node_distance_measure<'b, S: StatsNodeRead>(
         &'b self,
         index_pointer: IndexPointer,
@@ -208,10 +244,33 @@ impl<'a> Storage for PlainStorage<'a> {
         IndexFullDistanceMeasure::with_index_pointer(self, index_pointer, stats)
     }
 
+    /// With an offload backend, the vectors of the nodes are handed to it in one batch.
+    #[cfg(feature = "build_offload")]
+    fn get_node_distances<S: StatsNodeRead + StatsDistanceComparison>(
+        &self,
+        from: IndexPointer,
+        to: &[IndexPointer],
+        stats: &mut S,
+    ) -> Vec<f32> {
+        let Some((backend, distance)) = &self.offload else {
+            return get_node_distances_one_by_one(self, from, to, stats);
+        };
+        let rn = unsafe { Node::read(self.index, from, stats) };
+        let query = rn.get_archived_node().vector.as_slice().to_vec();
+        let mut vectors = Vec::with_capacity(to.len() * query.len());
+        for index_pointer in to {
+            let rn = unsafe { Node::read(self.index, *index_pointer, stats) };
+            vectors.extend_from_slice(rn.get_archived_node().vector.as_slice());
+        }
+        backend.get_distances(*distance, &query, &vectors)
+    }
+
     fn get_query_distance_measure(&self, query: PgVector) -> PlainDistanceMeasure {
         return PlainDistanceMeasure::Full(query);
     }
-    fn get_full_distance_for_resort<S: StatsHeapNodeRead + StatsDistanceComparison>(
+    fn get_full_distance_for_resort<
+        S: StatsHeapNodeRead + StatsNodeRead + StatsDistanceComparison,
+    >(
         &self,
         qdm: &Self::QueryDistanceMeasure,
         _index_pointer: IndexPointer,
@@ -222,10 +281,12 @@ impl<'a> Storage for PlainStorage<'a> {
         /* Plain storage only needs to resort when the index is using less dimensions than the underlying data. */
         assert!(meta_page.get_num_dimensions() > meta_page.get_num_dimensions_to_index());
 
-        let slot = unsafe { TableSlot::new(self.heap_rel, heap_pointer, stats) };
+        let mut heap_slot = self.heap_slot.borrow_mut();
+        let slot = heap_slot.get_or_insert_with(|| unsafe { TableSlot::new(self.heap_rel) });
+        unsafe { slot.fetch(self.heap_rel, heap_pointer, stats) };
         match qdm {
             PlainDistanceMeasure::Full(query) => {
-                let datum = unsafe { slot.get_attribute(self.heap_attr).unwrap() };
+                let datum = unsafe { self.indexed_attr.get_datum(slot).unwrap() };
                 let vec = unsafe { PgVector::from_datum(datum, meta_page, false, true) };
                 self.get_distance_function()(vec.to_full_slice(), query.to_full_slice())
             }
@@ -408,4 +469,22 @@ mod tests {
         )?;
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_expression_index() -> spi::Result<()> {
+        crate::access_method::build::tests::test_expression_index_scaffold(
+            "num_neighbors=38, storage_layout = plain, num_dimensions=2",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_partial_index() -> spi::Result<()> {
+        crate::access_method::build::tests::test_partial_index_scaffold("storage_layout = plain")
+    }
+
+    #[pg_test]
+    unsafe fn test_plain_storage_temp_table() -> spi::Result<()> {
+        crate::access_method::build::tests::test_temp_table_scaffold("storage_layout = plain")
+    }
 }
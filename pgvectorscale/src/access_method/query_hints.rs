@@ -0,0 +1,161 @@
+//! Search parameters for a single query.
+//!
+//! `diskann_query_params` returns its query unchanged and remembers the parameters for that
+//! query value. An index scan ordered by the distance to the same value uses them in place of
+//! the session settings:
+//!
+//! ```sql
+//! SELECT * FROM t ORDER BY embedding <=> diskann_query_params($1, search_list_size => 200) LIMIT 10;
+//! ```
+//!
+//! The query is evaluated right before the index scan starts, so the scan finds the parameters
+//! by comparing the bytes of the values. Parameters are only kept for the statement that set
+//! them, and a scan consumes the ones it uses. The parameters are local to the backend, so the
+//! function is parallel unsafe.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use pgrx::{pg_sys, *};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QueryHint {
+    pub search_list_size: Option<usize>,
+    pub rescore: Option<usize>,
+}
+
+struct PendingHints {
+    /* the start of the statement the hints were set in */
+    statement_start: pg_sys::TimestampTz,
+    hints: HashMap<Vec<u8>, QueryHint>,
+}
+
+thread_local! {
+    static PENDING_HINTS: RefCell<PendingHints> = RefCell::new(PendingHints {
+        statement_start: 0,
+        hints: HashMap::new(),
+    });
+}
+
+/// The bytes of a varlena value, without the header.
+unsafe fn query_bytes(query: pg_sys::Datum) -> Vec<u8> {
+    let detoasted = pg_sys::pg_detoast_datum_packed(query.cast_mut_ptr());
+    let bytes = std::slice::from_raw_parts(
+        vardata_any(detoasted) as *const u8,
+        varsize_any_exhdr(detoasted),
+    )
+    .to_vec();
+    if !std::ptr::eq(detoasted, query.cast_mut_ptr()) {
+        pg_sys::pfree(detoasted.cast());
+    }
+    bytes
+}
+
+/// Runs `f` on the hints of the current statement, dropping the ones of earlier statements.
+fn with_pending_hints<T>(f: impl FnOnce(&mut HashMap<Vec<u8>, QueryHint>) -> T) -> T {
+    let statement_start = unsafe { pg_sys::GetCurrentStatementStartTimestamp() };
+    PENDING_HINTS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        if pending.statement_start != statement_start {
+            pending.statement_start = statement_start;
+            pending.hints.clear();
+        }
+        f(&mut pending.hints)
+    })
+}
+
+/// Returns the parameters set with `diskann_query_params` for the query of an index scan.
+pub unsafe fn take_query_hint(query: pg_sys::Datum) -> Option<QueryHint> {
+    with_pending_hints(|hints| {
+        if hints.is_empty() {
+            return None;
+        }
+        hints.remove(&query_bytes(query))
+    })
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+fn num_pending_hints() -> usize {
+    with_pending_hints(|hints| hints.len())
+}
+
+/// Returns `query` unchanged, and makes the index scans ordered by the distance to it use
+/// `search_list_size` and `rescore` instead of the `diskann.query_search_list_size` and
+/// `diskann.query_rescore` settings. NULL keeps the setting. `rescore => 0` disables rescoring.
+#[pg_extern(stable)]
+pub fn diskann_query_params(
+    query: AnyElement,
+    search_list_size: default!(Option<i32>, "NULL"),
+    rescore: default!(Option<i32>, "NULL"),
+) -> AnyElement {
+    if unsafe { pg_sys::get_typlen(query.oid()) } != -1 {
+        error!("the query must be a vector");
+    }
+    if search_list_size.map_or(false, |v| v <= 0) {
+        error!("search_list_size must be positive");
+    }
+    if rescore.map_or(false, |v| v < 0) {
+        error!("rescore must not be negative");
+    }
+    let hint = QueryHint {
+        search_list_size: search_list_size.map(|v| v as usize),
+        rescore: rescore.map(|v| v as usize),
+    };
+    let bytes = unsafe { query_bytes(query.datum()) };
+    with_pending_hints(|hints| hints.insert(bytes, hint));
+    query
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::num_pending_hints;
+
+    #[pg_test]
+    unsafe fn test_query_params() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+
+            SET enable_seqscan = 0;",
+        )?;
+
+        let first = Spi::get_one::<i32>(
+            "SELECT id FROM test
+            ORDER BY embedding <=> diskann_query_params('[100, 0, 1]'::vector,
+                search_list_size => 200, rescore => 50)
+            LIMIT 1",
+        )?;
+        assert_eq!(first, Some(100));
+        /* the scan used the parameters */
+        assert_eq!(num_pending_hints(), 0);
+
+        let count = Spi::get_one::<i64>(
+            "WITH cte AS (
+                SELECT id FROM test
+                ORDER BY embedding <=> diskann_query_params('[100, 0, 1]'::vector,
+                    search_list_size => 2)
+            ) SELECT count(*) FROM cte",
+        )?;
+        assert_eq!(count, Some(300));
+
+        /* the value is returned unchanged, and the parameters wait for a scan */
+        let unchanged = Spi::get_one::<bool>(
+            "SELECT diskann_query_params('[1, 2, 3]'::vector, rescore => 10) = '[1, 2, 3]'::vector",
+        )?;
+        assert_eq!(unchanged, Some(true));
+        assert_eq!(num_pending_hints(), 1);
+        Ok(())
+    }
+
+    #[pg_test(error = "search_list_size must be positive")]
+    unsafe fn test_query_params_invalid() -> spi::Result<()> {
+        Spi::run("SELECT diskann_query_params('[1, 2, 3]'::vector, search_list_size => 0)")?;
+        Ok(())
+    }
+}
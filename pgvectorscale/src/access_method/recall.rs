@@ -0,0 +1,259 @@
+//! Measures the recall of an index against an exact search over the same rows.
+
+use std::time::Instant;
+
+use pgrx::{pg_sys, *};
+use rand::{rngs::SmallRng, Rng, SeedableRng};
+
+use crate::util::{table_slot::TableSlot, HeapPointer};
+
+use super::{
+    guc::{get_query_rescore, get_query_search_list_size},
+    introspection::for_each_live_node,
+    meta_page::MetaPage,
+    pg_vector::PgVector,
+    plain_storage::PlainStorage,
+    sbq::SbqSpeedupStorage,
+    scan::search_heap_pointers,
+    stats::GreedySearchStats,
+    storage::{ArchivedData, Storage, StorageType},
+    storage_common::{open_diskann_index, IndexedAttribute},
+};
+
+const RECALL_SAMPLE_SEED: u64 = 0x2eca11;
+
+/// Heap pointers of all the live nodes of the index and a reservoir sample of them.
+pub fn scan_heap_pointers<S: Storage>(
+    index: &PgRelation,
+    sample_size: usize,
+) -> (Vec<HeapPointer>, Vec<HeapPointer>) {
+    let mut rng = SmallRng::seed_from_u64(RECALL_SAMPLE_SEED);
+    let mut heap_pointers = Vec::new();
+    let mut sample = Vec::new();
+    for_each_live_node::<S>(index, |_, node| {
+        let heap_pointer = node.get_heap_item_pointer();
+        heap_pointers.push(heap_pointer);
+        if heap_pointers.len() <= sample_size {
+            sample.push(heap_pointer);
+        } else {
+            let slot = rng.gen_range(0..heap_pointers.len());
+            if slot < sample_size {
+                sample[slot] = heap_pointer;
+            }
+        }
+    });
+    (heap_pointers, sample)
+}
+
+/// Reads the indexed vectors of the rows of an index.
+pub struct VectorReader<'a> {
+    heap: &'a PgRelation,
+    meta_page: &'a MetaPage,
+    indexed_attr: IndexedAttribute,
+    slot: TableSlot,
+    stats: GreedySearchStats,
+}
+
+impl<'a> VectorReader<'a> {
+    pub fn new(index: &PgRelation, heap: &'a PgRelation, meta_page: &'a MetaPage) -> Self {
+        Self {
+            heap,
+            meta_page,
+            indexed_attr: IndexedAttribute::new(index),
+            slot: unsafe { TableSlot::new(heap) },
+            stats: GreedySearchStats::new(),
+        }
+    }
+
+    pub fn read(&mut self, heap_pointer: HeapPointer) -> Option<PgVector> {
+        unsafe {
+            if !self.slot.fetch(self.heap, heap_pointer, &mut self.stats) {
+                return None;
+            }
+            let datum = self.indexed_attr.get_datum(&self.slot)?;
+            Some(PgVector::from_datum(datum, self.meta_page, true, true))
+        }
+    }
+}
+
+/// The `k` nearest rows of every query, from an exact search over `heap_pointers`.
+fn exact_nearest(
+    reader: &mut VectorReader,
+    heap_pointers: &[HeapPointer],
+    queries: &[Vec<f32>],
+    k: usize,
+) -> Vec<Vec<HeapPointer>> {
+    let distance_fn = reader.meta_page.get_distance_function();
+    /* sorted by distance, at most k entries per query */
+    let mut nearest: Vec<Vec<(f32, HeapPointer)>> = vec![Vec::with_capacity(k + 1); queries.len()];
+    for heap_pointer in heap_pointers {
+        let Some(vector) = reader.read(*heap_pointer) else {
+            continue;
+        };
+        for (query, nearest) in queries.iter().zip(nearest.iter_mut()) {
+            let distance = distance_fn(query, vector.to_full_slice());
+            if nearest.len() == k && distance >= nearest[k - 1].0 {
+                continue;
+            }
+            let position = nearest.partition_point(|(d, _)| *d <= distance);
+            nearest.insert(position, (distance, *heap_pointer));
+            nearest.truncate(k);
+        }
+    }
+    nearest
+        .into_iter()
+        .map(|n| n.into_iter().map(|(_, heap_pointer)| heap_pointer).collect())
+        .collect()
+}
+
+/// Rows of an index sampled as queries, with their exact nearest rows, to measure the recall of
+/// searches with different settings.
+pub struct RecallQueries {
+    queries: Vec<HeapPointer>,
+    expected: Vec<Vec<HeapPointer>>,
+    k: usize,
+}
+
+impl RecallQueries {
+    pub fn sample(
+        index: &PgRelation,
+        heap: &PgRelation,
+        meta_page: &MetaPage,
+        num_queries: usize,
+        k: usize,
+    ) -> Self {
+        let (heap_pointers, sample) = match meta_page.get_storage_type() {
+            StorageType::Plain => scan_heap_pointers::<PlainStorage>(index, num_queries),
+            StorageType::SbqSpeedup | StorageType::SbqCompression => {
+                scan_heap_pointers::<SbqSpeedupStorage>(index, num_queries)
+            }
+        };
+
+        let mut reader = VectorReader::new(index, heap, meta_page);
+        let mut queries = Vec::with_capacity(sample.len());
+        let mut query_vectors = Vec::with_capacity(sample.len());
+        for heap_pointer in sample {
+            let Some(query) = reader.read(heap_pointer) else {
+                continue;
+            };
+            queries.push(heap_pointer);
+            query_vectors.push(query.to_full_slice().to_vec());
+        }
+        if queries.is_empty() {
+            error!("the index \"{}\" is empty", index.name());
+        }
+
+        let expected = exact_nearest(&mut reader, &heap_pointers, &query_vectors, k);
+        Self {
+            queries,
+            expected,
+            k,
+        }
+    }
+
+    /// Runs all the queries through the graph and returns the average recall and the latency
+    /// of every query in milliseconds.
+    pub fn run(
+        &self,
+        index: &PgRelation,
+        heap: &PgRelation,
+        meta_page: &MetaPage,
+        search_list_size: usize,
+        resort_size: usize,
+        stats: &mut GreedySearchStats,
+    ) -> (f64, Vec<f64>) {
+        let mut reader = VectorReader::new(index, heap, meta_page);
+        let mut total_recall = 0.0;
+        let mut latencies = Vec::with_capacity(self.queries.len());
+        for (heap_pointer, expected) in self.queries.iter().zip(self.expected.iter()) {
+            let query = reader
+                .read(*heap_pointer)
+                .expect("sampled query row disappeared");
+            let start = Instant::now();
+            let found = search_heap_pointers(
+                index,
+                heap,
+                query,
+                self.k,
+                search_list_size,
+                resort_size,
+                stats,
+            );
+            latencies.push(start.elapsed().as_secs_f64() * 1000.0);
+
+            let num_found = expected.iter().filter(|hp| found.contains(*hp)).count();
+            total_recall += num_found as f64 / expected.len().max(1) as f64;
+        }
+        (total_recall / self.queries.len() as f64, latencies)
+    }
+}
+
+/// Estimates the recall@k of an index: runs `sample_queries` searches through the graph with
+/// the current search settings, using the vectors of sampled rows as queries, and compares
+/// their first `k` results to an exact search over all the rows of the index. Returns the
+/// average fraction of the exact results found by the graph search.
+///
+/// The exact search reads every row of the index once, so this takes a while on large tables.
+/// Rows deleted since the last vacuum are still in the index and count as well.
+#[pg_extern(volatile)]
+pub fn diskann_estimate_recall(
+    index: pg_sys::Oid,
+    sample_queries: default!(i32, 100),
+    k: default!(i32, 10),
+) -> f64 {
+    if sample_queries <= 0 {
+        error!("sample_queries must be positive");
+    }
+    if k <= 0 {
+        error!("k must be positive");
+    }
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let meta_page = MetaPage::fetch(&index_relation);
+
+    let queries = RecallQueries::sample(
+        &index_relation,
+        &heap_relation,
+        &meta_page,
+        sample_queries as _,
+        k as _,
+    );
+    let (recall, _) = queries.run(
+        &index_relation,
+        &heap_relation,
+        &meta_page,
+        get_query_search_list_size(&index_relation, &meta_page),
+        get_query_rescore(&index_relation, &meta_page),
+        &mut GreedySearchStats::new(),
+    );
+    recall
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_estimate_recall() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+
+            INSERT INTO test(embedding)
+            SELECT ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (num_neighbors = 20, storage_layout = plain);",
+        )?;
+
+        let recall = Spi::get_one::<f64>(
+            "SELECT diskann_estimate_recall('idxtest'::regclass, sample_queries => 20, k => 5)",
+        )?
+        .unwrap();
+        assert!(recall > 0.9, "recall was {}", recall);
+        assert!(recall <= 1.0);
+        Ok(())
+    }
+}
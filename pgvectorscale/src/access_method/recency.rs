@@ -0,0 +1,232 @@
+//! Recency-weighted searches.
+//!
+//! Queries for rows that are both similar and recent would otherwise fetch many more rows than
+//! they need by distance and sort them again by a score that includes their age.
+//! `diskann_search_recent` searches an index with a `timestamp` or `timestamptz` filter column
+//! and returns the rows ordered by their distance plus a penalty that grows with their age, up
+//! to `weight`:
+//!
+//! `distance + weight * (1 - 2 ^ (-age / half_life))`
+//!
+//! The age is measured from the start of the transaction, and rows with a NULL timestamp get the
+//! whole penalty. The penalty is never negative, so the search returns a row as soon as the
+//! distances of the rows still to come reach its score, and only holds the rows within `weight`
+//! of the last distance. Index scans, and so the order of the `<=>` operator, are not affected.
+
+use pgrx::{pg_sys, *};
+
+use crate::util::HeapPointer;
+
+use super::{
+    meta_page::MetaPage,
+    payload::find_timestamp_column,
+    pg_vector::PgVector,
+    scan::search_heap_pointers_recent,
+    search::SearchParams,
+    stats::GreedySearchStats,
+    storage_common::{check_query_type, open_diskann_index},
+};
+
+const MICROSECONDS_PER_SECOND: f64 = 1_000_000.0;
+const MICROSECONDS_PER_DAY: f64 = 86_400.0 * MICROSECONDS_PER_SECOND;
+/* like Postgres, count 30 days per month in the interval of the half-life */
+const DAYS_PER_MONTH: f64 = 30.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RecencyDecay {
+    /* the payload column holding the timestamps */
+    column: usize,
+    /* the start of the transaction, as a timestamp of the column */
+    now: i64,
+    half_life: f64,
+    weight: f32,
+}
+
+impl RecencyDecay {
+    /// The decay of the searches of `index` with a `half_life` in microseconds, which must
+    /// have a timestamp column.
+    pub fn new(index: &PgRelation, half_life: f64, weight: f32) -> Self {
+        let Some((column, type_oid)) = find_timestamp_column(index) else {
+            error!(
+                "index \"{}\" has no timestamp or timestamptz column",
+                index.name()
+            );
+        };
+
+        let now = unsafe { pg_sys::GetCurrentTransactionStartTimestamp() };
+        /* a timestamp without time zone holds the local time */
+        let now = if type_oid == pg_sys::TIMESTAMPOID {
+            unsafe {
+                direct_function_call::<i64>(pg_sys::timestamptz_timestamp, &[now.into_datum()])
+            }
+            .unwrap()
+        } else {
+            now
+        };
+        Self::with_settings(column, now, half_life, weight)
+    }
+
+    fn with_settings(column: usize, now: i64, half_life: f64, weight: f32) -> Self {
+        Self {
+            column,
+            now,
+            half_life,
+            weight,
+        }
+    }
+
+    /// The payload column holding the timestamps.
+    pub fn get_column(&self) -> usize {
+        self.column
+    }
+
+    /// The penalty of a row with `timestamp`. Rows from the future have none.
+    pub fn get_penalty(&self, timestamp: Option<i64>) -> f32 {
+        let Some(timestamp) = timestamp else {
+            return self.weight;
+        };
+        let age = self.now.saturating_sub(timestamp).max(0) as f64;
+        (self.weight as f64 * (1.0 - 0.5f64.powf(age / self.half_life))) as f32
+    }
+}
+
+/// Returns the `k` rows of the table with the smallest score found by the index, smallest
+/// first: their distance to `query` plus a penalty for their age, which reaches half of `weight`
+/// at the age `half_life` and tends to `weight`. The distance is the one `diskann_search`
+/// returns, and the age is the one of the first `timestamp` or `timestamptz` filter column of
+/// the index. The search settings can be given in `params` like for `diskann_search`:
+///
+/// `SELECT * FROM diskann_search_recent('idx'::regclass, '[1,2,3]'::vector, 10, '7 days', 0.1);`
+///
+/// Index scans keep ordering the rows by their distance alone.
+#[pg_extern(volatile)]
+pub fn diskann_search_recent(
+    index: pg_sys::Oid,
+    query: AnyElement,
+    k: i32,
+    half_life: Interval,
+    weight: default!(f64, 0.1),
+    params: default!(Option<JsonB>, "NULL"),
+) -> TableIterator<'static, (name!(ctid, pg_sys::ItemPointerData), name!(score, f64))> {
+    if k <= 0 {
+        error!("k must be positive");
+    }
+    let half_life = half_life.months() as f64 * DAYS_PER_MONTH * MICROSECONDS_PER_DAY
+        + half_life.days() as f64 * MICROSECONDS_PER_DAY
+        + half_life.micros() as f64;
+    if half_life <= 0.0 {
+        error!("half_life must be positive");
+    }
+    if !(weight >= 0.0 && weight.is_finite()) {
+        error!("weight must be a non-negative number");
+    }
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let meta_page = MetaPage::fetch(&index_relation);
+    let params = SearchParams::new(&index_relation, &meta_page, params);
+    let recency = RecencyDecay::new(&index_relation, half_life, weight as f32);
+
+    check_query_type(&index_relation, &query);
+    let query = unsafe { PgVector::from_datum(query.datum(), &meta_page, true, true) };
+    let rows: Vec<_> = search_heap_pointers_recent(
+        &index_relation,
+        &heap_relation,
+        query,
+        recency,
+        k as _,
+        params.search_list_size,
+        params.rescore,
+        &mut GreedySearchStats::new(),
+    )
+    .into_iter()
+    .map(|(heap_pointer, score): (HeapPointer, f32)| {
+        let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
+            ..Default::default()
+        };
+        heap_pointer.to_item_pointer_data(&mut ctid);
+        (ctid, score as f64)
+    })
+    .collect();
+    TableIterator::new(rows)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::{RecencyDecay, MICROSECONDS_PER_SECOND};
+
+    #[pg_test]
+    fn test_recency_penalty() {
+        let day = 86400.0 * MICROSECONDS_PER_SECOND;
+        let now = 1000 * day as i64;
+        let decay = RecencyDecay::with_settings(0, now, day, 0.2);
+        assert_eq!(decay.get_penalty(Some(now)), 0.0);
+        assert_eq!(decay.get_penalty(Some(now + day as i64)), 0.0);
+        assert!((decay.get_penalty(Some(now - day as i64)) - 0.1).abs() < 1e-6);
+        assert!((decay.get_penalty(Some(now - 2 * day as i64)) - 0.15).abs() < 1e-6);
+        assert_eq!(decay.get_penalty(None), 0.2);
+        assert!(decay.get_penalty(Some(i64::MIN)) <= 0.2);
+    }
+
+    #[pg_test]
+    unsafe fn test_search_recent() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3), created_at timestamptz);
+
+            INSERT INTO test(id, embedding, created_at)
+            SELECT i, ARRAY[1, i * 0.01, 0]::vector, now() - i * interval '1 day'
+            FROM generate_series(1, 300) i;
+            INSERT INTO test(id, embedding, created_at) VALUES (0, '[1, 0.5, 0]', now());
+
+            CREATE INDEX idxtest ON test USING diskann(embedding, created_at);
+            SET enable_seqscan = 0;",
+        )?;
+
+        /* by distance, the newest row is far down */
+        let first =
+            Spi::get_one::<i32>("SELECT id FROM test ORDER BY embedding <=> '[1, 0, 0]' LIMIT 1")?;
+        assert_eq!(first, Some(1));
+
+        /* with a short half-life, it comes first */
+        let (first, in_order) = Spi::get_two::<i32, bool>(
+            "WITH s AS (
+                SELECT t.id, s.score, s.n
+                FROM diskann_search_recent('idxtest'::regclass, '[1, 0, 0]'::vector, 400,
+                    '1 hour', 1) WITH ORDINALITY AS s(ctid, score, n)
+                JOIN test t ON t.ctid = s.ctid
+            ) SELECT (SELECT id FROM s WHERE n = 1),
+                bool_and(score >= coalesce((SELECT p.score FROM s p WHERE p.n = s.n - 1), 0))
+            FROM s",
+        )?;
+        assert_eq!(first, Some(0));
+        assert_eq!(in_order, Some(true));
+
+        /* all the rows are still returned */
+        let count = Spi::get_one::<i64>(
+            "SELECT count(*)
+            FROM diskann_search_recent('idxtest'::regclass, '[1, 0, 0]'::vector, 400, '1 hour', 1)",
+        )?;
+        assert_eq!(count, Some(301));
+
+        /* and index scans still order the rows by distance */
+        let first =
+            Spi::get_one::<i32>("SELECT id FROM test ORDER BY embedding <=> '[1, 0, 0]' LIMIT 1")?;
+        assert_eq!(first, Some(1));
+        Ok(())
+    }
+
+    #[pg_test(error = "index \"idxtest\" has no timestamp or timestamptz column")]
+    unsafe fn test_search_recent_no_timestamp() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test SELECT ARRAY[i, 1, 1]::vector FROM generate_series(1, 10) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            SELECT * FROM diskann_search_recent('idxtest'::regclass, '[1, 1, 1]'::vector, 5, '1 day');",
+        )?;
+        Ok(())
+    }
+}
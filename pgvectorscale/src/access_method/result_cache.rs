@@ -0,0 +1,415 @@
+//! A cache of the results of `diskann_search`, shared by the backends.
+//!
+//! Workloads that repeat the same searches, e.g. the "related items" of popular rows, walk the
+//! graph again for every query. With `diskann.result_cache` on, `diskann_search` keeps the
+//! rows it found in a least recently used cache in shared memory, keyed by the database and the
+//! oid of the index, the query vector, `k` and the search parameters, and a repeated search reads
+//! the rows from the cache. Lookups only take the lock of the cache in shared mode.
+//!
+//! The cache is only available when the library is loaded with `shared_preload_libraries`,
+//! because the shared memory has to be reserved when the server starts. Any change to an index,
+//! i.e. an insert, a vacuum or a rebuild, invalidates its cached results, and a cached result is
+//! only used if all its rows are still visible to the snapshot of the query. Inserts invalidate
+//! the results when their transaction commits, and the results of searches whose snapshots
+//! don't see the last committed insert yet are not cached. A cached result is only used for
+//! snapshots that are not older than the snapshot of the search that found it.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use pgrx::{
+    pg_shmem_init, pg_sys, register_xact_callback, PGRXSharedMemory, PgLwLock, PgRelation,
+    PgXactCallbackEvent,
+};
+
+use crate::util::HeapPointer;
+
+use super::{guc::TSV_RESULT_CACHE, storage_common::SharedIndexId};
+
+/// The number of results cached.
+const NUM_CACHED_RESULTS: usize = 256;
+
+/// The largest `k` whose results are cached.
+pub const MAX_CACHED_K: usize = 100;
+
+/// The number of change counters of the indexes. Indexes whose databases and oids map to the
+/// same counter invalidate each other's results.
+const NUM_GENERATIONS: usize = 64;
+
+fn get_generation_slot(index: SharedIndexId) -> usize {
+    (index.database.as_u32() as usize)
+        .wrapping_mul(31)
+        .wrapping_add(index.index.as_u32() as usize)
+        % NUM_GENERATIONS
+}
+
+struct CachedResult {
+    index: SharedIndexId,
+    /* the hash of the query vector, k and the search parameters */
+    key: u64,
+    /* the change counter of the index when the result was cached */
+    generation: u64,
+    /* the xmin of the snapshot of the search */
+    xmin: pg_sys::TransactionId,
+    /* the clock of the last lookup or insert, updated under the shared lock */
+    last_used: AtomicU64,
+    num_rows: usize,
+    rows: [HeapPointer; MAX_CACHED_K],
+}
+
+impl Default for CachedResult {
+    fn default() -> Self {
+        Self {
+            index: SharedIndexId::INVALID,
+            key: 0,
+            generation: 0,
+            xmin: pg_sys::InvalidTransactionId,
+            last_used: AtomicU64::new(0),
+            num_rows: 0,
+            rows: [HeapPointer::new(pg_sys::InvalidBlockNumber, 0); MAX_CACHED_K],
+        }
+    }
+}
+
+pub struct ResultCache {
+    /* the change counters of the indexes, updated under the exclusive lock */
+    generations: [AtomicU64; NUM_GENERATIONS],
+    /* the last transaction whose inserts invalidated the results of the indexes */
+    last_writers: [AtomicU32; NUM_GENERATIONS],
+    /* incremented under the shared lock */
+    clock: AtomicU64,
+    results: [CachedResult; NUM_CACHED_RESULTS],
+}
+
+impl Default for ResultCache {
+    fn default() -> Self {
+        Self {
+            generations: std::array::from_fn(|_| AtomicU64::new(0)),
+            last_writers: std::array::from_fn(|_| AtomicU32::new(pg_sys::InvalidTransactionId)),
+            clock: AtomicU64::new(0),
+            results: std::array::from_fn(|_| CachedResult::default()),
+        }
+    }
+}
+
+unsafe impl PGRXSharedMemory for ResultCache {}
+
+impl ResultCache {
+    fn get_generation(&self, index: SharedIndexId) -> u64 {
+        self.generations[get_generation_slot(index)].load(Ordering::Acquire)
+    }
+
+    /// Invalidates the cached results of `index`, changed by the committed transaction `writer`,
+    /// or by a vacuum or a rebuild if it is invalid.
+    fn invalidate(&mut self, index: SharedIndexId, writer: pg_sys::TransactionId) {
+        let slot = get_generation_slot(index);
+        let last_writer = self.last_writers[slot].load(Ordering::Acquire);
+        if writer != pg_sys::InvalidTransactionId
+            && unsafe { pg_sys::TransactionIdPrecedes(last_writer, writer) }
+        {
+            self.last_writers[slot].store(writer, Ordering::Release);
+        }
+        self.generations[slot].fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Whether a snapshot with `xmin` sees the inserts of the last transaction that invalidated
+    /// the results of `index`, i.e. that transaction had ended when the snapshot was taken.
+    fn sees_last_writer(&self, index: SharedIndexId, xmin: pg_sys::TransactionId) -> bool {
+        let last_writer = self.last_writers[get_generation_slot(index)].load(Ordering::Acquire);
+        unsafe { pg_sys::TransactionIdPrecedes(last_writer, xmin) }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn lookup(
+        &self,
+        index: SharedIndexId,
+        key: u64,
+        xmin: pg_sys::TransactionId,
+    ) -> Option<Vec<HeapPointer>> {
+        let generation = self.get_generation(index);
+        let result = self.results.iter().find(|r| {
+            r.index == index
+                && r.key == key
+                && r.generation == generation
+                && unsafe { pg_sys::TransactionIdPrecedesOrEquals(r.xmin, xmin) }
+        })?;
+        result.last_used.store(self.tick(), Ordering::Relaxed);
+        Some(result.rows[..result.num_rows].to_vec())
+    }
+
+    /// Caches `rows`, found by a search with a snapshot with `xmin`, in place of the least
+    /// recently used result.
+    fn insert(
+        &mut self,
+        index: SharedIndexId,
+        key: u64,
+        generation: u64,
+        xmin: pg_sys::TransactionId,
+        rows: &[HeapPointer],
+    ) {
+        if rows.len() > MAX_CACHED_K
+            || generation != self.get_generation(index)
+            || !self.sees_last_writer(index, xmin)
+        {
+            return;
+        }
+        let clock = self.tick();
+        let slot = match self
+            .results
+            .iter()
+            .position(|r| r.index == index && r.key == key)
+        {
+            Some(position) => position,
+            None => {
+                let mut oldest = 0;
+                let last_used = |result: &CachedResult| result.last_used.load(Ordering::Relaxed);
+                for (i, result) in self.results.iter().enumerate() {
+                    if last_used(result) < last_used(&self.results[oldest]) {
+                        oldest = i;
+                    }
+                }
+                oldest
+            }
+        };
+        let result = &mut self.results[slot];
+        result.index = index;
+        result.key = key;
+        result.generation = generation;
+        result.xmin = xmin;
+        result.last_used.store(clock, Ordering::Relaxed);
+        result.num_rows = rows.len();
+        result.rows[..rows.len()].copy_from_slice(rows);
+    }
+
+    /// Drops the cached result of a query, e.g. when one of its rows is no longer visible.
+    fn remove(&mut self, index: SharedIndexId, key: u64) {
+        for result in self.results.iter_mut() {
+            if result.index == index && result.key == key {
+                *result = CachedResult::default();
+            }
+        }
+    }
+}
+
+static RESULT_CACHE: PgLwLock<ResultCache> = PgLwLock::new();
+
+thread_local! {
+    /// The indexes the current transaction inserted into, whose cached results are invalidated
+    /// when it commits.
+    static PENDING_INVALIDATIONS: RefCell<Vec<SharedIndexId>> = RefCell::new(Vec::new());
+}
+
+/* whether the shared memory of the cache was reserved, i.e. the library is preloaded */
+static RESULT_CACHE_AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+/// Reserves the shared memory of the cache. Only called while the library is preloaded.
+pub fn init() {
+    pg_shmem_init!(RESULT_CACHE);
+    RESULT_CACHE_AVAILABLE.store(true, Ordering::Relaxed);
+}
+
+fn is_enabled() -> bool {
+    RESULT_CACHE_AVAILABLE.load(Ordering::Relaxed) && TSV_RESULT_CACHE.get()
+}
+
+/// Whether the current transaction inserted into `index`. Its searches see rows the other
+/// backends don't, so they neither read nor cache results.
+fn is_pending(index: SharedIndexId) -> bool {
+    PENDING_INVALIDATIONS.with(|pending| pending.borrow().contains(&index))
+}
+
+fn get_snapshot_xmin() -> pg_sys::TransactionId {
+    unsafe { (*pg_sys::GetActiveSnapshot()).xmin }
+}
+
+/// The key of a search of the `k` nearest rows to the vector `query`.
+pub fn get_key(query: &[f32], k: usize, search_list_size: usize, rescore: usize) -> u64 {
+    /* FNV-1a */
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut add = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    add(&(query.len() as u64).to_le_bytes());
+    for value in query {
+        add(&value.to_le_bytes());
+    }
+    add(&(k as u64).to_le_bytes());
+    add(&(search_list_size as u64).to_le_bytes());
+    add(&(rescore as u64).to_le_bytes());
+    hash
+}
+
+/// The cached rows of a search, None if it isn't cached or a row is no longer visible.
+pub fn lookup(index: &PgRelation, heap: &PgRelation, key: u64) -> Option<Vec<HeapPointer>> {
+    if !is_enabled() {
+        return None;
+    }
+    let index = SharedIndexId::of(index);
+    if is_pending(index) {
+        return None;
+    }
+    let rows = RESULT_CACHE
+        .share()
+        .lookup(index, key, get_snapshot_xmin())?;
+    let visible = rows
+        .iter()
+        .all(|heap_pointer| unsafe { is_visible(heap, *heap_pointer) });
+    if !visible {
+        RESULT_CACHE.exclusive().remove(index, key);
+        return None;
+    }
+    Some(rows)
+}
+
+/// The change counter of `index`, to pass to `insert` with the result of a search started after
+/// reading it.
+pub fn get_generation(index: &PgRelation) -> Option<u64> {
+    if !is_enabled() {
+        return None;
+    }
+    let index = SharedIndexId::of(index);
+    Some(RESULT_CACHE.share().get_generation(index))
+}
+
+/// Caches the rows found by a search, unless the index changed since `generation`.
+pub fn insert(index: &PgRelation, key: u64, generation: Option<u64>, rows: &[HeapPointer]) {
+    let Some(generation) = generation else {
+        return;
+    };
+    let index = SharedIndexId::of(index);
+    if is_pending(index) {
+        return;
+    }
+    RESULT_CACHE
+        .exclusive()
+        .insert(index, key, generation, get_snapshot_xmin(), rows);
+}
+
+/// Invalidates the cached results of `index`, which a vacuum or a rebuild changed.
+pub fn invalidate(index: &PgRelation) {
+    if !RESULT_CACHE_AVAILABLE.load(Ordering::Relaxed) {
+        return;
+    }
+    RESULT_CACHE
+        .exclusive()
+        .invalidate(SharedIndexId::of(index), pg_sys::InvalidTransactionId);
+}
+
+/// Invalidates the cached results of `index`, which the current transaction inserted into,
+/// when the transaction commits. Until then the other backends don't see the inserts.
+pub fn invalidate_at_commit(index: &PgRelation) {
+    if !RESULT_CACHE_AVAILABLE.load(Ordering::Relaxed) {
+        return;
+    }
+    let index = SharedIndexId::of(index);
+    PENDING_INVALIDATIONS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        if pending.is_empty() {
+            /* a prepared transaction is committed by another backend, so invalidate then */
+            register_xact_callback(PgXactCallbackEvent::Commit, invalidate_pending);
+            register_xact_callback(PgXactCallbackEvent::Prepare, invalidate_pending);
+            register_xact_callback(PgXactCallbackEvent::Abort, || {
+                PENDING_INVALIDATIONS.with(|pending| pending.borrow_mut().clear())
+            });
+        }
+        if !pending.contains(&index) {
+            pending.push(index);
+        }
+    });
+}
+
+fn invalidate_pending() {
+    let pending = PENDING_INVALIDATIONS.with(|pending| pending.take());
+    let writer = unsafe { pg_sys::GetTopTransactionIdIfAny() };
+    let mut cache = RESULT_CACHE.exclusive();
+    for index in pending {
+        cache.invalidate(index, writer);
+    }
+}
+
+/// Whether the row at `heap_pointer` is visible to the snapshot of the query.
+unsafe fn is_visible(heap: &PgRelation, heap_pointer: HeapPointer) -> bool {
+    let mut ctid = pg_sys::ItemPointerData {
+        ..Default::default()
+    };
+    heap_pointer.to_item_pointer_data(&mut ctid);
+    let slot = pg_sys::table_slot_create(heap.as_ptr(), std::ptr::null_mut());
+    let fetch_row_version = (*heap.rd_tableam).tuple_fetch_row_version.unwrap();
+    let visible = fetch_row_version(heap.as_ptr(), &mut ctid, pg_sys::GetActiveSnapshot(), slot);
+    pg_sys::ExecDropSingleTupleTableSlot(slot);
+    visible
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::{get_key, ResultCache, SharedIndexId, NUM_CACHED_RESULTS};
+    use crate::util::HeapPointer;
+
+    const XMIN: pg_sys::TransactionId = 1000;
+
+    #[pg_test]
+    fn test_result_cache() {
+        let mut cache = Box::<ResultCache>::default();
+        let index = SharedIndexId {
+            database: pg_sys::Oid::from(5),
+            index: pg_sys::Oid::from(16384),
+        };
+        let rows = vec![HeapPointer::new(1, 1), HeapPointer::new(2, 3)];
+        let key = get_key(&[1.0, 2.0, 3.0], 10, 100, 50);
+        assert_ne!(key, get_key(&[1.0, 2.0, 3.0], 11, 100, 50));
+
+        cache.insert(index, key, cache.get_generation(index), XMIN, &rows);
+        assert_eq!(cache.lookup(index, key, XMIN), Some(rows.clone()));
+        let other_index = SharedIndexId {
+            index: pg_sys::Oid::from(16385),
+            ..index
+        };
+        assert_eq!(cache.lookup(other_index, key, XMIN), None);
+        /* an index of another database with the same oid is another index */
+        let other_database = SharedIndexId {
+            database: pg_sys::Oid::from(16386),
+            ..index
+        };
+        assert_eq!(cache.lookup(other_database, key, XMIN), None);
+
+        /* a change to the index invalidates its results */
+        let generation = cache.get_generation(index);
+        cache.invalidate(index, pg_sys::InvalidTransactionId);
+        assert_eq!(cache.lookup(index, key, XMIN), None);
+        /* and a search started before the change is not cached */
+        cache.insert(index, key, generation, XMIN, &rows);
+        assert_eq!(cache.lookup(index, key, XMIN), None);
+
+        /* nor a search whose snapshot doesn't see the last committed insert */
+        let generation = cache.get_generation(index);
+        cache.invalidate(index, XMIN + 10);
+        let generation_after_insert = cache.get_generation(index);
+        assert_ne!(generation, generation_after_insert);
+        cache.insert(index, key, generation_after_insert, XMIN + 10, &rows);
+        assert_eq!(cache.lookup(index, key, XMIN + 20), None);
+        cache.insert(index, key, generation_after_insert, XMIN + 11, &rows);
+        assert_eq!(cache.lookup(index, key, XMIN + 20), Some(rows.clone()));
+        /* a result is not used for older snapshots */
+        assert_eq!(cache.lookup(index, key, XMIN + 10), None);
+
+        /* the least recently used result is evicted */
+        let generation = cache.get_generation(index);
+        let xmin = XMIN + 11;
+        for i in 0..NUM_CACHED_RESULTS as u64 {
+            cache.insert(index, i, generation, xmin, &rows);
+        }
+        assert!(cache.lookup(index, 0, xmin).is_some());
+        cache.insert(index, NUM_CACHED_RESULTS as u64, generation, xmin, &rows);
+        assert!(cache.lookup(index, 0, xmin).is_some());
+        assert_eq!(cache.lookup(index, 1, xmin), None);
+    }
+}
@@ -1,14 +1,17 @@
 use super::{
     distance::distance_xor_optimized,
+    full_vectors::{FullVectorStore, FULL_VECTOR_POINTER_SIZE},
     graph::{ListSearchNeighbor, ListSearchResult},
     graph_neighbor_store::GraphNeighborStore,
+    neighbor_compression::{NeighborBlock, NeighborEncoding, PACKED_BYTES_PER_NEIGHBOR},
+    payload::NodePayload,
     pg_vector::PgVector,
     stats::{
         GreedySearchStats, StatsDistanceComparison, StatsHeapNodeRead, StatsNodeModify,
         StatsNodeRead, StatsNodeWrite, WriteStats,
     },
-    storage::{ArchivedData, NodeDistanceMeasure, Storage},
-    storage_common::get_attribute_number_from_index,
+    storage::{ArchivedData, NodeDistanceMeasure, Storage, StorageType},
+    storage_common::IndexedAttribute,
 };
 use std::{cell::RefCell, collections::HashMap, iter::once, marker::PhantomData, pin::Pin};
 
@@ -23,11 +26,20 @@ use crate::util::{
     IndexPointer, ItemPointer, ReadableBuffer,
 };
 
-use super::{meta_page::MetaPage, neighbor_with_distance::NeighborWithDistance};
+use super::{
+    build_checkpoint::QuantizerState, meta_page::MetaPage,
+    neighbor_with_distance::NeighborWithDistance,
+};
 use crate::util::WritableBuffer;
 
-type SbqVectorElement = u64;
-const BITS_STORE_TYPE_SIZE: usize = 64;
+/* The quantizer lives in the diskann module, which doesn't depend on Postgres. */
+pub use crate::diskann::sbq::SbqQuantizer;
+use crate::diskann::sbq::SbqVectorElement;
+
+#[cfg(feature = "build_offload")]
+use super::guc::get_build_offload_backend;
+#[cfg(feature = "build_offload")]
+use crate::diskann::offload::BatchedTraining;
 
 #[derive(Archive, Deserialize, Serialize, Readable, Writeable)]
 #[archive(check_bytes)]
@@ -44,7 +56,7 @@ impl SbqMeans {
         meta_page: &super::meta_page::MetaPage,
         stats: &mut S,
     ) -> SbqQuantizer {
-        let mut quantizer = SbqQuantizer::new(meta_page);
+        let mut quantizer = SbqQuantizer::new(meta_page.get_bq_num_bits_per_dimension());
         if quantizer.use_mean {
             if meta_page.get_quantizer_metadata_pointer().is_none() {
                 pgrx::error!("No SBQ pointer found in meta page");
@@ -79,167 +91,10 @@ impl SbqMeans {
     }
 }
 
-#[derive(Clone)]
-pub struct SbqQuantizer {
-    pub use_mean: bool,
-    training: bool,
-    pub count: u64,
-    pub mean: Vec<f32>,
-    pub m2: Vec<f32>,
-    pub num_bits_per_dimension: u8,
-}
-
-impl SbqQuantizer {
-    fn new(meta_page: &super::meta_page::MetaPage) -> SbqQuantizer {
-        Self {
-            use_mean: true,
-            training: false,
-            count: 0,
-            mean: vec![],
-            m2: vec![],
-            num_bits_per_dimension: meta_page.get_bq_num_bits_per_dimension(),
-        }
-    }
-
-    fn load(&mut self, count: u64, mean: Vec<f32>, m2: Vec<f32>) {
-        self.count = count;
-        self.mean = mean;
-        self.m2 = m2
-    }
-
-    fn quantized_size(&self, full_vector_size: usize) -> usize {
-        Self::quantized_size_internal(full_vector_size, self.num_bits_per_dimension)
-    }
-
-    fn quantized_size_internal(full_vector_size: usize, num_bits_per_dimension: u8) -> usize {
-        let num_bits = full_vector_size * num_bits_per_dimension as usize;
-
-        if num_bits % BITS_STORE_TYPE_SIZE == 0 {
-            num_bits / BITS_STORE_TYPE_SIZE
-        } else {
-            (num_bits / BITS_STORE_TYPE_SIZE) + 1
-        }
-    }
-
-    fn quantized_size_bytes(num_dimensions: usize, num_bits_per_dimension: u8) -> usize {
-        Self::quantized_size_internal(num_dimensions, num_bits_per_dimension)
-            * std::mem::size_of::<SbqVectorElement>()
-    }
-
-    fn quantize(&self, full_vector: &[f32]) -> Vec<SbqVectorElement> {
-        assert!(!self.training);
-        if self.use_mean {
-            let mut res_vector = vec![0; self.quantized_size(full_vector.len())];
-
-            if self.num_bits_per_dimension == 1 {
-                for (i, &v) in full_vector.iter().enumerate() {
-                    if v > self.mean[i] {
-                        res_vector[i / BITS_STORE_TYPE_SIZE] |= 1 << (i % BITS_STORE_TYPE_SIZE);
-                    }
-                }
-            } else {
-                for (i, &v) in full_vector.iter().enumerate() {
-                    let mean = self.mean[i];
-                    let variance = self.m2[i] / self.count as f32;
-                    let std_dev = variance.sqrt();
-                    let ranges = self.num_bits_per_dimension + 1;
-
-                    let v_z_score = (v - mean) / std_dev;
-                    let index = (v_z_score + 2.0) / (4.0 / ranges as f32); //we consider z scores between -2 and 2 and divide them into {ranges} ranges
-
-                    let bit_position = i * self.num_bits_per_dimension as usize;
-                    if index < 1.0 {
-                        //all zeros
-                    } else {
-                        let count_ones =
-                            (index.floor() as usize).min(self.num_bits_per_dimension as usize);
-                        //fill in count_ones bits from the left
-                        // ex count_ones=1: 100
-                        // ex count_ones=2: 110
-                        // ex count_ones=3: 111
-                        for j in 0..count_ones {
-                            res_vector[(bit_position + j) / BITS_STORE_TYPE_SIZE] |=
-                                1 << ((bit_position + j) % BITS_STORE_TYPE_SIZE);
-                        }
-                    }
-                }
-            }
-            res_vector
-        } else {
-            let mut res_vector = vec![0; self.quantized_size(full_vector.len())];
-
-            for (i, &v) in full_vector.iter().enumerate() {
-                if v > 0.0 {
-                    res_vector[i / BITS_STORE_TYPE_SIZE] |= 1 << (i % BITS_STORE_TYPE_SIZE);
-                }
-            }
-
-            res_vector
-        }
-    }
-
-    fn start_training(&mut self, meta_page: &super::meta_page::MetaPage) {
-        self.training = true;
-        if self.use_mean {
-            self.count = 0;
-            self.mean = vec![0.0; meta_page.get_num_dimensions_to_index() as _];
-            if self.num_bits_per_dimension > 1 {
-                self.m2 = vec![0.0; meta_page.get_num_dimensions_to_index() as _];
-            }
-        }
-    }
-
-    fn add_sample(&mut self, sample: &[f32]) {
-        if self.use_mean {
-            self.count += 1;
-            assert!(self.mean.len() == sample.len());
-
-            if self.num_bits_per_dimension > 1 {
-                assert!(self.m2.len() == sample.len());
-                let delta: Vec<_> = self
-                    .mean
-                    .iter()
-                    .zip(sample.iter())
-                    .map(|(m, s)| s - *m)
-                    .collect();
-
-                self.mean
-                    .iter_mut()
-                    .zip(sample.iter())
-                    .for_each(|(m, s)| *m += (s - *m) / self.count as f32);
-
-                let delta2 = self.mean.iter().zip(sample.iter()).map(|(m, s)| s - *m);
-
-                self.m2
-                    .iter_mut()
-                    .zip(delta.iter())
-                    .zip(delta2)
-                    .for_each(|((m2, d), d2)| *m2 += d * d2);
-            } else {
-                self.mean
-                    .iter_mut()
-                    .zip(sample.iter())
-                    .for_each(|(m, s)| *m += (s - *m) / self.count as f32);
-            }
-        }
-    }
-
-    fn finish_training(&mut self) {
-        self.training = false;
-    }
-
-    fn vector_for_new_node(
-        &self,
-        _meta_page: &super::meta_page::MetaPage,
-        full_vector: &[f32],
-    ) -> Vec<SbqVectorElement> {
-        self.quantize(&full_vector)
-    }
-}
-
 pub struct SbqSearchDistanceMeasure {
     quantized_vector: Vec<SbqVectorElement>,
-    query: PgVector,
+    /* None if the query was given as codes, then it can't be rescored */
+    query: Option<PgVector>,
     num_dimensions_for_neighbors: usize,
     quantized_dimensions: usize,
 }
@@ -252,12 +107,33 @@ impl SbqSearchDistanceMeasure {
     ) -> SbqSearchDistanceMeasure {
         SbqSearchDistanceMeasure {
             quantized_vector: quantizer.quantize(query.to_index_slice()),
-            query,
+            query: Some(query),
             num_dimensions_for_neighbors,
             quantized_dimensions: quantizer.quantized_size(num_dimensions_for_neighbors),
         }
     }
 
+    /// A query given as the codes of a vector, see `SbqQuantizer::quantize`. Only the distances
+    /// of the codes are known, so the results can't be rescored.
+    pub fn with_codes(
+        quantizer: &SbqQuantizer,
+        codes: Vec<SbqVectorElement>,
+        num_dimensions_for_neighbors: usize,
+    ) -> SbqSearchDistanceMeasure {
+        SbqSearchDistanceMeasure {
+            quantized_vector: codes,
+            query: None,
+            num_dimensions_for_neighbors,
+            quantized_dimensions: quantizer.quantized_size(num_dimensions_for_neighbors),
+        }
+    }
+
+    fn get_query(&self) -> &PgVector {
+        self.query
+            .as_ref()
+            .expect("a query given as codes can't be rescored")
+    }
+
     pub fn calculate_bq_distance<S: StatsDistanceComparison>(
         &self,
         bq_vector: &[SbqVectorElement],
@@ -352,6 +228,20 @@ impl QuantizedVectorCache {
             })
     }
 
+    fn clear(&mut self) {
+        self.quantized_vector_map.clear();
+    }
+
+    /// An estimate of the memory held by the cache. All the cached vectors have the same size.
+    fn get_memory_bytes(&self) -> usize {
+        let vector_bytes = self.quantized_vector_map.values().next().map_or(0, |v| {
+            v.capacity() * std::mem::size_of::<SbqVectorElement>()
+        });
+        self.quantized_vector_map.capacity()
+            * (std::mem::size_of::<ItemPointer>() + std::mem::size_of::<Vec<SbqVectorElement>>())
+            + self.quantized_vector_map.len() * vector_bytes
+    }
+
     fn must_get(&self, index_pointer: IndexPointer) -> &[SbqVectorElement] {
         self.quantized_vector_map.get(&index_pointer).unwrap()
     }
@@ -376,9 +266,22 @@ pub struct SbqSpeedupStorage<'a> {
     pub distance_fn: fn(&[f32], &[f32]) -> f32,
     quantizer: SbqQuantizer,
     heap_rel: &'a PgRelation,
-    heap_attr: pgrx::pg_sys::AttrNumber,
+    indexed_attr: IndexedAttribute,
     qv_cache: RefCell<QuantizedVectorCache>,
     num_dimensions_for_neighbors: usize,
+    /* reused across heap fetches to avoid creating a slot for every rescored candidate */
+    heap_slot: RefCell<Option<TableSlot>>,
+    /* where the neighbors are stored if the index compresses them */
+    neighbor_block: Option<NeighborBlock>,
+    /* where the full vectors are stored if the index stores them */
+    full_vectors: Option<FullVectorStore>,
+    /* the tape of the full vectors, created by the first node written */
+    full_vector_tape: RefCell<Option<Tape<'a>>>,
+    /* whether the full vectors are written after the graph, by the build */
+    defer_full_vectors: bool,
+    /* the training of the quantizer by the backend of diskann.build_offload */
+    #[cfg(feature = "build_offload")]
+    offloaded_training: Option<BatchedTraining>,
 }
 
 impl<'a> SbqSpeedupStorage<'a> {
@@ -390,11 +293,18 @@ impl<'a> SbqSpeedupStorage<'a> {
         Self {
             index: index,
             distance_fn: meta_page.get_distance_function(),
-            quantizer: SbqQuantizer::new(meta_page),
+            quantizer: SbqQuantizer::new(meta_page.get_bq_num_bits_per_dimension()),
             heap_rel: heap_rel,
-            heap_attr: get_attribute_number_from_index(index),
+            indexed_attr: IndexedAttribute::new(index),
             qv_cache: RefCell::new(QuantizedVectorCache::new(1000)),
             num_dimensions_for_neighbors: meta_page.get_num_dimensions_for_neighbors() as usize,
+            heap_slot: RefCell::new(None),
+            neighbor_block: NeighborBlock::for_index(index, meta_page),
+            full_vectors: FullVectorStore::for_index(index, meta_page),
+            full_vector_tape: RefCell::new(None),
+            defer_full_vectors: true,
+            #[cfg(feature = "build_offload")]
+            offloaded_training: None,
         }
     }
 
@@ -417,9 +327,16 @@ impl<'a> SbqSpeedupStorage<'a> {
             distance_fn: meta_page.get_distance_function(),
             quantizer: Self::load_quantizer(index_relation, meta_page, stats),
             heap_rel: heap_rel,
-            heap_attr: get_attribute_number_from_index(index_relation),
+            indexed_attr: IndexedAttribute::new(index_relation),
             qv_cache: RefCell::new(QuantizedVectorCache::new(1000)),
             num_dimensions_for_neighbors: meta_page.get_num_dimensions_for_neighbors() as usize,
+            heap_slot: RefCell::new(None),
+            neighbor_block: NeighborBlock::for_index(index_relation, meta_page),
+            full_vectors: FullVectorStore::for_index(index_relation, meta_page),
+            full_vector_tape: RefCell::new(None),
+            defer_full_vectors: false,
+            #[cfg(feature = "build_offload")]
+            offloaded_training: None,
         }
     }
 
@@ -435,13 +352,20 @@ impl<'a> SbqSpeedupStorage<'a> {
             //OPT: get rid of clone
             quantizer: quantizer.clone(),
             heap_rel: heap_relation,
-            heap_attr: get_attribute_number_from_index(index_relation),
+            indexed_attr: IndexedAttribute::new(index_relation),
             qv_cache: RefCell::new(QuantizedVectorCache::new(1000)),
             num_dimensions_for_neighbors: meta_page.get_num_dimensions_for_neighbors() as usize,
+            heap_slot: RefCell::new(None),
+            neighbor_block: NeighborBlock::for_index(index_relation, meta_page),
+            full_vectors: FullVectorStore::for_index(index_relation, meta_page),
+            full_vector_tape: RefCell::new(None),
+            defer_full_vectors: false,
+            #[cfg(feature = "build_offload")]
+            offloaded_training: None,
         }
     }
 
-    fn get_quantized_vector_from_index_pointer<S: StatsNodeRead>(
+    pub fn get_quantized_vector_from_index_pointer<S: StatsNodeRead>(
         &self,
         index_pointer: IndexPointer,
         stats: &mut S,
@@ -451,6 +375,55 @@ impl<'a> SbqSpeedupStorage<'a> {
         node.bq_vector.as_slice().to_vec()
     }
 
+    /// Uses the quantizer of another index instead of training one, so that both indexes
+    /// quantize vectors identically. The means are copied, the indexes don't depend on each
+    /// other afterwards.
+    pub fn copy_quantizer_from(
+        &mut self,
+        source: &PgRelation,
+        meta_page: &super::meta_page::MetaPage,
+        stats: &mut WriteStats,
+    ) {
+        let source_meta = super::meta_page::MetaPage::fetch(source);
+        if source_meta.get_storage_type() == StorageType::Plain {
+            pgrx::error!(
+                "the quantizer_source index \"{}\" does not use SBQ",
+                source.name()
+            );
+        }
+        if meta_page.uses_pca() || source_meta.uses_pca() {
+            pgrx::error!("the quantizer_source and pca_dimensions options cannot be used together");
+        }
+        if source_meta.get_num_dimensions_to_index() != meta_page.get_num_dimensions_to_index()
+            || source_meta.get_bq_num_bits_per_dimension()
+                != meta_page.get_bq_num_bits_per_dimension()
+            || source_meta.get_weights() != meta_page.get_weights()
+        {
+            pgrx::error!(
+                "the quantizer_source index \"{}\" must index the same number of dimensions with the same num_bits_per_dimension and weights",
+                source.name()
+            );
+        }
+
+        self.quantizer = unsafe { SbqMeans::load(source, &source_meta, stats) };
+        self.write_quantizer_metadata(stats);
+    }
+
+    /// The state of the trained quantizer, to save it in a build checkpoint.
+    pub fn get_quantizer_state(&self) -> QuantizerState {
+        QuantizerState {
+            count: self.quantizer.count,
+            mean: self.quantizer.mean.clone(),
+            m2: self.quantizer.m2.clone(),
+        }
+    }
+
+    /// Uses a quantizer restored from a build checkpoint instead of training one.
+    pub fn restore_quantizer(&mut self, state: QuantizerState, stats: &mut WriteStats) {
+        self.quantizer.load(state.count, state.mean, state.m2);
+        self.write_quantizer_metadata(stats);
+    }
+
     fn write_quantizer_metadata<S: StatsNodeWrite + StatsNodeModify>(&self, stats: &mut S) {
         if self.quantizer.use_mean {
             let index_pointer = unsafe { SbqMeans::store(&self.index, &self.quantizer, stats) };
@@ -462,6 +435,38 @@ impl<'a> SbqSpeedupStorage<'a> {
         }
     }
 
+    /// The neighbors of a node read from disk.
+    fn read_neighbors(&self, node: &ReadableSbqNode) -> Vec<ItemPointer> {
+        match self.neighbor_block {
+            Some(neighbor_block) => neighbor_block.read(node.get_data_slice()),
+            None => node.get_archived_node().get_index_pointer_to_neighbors(),
+        }
+    }
+
+    /// Stores the neighbors of a node being modified. The quantized vectors of the neighbors
+    /// must be in `cache`.
+    fn write_neighbors(
+        &self,
+        node: &mut WritableSbqNode,
+        neighbors: &[NeighborWithDistance],
+        meta: &MetaPage,
+        cache: &QuantizedVectorCache,
+    ) {
+        match self.neighbor_block {
+            Some(neighbor_block) => {
+                /* the neighbor vectors are stored in the order of the compressed list */
+                let stored = neighbor_block.write(node.get_data_slice_mut(), neighbors);
+                node.get_archived_node()
+                    .as_mut()
+                    .set_neighbors(&stored, meta, cache);
+            }
+            None => node
+                .get_archived_node()
+                .as_mut()
+                .set_neighbors(neighbors, meta, cache),
+        }
+    }
+
     fn visit_lsn_internal(
         &self,
         lsr: &mut ListSearchResult<
@@ -477,7 +482,7 @@ impl<'a> SbqSpeedupStorage<'a> {
                     unsafe { SbqNode::read(self.index, lsn_index_pointer, &mut lsr.stats) };
                 let node_visiting = rn_visiting.get_archived_node();
                 //OPT: get neighbors from private data just like plain storage in the self.num_dimensions_for_neighbors == 0 case
-                let neighbors = node_visiting.get_index_pointer_to_neighbors();
+                let neighbors = self.read_neighbors(&rn_visiting);
 
                 for (i, &neighbor_index_pointer) in neighbors.iter().enumerate() {
                     if !lsr.prepare_insert(neighbor_index_pointer) {
@@ -538,14 +543,6 @@ impl<'a> SbqSpeedupStorage<'a> {
             }
         }
     }
-
-    unsafe fn get_heap_table_slot_from_heap_pointer<T: StatsHeapNodeRead>(
-        &self,
-        heap_pointer: HeapPointer,
-        stats: &mut T,
-    ) -> TableSlot {
-        TableSlot::new(self.heap_rel, heap_pointer, stats)
-    }
 }
 
 pub type SbqSpeedupStorageLsnPrivateData = PhantomData<bool>; //no data stored
@@ -562,13 +559,14 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
 
     fn create_node<S: StatsNodeWrite>(
         &self,
-        full_vector: &[f32],
+        vector: &PgVector,
         heap_pointer: HeapPointer,
+        payload: &NodePayload,
         meta_page: &MetaPage,
         tape: &mut Tape,
         stats: &mut S,
     ) -> ItemPointer {
-        let bq_vector = self.quantizer.vector_for_new_node(meta_page, full_vector);
+        let bq_vector = self.quantizer.quantize(vector.to_index_slice());
 
         let node = SbqNode::with_meta(
             &self.quantizer,
@@ -577,19 +575,57 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
             bq_vector.as_slice(),
         );
 
-        let index_pointer: IndexPointer = node.write(tape, stats);
-        index_pointer
+        let mut prefix = payload.as_bytes().to_vec();
+        if let Some(neighbor_block) = self.neighbor_block {
+            prefix.extend_from_slice(&neighbor_block.empty());
+        }
+        match self.full_vectors {
+            /* the build stores the pointer with the FullVectorBuilder */
+            Some(_) if self.defer_full_vectors => {
+                prefix.extend_from_slice(&[0; FULL_VECTOR_POINTER_SIZE])
+            }
+            Some(full_vectors) => {
+                let mut full_vector_tape = self.full_vector_tape.borrow_mut();
+                let full_vector_tape = full_vector_tape.get_or_insert_with(|| unsafe {
+                    Tape::resume(self.index, PageType::FullVector)
+                });
+                prefix.extend_from_slice(&full_vectors.write(
+                    vector.to_full_slice(),
+                    full_vector_tape,
+                    stats,
+                ));
+            }
+            None => {}
+        }
+        node.write_with_prefix(&prefix, tape, stats)
     }
 
     fn start_training(&mut self, meta_page: &super::meta_page::MetaPage) {
-        self.quantizer.start_training(meta_page);
+        self.quantizer
+            .start_training(meta_page.get_num_dimensions_to_index() as _);
+        #[cfg(feature = "build_offload")]
+        if self.quantizer.use_mean {
+            self.offloaded_training = Some(BatchedTraining::new(
+                get_build_offload_backend(),
+                meta_page.get_num_dimensions_to_index() as _,
+            ));
+        }
     }
 
     fn add_sample(&mut self, sample: &[f32]) {
+        #[cfg(feature = "build_offload")]
+        if let Some(training) = &mut self.offloaded_training {
+            training.add_sample(&mut self.quantizer, sample);
+            return;
+        }
         self.quantizer.add_sample(sample);
     }
 
     fn finish_training(&mut self, stats: &mut WriteStats) {
+        #[cfg(feature = "build_offload")]
+        if let Some(mut training) = self.offloaded_training.take() {
+            training.flush(&mut self.quantizer);
+        }
         self.quantizer.finish_training();
         self.write_quantizer_metadata(stats);
     }
@@ -610,13 +646,22 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
             .chain(once(index_pointer));
         cache.preload(iter, self, stats);
 
-        let node = unsafe { SbqNode::modify(self.index, index_pointer, stats) };
-        let mut archived = node.get_archived_node();
-        archived.as_mut().set_neighbors(neighbors, &meta, &cache);
-
+        let mut node = unsafe { SbqNode::modify(self.index, index_pointer, stats) };
+        self.write_neighbors(&mut node, neighbors, meta, &cache);
         node.commit();
     }
 
+    fn clear_node_cache(&mut self) {
+        self.qv_cache.borrow_mut().clear();
+    }
+
+    fn get_memory_bytes(&self) -> usize {
+        let quantizer = &self.quantizer;
+        let quantizer_bytes =
+            (quantizer.mean.capacity() + quantizer.m2.capacity()) * std::mem::size_of::<f32>();
+        self.qv_cache.borrow().get_memory_bytes() + quantizer_bytes
+    }
+
     unsafe fn get_node_distance_measure<'b, S: StatsNodeRead>(
         &'b self,
         index_pointer: IndexPointer,
@@ -633,19 +678,34 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
         );
     }
 
-    fn get_full_distance_for_resort<S: StatsHeapNodeRead + StatsDistanceComparison>(
+    fn get_full_distance_for_resort<
+        S: StatsHeapNodeRead + StatsNodeRead + StatsDistanceComparison,
+    >(
         &self,
         qdm: &Self::QueryDistanceMeasure,
-        _index_pointer: IndexPointer,
+        index_pointer: IndexPointer,
         heap_pointer: HeapPointer,
         meta_page: &MetaPage,
         stats: &mut S,
     ) -> f32 {
-        let slot = unsafe { self.get_heap_table_slot_from_heap_pointer(heap_pointer, stats) };
+        if let Some(full_vectors) = self.full_vectors {
+            let pointer = {
+                let node = unsafe { SbqNode::read(self.index, index_pointer, stats) };
+                full_vectors.read_pointer(node.get_data_slice())
+            };
+            if let Some(pointer) = pointer {
+                let vector = unsafe { full_vectors.read(self.index, pointer, stats) };
+                return self.get_distance_function()(&vector, qdm.get_query().to_full_slice());
+            }
+        }
+
+        let mut heap_slot = self.heap_slot.borrow_mut();
+        let slot = heap_slot.get_or_insert_with(|| unsafe { TableSlot::new(self.heap_rel) });
+        unsafe { slot.fetch(self.heap_rel, heap_pointer, stats) };
 
-        let datum = unsafe { slot.get_attribute(self.heap_attr).unwrap() };
+        let datum = unsafe { self.indexed_attr.get_datum(slot).unwrap() };
         let vec = unsafe { PgVector::from_datum(datum, meta_page, false, true) };
-        self.get_distance_function()(vec.to_full_slice(), qdm.query.to_full_slice())
+        self.get_distance_function()(vec.to_full_slice(), qdm.get_query().to_full_slice())
     }
 
     fn get_neighbors_with_distances_from_disk<S: StatsNodeRead + StatsDistanceComparison>(
@@ -658,7 +718,7 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
         let archived = rn.get_archived_node();
         let q = archived.bq_vector.as_slice();
 
-        for n in rn.get_archived_node().iter_neighbors() {
+        for n in self.read_neighbors(&rn) {
             //OPT: we can optimize this if num_dimensions_for_neighbors == num_dimensions_to_index
             let rn1 = unsafe { SbqNode::read(self.index, n, stats) };
             stats.record_quantized_distance_comparison();
@@ -730,9 +790,8 @@ impl<'a> Storage for SbqSpeedupStorage<'a> {
             .chain(once(index_pointer));
         cache.preload(iter, self, stats);
 
-        let node = unsafe { SbqNode::modify(self.index, index_pointer, stats) };
-        let mut archived = node.get_archived_node();
-        archived.as_mut().set_neighbors(neighbors, &meta, &cache);
+        let mut node = unsafe { SbqNode::modify(self.index, index_pointer, stats) };
+        self.write_neighbors(&mut node, neighbors, meta, &cache);
         node.commit();
     }
 
@@ -759,8 +818,14 @@ impl SbqNode {
         meta_page: &MetaPage,
         bq_vector: &[SbqVectorElement],
     ) -> Self {
+        let num_neighbor_index_pointers = match meta_page.get_neighbor_encoding() {
+            NeighborEncoding::Inline => meta_page.get_num_neighbors() as usize,
+            /* the neighbors are stored in the neighbor block instead */
+            NeighborEncoding::Packed | NeighborEncoding::Varint => 0,
+        };
         Self::new(
             heap_pointer,
+            num_neighbor_index_pointers,
             meta_page.get_num_neighbors() as usize,
             meta_page.get_num_dimensions_to_index() as usize,
             meta_page.get_num_dimensions_for_neighbors() as usize,
@@ -771,6 +836,7 @@ impl SbqNode {
 
     fn new(
         heap_pointer: HeapPointer,
+        num_neighbor_index_pointers: usize,
         num_neighbors: usize,
         _num_dimensions: usize,
         num_dimensions_for_neighbors: usize,
@@ -778,7 +844,7 @@ impl SbqNode {
         bq_vector: &[SbqVectorElement],
     ) -> Self {
         // always use vectors of num_neighbors in length because we never want the serialized size of a Node to change
-        let neighbor_index_pointers: Vec<_> = (0..num_neighbors)
+        let neighbor_index_pointers: Vec<_> = (0..num_neighbor_index_pointers)
             .map(|_| ItemPointer::new(InvalidBlockNumber, InvalidOffsetNumber))
             .collect();
 
@@ -806,7 +872,9 @@ impl SbqNode {
         }
     }
 
-    fn test_size(
+    /// The size of a node of a new index, without the payload. The neighbors are stored as
+    /// packed TIDs in front of the archived node.
+    pub fn test_size(
         num_neighbors: usize,
         num_dimensions: usize,
         num_dimensions_for_neighbors: usize,
@@ -817,33 +885,36 @@ impl SbqNode {
         let hp = HeapPointer::new(InvalidBlockNumber, InvalidOffsetNumber);
         let n = Self::new(
             hp,
+            0,
             num_neighbors,
             num_dimensions,
             num_dimensions_for_neighbors,
             num_bits_per_dimension,
             &v,
         );
-        n.serialize_to_vec().len()
+        n.serialize_to_vec().len() + NeighborEncoding::Packed.get_block_size(num_neighbors)
     }
 
     pub fn get_default_num_neighbors(
         num_dimensions: usize,
         num_dimensions_for_neighbors: usize,
         num_bits_per_dimension: u8,
+        payload_size: usize,
     ) -> usize {
         //how many neighbors can fit on one page? That's what we choose.
 
         //we first overapproximate the number of neighbors and then double check by actually calculating the size of the SbqNode.
 
         //blocksize - 100 bytes for the padding/header/etc.
-        let page_size = BLCKSZ as usize - 50;
+        //the filter payload is stored in the same item as the node.
+        let page_size = BLCKSZ as usize - 50 - payload_size;
         //one quantized_vector takes this many bytes
         let vec_size =
             SbqQuantizer::quantized_size_bytes(num_dimensions as usize, num_bits_per_dimension) + 1;
         //start from the page size then subtract the heap_item_pointer and bq_vector elements of SbqNode.
         let starting = BLCKSZ as usize - std::mem::size_of::<HeapPointer>() - vec_size;
-        //one neigbors contribution to neighbor_index_pointers + neighbor_vectors in SbqNode.
-        let one_neighbor = vec_size + std::mem::size_of::<ItemPointer>();
+        //one neigbors contribution to the neighbor block + neighbor_vectors in SbqNode.
+        let one_neighbor = vec_size + PACKED_BYTES_PER_NEIGHBOR;
 
         let mut num_neighbors_overapproximate: usize = starting / one_neighbor;
         while num_neighbors_overapproximate > 0 {
@@ -879,12 +950,16 @@ impl ArchivedSbqNode {
         meta_page: &MetaPage,
         cache: &QuantizedVectorCache,
     ) {
+        /* nodes of indexes that compress the neighbors have no room for index pointers */
+        let store_index_pointers = !self.neighbor_index_pointers.is_empty();
         for (i, new_neighbor) in neighbors.iter().enumerate() {
-            let mut a_index_pointer = self.as_mut().neighbor_index_pointer().index_pin(i);
             let ip = new_neighbor.get_index_pointer_to_neighbor();
-            //TODO hate that we have to set each field like this
-            a_index_pointer.block_number = ip.block_number;
-            a_index_pointer.offset = ip.offset;
+            if store_index_pointers {
+                let mut a_index_pointer = self.as_mut().neighbor_index_pointer().index_pin(i);
+                //TODO hate that we have to set each field like this
+                a_index_pointer.block_number = ip.block_number;
+                a_index_pointer.offset = ip.offset;
+            }
 
             if meta_page.get_num_dimensions_for_neighbors() > 0 {
                 let quantized = &cache.must_get(ip)[..SbqQuantizer::quantized_size_internal(
@@ -899,7 +974,7 @@ impl ArchivedSbqNode {
             }
         }
         //set the marker that the list ended
-        if neighbors.len() < meta_page.get_num_neighbors() as _ {
+        if store_index_pointers && neighbors.len() < meta_page.get_num_neighbors() as _ {
             let mut past_last_index_pointers =
                 self.neighbor_index_pointer().index_pin(neighbors.len());
             past_last_index_pointers.block_number = InvalidBlockNumber;
@@ -945,6 +1020,13 @@ impl ArchivedData for ArchivedSbqNode {
     fn get_heap_item_pointer(&self) -> HeapPointer {
         self.heap_item_pointer.deserialize_item_pointer()
     }
+
+    fn get_vector_code(&self) -> Vec<u8> {
+        self.bq_vector
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect()
+    }
 }
 
 #[cfg(any(test, feature = "pg_test"))]
@@ -1014,6 +1096,23 @@ mod tests {
         Ok(())
     }
 
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_index_creation_two_bits() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
+            "storage_layout = io_optimized, num_dimensions=768, num_bits_per_dimension=2",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_index_updates_two_bits() -> spi::Result<()> {
+        crate::access_method::build::tests::test_index_updates(
+            "storage_layout = io_optimized, num_neighbors=10, num_dimensions=768, num_bits_per_dimension=2",
+            300,
+        )?;
+        Ok(())
+    }
+
     #[pg_test]
     unsafe fn test_bq_speedup_compressed_index_creation_default_neighbors() -> spi::Result<()> {
         crate::access_method::build::tests::test_index_creation_and_accuracy_scaffold(
@@ -1075,4 +1174,74 @@ mod tests {
         )?;
         Ok(())
     }
+
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_expression_index() -> spi::Result<()> {
+        crate::access_method::build::tests::test_expression_index_scaffold(
+            "num_neighbors=38, storage_layout = io_optimized",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_expression_index() -> spi::Result<()> {
+        crate::access_method::build::tests::test_expression_index_scaffold(
+            "num_neighbors=38, storage_layout = memory_optimized",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_quantizer_source() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test_old(embedding vector(3));
+            CREATE TABLE test_new(embedding vector(3));
+
+            INSERT INTO test_old(embedding)
+            SELECT ARRAY[i % 10, i % 7, 1]::vector FROM generate_series(1, 300) i;
+            INSERT INTO test_new(embedding)
+            SELECT ARRAY[1, i % 5, i % 3]::vector FROM generate_series(1, 30) i;
+
+            CREATE INDEX idx_old ON test_old USING diskann(embedding);
+            CREATE INDEX idx_new ON test_new USING diskann(embedding)
+                WITH (quantizer_source = 'idx_old');",
+        )?;
+
+        let quantizer_of = |name: &str| -> spi::Result<(u64, Vec<f32>)> {
+            let index_oid = Spi::get_one::<pg_sys::Oid>(&format!("SELECT '{name}'::regclass::oid"))?
+                .expect("oid was null");
+            let index = PgRelation::from_pg(pg_sys::RelationIdGetRelation(index_oid));
+            let meta_page = crate::access_method::meta_page::MetaPage::fetch(&index);
+            let mut stats = crate::access_method::stats::QuantizerStats::new();
+            let quantizer = super::SbqMeans::load(&index, &meta_page, &mut stats);
+            Ok((quantizer.count, quantizer.mean))
+        };
+        /* the new index was built with the means of the old table */
+        assert_eq!(quantizer_of("idx_old")?, quantizer_of("idx_new")?);
+        assert_eq!(quantizer_of("idx_new")?.0, 300);
+
+        let res: Option<i64> = Spi::get_one(
+            "   set enable_seqscan = 0;
+                WITH cte as (select * from test_new order by embedding <=> '[1,1,1]') SELECT count(*) from cte;",
+        )?;
+        assert_eq!(res, Some(30));
+
+        Spi::run("DROP TABLE test_old, test_new")?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_speedup_storage_partial_index() -> spi::Result<()> {
+        crate::access_method::build::tests::test_partial_index_scaffold("storage_layout = io_optimized")
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_partial_index() -> spi::Result<()> {
+        crate::access_method::build::tests::test_partial_index_scaffold("storage_layout = memory_optimized")
+    }
+
+    #[pg_test]
+    unsafe fn test_bq_compressed_storage_temp_table() -> spi::Result<()> {
+        crate::access_method::build::tests::test_temp_table_scaffold("storage_layout = memory_optimized")
+    }
 }
@@ -1,21 +1,39 @@
-use std::collections::BinaryHeap;
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    time::{Duration, Instant},
+};
 
 use pgrx::{pg_sys::InvalidOffsetNumber, *};
 
 use crate::{
     access_method::{
-        graph_neighbor_store::GraphNeighborStore, meta_page::MetaPage, pg_vector::PgVector,
+        graph_neighbor_store::GraphNeighborStore,
+        meta_page::MetaPage,
+        pg_vector::{read_weighted_query, PgVector},
         sbq::SbqSpeedupStorage,
     },
-    util::{buffer::PinnedBufferShare, HeapPointer, IndexPointer},
+    util::{
+        buffer::{self, LockedBufferShare, PinnedBufferShare},
+        ports::{PageGetItem, PageGetItemId},
+        HeapPointer, IndexPointer,
+    },
 };
 
 use super::{
-    graph::{Graph, ListSearchResult},
+    custom_distance::CustomDistance,
+    distance::distance_xor_optimized,
+    graph::{Graph, ListSearchNeighbor, ListSearchResult},
+    guc::TSV_LOG_MIN_DURATION,
+    payload::{read_payload_column, PayloadFilter},
     plain_storage::{PlainDistanceMeasure, PlainStorage, PlainStorageLsnPrivateData},
+    query_hints::take_query_hint,
+    recency::RecencyDecay,
     sbq::{SbqMeans, SbqQuantizer, SbqSearchDistanceMeasure, SbqSpeedupStorageLsnPrivateData},
-    stats::QuantizerStats,
-    storage::{Storage, StorageType},
+    stats::{GreedySearchStats, QuantizerStats},
+    storage::{ArchivedData, Storage, StorageType},
+    tenant::get_scan_bucket,
+    time_buckets::get_scan_buckets,
 };
 
 /* Be very careful not to transfer PgRelations in the state, as they can change between calls. That means we shouldn't be
@@ -34,6 +52,12 @@ struct TSVScanState {
     distance_fn: Option<fn(&[f32], &[f32]) -> f32>,
     meta_page: MetaPage,
     last_buffer: Option<PinnedBufferShare>,
+    /* the tuple last returned by amgettuple, which the executor may report dead */
+    last_returned: Option<(HeapPointer, IndexPointer)>,
+    /* the buffers pinned by the backend when the scan started, see buffer::get_peak_pins */
+    pins_at_start: usize,
+    /* the time spent in the index since the scan started, for diskann.log_min_duration */
+    elapsed: Duration,
 }
 
 impl TSVScanState {
@@ -43,6 +67,9 @@ impl TSVScanState {
             distance_fn: None,
             meta_page: meta_page,
             last_buffer: None,
+            last_returned: None,
+            pins_at_start: 0,
+            elapsed: Duration::ZERO,
         }
     }
 
@@ -51,8 +78,17 @@ impl TSVScanState {
         index: &PgRelation,
         heap: &PgRelation,
         query: PgVector,
+        filter: PayloadFilter,
+        custom_distance: Option<CustomDistance>,
         search_list_size: usize,
+        resort_size: usize,
+        ignore_killed: bool,
     ) {
+        self.pins_at_start = buffer::get_num_pins();
+        buffer::reset_peak_pins();
+        self.last_returned = None;
+        self.elapsed = Duration::ZERO;
+
         let meta_page = MetaPage::fetch(&index);
         let storage = meta_page.get_storage_type();
         let distance = meta_page.get_distance_function();
@@ -62,16 +98,36 @@ impl TSVScanState {
                 let stats = QuantizerStats::new();
                 let bq =
                     PlainStorage::load_for_search(index, heap, meta_page.get_distance_function());
-                let it =
-                    TSVResponseIterator::new(&bq, index, query, search_list_size, meta_page, stats);
+                let it = TSVResponseIterator::new(
+                    &bq,
+                    index,
+                    bq.get_query_distance_measure(query),
+                    filter,
+                    custom_distance,
+                    search_list_size,
+                    resort_size,
+                    meta_page,
+                    stats,
+                )
+                .with_ignore_killed(ignore_killed);
                 StorageState::Plain(it)
             }
             StorageType::SbqSpeedup | StorageType::SbqCompression => {
                 let mut stats = QuantizerStats::new();
                 let quantizer = unsafe { SbqMeans::load(index, &meta_page, &mut stats) };
                 let bq = SbqSpeedupStorage::load_for_search(index, heap, &quantizer, &meta_page);
-                let it =
-                    TSVResponseIterator::new(&bq, index, query, search_list_size, meta_page, stats);
+                let it = TSVResponseIterator::new(
+                    &bq,
+                    index,
+                    bq.get_query_distance_measure(query),
+                    filter,
+                    custom_distance,
+                    search_list_size,
+                    resort_size,
+                    meta_page,
+                    stats,
+                )
+                .with_ignore_killed(ignore_killed);
                 StorageState::SbqSpeedup(quantizer, it)
             }
         };
@@ -109,6 +165,31 @@ impl Ord for ResortData {
     }
 }
 
+/// The search list size and the most results held for rescoring at once that fit the scan in
+/// `work_mem` kilobytes. Every element of the search list can add the neighbors of a node to
+/// the candidates, so a huge `search_list_size` would allocate far more than `work_mem`.
+/// A quarter of the budget goes to the results waiting for rescoring: once it is full, the
+/// closest one is returned before more are rescored.
+fn scan_memory_bounds<PD>(
+    search_list_size: usize,
+    num_neighbors: usize,
+    work_mem: usize,
+) -> (usize, usize) {
+    let budget = work_mem * 1024;
+    /* the candidates and the inserted set grow by the neighbors of each visited node */
+    let bytes_per_list_element = num_neighbors
+        * (std::mem::size_of::<Reverse<ListSearchNeighbor<PD>>>()
+            + std::mem::size_of::<IndexPointer>()
+            + 1)
+        + 2 * std::mem::size_of::<ListSearchNeighbor<PD>>();
+    let max_search_list_size = (budget - budget / 4) / bytes_per_list_element.max(1);
+    let max_resort_buffer = budget / 4 / std::mem::size_of::<ResortData>();
+    (
+        search_list_size.min(max_search_list_size.max(1)),
+        max_resort_buffer.max(2),
+    )
+}
+
 struct StreamingStats {
     count: i32,
     mean: f32,
@@ -153,12 +234,24 @@ impl StreamingStats {
 
 struct TSVResponseIterator<QDM, PD> {
     lsr: ListSearchResult<QDM, PD>,
+    filter: PayloadFilter,
+    custom_distance: Option<CustomDistance>,
     search_list_size: usize,
     meta_page: MetaPage,
     quantizer_stats: QuantizerStats,
     resort_size: usize,
     resort_buffer: BinaryHeap<ResortData>,
+    /* the most results held for rescoring at once, see scan_memory_bounds */
+    max_resort_buffer: usize,
     streaming_stats: StreamingStats,
+    /* the decay of searches ordered by recency, see the recency module */
+    recency: Option<RecencyDecay>,
+    /* the results waiting for the distances of the results to come to reach their score */
+    recency_buffer: BinaryHeap<ResortData>,
+    /* the distance of the last result added to recency_buffer */
+    recency_distance: f32,
+    /* whether results with the LP_DEAD hint are skipped, see kill_node */
+    ignore_killed: bool,
     next_calls: i32,
     next_calls_with_resort: i32,
     full_distance_comparisons: i32,
@@ -168,38 +261,109 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
     fn new<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
         storage: &S,
         index: &PgRelation,
-        query: PgVector,
+        query: QDM,
+        filter: PayloadFilter,
+        custom_distance: Option<CustomDistance>,
         search_list_size: usize,
+        resort_size: usize,
         //FIXME?
         _meta_page: MetaPage,
         quantizer_stats: QuantizerStats,
     ) -> Self {
         let mut meta_page = MetaPage::fetch(&index);
-        let graph = Graph::new(GraphNeighborStore::Disk, &mut meta_page);
+        let (bounded_search_list_size, max_resort_buffer) = scan_memory_bounds::<PD>(
+            search_list_size,
+            meta_page.get_num_neighbors() as usize,
+            unsafe { pg_sys::work_mem } as usize,
+        );
+        if bounded_search_list_size < search_list_size {
+            debug1!(
+                "Reducing the search list size from {} to {} to fit the scan in work_mem",
+                search_list_size,
+                bounded_search_list_size
+            );
+        }
+        let search_list_size = bounded_search_list_size;
+        let buckets = get_scan_bucket(&meta_page, &filter)
+            .map(|bucket| vec![bucket])
+            .or_else(|| get_scan_buckets(index, &meta_page, &filter));
+        let mut graph = Graph::new(GraphNeighborStore::Disk, &mut meta_page);
+        graph.set_buckets(buckets);
 
         let lsr = graph.greedy_search_streaming_init(query, search_list_size, storage);
-        let resort_size = super::guc::TSV_RESORT_SIZE.get() as usize;
 
         Self {
             search_list_size,
             lsr,
+            filter,
+            custom_distance,
             meta_page,
             quantizer_stats,
             resort_size,
-            resort_buffer: BinaryHeap::with_capacity(resort_size),
+            resort_buffer: BinaryHeap::with_capacity(resort_size.min(max_resort_buffer)),
+            max_resort_buffer,
             streaming_stats: StreamingStats::new(resort_size),
+            recency: None,
+            recency_buffer: BinaryHeap::new(),
+            recency_distance: 0.0,
+            ignore_killed: false,
             next_calls: 0,
             next_calls_with_resort: 0,
             full_distance_comparisons: 0,
         }
     }
+
+    /// Orders the results by their distance plus the penalty of `recency`. The penalty is added
+    /// to exact distances, so the results are always rescored.
+    fn with_recency(mut self, recency: RecencyDecay) -> Self {
+        if self.resort_buffer.capacity() == 0 {
+            self.resort_size = 1;
+            self.resort_buffer = BinaryHeap::with_capacity(1);
+        }
+        self.recency = Some(recency);
+        self
+    }
+
+    /// Skips the results whose items have the LP_DEAD hint, unless the scan runs in hot
+    /// standby: hints set on the primary can reach it through full page images before the
+    /// tuples are dead to its snapshots.
+    fn with_ignore_killed(mut self, ignore_killed: bool) -> Self {
+        self.ignore_killed = ignore_killed;
+        self
+    }
 }
 
 impl<QDM, PD> TSVResponseIterator<QDM, PD> {
+    /// The next result of an index scan, ordered by the distance and rescored if `resort`.
+    fn next_for_scan<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
+        &mut self,
+        index: &PgRelation,
+        heap: &PgRelation,
+        storage: &S,
+        resort: bool,
+    ) -> Option<(HeapPointer, IndexPointer)> {
+        if resort {
+            self.next_with_resort(index, heap, storage)
+        } else {
+            self.next(index, storage)
+        }
+    }
+
     fn next<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
         &mut self,
+        index: &PgRelation,
         storage: &S,
     ) -> Option<(HeapPointer, IndexPointer)> {
+        self.next_scored(index, storage)
+            .map(|rd| (rd.heap_pointer, rd.index_pointer))
+    }
+
+    /// The next result with its distance, as the search list orders it.
+    fn next_scored<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
+        &mut self,
+        index: &PgRelation,
+        storage: &S,
+    ) -> Option<ResortData> {
         self.next_calls += 1;
         let graph = Graph::new(GraphNeighborStore::Disk, &mut self.meta_page);
 
@@ -210,12 +374,26 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
             let item = self.lsr.consume(storage);
 
             match item {
-                Some((heap_pointer, index_pointer)) => {
+                Some((heap_pointer, index_pointer, distance)) => {
                     if heap_pointer.offset == InvalidOffsetNumber {
                         /* deleted tuple */
                         continue;
                     }
-                    return Some((heap_pointer, index_pointer));
+                    if self.ignore_killed && unsafe { is_killed(index, index_pointer) } {
+                        /* a previous scan found the tuple dead to all transactions */
+                        continue;
+                    }
+                    let matches =
+                        unsafe { self.filter.matches(index, index_pointer, &mut self.lsr.stats) };
+                    if !matches {
+                        /* filtered out by the quals on the payload columns */
+                        continue;
+                    }
+                    return Some(ResortData {
+                        heap_pointer,
+                        index_pointer,
+                        distance,
+                    });
                 }
                 None => {
                     return None;
@@ -226,29 +404,48 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
 
     fn next_with_resort<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
         &mut self,
-        _index: &PgRelation,
+        index: &PgRelation,
+        heap: &PgRelation,
         storage: &S,
     ) -> Option<(HeapPointer, IndexPointer)> {
+        self.next_with_resort_scored(index, heap, storage)
+            .map(|rd| (rd.heap_pointer, rd.index_pointer))
+    }
+
+    /// The next result with its exact distance.
+    fn next_with_resort_scored<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
+        &mut self,
+        index: &PgRelation,
+        heap: &PgRelation,
+        storage: &S,
+    ) -> Option<ResortData> {
         self.next_calls_with_resort += 1;
         if self.resort_buffer.capacity() == 0 {
-            return self.next(storage);
+            return self.next_scored(index, storage);
         }
 
-        while self.resort_buffer.len() < 2
-            || self.streaming_stats.count < 2
-            || (self.streaming_stats.max_distance - self.resort_buffer.peek().unwrap().distance)
-                < self.streaming_stats.variance().sqrt() * (self.resort_size as f32 / 100.0)
+        while self.resort_buffer.len() < self.max_resort_buffer
+            && (self.resort_buffer.len() < 2
+                || self.streaming_stats.count < 2
+                || (self.streaming_stats.max_distance
+                    - self.resort_buffer.peek().unwrap().distance)
+                    < self.streaming_stats.variance().sqrt() * (self.resort_size as f32 / 100.0))
         {
-            match self.next(storage) {
+            match self.next(index, storage) {
                 Some((heap_pointer, index_pointer)) => {
                     self.full_distance_comparisons += 1;
-                    let distance = storage.get_full_distance_for_resort(
-                        self.lsr.sdm.as_ref().unwrap(),
-                        index_pointer,
-                        heap_pointer,
-                        &self.meta_page,
-                        &mut self.lsr.stats,
-                    );
+                    let distance = match self.custom_distance.as_mut() {
+                        Some(custom_distance) => unsafe {
+                            custom_distance.get_distance(heap, heap_pointer, &mut self.lsr.stats)
+                        },
+                        None => storage.get_full_distance_for_resort(
+                            self.lsr.sdm.as_ref().unwrap(),
+                            index_pointer,
+                            heap_pointer,
+                            &self.meta_page,
+                            &mut self.lsr.stats,
+                        ),
+                    };
 
                     if self.resort_buffer.len() > 1 {
                         self.streaming_stats
@@ -276,13 +473,374 @@ impl<QDM, PD> TSVResponseIterator<QDM, PD> {
             self.streaming_stats.max_distance - self.resort_buffer.peek().unwrap().distance
         );*/
 
-        match self.resort_buffer.pop() {
-            Some(rd) => Some((rd.heap_pointer, rd.index_pointer)),
-            None => None,
+        self.resort_buffer.pop()
+    }
+
+    /// The next result with its score, its distance plus the penalty of its age, as the
+    /// distance of the result. The results come by distance, and the penalty is never negative,
+    /// so the result with the smallest score is returned once the distance of the last result
+    /// reaches it.
+    fn next_with_recency_scored<S: Storage<QueryDistanceMeasure = QDM, LSNPrivateData = PD>>(
+        &mut self,
+        index: &PgRelation,
+        heap: &PgRelation,
+        storage: &S,
+        resort: bool,
+    ) -> Option<ResortData> {
+        let recency = self.recency.unwrap();
+        while self.recency_buffer.len() < self.max_resort_buffer
+            && !matches!(self.recency_buffer.peek(), Some(rd) if rd.distance <= self.recency_distance)
+        {
+            let next = if resort {
+                self.next_with_resort_scored(index, heap, storage)
+            } else {
+                self.next_scored(index, storage)
+            };
+            let Some(rd) = next else {
+                /* all the results are in the buffer */
+                self.recency_distance = f32::INFINITY;
+                break;
+            };
+            self.recency_distance = rd.distance;
+            let timestamp = unsafe {
+                read_payload_column(
+                    index,
+                    rd.index_pointer,
+                    recency.get_column(),
+                    &mut self.lsr.stats,
+                )
+            };
+            self.recency_buffer.push(ResortData {
+                distance: rd.distance + recency.get_penalty(timestamp.map(|t| t as i64)),
+                ..rd
+            });
         }
+        self.recency_buffer.pop()
     }
 }
 
+/// Searches the index outside of an index scan, for example to measure its recall, and returns
+/// the heap pointers of the first `limit` results in the order an index scan returns them.
+/// `search_list_size` and `resort_size` replace the query_search_list_size and query_rescore
+/// settings. The counters of the search are added to `stats`.
+pub fn search_heap_pointers(
+    index: &PgRelation,
+    heap: &PgRelation,
+    query: PgVector,
+    limit: usize,
+    search_list_size: usize,
+    resort_size: usize,
+    stats: &mut GreedySearchStats,
+) -> Vec<HeapPointer> {
+    search_heap_pointers_filtered(
+        index,
+        heap,
+        query,
+        limit,
+        search_list_size,
+        resort_size,
+        stats,
+        &mut |_| true,
+    )
+}
+
+/// Like `search_heap_pointers`, but only returns, and counts towards `limit`, the results for
+/// which `keep` returns true, e.g. the results after the last one of a previous page.
+#[allow(clippy::too_many_arguments)]
+pub fn search_heap_pointers_filtered(
+    index: &PgRelation,
+    heap: &PgRelation,
+    query: PgVector,
+    limit: usize,
+    search_list_size: usize,
+    resort_size: usize,
+    stats: &mut GreedySearchStats,
+    keep: &mut dyn FnMut(HeapPointer) -> bool,
+) -> Vec<HeapPointer> {
+    let meta_page = MetaPage::fetch(index);
+    let filter = unsafe { PayloadFilter::new(index, std::ptr::null_mut(), 0) };
+
+    match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::load_for_search(index, heap, meta_page.get_distance_function());
+            let resort =
+                meta_page.get_num_dimensions() != meta_page.get_num_dimensions_to_index();
+            let mut iter = TSVResponseIterator::new(
+                &storage,
+                index,
+                storage.get_query_distance_measure(query),
+                filter,
+                None,
+                search_list_size,
+                resort_size,
+                meta_page.clone(),
+                QuantizerStats::new(),
+            );
+            collect_heap_pointers(&mut iter, index, heap, &storage, resort, limit, stats, keep)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let mut quantizer_stats = QuantizerStats::new();
+            let quantizer = unsafe { SbqMeans::load(index, &meta_page, &mut quantizer_stats) };
+            let storage = SbqSpeedupStorage::load_for_search(index, heap, &quantizer, &meta_page);
+            let mut iter = TSVResponseIterator::new(
+                &storage,
+                index,
+                storage.get_query_distance_measure(query),
+                filter,
+                None,
+                search_list_size,
+                resort_size,
+                meta_page.clone(),
+                quantizer_stats,
+            );
+            collect_heap_pointers(&mut iter, index, heap, &storage, true, limit, stats, keep)
+        }
+    }
+}
+
+/// Searches the index for the `limit` rows with the smallest distance to `query` plus the
+/// penalty of `recency` for their age, and returns them with that score, smallest first.
+#[allow(clippy::too_many_arguments)]
+pub fn search_heap_pointers_recent(
+    index: &PgRelation,
+    heap: &PgRelation,
+    query: PgVector,
+    recency: RecencyDecay,
+    limit: usize,
+    search_list_size: usize,
+    resort_size: usize,
+    stats: &mut GreedySearchStats,
+) -> Vec<(HeapPointer, f32)> {
+    let meta_page = MetaPage::fetch(index);
+    let filter = unsafe { PayloadFilter::new(index, std::ptr::null_mut(), 0) };
+
+    match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::load_for_search(index, heap, meta_page.get_distance_function());
+            let resort =
+                meta_page.get_num_dimensions() != meta_page.get_num_dimensions_to_index();
+            let mut iter = TSVResponseIterator::new(
+                &storage,
+                index,
+                storage.get_query_distance_measure(query),
+                filter,
+                None,
+                search_list_size,
+                resort_size,
+                meta_page.clone(),
+                QuantizerStats::new(),
+            )
+            .with_recency(recency);
+            collect_recent(&mut iter, index, heap, &storage, resort, limit, stats)
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let mut quantizer_stats = QuantizerStats::new();
+            let quantizer = unsafe { SbqMeans::load(index, &meta_page, &mut quantizer_stats) };
+            let storage = SbqSpeedupStorage::load_for_search(index, heap, &quantizer, &meta_page);
+            let mut iter = TSVResponseIterator::new(
+                &storage,
+                index,
+                storage.get_query_distance_measure(query),
+                filter,
+                None,
+                search_list_size,
+                resort_size,
+                meta_page.clone(),
+                quantizer_stats,
+            )
+            .with_recency(recency);
+            collect_recent(&mut iter, index, heap, &storage, true, limit, stats)
+        }
+    }
+}
+
+/// Searches the index for the `limit` nearest rows to each of `queries`, and returns them with
+/// the distances the index orders them by, after the key of their query. The storage of the
+/// index, e.g. its quantizer, is loaded once for all the queries instead of once per search.
+pub fn search_heap_pointers_batch<T>(
+    index: &PgRelation,
+    heap: &PgRelation,
+    queries: impl Iterator<Item = (T, PgVector)>,
+    limit: usize,
+    search_list_size: usize,
+    resort_size: usize,
+    stats: &mut GreedySearchStats,
+) -> Vec<(T, Vec<(HeapPointer, f32)>)> {
+    let meta_page = MetaPage::fetch(index);
+
+    match meta_page.get_storage_type() {
+        StorageType::Plain => {
+            let storage =
+                PlainStorage::load_for_search(index, heap, meta_page.get_distance_function());
+            let resort =
+                meta_page.get_num_dimensions() != meta_page.get_num_dimensions_to_index();
+            queries
+                .map(|(key, query)| {
+                    let mut iter = TSVResponseIterator::new(
+                        &storage,
+                        index,
+                        storage.get_query_distance_measure(query),
+                        unsafe { PayloadFilter::new(index, std::ptr::null_mut(), 0) },
+                        None,
+                        search_list_size,
+                        resort_size,
+                        meta_page.clone(),
+                        QuantizerStats::new(),
+                    );
+                    let results =
+                        collect_scored(&mut iter, index, heap, &storage, resort, limit, stats);
+                    (key, results)
+                })
+                .collect()
+        }
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let mut quantizer_stats = QuantizerStats::new();
+            let quantizer = unsafe { SbqMeans::load(index, &meta_page, &mut quantizer_stats) };
+            let storage = SbqSpeedupStorage::load_for_search(index, heap, &quantizer, &meta_page);
+            queries
+                .map(|(key, query)| {
+                    let mut iter = TSVResponseIterator::new(
+                        &storage,
+                        index,
+                        storage.get_query_distance_measure(query),
+                        unsafe { PayloadFilter::new(index, std::ptr::null_mut(), 0) },
+                        None,
+                        search_list_size,
+                        resort_size,
+                        meta_page.clone(),
+                        QuantizerStats::new(),
+                    );
+                    let results =
+                        collect_scored(&mut iter, index, heap, &storage, true, limit, stats);
+                    (key, results)
+                })
+                .collect()
+        }
+    }
+}
+
+/// Searches an SBQ index for a query given as codes, see `SbqQuantizer::quantize`, and returns
+/// the heap pointers of the `limit` nearest nodes with the distances of their codes to the
+/// query. There is no vector to rescore with, so the results are in the order of the codes.
+pub fn search_heap_pointers_by_codes(
+    index: &PgRelation,
+    heap: &PgRelation,
+    codes: Vec<u64>,
+    limit: usize,
+    search_list_size: usize,
+) -> Vec<(HeapPointer, f32)> {
+    let meta_page = MetaPage::fetch(index);
+    let filter = unsafe { PayloadFilter::new(index, std::ptr::null_mut(), 0) };
+    let mut stats = QuantizerStats::new();
+    let quantizer = unsafe { SbqMeans::load(index, &meta_page, &mut stats) };
+    let storage = SbqSpeedupStorage::load_for_search(index, heap, &quantizer, &meta_page);
+    let query = SbqSearchDistanceMeasure::with_codes(
+        &quantizer,
+        codes.clone(),
+        meta_page.get_num_dimensions_for_neighbors() as usize,
+    );
+    let mut iter = TSVResponseIterator::new(
+        &storage,
+        index,
+        query,
+        filter,
+        None,
+        search_list_size,
+        0,
+        meta_page.clone(),
+        stats,
+    );
+
+    let mut results = Vec::with_capacity(limit);
+    while results.len() < limit {
+        let Some((heap_pointer, index_pointer)) = iter.next(index, &storage) else {
+            break;
+        };
+        let node_codes =
+            storage.get_quantized_vector_from_index_pointer(index_pointer, &mut iter.lsr.stats);
+        let distance = distance_xor_optimized(&codes, &node_codes);
+        results.push((heap_pointer, distance as f32));
+    }
+    results
+}
+
+#[allow(clippy::too_many_arguments)]
+fn collect_heap_pointers<S: Storage>(
+    iter: &mut TSVResponseIterator<S::QueryDistanceMeasure, S::LSNPrivateData>,
+    index: &PgRelation,
+    heap: &PgRelation,
+    storage: &S,
+    resort: bool,
+    limit: usize,
+    stats: &mut GreedySearchStats,
+    keep: &mut dyn FnMut(HeapPointer) -> bool,
+) -> Vec<HeapPointer> {
+    let mut results = Vec::with_capacity(limit);
+    while results.len() < limit {
+        let next = if resort {
+            iter.next_with_resort(index, heap, storage)
+        } else {
+            iter.next(index, storage)
+        };
+        match next {
+            Some((heap_pointer, _)) if keep(heap_pointer) => results.push(heap_pointer),
+            Some(_) => continue,
+            None => break,
+        }
+    }
+    stats.combine(&iter.lsr.stats);
+    results
+}
+
+/// The first `limit` results of a search, with their distances.
+fn collect_scored<S: Storage>(
+    iter: &mut TSVResponseIterator<S::QueryDistanceMeasure, S::LSNPrivateData>,
+    index: &PgRelation,
+    heap: &PgRelation,
+    storage: &S,
+    resort: bool,
+    limit: usize,
+    stats: &mut GreedySearchStats,
+) -> Vec<(HeapPointer, f32)> {
+    let mut results = Vec::with_capacity(limit);
+    while results.len() < limit {
+        let next = if resort {
+            iter.next_with_resort_scored(index, heap, storage)
+        } else {
+            iter.next_scored(index, storage)
+        };
+        match next {
+            Some(rd) => results.push((rd.heap_pointer, rd.distance)),
+            None => break,
+        }
+    }
+    stats.combine(&iter.lsr.stats);
+    results
+}
+
+/// The first `limit` results of a search ordered by recency, with their scores.
+fn collect_recent<S: Storage>(
+    iter: &mut TSVResponseIterator<S::QueryDistanceMeasure, S::LSNPrivateData>,
+    index: &PgRelation,
+    heap: &PgRelation,
+    storage: &S,
+    resort: bool,
+    limit: usize,
+    stats: &mut GreedySearchStats,
+) -> Vec<(HeapPointer, f32)> {
+    let mut results = Vec::with_capacity(limit);
+    while results.len() < limit {
+        match iter.next_with_recency_scored(index, heap, storage, resort) {
+            Some(rd) => results.push((rd.heap_pointer, rd.distance)),
+            None => break,
+        }
+    }
+    stats.combine(&iter.lsr.stats);
+    results
+}
+
 /*
 struct TSVScanState<'a, 'b> {
     iterator: *mut TSVResponseIterator<'a, 'b>,
@@ -314,7 +872,7 @@ pub extern "C" fn ambeginscan(
 #[pg_guard]
 pub extern "C" fn amrescan(
     scan: pg_sys::IndexScanDesc,
-    _keys: pg_sys::ScanKey,
+    keys: pg_sys::ScanKey,
     nkeys: ::std::os::raw::c_int,
     orderbys: pg_sys::ScanKey,
     norderbys: ::std::os::raw::c_int,
@@ -329,27 +887,60 @@ pub extern "C" fn amrescan(
     let indexrel = unsafe { PgRelation::from_pg(scan.indexRelation) };
     let heaprel = unsafe { PgRelation::from_pg(scan.heapRelation) };
 
-    if nkeys > 0 {
-        scan.xs_recheck = true;
-    }
+    /* the filter operators are evaluated exactly on the payload, no need to recheck on the heap */
+    scan.xs_recheck = false;
+    let filter = unsafe { PayloadFilter::new(&indexrel, keys, nkeys as _) };
 
     let orderby_keys = unsafe {
         std::slice::from_raw_parts(orderbys as *const pg_sys::ScanKeyData, norderbys as _)
     };
 
-    let search_list_size = super::guc::TSV_QUERY_SEARCH_LIST_SIZE.get() as usize;
+    let custom_distance = unsafe {
+        CustomDistance::new(
+            &indexrel,
+            orderby_keys[0].sk_argument,
+            orderby_keys[0].sk_collation,
+        )
+    };
 
     let state = unsafe { (scan.opaque as *mut TSVScanState).as_mut() }.expect("no scandesc state");
+    let mut search_list_size = super::guc::get_query_search_list_size(&indexrel, &state.meta_page);
+    let mut resort_size = super::guc::get_query_rescore(&indexrel, &state.meta_page);
+    /* parameters set for this query with diskann_query_params take precedence */
+    if let Some(hint) = unsafe { take_query_hint(orderby_keys[0].sk_argument) } {
+        search_list_size = hint.search_list_size.unwrap_or(search_list_size);
+        resort_size = hint.rescore.unwrap_or(resort_size);
+    }
 
+    let started = Instant::now();
+    let mut query = orderby_keys[0].sk_argument;
+    if !state.meta_page.get_weights().is_empty() {
+        /* the <*> operator of vector_weighted_cosine_ops, its weights have to be the index's */
+        let (vector, weights) = unsafe { read_weighted_query(query) };
+        if weights != state.meta_page.get_weights() {
+            error!("the weights of the query differ from the weights of the index");
+        }
+        query = vector;
+    }
     let query = unsafe {
         PgVector::from_datum(
-            orderby_keys[0].sk_argument,
+            query,
             &state.meta_page,
             true, /* needed for search */
             true, /* needed for resort */
         )
     };
-    state.initialize(&indexrel, &heaprel, query, search_list_size);
+    state.initialize(
+        &indexrel,
+        &heaprel,
+        query,
+        filter,
+        custom_distance,
+        search_list_size,
+        resort_size,
+        scan.ignore_killed_tuples,
+    );
+    state.elapsed = started.elapsed();
 }
 
 #[pg_guard]
@@ -357,6 +948,7 @@ pub extern "C" fn amgettuple(
     scan: pg_sys::IndexScanDesc,
     _direction: pg_sys::ScanDirection,
 ) -> bool {
+    let started = Instant::now();
     let scan: PgBox<pg_sys::IndexScanDescData> = unsafe { PgBox::from_pg(scan) };
     let state = unsafe { (scan.opaque as *mut TSVScanState).as_mut() }.expect("no scandesc state");
     //let iter = unsafe { state.iterator.as_mut() }.expect("no iterator in state");
@@ -364,8 +956,23 @@ pub extern "C" fn amgettuple(
     let indexrel = unsafe { PgRelation::from_pg(scan.indexRelation) };
     let heaprel = unsafe { PgRelation::from_pg(scan.heapRelation) };
 
+    if scan.kill_prior_tuple {
+        if let Some((heap_pointer, index_pointer)) = state.last_returned {
+            unsafe {
+                match state.meta_page.get_storage_type() {
+                    StorageType::Plain => {
+                        kill_node::<PlainStorage>(&indexrel, heap_pointer, index_pointer)
+                    }
+                    StorageType::SbqSpeedup | StorageType::SbqCompression => {
+                        kill_node::<SbqSpeedupStorage>(&indexrel, heap_pointer, index_pointer)
+                    }
+                }
+            }
+        }
+    }
+
     let mut storage = unsafe { state.storage.as_mut() }.expect("no storage in state");
-    match &mut storage {
+    let found = match &mut storage {
         StorageState::SbqSpeedup(quantizer, iter) => {
             let bq = SbqSpeedupStorage::load_for_search(
                 &indexrel,
@@ -373,23 +980,57 @@ pub extern "C" fn amgettuple(
                 quantizer,
                 &state.meta_page,
             );
-            let next = iter.next_with_resort(&indexrel, &bq);
+            let next = iter.next_for_scan(&indexrel, &heaprel, &bq, true);
             get_tuple(state, next, scan)
         }
         StorageState::Plain(iter) => {
             let storage =
                 PlainStorage::load_for_search(&indexrel, &heaprel, state.distance_fn.unwrap());
-            let next = if state.meta_page.get_num_dimensions()
-                == state.meta_page.get_num_dimensions_to_index()
-            {
-                /* no need to resort */
-                iter.next(&storage)
-            } else {
-                iter.next_with_resort(&indexrel, &storage)
-            };
+            /* no need to resort if the index has the full vectors */
+            let resort = state.meta_page.get_num_dimensions()
+                != state.meta_page.get_num_dimensions_to_index()
+                || iter.custom_distance.is_some();
+            let next = iter.next_for_scan(&indexrel, &heaprel, &storage, resort);
             get_tuple(state, next, scan)
         }
+    };
+    state.elapsed += started.elapsed();
+    found
+}
+
+/// Sets the LP_DEAD hint on the item of the node of a tuple that the executor found dead to all
+/// transactions, so that later scans skip it without fetching the tuple. Like the LP_DEAD hints
+/// of btree, only the line pointer changes, under a share lock, and it is not WAL-logged. The
+/// node keeps its heap pointer and its edges, the next VACUUM deletes it as usual, and nothing
+/// but scans relies on the hint, which can be lost. The heap pointer is checked in case the
+/// node is not the one the scan returned.
+unsafe fn kill_node<S: Storage>(
+    index: &PgRelation,
+    heap_pointer: HeapPointer,
+    index_pointer: IndexPointer,
+) {
+    let buffer = LockedBufferShare::read(index, index_pointer.block_number);
+    let page = pg_sys::BufferGetPage(*buffer);
+    let item_id = PageGetItemId(page, index_pointer.offset);
+    if (*item_id).lp_flags() != pg_sys::LP_NORMAL {
+        return;
+    }
+    let item = PageGetItem(page, item_id) as *mut u8;
+    let data = std::slice::from_raw_parts_mut(item, (*item_id).lp_len() as _);
+    let node = S::ArchivedType::with_data(data);
+    if node.is_deleted() || node.get_heap_item_pointer() != heap_pointer {
+        return;
     }
+    (*item_id).set_lp_flags(pg_sys::LP_DEAD);
+    pg_sys::MarkBufferDirtyHint(*buffer, true);
+}
+
+/// Whether a scan set the LP_DEAD hint on the item of the node at `index_pointer`.
+unsafe fn is_killed(index: &PgRelation, index_pointer: IndexPointer) -> bool {
+    let buffer = LockedBufferShare::read(index, index_pointer.block_number);
+    let page = pg_sys::BufferGetPage(*buffer);
+    let item_id = PageGetItemId(page, index_pointer.offset);
+    (*item_id).lp_flags() == pg_sys::LP_DEAD
 }
 
 fn get_tuple(
@@ -402,6 +1043,7 @@ fn get_tuple(
         Some((heap_pointer, index_pointer)) => {
             let tid_to_set = &mut scan.xs_heaptid;
             heap_pointer.to_item_pointer_data(tid_to_set);
+            state.last_returned = Some((heap_pointer, index_pointer));
 
             /*
              * An index scan must maintain a pin on the index page holding the
@@ -418,6 +1060,7 @@ fn get_tuple(
         }
         None => {
             state.last_buffer = None;
+            state.last_returned = None;
             false
         }
     }
@@ -430,27 +1073,55 @@ pub extern "C" fn amendscan(scan: pg_sys::IndexScanDesc) {
         let c = pg_sys::client_min_messages;
         std::cmp::min(l, c)
     };
-    if min_level <= pg_sys::DEBUG1 as _ {
-        let scan: PgBox<pg_sys::IndexScanDescData> = unsafe { PgBox::from_pg(scan) };
-        let state =
-            unsafe { (scan.opaque as *mut TSVScanState).as_mut() }.expect("no scandesc state");
-
+    let scan: PgBox<pg_sys::IndexScanDescData> = unsafe { PgBox::from_pg(scan) };
+    let state = unsafe { (scan.opaque as *mut TSVScanState).as_mut() }.expect("no scandesc state");
+    let log_min_duration = TSV_LOG_MIN_DURATION.get();
+    let slow = log_min_duration >= 0 && state.elapsed.as_millis() >= log_min_duration as u128;
+    if min_level <= pg_sys::DEBUG1 as _ || slow {
+        let indexrel = unsafe { PgRelation::from_pg(scan.indexRelation) };
+        let max_pins = buffer::get_peak_pins().saturating_sub(state.pins_at_start);
+        let elapsed = slow.then_some(state.elapsed);
         let mut storage = unsafe { state.storage.as_mut() }.expect("no storage in state");
         match &mut storage {
-            StorageState::SbqSpeedup(_bq, iter) => end_scan::<SbqSpeedupStorage>(iter),
-            StorageState::Plain(iter) => end_scan::<PlainStorage>(iter),
+            StorageState::SbqSpeedup(_bq, iter) => {
+                end_scan::<SbqSpeedupStorage>(iter, &indexrel, max_pins, elapsed)
+            }
+            StorageState::Plain(iter) => {
+                end_scan::<PlainStorage>(iter, &indexrel, max_pins, elapsed)
+            }
         }
     }
 }
 
+/// Logs the counters of a scan, at DEBUG1, and at LOG if it took `elapsed`, longer than
+/// `diskann.log_min_duration`.
 fn end_scan<S: Storage>(
     iter: &mut TSVResponseIterator<S::QueryDistanceMeasure, S::LSNPrivateData>,
+    index: &PgRelation,
+    max_pins: usize,
+    elapsed: Option<Duration>,
 ) {
     debug_assert!(iter.quantizer_stats.node_reads == 1);
     debug_assert!(iter.quantizer_stats.node_writes == 0);
 
+    if let Some(elapsed) = elapsed {
+        log!(
+            "diskann scan of index \"{}\" took {:.3} ms: search_list_size={} rescore={} next={} visits={} reads_index={} reads_heap={} rescored={} d_total={}",
+            index.name(),
+            elapsed.as_secs_f64() * 1000.0,
+            iter.search_list_size,
+            iter.resort_size,
+            iter.next_calls,
+            iter.lsr.stats.get_visited_nodes(),
+            iter.lsr.stats.get_node_reads(),
+            iter.lsr.stats.get_node_heap_reads(),
+            iter.full_distance_comparisons,
+            iter.lsr.stats.get_total_distance_comparisons(),
+        );
+    }
+
     debug1!(
-        "Query stats - reads_index={} reads_heap={} d_total={} d_quantized={} d_full={} next={} resort={} visits={} candidate={}",
+        "Query stats - reads_index={} reads_heap={} d_total={} d_quantized={} d_full={} next={} resort={} visits={} candidate={} max_pins={}",
         iter.lsr.stats.get_node_reads(),
         iter.lsr.stats.get_node_heap_reads(),
         iter.lsr.stats.get_total_distance_comparisons(),
@@ -460,5 +1131,85 @@ fn end_scan<S: Storage>(
         iter.next_calls_with_resort,
         iter.lsr.stats.get_visited_nodes(),
         iter.lsr.stats.get_candidate_nodes(),
+        max_pins,
     );
 }
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::scan_memory_bounds;
+
+    #[pg_test]
+    unsafe fn test_scan_memory_bounds() {
+        /* small search lists are not changed */
+        let (search_list_size, max_resort_buffer) = scan_memory_bounds::<()>(100, 50, 4096);
+        assert_eq!(search_list_size, 100);
+        assert!(max_resort_buffer >= 1000);
+
+        /* huge ones are reduced, the less work_mem the more */
+        let (large, _) = scan_memory_bounds::<()>(10000, 50, 4096);
+        let (small, max_resort_buffer) = scan_memory_bounds::<()>(10000, 50, 64);
+        assert!(large < 10000);
+        assert!(small < large);
+        assert!(small >= 1);
+        assert!(max_resort_buffer >= 2);
+    }
+
+    #[pg_test]
+    unsafe fn test_scan_small_work_mem() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(32));
+
+            INSERT INTO test(id, embedding)
+            SELECT i::int, v::vector
+            FROM diskann_generate_vectors(1000, 32, num_clusters => 10, seed => 7)
+                WITH ORDINALITY AS g(v, i);
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+
+            SET enable_seqscan = 0;
+            SET work_mem = '64kB';
+            SET diskann.query_search_list_size = 10000;
+            SET diskann.query_rescore = 1000;",
+        )?;
+
+        /* the scan still returns all the rows, starting with the row of the query vector */
+        let first: Option<i32> = Spi::get_one(
+            "SELECT id FROM test
+            ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 10) LIMIT 1",
+        )?;
+        assert_eq!(first, Some(10));
+
+        let count: Option<i64> = Spi::get_one(
+            "WITH cte AS (
+                SELECT id FROM test
+                ORDER BY embedding <=> (SELECT embedding FROM test WHERE id = 10)
+            ) SELECT count(*) FROM cte",
+        )?;
+        assert_eq!(count, Some(1000));
+        Ok(())
+    }
+    #[pg_test]
+    unsafe fn test_log_min_duration() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+
+            SET enable_seqscan = 0;
+            SET diskann.log_min_duration = 0;",
+        )?;
+
+        /* every scan is logged, which does not change its results */
+        let first: Option<i32> =
+            Spi::get_one("SELECT id FROM test ORDER BY embedding <=> '[100, 0, 1]' LIMIT 1")?;
+        assert_eq!(first, Some(100));
+        Ok(())
+    }
+}
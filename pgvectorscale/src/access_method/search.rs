@@ -0,0 +1,549 @@
+//! Searches an index directly from SQL, independently of the plans chosen by the planner.
+//!
+//! The query can also be given as the codes the SBQ storage layouts compute for it, e.g. by an
+//! application that caches the codes of its queries. `diskann_quantizer_id` identifies the
+//! quantizer the codes have to be computed with.
+
+use pgrx::{pg_sys, *};
+
+use crate::util::{HeapPointer, ItemPointer};
+
+use super::{
+    guc::{get_query_rescore, get_query_search_list_size},
+    meta_page::MetaPage,
+    pg_vector::PgVector,
+    recall::VectorReader,
+    result_cache,
+    sbq::{SbqMeans, SbqQuantizer},
+    scan::{search_heap_pointers, search_heap_pointers_by_codes, search_heap_pointers_filtered},
+    stats::{GreedySearchStats, NoStats},
+    storage::StorageType,
+    storage_common::{check_query_type, open_diskann_index},
+};
+
+/// The search settings of `diskann_search` and `diskann_knn_join`, from their `params` argument
+/// or the settings of an index scan.
+pub struct SearchParams {
+    pub search_list_size: usize,
+    pub rescore: usize,
+}
+
+impl SearchParams {
+    pub fn new(index: &PgRelation, meta_page: &MetaPage, params: Option<JsonB>) -> Self {
+        let mut search_params = Self {
+            search_list_size: get_query_search_list_size(index, meta_page),
+            rescore: get_query_rescore(index, meta_page),
+        };
+        let Some(JsonB(params)) = params else {
+            return search_params;
+        };
+        let Some(params) = params.as_object() else {
+            error!("params must be a JSON object");
+        };
+        for (key, value) in params.iter() {
+            let Some(value) = value.as_u64() else {
+                error!("the value of \"{}\" must be a non-negative integer", key);
+            };
+            match key.as_str() {
+                "search_list_size" if value > 0 => search_params.search_list_size = value as _,
+                "search_list_size" => error!("search_list_size must be positive"),
+                "rescore" => search_params.rescore = value as _,
+                _ => error!(
+                    "unknown search parameter \"{}\", expected search_list_size or rescore",
+                    key
+                ),
+            }
+        }
+        search_params
+    }
+}
+
+/// Returns the `k` rows of the table nearest to `query` found by the index, closest first, with
+/// the distance the index orders them by: the distance of the `<=>` operator for cosine
+/// indexes, and the distance of the vectors as preprocessed by the index otherwise. The index
+/// is searched directly, whatever plan the planner would choose for the query, with the
+/// `search_list_size` and `rescore` of `params` if set, and with the settings of an index scan
+/// otherwise:
+///
+/// `SELECT * FROM diskann_search('idx'::regclass, '[1,2,3]'::vector, 10, '{"rescore": 100}');`
+///
+/// `query` has the type of the indexed column. The rows are only identified by their `ctid`, so
+/// join on it to read the other columns. Like an index scan, the search returns the rows deleted
+/// since the last vacuum as well.
+///
+/// The rows are ordered by distance and then by `ctid`. Given the distance and `ctid` of the last
+/// row of a page of results as `after_distance` and `after_ctid`, the search returns the next
+/// page: the `k` rows after it in that order. The search still walks past the rows of the
+/// earlier pages, but skips them as it finds them instead of collecting them with the others.
+///
+/// With `diskann.result_cache` on, the rows of first pages are cached in shared memory for
+/// repeated searches.
+#[pg_extern(volatile)]
+pub fn diskann_search(
+    index: pg_sys::Oid,
+    query: AnyElement,
+    k: i32,
+    params: default!(Option<JsonB>, "NULL"),
+    after_distance: default!(Option<f64>, "NULL"),
+    after_ctid: default!(Option<pg_sys::ItemPointerData>, "NULL"),
+) -> TableIterator<'static, (name!(ctid, pg_sys::ItemPointerData), name!(distance, f64))> {
+    if k <= 0 {
+        error!("k must be positive");
+    }
+    let after = match (after_distance, after_ctid) {
+        (Some(distance), Some(ctid)) => {
+            let heap_pointer = unsafe { ItemPointer::with_item_pointer_data(ctid) };
+            Some((distance, heap_pointer))
+        }
+        (None, None) => None,
+        _ => error!("after_distance and after_ctid must be given together"),
+    };
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let meta_page = MetaPage::fetch(&index_relation);
+    let params = SearchParams::new(&index_relation, &meta_page, params);
+
+    check_query_type(&index_relation, &query);
+    let query = unsafe { PgVector::from_datum(query.datum(), &meta_page, true, true) };
+    let query_vector = query.to_full_slice().to_vec();
+
+    /* the index only keeps the distances of the quantized vectors, so compute the exact ones */
+    let distance_fn = meta_page.get_distance_function();
+    let mut reader = VectorReader::new(&index_relation, &heap_relation, &meta_page);
+    let mut distance_of = |heap_pointer: HeapPointer| {
+        reader
+            .read(heap_pointer)
+            .map(|vector| distance_fn(&query_vector, vector.to_full_slice()) as f64)
+    };
+
+    let mut rows: Vec<(HeapPointer, f64)> = Vec::with_capacity(k as _);
+    match after {
+        None => {
+            let cache_key = result_cache::get_key(
+                &query_vector,
+                k as _,
+                params.search_list_size,
+                params.rescore,
+            );
+            let heap_pointers =
+                match result_cache::lookup(&index_relation, &heap_relation, cache_key) {
+                    Some(heap_pointers) => heap_pointers,
+                    None => {
+                        let generation = result_cache::get_generation(&index_relation);
+                        let heap_pointers = search_heap_pointers(
+                            &index_relation,
+                            &heap_relation,
+                            query,
+                            k as _,
+                            params.search_list_size,
+                            params.rescore,
+                            &mut GreedySearchStats::new(),
+                        );
+                        result_cache::insert(
+                            &index_relation,
+                            cache_key,
+                            generation,
+                            &heap_pointers,
+                        );
+                        heap_pointers
+                    }
+                };
+            for heap_pointer in heap_pointers {
+                if let Some(distance) = distance_of(heap_pointer) {
+                    rows.push((heap_pointer, distance));
+                }
+            }
+        }
+        Some((after_distance, after_ctid)) => {
+            search_heap_pointers_filtered(
+                &index_relation,
+                &heap_relation,
+                query,
+                k as _,
+                params.search_list_size,
+                params.rescore,
+                &mut GreedySearchStats::new(),
+                &mut |heap_pointer| {
+                    let Some(distance) = distance_of(heap_pointer) else {
+                        return false;
+                    };
+                    if (distance, heap_pointer) <= (after_distance, after_ctid) {
+                        /* returned by an earlier page */
+                        return false;
+                    }
+                    rows.push((heap_pointer, distance));
+                    true
+                },
+            );
+        }
+    }
+
+    rows.sort_by(|(a_pointer, a_distance), (b_pointer, b_distance)| {
+        a_distance
+            .total_cmp(b_distance)
+            .then_with(|| a_pointer.cmp(b_pointer))
+    });
+    let rows: Vec<_> = rows
+        .into_iter()
+        .map(|(heap_pointer, distance)| {
+            let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
+                ..Default::default()
+            };
+            heap_pointer.to_item_pointer_data(&mut ctid);
+            (ctid, distance)
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+/// The quantizer of an index with an SBQ storage layout.
+fn load_quantizer(index: &PgRelation, meta_page: &MetaPage) -> SbqQuantizer {
+    if meta_page.get_storage_type() == StorageType::Plain {
+        error!("the index has no quantizer, its storage layout is plain");
+    }
+    unsafe { SbqMeans::load(index, meta_page, &mut NoStats) }
+}
+
+/// Returns the codes the index computes for `query`: the bits of every dimension of the vector
+/// as the index preprocesses it, in little-endian 64 bit words. `query` has the type of the
+/// indexed column.
+#[pg_extern(stable)]
+pub fn diskann_quantize(index: pg_sys::Oid, query: AnyElement) -> Vec<u8> {
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let meta_page = MetaPage::fetch(&index_relation);
+    let quantizer = load_quantizer(&index_relation, &meta_page);
+    check_query_type(&index_relation, &query);
+    let query = unsafe { PgVector::from_datum(query.datum(), &meta_page, true, false) };
+    quantizer
+        .quantize(query.to_index_slice())
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect()
+}
+
+/// Identifies the quantizer of an index. It only changes when the index is rebuilt, so clients
+/// that cache the codes of `diskann_quantize` can check that they are still valid.
+#[pg_extern(stable)]
+pub fn diskann_quantizer_id(index: pg_sys::Oid) -> i64 {
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let meta_page = MetaPage::fetch(&index_relation);
+    load_quantizer(&index_relation, &meta_page).get_id()
+}
+
+/// Returns the quantizer of an index with an SBQ storage layout, one row per indexed dimension:
+/// the mean and the standard deviation of the dimension over the vectors the quantizer was
+/// trained on, as the index preprocesses them, and the values above which the codes set the bits
+/// of the dimension. The standard deviation is NULL with one bit per dimension, where the mean
+/// is the only threshold. Comparing the rows of two builds shows how much their quantizers
+/// differ.
+#[pg_extern(stable)]
+pub fn diskann_quantizer_codebook(
+    index: pg_sys::Oid,
+) -> TableIterator<
+    'static,
+    (
+        name!(dimension, i32),
+        name!(mean, Option<f64>),
+        name!(std_dev, Option<f64>),
+        name!(thresholds, Vec<f64>),
+    ),
+> {
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let meta_page = MetaPage::fetch(&index_relation);
+    let quantizer = load_quantizer(&index_relation, &meta_page);
+    let rows: Vec<_> = (0..meta_page.get_num_dimensions_to_index() as usize)
+        .map(|dimension| {
+            (
+                dimension as i32 + 1,
+                quantizer.use_mean.then(|| quantizer.mean[dimension] as f64),
+                quantizer.get_std_dev(dimension).map(|s| s as f64),
+                quantizer
+                    .get_thresholds(dimension)
+                    .into_iter()
+                    .map(|t| t as f64)
+                    .collect(),
+            )
+        })
+        .collect();
+    TableIterator::new(rows)
+}
+
+/// Like `diskann_search`, for a query given as the codes of `diskann_quantize`. The distance is
+/// the number of bits in which the codes differ, and the results are not rescored. If
+/// `quantizer_id` is set, the search fails unless it is the `diskann_quantizer_id` of the index.
+#[pg_extern(volatile)]
+pub fn diskann_search_codes(
+    index: pg_sys::Oid,
+    codes: &[u8],
+    k: i32,
+    quantizer_id: default!(Option<i64>, "NULL"),
+    search_list_size: default!(Option<i32>, "NULL"),
+) -> TableIterator<'static, (name!(ctid, pg_sys::ItemPointerData), name!(distance, f64))> {
+    if k <= 0 {
+        error!("k must be positive");
+    }
+    if search_list_size.map_or(false, |v| v <= 0) {
+        error!("search_list_size must be positive");
+    }
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let meta_page = MetaPage::fetch(&index_relation);
+    let quantizer = load_quantizer(&index_relation, &meta_page);
+    if quantizer_id.map_or(false, |id| id != quantizer.get_id()) {
+        error!("the codes were computed with another quantizer, compute them again with diskann_quantize");
+    }
+    let num_bytes = SbqQuantizer::quantized_size_bytes(
+        meta_page.get_num_dimensions_to_index() as usize,
+        meta_page.get_bq_num_bits_per_dimension(),
+    );
+    if codes.len() != num_bytes {
+        error!(
+            "the codes of the index are {} bytes long, not {}",
+            num_bytes,
+            codes.len()
+        );
+    }
+    let codes = codes
+        .chunks_exact(std::mem::size_of::<u64>())
+        .map(|word| u64::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+
+    let search_list_size = search_list_size.map_or_else(
+        || get_query_search_list_size(&index_relation, &meta_page),
+        |v| v as usize,
+    );
+    let rows: Vec<_> = search_heap_pointers_by_codes(
+        &index_relation,
+        &heap_relation,
+        codes,
+        k as _,
+        search_list_size,
+    )
+    .into_iter()
+    .map(|(heap_pointer, distance)| {
+        let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
+            ..Default::default()
+        };
+        heap_pointer.to_item_pointer_data(&mut ctid);
+        (ctid, distance as f64)
+    })
+    .collect();
+    TableIterator::new(rows)
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_search() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (num_neighbors = 20, storage_layout = plain);",
+        )?;
+
+        let (count, first) = Spi::get_two::<i64, i32>(
+            "SELECT count(*), (array_agg(t.id ORDER BY s.distance))[1]
+            FROM diskann_search('idxtest'::regclass, '[100, 0, 1]'::vector, 10) s
+            JOIN test t ON t.ctid = s.ctid",
+        )?;
+        assert_eq!(count, Some(10));
+        assert_eq!(first, Some(100));
+
+        /* the results come closest first, with the distances of the operator */
+        let in_order = Spi::get_one::<bool>(
+            "WITH s AS (
+                SELECT s.n, s.distance, t.embedding <=> '[100, 0, 1]' AS d
+                FROM diskann_search('idxtest'::regclass, '[100, 0, 1]'::vector, 10,
+                    '{\"search_list_size\": 50, \"rescore\": 20}')
+                    WITH ORDINALITY AS s(ctid, distance, n)
+                JOIN test t ON t.ctid = s.ctid
+            ) SELECT bool_and(abs(s.distance - s.d) < 1e-5)
+                AND bool_and(s.distance >= coalesce(
+                    (SELECT p.distance FROM s p WHERE p.n = s.n - 1), 0))
+            FROM s",
+        )?;
+        assert_eq!(in_order, Some(true));
+        Ok(())
+    }
+
+    #[pg_test(error = "the query has type real[], but the index is on a column of type vector")]
+    unsafe fn test_search_query_type() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test SELECT ARRAY[i, 1, 1]::vector FROM generate_series(1, 10) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            SELECT * FROM diskann_search('idxtest'::regclass, '{1, 1, 1}'::real[], 5);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(error = "permission denied for table test")]
+    unsafe fn test_search_permission() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test SELECT ARRAY[i, 1, 1]::vector FROM generate_series(1, 10) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            CREATE ROLE diskann_no_select;",
+        )?;
+        let index = Spi::get_one::<pg_sys::Oid>("SELECT 'idxtest'::regclass::oid")?.unwrap();
+
+        /* the user cannot read the rows of the table through the index either */
+        Spi::run(&format!(
+            "SET ROLE diskann_no_select;
+            SELECT * FROM diskann_search('{}'::oid, '[1, 1, 1]'::vector, 5);",
+            index.as_u32()
+        ))?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_search_codes() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(64));
+
+            INSERT INTO test(id, embedding)
+            SELECT i::int, v::vector
+            FROM diskann_generate_vectors(1000, 64, num_clusters => 5, seed => 3)
+                WITH ORDINALITY AS g(v, i);
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (storage_layout = io_optimized);",
+        )?;
+
+        /* one bit per dimension */
+        let num_bytes = Spi::get_one::<i32>(
+            "SELECT length(diskann_quantize('idxtest'::regclass, embedding)) FROM test WHERE id = 7",
+        )?;
+        assert_eq!(num_bytes, Some(8));
+
+        let (count, first_distance, in_order) = Spi::get_three::<i64, f64, bool>(
+            "WITH s AS (
+                SELECT s.n, s.distance
+                FROM diskann_search_codes('idxtest'::regclass,
+                    (SELECT diskann_quantize('idxtest'::regclass, embedding) FROM test WHERE id = 7),
+                    10, quantizer_id => diskann_quantizer_id('idxtest'::regclass))
+                    WITH ORDINALITY AS s(ctid, distance, n)
+            ) SELECT count(*), min(distance) FILTER (WHERE n = 1),
+                bool_and(distance >= coalesce((SELECT p.distance FROM s p WHERE p.n = s.n - 1), 0))
+            FROM s",
+        )?;
+        assert_eq!(count, Some(10));
+        assert_eq!(first_distance, Some(0.0));
+        assert_eq!(in_order, Some(true));
+        Ok(())
+    }
+
+    #[pg_test(
+        error = "the codes were computed with another quantizer, compute them again with diskann_quantize"
+    )]
+    unsafe fn test_search_codes_other_quantizer() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test SELECT ARRAY[i, 1, 1]::vector FROM generate_series(1, 10) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            SELECT * FROM diskann_search_codes('idxtest'::regclass,
+                diskann_quantize('idxtest'::regclass, '[1, 1, 1]'::vector), 5,
+                quantizer_id => diskann_quantizer_id('idxtest'::regclass) + 1);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_quantizer_codebook() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (storage_layout = memory_optimized, num_bits_per_dimension = 2);",
+        )?;
+
+        let (num_dimensions, first) = Spi::get_two::<i64, i32>(
+            "SELECT count(*), min(dimension) FROM diskann_quantizer_codebook('idxtest'::regclass)",
+        )?;
+        assert_eq!(num_dimensions, Some(3));
+        assert_eq!(first, Some(1));
+
+        /* two thresholds per dimension, around the mean */
+        let (num_thresholds, ordered) = Spi::get_two::<i32, bool>(
+            "SELECT max(cardinality(thresholds)),
+                bool_and(thresholds[1] < mean AND mean < thresholds[2])
+            FROM diskann_quantizer_codebook('idxtest'::regclass)",
+        )?;
+        assert_eq!(num_thresholds, Some(2));
+        assert_eq!(ordered, Some(true));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_search_pages() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 5, 1]::vector FROM generate_series(1, 300) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding)
+                WITH (num_neighbors = 20, storage_layout = plain);",
+        )?;
+
+        /* the second page continues after the last row of the first one */
+        let (num_rows, num_repeated, num_in_top_20) = Spi::get_three::<i64, i64, i64>(
+            "WITH p1 AS (
+                SELECT * FROM diskann_search('idxtest'::regclass, '[100, 0, 1]'::vector, 10,
+                    '{\"search_list_size\": 300}')
+            ), last AS (
+                SELECT * FROM p1 ORDER BY distance DESC, ctid DESC LIMIT 1
+            ), p2 AS (
+                SELECT s.* FROM last, diskann_search('idxtest'::regclass, '[100, 0, 1]'::vector,
+                    10, '{\"search_list_size\": 300}', after_distance => last.distance,
+                    after_ctid => last.ctid) s
+            ), top_20 AS (
+                SELECT * FROM diskann_search('idxtest'::regclass, '[100, 0, 1]'::vector, 20,
+                    '{\"search_list_size\": 300}')
+            ) SELECT (SELECT count(*) FROM p2),
+                (SELECT count(*) FROM p1 JOIN p2 USING (ctid)),
+                (SELECT count(*) FROM (SELECT ctid FROM p1 UNION SELECT ctid FROM p2) u
+                    JOIN top_20 USING (ctid))",
+        )?;
+        assert_eq!(num_rows, Some(10));
+        assert_eq!(num_repeated, Some(0));
+        assert_eq!(num_in_top_20, Some(20));
+        Ok(())
+    }
+
+    #[pg_test(error = "after_distance and after_ctid must be given together")]
+    unsafe fn test_search_after_distance_only() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            SELECT * FROM diskann_search('idxtest'::regclass, '[1, 1, 1]'::vector, 5,
+                after_distance => 0.5);",
+        )?;
+        Ok(())
+    }
+
+    #[pg_test(error = "unknown search parameter \"beam\", expected search_list_size or rescore")]
+    unsafe fn test_search_unknown_param() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3));
+            INSERT INTO test SELECT ARRAY[i, 1, 1]::vector FROM generate_series(1, 10) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding);
+            SELECT * FROM diskann_search('idxtest'::regclass, '[1, 1, 1]'::vector, 5, '{\"beam\": 5}');",
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Estimates the size of a diskann index before building it.
+
+use pgrx::*;
+
+use crate::util::{page::items_per_page, HeapPointer};
+
+use super::{
+    meta_page::{MetaPage, GRAPH_SLACK_FACTOR},
+    neighbor_with_distance::NeighborWithDistance,
+    options::{NUM_NEIGHBORS_DEFAULT_SENTINEL, SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL},
+    payload::get_payload_size,
+    plain_node::Node,
+    sbq::{SbqNode, SbqQuantizer},
+    storage::StorageType,
+};
+
+/// Slack for the allocator and the maps of the in-memory graph of a build.
+const BUILD_MEMORY_OVERHEAD_FACTOR: f64 = 1.5;
+
+/// Estimates the size of a diskann index on `num_rows` vectors of `num_dimensions` dimensions
+/// with the given options, before running CREATE INDEX. The options have the same defaults as
+/// in CREATE INDEX, and the resolved `num_neighbors` and `num_bits_per_dimension` are returned.
+/// `num_filter_columns` is the number of columns after the vector column.
+///
+/// `build_memory_bytes` estimates the memory of the graph a build keeps in memory until all
+/// nodes are written. The index size assumes no deleted nodes.
+#[pg_extern(immutable, parallel_safe)]
+pub fn diskann_estimate_size(
+    num_rows: i64,
+    num_dimensions: i32,
+    storage_layout: default!(&str, "'memory_optimized'"),
+    num_neighbors: default!(i32, -1),
+    num_bits_per_dimension: default!(i32, 0),
+    num_filter_columns: default!(i32, 0),
+) -> TableIterator<
+    'static,
+    (
+        name!(num_neighbors, i32),
+        name!(num_bits_per_dimension, i32),
+        name!(node_size_bytes, i64),
+        name!(nodes_per_page, i32),
+        name!(num_pages, i64),
+        name!(index_size_bytes, i64),
+        name!(build_memory_bytes, i64),
+    ),
+> {
+    if num_rows < 0 {
+        error!("num_rows must not be negative");
+    }
+    if num_dimensions <= 0 {
+        error!("num_dimensions must be positive");
+    }
+    if num_filter_columns < 0 {
+        error!("num_filter_columns must not be negative");
+    }
+    if num_neighbors != NUM_NEIGHBORS_DEFAULT_SENTINEL && num_neighbors < 10 {
+        error!("num_neighbors must be greater than 10, or -1 for default");
+    }
+    let storage_type = StorageType::from_str(storage_layout);
+    let num_dimensions = num_dimensions as u32;
+    let payload_size = get_payload_size(num_filter_columns as usize);
+
+    let num_bits_per_dimension =
+        if num_bits_per_dimension as u32 == SBQ_NUM_BITS_PER_DIMENSION_DEFAULT_SENTINEL {
+            MetaPage::default_bq_num_bits_per_dimension(storage_type, num_dimensions)
+        } else {
+            num_bits_per_dimension as u8
+        };
+    if num_bits_per_dimension > 1 && storage_type == StorageType::Plain {
+        error!(
+            "SBQ with more than 1 bit per dimension is only supported with the memory_optimized and io_optimized storage layouts"
+        );
+    }
+    let num_neighbors = if num_neighbors == NUM_NEIGHBORS_DEFAULT_SENTINEL {
+        MetaPage::default_num_neighbors(
+            storage_type,
+            num_dimensions,
+            num_bits_per_dimension,
+            payload_size,
+        ) as usize
+    } else {
+        num_neighbors as usize
+    };
+
+    let num_dimensions = num_dimensions as usize;
+    let (node_size, quantized_size, num_extra_pages) = match storage_type {
+        StorageType::Plain => (Node::test_size(num_neighbors, num_dimensions), 0, 1),
+        StorageType::SbqSpeedup | StorageType::SbqCompression => {
+            let num_dimensions_for_neighbors = if storage_type == StorageType::SbqSpeedup {
+                num_dimensions
+            } else {
+                0
+            };
+            (
+                SbqNode::test_size(
+                    num_neighbors,
+                    num_dimensions,
+                    num_dimensions_for_neighbors,
+                    num_bits_per_dimension,
+                ),
+                SbqQuantizer::quantized_size_bytes(num_dimensions, num_bits_per_dimension),
+                /* the meta page and the page of the quantizer */
+                2,
+            )
+        }
+    };
+    let node_size = node_size + payload_size;
+    let nodes_per_page = items_per_page(node_size);
+    if nodes_per_page == 0 {
+        error!(
+            "a node of {} bytes does not fit on a page, use fewer neighbors",
+            node_size
+        );
+    }
+
+    let num_rows = num_rows as usize;
+    let num_pages = (num_rows + nodes_per_page - 1) / nodes_per_page + num_extra_pages;
+
+    /* the build keeps the neighbors of every node, with some slack, and for SBQ the quantized
+    vector of every node in memory */
+    let max_neighbors_during_build = (num_neighbors as f64 * GRAPH_SLACK_FACTOR).ceil() as usize;
+    let build_memory_per_node = std::mem::size_of::<HeapPointer>()
+        + std::mem::size_of::<Vec<NeighborWithDistance>>()
+        + max_neighbors_during_build * std::mem::size_of::<NeighborWithDistance>()
+        + if quantized_size > 0 {
+            std::mem::size_of::<HeapPointer>() + std::mem::size_of::<Vec<u64>>() + quantized_size
+        } else {
+            0
+        };
+    let build_memory =
+        (num_rows * build_memory_per_node) as f64 * BUILD_MEMORY_OVERHEAD_FACTOR;
+
+    TableIterator::once((
+        num_neighbors as i32,
+        if quantized_size > 0 {
+            num_bits_per_dimension as i32
+        } else {
+            0
+        },
+        node_size as i64,
+        nodes_per_page as i32,
+        num_pages as i64,
+        (num_pages * pg_sys::BLCKSZ as usize) as i64,
+        build_memory as i64,
+    ))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_estimate_size() -> spi::Result<()> {
+        for storage_layout in ["plain", "io_optimized", "memory_optimized"] {
+            Spi::run(&format!(
+                "CREATE TABLE test(embedding vector(64));
+
+                INSERT INTO test(embedding)
+                SELECT v::vector FROM diskann_generate_vectors(2000, 64, seed => 1) v;
+
+                CREATE INDEX idxtest ON test USING diskann(embedding)
+                    WITH (storage_layout = {storage_layout});",
+            ))?;
+
+            let (estimated_pages, num_neighbors) = Spi::get_two::<i64, i32>(&format!(
+                "SELECT num_pages, num_neighbors FROM diskann_estimate_size(2000, 64, '{storage_layout}')",
+            ))?;
+            let (actual_pages, actual_num_neighbors) = Spi::get_two::<i64, i32>(
+                "SELECT num_pages, num_neighbors FROM diskann_index_info('idxtest'::regclass)",
+            )?;
+            assert_eq!(num_neighbors, actual_num_neighbors, "{}", storage_layout);
+            /* the last node on a page may fit in the alignment padding the estimate assumes */
+            assert!(
+                (estimated_pages.unwrap() - actual_pages.unwrap()).abs() <= 2,
+                "{}: estimated {:?} pages, actual {:?}",
+                storage_layout,
+                estimated_pages,
+                actual_pages
+            );
+
+            Spi::run("DROP TABLE test")?;
+        }
+        Ok(())
+    }
+}
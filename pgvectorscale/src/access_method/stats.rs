@@ -1,4 +1,6 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use pgrx::pg_sys;
 
 pub trait StatsNodeRead {
     fn record_read(&mut self);
@@ -26,6 +28,148 @@ pub trait StatsNodeVisit {
     fn record_candidate(&mut self);
 }
 
+/// Implements the stats traits for `&mut S`, so that stats can be passed on by reference, and for
+/// pairs, which record into both of their stats, e.g. a count per call and a total.
+macro_rules! impl_stats_composition {
+    ($($trait:ident { $($method:ident),* }),* $(,)?) => {
+        $(
+            impl<S: $trait + ?Sized> $trait for &mut S {
+                $(
+                    #[inline]
+                    fn $method(&mut self) {
+                        (**self).$method();
+                    }
+                )*
+            }
+
+            impl<A: $trait, B: $trait> $trait for (A, B) {
+                $(
+                    #[inline]
+                    fn $method(&mut self) {
+                        self.0.$method();
+                        self.1.$method();
+                    }
+                )*
+            }
+
+            impl $trait for NoStats {
+                $(
+                    #[inline]
+                    fn $method(&mut self) {}
+                )*
+            }
+        )*
+    };
+}
+
+/// Stats that record nothing, for callers that don't report them. Compiles to nothing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoStats;
+
+impl_stats_composition!(
+    StatsNodeRead { record_read },
+    StatsHeapNodeRead { record_heap_read },
+    StatsNodeModify { record_modify },
+    StatsNodeWrite { record_write },
+    StatsDistanceComparison {
+        record_full_distance_comparison,
+        record_quantized_distance_comparison
+    },
+    StatsNodeVisit {
+        record_visit,
+        record_candidate
+    },
+);
+
+/// The time spent in each phase of an operation, e.g. the subphases of an index build. A phase
+/// can be entered several times and its time adds up.
+#[derive(Debug, Default)]
+pub struct PhaseTimings {
+    current: Option<(i64, Instant)>,
+    durations: Vec<(i64, Duration)>,
+}
+
+impl PhaseTimings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ends the current phase, if any, and starts `phase`.
+    pub fn enter(&mut self, phase: i64) {
+        self.finish();
+        self.current = Some((phase, Instant::now()));
+    }
+
+    /// Ends the current phase.
+    pub fn finish(&mut self) {
+        if let Some((phase, started)) = self.current.take() {
+            self.add(phase, started.elapsed());
+        }
+    }
+
+    fn add(&mut self, phase: i64, duration: Duration) {
+        match self.durations.iter_mut().find(|(p, _)| *p == phase) {
+            Some((_, total)) => *total += duration,
+            None => self.durations.push((phase, duration)),
+        }
+    }
+
+    /// The time spent in `phase` so far, including the current phase, in milliseconds.
+    pub fn get_ms(&self, phase: i64) -> f64 {
+        let finished: Duration = self
+            .durations
+            .iter()
+            .filter(|(p, _)| *p == phase)
+            .map(|(_, d)| *d)
+            .sum();
+        let running = match self.current {
+            Some((p, started)) if p == phase => started.elapsed(),
+            _ => Duration::ZERO,
+        };
+        (finished + running).as_secs_f64() * 1000.0
+    }
+}
+
+/// The buffers and the WAL used by the backend, counted like VACUUM VERBOSE counts them: hits,
+/// reads and dirtied buffers of both shared and local buffers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoUsage {
+    pub buffers_hit: i64,
+    pub buffers_read: i64,
+    pub buffers_dirtied: i64,
+    pub wal_records: i64,
+    pub wal_fpi: i64,
+    pub wal_bytes: i64,
+}
+
+impl IoUsage {
+    /// The usage of the backend since it started.
+    pub fn current() -> Self {
+        let (buffers, wal) = unsafe { (pg_sys::pgBufferUsage, pg_sys::pgWalUsage) };
+        Self {
+            buffers_hit: buffers.shared_blks_hit + buffers.local_blks_hit,
+            buffers_read: buffers.shared_blks_read + buffers.local_blks_read,
+            buffers_dirtied: buffers.shared_blks_dirtied + buffers.local_blks_dirtied,
+            wal_records: wal.wal_records,
+            wal_fpi: wal.wal_fpi,
+            wal_bytes: wal.wal_bytes as i64,
+        }
+    }
+
+    /// The usage of the backend since `start`, an earlier `current`.
+    pub fn since(start: &IoUsage) -> Self {
+        let now = Self::current();
+        Self {
+            buffers_hit: now.buffers_hit - start.buffers_hit,
+            buffers_read: now.buffers_read - start.buffers_read,
+            buffers_dirtied: now.buffers_dirtied - start.buffers_dirtied,
+            wal_records: now.wal_records - start.wal_records,
+            wal_fpi: now.wal_fpi - start.wal_fpi,
+            wal_bytes: now.wal_bytes - start.wal_bytes,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PruneNeighborStats {
     pub calls: usize,
@@ -101,6 +245,8 @@ impl GreedySearchStats {
         self.node_reads += other.node_reads;
         self.node_heap_reads += other.node_heap_reads;
         self.quantized_distance_comparisons += other.quantized_distance_comparisons;
+        self.visited_nodes += other.visited_nodes;
+        self.candidate_nodes += other.candidate_nodes;
     }
 
     pub fn get_calls(&self) -> usize {
@@ -241,6 +387,9 @@ impl StatsNodeWrite for InsertStats {
 
 pub struct WriteStats {
     pub started: Instant,
+    /* the usage of the backend when the stats were created */
+    pub io_started: IoUsage,
+    pub phases: PhaseTimings,
     pub num_nodes: usize,
     pub nodes_read: usize,
     pub nodes_modified: usize,
@@ -253,6 +402,8 @@ impl WriteStats {
     pub fn new() -> Self {
         Self {
             started: Instant::now(),
+            io_started: IoUsage::current(),
+            phases: PhaseTimings::new(),
             num_nodes: 0,
             prune_stats: PruneNeighborStats::new(),
             num_neighbors: 0,
@@ -280,3 +431,45 @@ impl StatsNodeWrite for WriteStats {
         self.nodes_written += 1;
     }
 }
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::{GreedySearchStats, NoStats, PhaseTimings, StatsDistanceComparison, StatsNodeRead};
+
+    fn read_twice<S: StatsNodeRead + StatsDistanceComparison>(stats: &mut S) {
+        stats.record_read();
+        stats.record_read();
+        stats.record_quantized_distance_comparison();
+    }
+
+    #[pg_test]
+    fn test_stats_composition() {
+        let mut total = GreedySearchStats::new();
+        let mut call = GreedySearchStats::new();
+        read_twice(&mut (&mut total, &mut call));
+        read_twice(&mut (&mut total, NoStats));
+        assert_eq!(total.get_node_reads(), 4);
+        assert_eq!(total.get_quantized_distance_comparisons(), 2);
+        assert_eq!(call.get_node_reads(), 2);
+        read_twice(&mut NoStats);
+    }
+
+    #[pg_test]
+    fn test_phase_timings() {
+        let mut phases = PhaseTimings::new();
+        assert_eq!(phases.get_ms(1), 0.0);
+        phases.enter(1);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        phases.enter(2);
+        let first = phases.get_ms(1);
+        assert!(first >= 5.0);
+        phases.enter(1);
+        phases.finish();
+        assert!(phases.get_ms(1) >= first);
+        assert!(phases.get_ms(2) > 0.0);
+        assert_eq!(phases.get_ms(3), 0.0);
+    }
+}
@@ -7,6 +7,7 @@ use super::{
     graph_neighbor_store::GraphNeighborStore,
     meta_page::MetaPage,
     neighbor_with_distance::NeighborWithDistance,
+    payload::NodePayload,
     pg_vector::PgVector,
     stats::{
         GreedySearchStats, StatsDistanceComparison, StatsHeapNodeRead, StatsNodeModify,
@@ -29,6 +30,23 @@ pub trait ArchivedData {
     fn delete(self: Pin<&mut Self>);
     fn get_heap_item_pointer(&self) -> HeapPointer;
     fn get_index_pointer_to_neighbors(&self) -> Vec<ItemPointer>;
+    /// The vector stored in the node as little-endian bytes: the full vector for plain
+    /// storage, the quantized vector for SBQ.
+    fn get_vector_code(&self) -> Vec<u8>;
+}
+
+/// The distances from the node `from` to each of the nodes `to`, computed with the
+/// `NodeDistanceMeasure` of the storage.
+pub fn get_node_distances_one_by_one<T: Storage, S: StatsNodeRead + StatsDistanceComparison>(
+    storage: &T,
+    from: IndexPointer,
+    to: &[IndexPointer],
+    stats: &mut S,
+) -> Vec<f32> {
+    let dist_state = unsafe { storage.get_node_distance_measure(from, stats) };
+    to.iter()
+        .map(|index_pointer| unsafe { dist_state.get_distance(*index_pointer, stats) })
+        .collect()
 }
 
 pub trait Storage {
@@ -45,8 +63,9 @@ pub trait Storage {
 
     fn create_node<S: StatsNodeWrite>(
         &self,
-        full_vector: &[f32],
+        vector: &PgVector,
         heap_pointer: HeapPointer,
+        payload: &NodePayload,
         meta_page: &MetaPage,
         tape: &mut Tape,
         stats: &mut S,
@@ -64,15 +83,38 @@ pub trait Storage {
         stats: &mut S,
     );
 
+    /// Forgets the data cached by node location, after the nodes were moved.
+    fn clear_node_cache(&mut self);
+
+    /// An estimate of the memory held by the caches and the quantizer, for the progress of a
+    /// build.
+    fn get_memory_bytes(&self) -> usize;
+
     unsafe fn get_node_distance_measure<'a, S: StatsNodeRead>(
         &'a self,
         index_pointer: IndexPointer,
         stats: &mut S,
     ) -> Self::NodeDistanceMeasure<'a>;
 
+    /// The distances from the node `from` to each of the nodes `to`, for the pruning of the
+    /// neighbors of a node.
+    fn get_node_distances<S: StatsNodeRead + StatsDistanceComparison>(
+        &self,
+        from: IndexPointer,
+        to: &[IndexPointer],
+        stats: &mut S,
+    ) -> Vec<f32>
+    where
+        Self: Sized,
+    {
+        get_node_distances_one_by_one(self, from, to, stats)
+    }
+
     fn get_query_distance_measure(&self, query: PgVector) -> Self::QueryDistanceMeasure;
 
-    fn get_full_distance_for_resort<S: StatsHeapNodeRead + StatsDistanceComparison>(
+    fn get_full_distance_for_resort<
+        S: StatsHeapNodeRead + StatsNodeRead + StatsDistanceComparison,
+    >(
         &self,
         query: &Self::QueryDistanceMeasure,
         index_pointer: IndexPointer,
@@ -124,7 +166,7 @@ pub trait Storage {
     fn get_distance_function(&self) -> fn(&[f32], &[f32]) -> f32;
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum StorageType {
     Plain = 0,
     SbqSpeedup = 1,
@@ -143,6 +185,15 @@ impl StorageType {
         }
     }
 
+    /// The storage_layout name of the storage type.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StorageType::Plain => "plain",
+            StorageType::SbqSpeedup => "io_optimized",
+            StorageType::SbqCompression => "memory_optimized",
+        }
+    }
+
     pub fn from_str(value: &str) -> Self {
         match value.to_lowercase().as_str() {
             "plain" => StorageType::Plain,
@@ -1,10 +1,260 @@
-use pgrx::{pg_sys, PgRelation};
+use std::ffi::CStr;
+
+use pgrx::{pg_sys, pg_sys::AsPgCStr, AnyElement, PgMemoryContexts, PgRelation};
+
+use crate::util::table_slot::TableSlot;
+
+pub fn is_diskann_index(relation: &PgRelation) -> bool {
+    let diskann_am = unsafe { pg_sys::get_am_oid("diskann".as_pg_cstr(), true) };
+    !relation.rd_index.is_null() && unsafe { (*relation.rd_rel).relam } == diskann_am
+}
+
+/// Opens a relation given by the user that must be a diskann index, for a function that reads
+/// it. The functions return the rows of the table or data derived from their vectors, so the
+/// user needs the SELECT privilege on the table, like for a query that scans the index.
+pub fn open_diskann_index(index: pg_sys::Oid, lockmode: pg_sys::LOCKMODE) -> PgRelation {
+    let index_relation = lock_diskann_index(index, lockmode);
+    check_select_privilege(unsafe { (*index_relation.rd_index).indrelid });
+    index_relation
+}
+
+/// Opens a relation given by the user that must be a diskann index, for a function that
+/// modifies it. Like `ALTER INDEX`, only the owner of the index may call the function. The
+/// ownership is checked before the index is locked, so that other users cannot block it.
+pub fn open_diskann_index_as_owner(index: pg_sys::Oid, lockmode: pg_sys::LOCKMODE) -> PgRelation {
+    check_index_owner(index);
+    lock_diskann_index(index, lockmode)
+}
+
+fn lock_diskann_index(index: pg_sys::Oid, lockmode: pg_sys::LOCKMODE) -> PgRelation {
+    let index_relation = unsafe { PgRelation::with_lock(index, lockmode) };
+    if !is_diskann_index(&index_relation) {
+        pgrx::error!("\"{}\" is not a diskann index", index_relation.name());
+    }
+    index_relation
+}
+
+/// Errors out unless the current user owns the relation `index`.
+fn check_index_owner(index: pg_sys::Oid) {
+    unsafe {
+        #[cfg(feature = "pg15")]
+        let is_owner = pg_sys::pg_class_ownercheck(index, pg_sys::GetUserId());
+        #[cfg(feature = "pg16")]
+        let is_owner =
+            pg_sys::object_ownercheck(pg_sys::RelationRelationId, index, pg_sys::GetUserId());
+        if !is_owner {
+            pg_sys::aclcheck_error(
+                pg_sys::AclResult_ACLCHECK_NOT_OWNER,
+                pg_sys::ObjectType_OBJECT_INDEX,
+                pg_sys::get_rel_name(index),
+            );
+        }
+    }
+}
+
+/// Errors out unless the current user has the SELECT privilege on the table `relation`.
+pub fn check_select_privilege(relation: pg_sys::Oid) {
+    unsafe {
+        let result = pg_sys::pg_class_aclcheck(
+            relation,
+            pg_sys::GetUserId(),
+            pg_sys::ACL_SELECT as pg_sys::AclMode,
+        );
+        if result != pg_sys::AclResult_ACLCHECK_OK {
+            pg_sys::aclcheck_error(
+                result,
+                pg_sys::get_relkind_objtype(pg_sys::get_rel_relkind(relation)),
+                pg_sys::get_rel_name(relation),
+            );
+        }
+    }
+}
+
+/// Errors out unless `query`, given to a function on `index`, has the type of the indexed
+/// column, which is how the function reads it.
+pub fn check_query_type(index: &PgRelation, query: &AnyElement) {
+    let expected = index
+        .tuple_desc()
+        .get(0)
+        .expect("index has no columns")
+        .atttypid;
+    if query.oid() != expected {
+        let type_name = |oid| unsafe { CStr::from_ptr(pg_sys::format_type_be(oid)) };
+        pgrx::error!(
+            "the query has type {}, but the index is on a column of type {}",
+            type_name(query.oid()).to_string_lossy(),
+            type_name(expected).to_string_lossy()
+        );
+    }
+}
+
+/// Identifies an index in the shared memory of the extension, which all the databases of the
+/// server share, while the oid of an index is only unique within its database.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SharedIndexId {
+    pub database: pg_sys::Oid,
+    pub index: pg_sys::Oid,
+}
+
+impl SharedIndexId {
+    pub const INVALID: Self = Self {
+        database: pg_sys::InvalidOid,
+        index: pg_sys::InvalidOid,
+    };
+
+    pub fn of(index: &PgRelation) -> Self {
+        Self {
+            database: unsafe { pg_sys::MyDatabaseId },
+            index: index.oid(),
+        }
+    }
+}
+
+/// Whether the operator class of the vector column of `index` orders by the distance to a query
+/// of another type than the column, like the weighted queries of `vector_weighted_cosine_ops`.
+/// The other operator classes order by the distance between two values of the column type.
+pub fn orders_by_weighted_query(index: &PgRelation) -> bool {
+    unsafe {
+        let opfamily = *index.rd_opfamily;
+        let input_type = *index.rd_opcintype;
+        pg_sys::get_opfamily_member(opfamily, input_type, input_type, 1) == pg_sys::InvalidOid
+    }
+}
 
 pub fn get_attribute_number_from_index(index: &PgRelation) -> pg_sys::AttrNumber {
     unsafe {
         let a = index.rd_index;
         let natts = (*a).indnatts;
-        assert!(natts == 1);
+        /* the vector is always the first column, the others are filter columns */
+        assert!(natts >= 1);
         (*a).indkey.values.as_slice(natts as _)[0]
     }
 }
+
+/// Reads the indexed value out of a heap tuple.
+///
+/// For an index on a plain column this is an attribute lookup. For an expression index
+/// the expression is evaluated against the heap tuple, the same way Postgres computes the
+/// value during build and insert.
+pub struct IndexedAttribute {
+    heap_attr: pg_sys::AttrNumber,
+    expression: Option<IndexExpression>,
+}
+
+impl IndexedAttribute {
+    pub fn new(index: &PgRelation) -> Self {
+        let heap_attr = get_attribute_number_from_index(index);
+        /* an attribute number of 0 means the index column is an expression */
+        let expression = if heap_attr == 0 {
+            Some(unsafe { IndexExpression::new(index) })
+        } else {
+            None
+        };
+        Self {
+            heap_attr,
+            expression,
+        }
+    }
+
+    /// Returns the indexed value for the tuple currently held in `slot`.
+    ///
+    /// For expression indexes the returned datum lives in a per-tuple memory context and is
+    /// only valid until the next call.
+    pub unsafe fn get_datum(&self, slot: &TableSlot) -> Option<pg_sys::Datum> {
+        match &self.expression {
+            None => slot.get_attribute(self.heap_attr),
+            Some(expression) => expression.evaluate(slot),
+        }
+    }
+}
+
+/// The predicate of a partial index, evaluated against heap tuples.
+pub struct IndexPredicate {
+    predicate: *mut pg_sys::ExprState,
+    estate: *mut pg_sys::EState,
+}
+
+impl IndexPredicate {
+    /// Returns None if the index is not a partial index.
+    pub unsafe fn new(index: &PgRelation) -> Option<Self> {
+        let index_info = pg_sys::BuildIndexInfo(index.as_ptr());
+        if (*index_info).ii_Predicate.is_null() {
+            return None;
+        }
+        let estate = pg_sys::CreateExecutorState();
+        Some(Self {
+            predicate: pg_sys::ExecPrepareQual((*index_info).ii_Predicate, estate),
+            estate,
+        })
+    }
+
+    /// Whether the tuple currently held in `slot` belongs in the index.
+    pub unsafe fn matches(&self, slot: &TableSlot) -> bool {
+        /* GetPerTupleExprContext() */
+        let mut econtext = (*self.estate).es_per_tuple_exprcontext;
+        if econtext.is_null() {
+            econtext = pg_sys::MakePerTupleExprContext(self.estate);
+        }
+        pg_sys::MemoryContextReset((*econtext).ecxt_per_tuple_memory);
+        (*econtext).ecxt_scantuple = slot.as_ptr();
+
+        /* ExecQual(): a NULL result means the tuple doesn't match */
+        let mut isnull = false;
+        let result = PgMemoryContexts::For((*econtext).ecxt_per_tuple_memory).switch_to(|_| {
+            (*self.predicate).evalfunc.unwrap()(self.predicate, econtext, &mut isnull)
+        });
+        !isnull && result.value() != 0
+    }
+}
+
+impl Drop for IndexPredicate {
+    fn drop(&mut self) {
+        unsafe { pg_sys::FreeExecutorState(self.estate) };
+    }
+}
+
+struct IndexExpression {
+    index_info: *mut pg_sys::IndexInfo,
+    estate: *mut pg_sys::EState,
+}
+
+impl IndexExpression {
+    unsafe fn new(index: &PgRelation) -> Self {
+        Self {
+            index_info: pg_sys::BuildIndexInfo(index.as_ptr()),
+            estate: pg_sys::CreateExecutorState(),
+        }
+    }
+
+    unsafe fn evaluate(&self, slot: &TableSlot) -> Option<pg_sys::Datum> {
+        /* GetPerTupleExprContext() */
+        let mut econtext = (*self.estate).es_per_tuple_exprcontext;
+        if econtext.is_null() {
+            econtext = pg_sys::MakePerTupleExprContext(self.estate);
+        }
+        /* ResetExprContext(): free the result of the previous evaluation */
+        pg_sys::MemoryContextReset((*econtext).ecxt_per_tuple_memory);
+        (*econtext).ecxt_scantuple = slot.as_ptr();
+
+        let mut values = [pg_sys::Datum::from(0usize); pg_sys::INDEX_MAX_KEYS as usize];
+        let mut isnull = [false; pg_sys::INDEX_MAX_KEYS as usize];
+        pg_sys::FormIndexDatum(
+            self.index_info,
+            slot.as_ptr(),
+            self.estate,
+            values.as_mut_ptr(),
+            isnull.as_mut_ptr(),
+        );
+        if isnull[0] {
+            None
+        } else {
+            Some(values[0])
+        }
+    }
+}
+
+impl Drop for IndexExpression {
+    fn drop(&mut self) {
+        unsafe { pg_sys::FreeExecutorState(self.estate) };
+    }
+}
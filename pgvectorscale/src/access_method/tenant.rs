@@ -0,0 +1,129 @@
+//! Indexes partitioned by tenant.
+//!
+//! A multi-tenant table with one index has one graph, so a search for the rows of one tenant
+//! walks through the nodes of all the others and filters them out, and the smaller the tenant,
+//! the more nodes it reads for every row it returns. With the `tenant_buckets` option, the
+//! first filter column of the index is the tenant column, and the rows are spread over that
+//! many separate subgraphs by a hash of their tenant. Every subgraph has its own entry point in
+//! the meta page and its nodes only have neighbors in the same subgraph, so a scan with an
+//! equality condition on the tenant column starts from the entry point of the tenant's bucket
+//! and never reaches the nodes of the tenants of other buckets. The tenants that share a bucket
+//! are told apart by the filter as usual.
+//!
+//! Scans without a condition on the tenant start from the entry points of all the subgraphs.
+
+use super::{meta_page::MetaPage, payload::NodePayload, payload::PayloadFilter};
+
+/// The tenant column is the first filter column.
+const TENANT_COLUMN: usize = 0;
+
+/// The bucket of the tenant `value`, which is the datum of an integer. NULLs go to the first
+/// bucket.
+fn get_bucket(value: Option<u64>, tenant_buckets: u32) -> u32 {
+    let Some(value) = value else {
+        return 0;
+    };
+    /* the finalizer of splitmix64, so that consecutive ids spread over the buckets */
+    let mut hash = value;
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    (hash % tenant_buckets as u64) as u32
+}
+
+/// The bucket of a new node with `payload`, None if the index isn't partitioned by tenant.
+pub fn get_node_bucket(meta_page: &MetaPage, payload: &NodePayload) -> Option<u32> {
+    let tenant_buckets = meta_page.get_tenant_buckets();
+    if tenant_buckets == 0 {
+        return None;
+    }
+    Some(get_bucket(
+        payload.get_column(TENANT_COLUMN),
+        tenant_buckets,
+    ))
+}
+
+/// The bucket a scan with `filter` is limited to, None if the index isn't partitioned by tenant
+/// or the scan has no equality condition on the tenant column.
+pub fn get_scan_bucket(meta_page: &MetaPage, filter: &PayloadFilter) -> Option<u32> {
+    let tenant_buckets = meta_page.get_tenant_buckets();
+    if tenant_buckets == 0 {
+        return None;
+    }
+    let value = filter.get_equal_value(TENANT_COLUMN)?;
+    Some(get_bucket(Some(value), tenant_buckets))
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::get_bucket;
+
+    #[pg_test]
+    fn test_tenant_bucket() {
+        assert_eq!(get_bucket(None, 16), 0);
+        assert_eq!(get_bucket(Some(42), 16), get_bucket(Some(42), 16));
+        assert!((0..1000).all(|v| get_bucket(Some(v), 16) < 16));
+        /* consecutive tenants use all the buckets */
+        let buckets: std::collections::HashSet<u32> =
+            (0..200).map(|v| get_bucket(Some(v), 16)).collect();
+        assert_eq!(buckets.len(), 16);
+    }
+
+    unsafe fn test_tenant_scan_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(id int, embedding vector(3), tenant int8);
+
+            INSERT INTO test(id, embedding, tenant)
+            SELECT i, ARRAY[i, i % 7, 1]::vector, i % 10
+            FROM generate_series(1, 500) i;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding, tenant)
+                WITH (tenant_buckets = 8, {index_options});
+
+            INSERT INTO test(id, embedding, tenant)
+            SELECT i, ARRAY[i, i % 7, 1]::vector, CASE WHEN i % 2 = 0 THEN 3 ELSE 1000 END
+            FROM generate_series(501, 600) i;
+            SET enable_seqscan = 0;",
+        ))?;
+
+        /* a tenant scan returns all the rows of the tenant, and only those */
+        for tenant in [3, 7, 1000] {
+            let (count, expected) = Spi::get_two::<i64, i64>(&format!(
+                "SELECT
+                    (SELECT count(*) FROM (SELECT id FROM test WHERE tenant = {tenant}::int8
+                        ORDER BY embedding <=> '[100, 3, 1]') t),
+                    (SELECT count(*) FROM test WHERE tenant + 0 = {tenant})",
+            ))?;
+            assert_eq!(count, expected, "tenant {}", tenant);
+        }
+
+        /* scans without a tenant see all the subgraphs */
+        let count = Spi::get_one::<i64>(
+            "SELECT count(*) FROM (SELECT id FROM test ORDER BY embedding <=> '[100, 3, 1]') t",
+        )?;
+        assert_eq!(count, Some(600));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_tenant_scan_plain() -> spi::Result<()> {
+        test_tenant_scan_scaffold("storage_layout = plain")
+    }
+
+    #[pg_test]
+    unsafe fn test_tenant_scan_memory_optimized() -> spi::Result<()> {
+        test_tenant_scan_scaffold("storage_layout = memory_optimized")
+    }
+
+    #[pg_test(error = "the tenant column \"score\" must be of type smallint, integer or bigint")]
+    unsafe fn test_tenant_column_type() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3), score float8);
+            CREATE INDEX idxtest ON test USING diskann(embedding, score) WITH (tenant_buckets = 8);",
+        )?;
+        Ok(())
+    }
+}
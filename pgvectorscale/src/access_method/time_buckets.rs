@@ -0,0 +1,203 @@
+//! Indexes split into time buckets.
+//!
+//! Searches of time-series embeddings are mostly limited to a recent time range, e.g. the last
+//! week, and with one graph they walk through the nodes of all the older rows and filter them
+//! out. With the `time_buckets` option, the rows are spread over that many separate subgraphs
+//! by the bucket of `time_bucket_width` seconds of their first `timestamp` or `timestamptz`
+//! filter column, and the buckets wrap around: the subgraph of bucket `b` holds the rows of the
+//! buckets `b`, `b + time_buckets`, `b + 2 * time_buckets`, ... The meta page records the first
+//! and the last bucket that have rows, so a scan with a range condition on the timestamp column
+//! starts from the entry points of the subgraphs of the buckets the range overlaps and never
+//! reaches the nodes of the others. Rows of older rounds of the same subgraphs are told apart by
+//! the filter as usual.
+//!
+//! The rows with a NULL timestamp go to the first subgraph. Scans without a range condition, or
+//! with a range spanning all the subgraphs, start from the entry points of all of them.
+
+use pgrx::PgRelation;
+
+use super::{
+    meta_page::MetaPage,
+    payload::{find_timestamp_column, NodePayload, PayloadFilter},
+    stats::StatsNodeModify,
+};
+
+/// The subgraph of the time bucket `bucket`.
+fn get_subgraph(bucket: i64, time_buckets: u32) -> u32 {
+    bucket.rem_euclid(time_buckets as i64) as u32
+}
+
+/// The subgraph of a new node with `payload`, None if the index isn't split by time. Extends
+/// the range of the buckets with rows recorded in the meta page if needed.
+pub fn get_node_bucket<S: StatsNodeModify>(
+    index: &PgRelation,
+    meta_page: &mut MetaPage,
+    payload: &NodePayload,
+    stats: &mut S,
+) -> Option<u32> {
+    let time_buckets = meta_page.get_time_buckets();
+    if time_buckets == 0 {
+        return None;
+    }
+    let (column, _) = find_timestamp_column(index).expect("time buckets without a time column");
+    let Some(timestamp) = payload.get_column(column) else {
+        return Some(0);
+    };
+    let bucket = (timestamp as i64).div_euclid(meta_page.get_time_bucket_width());
+    if !matches!(meta_page.get_time_bucket_range(),
+        Some((first, last)) if (first..=last).contains(&bucket))
+    {
+        MetaPage::extend_time_bucket_range(index, bucket, stats);
+        *meta_page = MetaPage::fetch(index);
+    }
+    Some(get_subgraph(bucket, time_buckets))
+}
+
+/// The subgraphs of the buckets between `lower` and `upper` that have rows, None if all of them.
+fn get_range_subgraphs(
+    lower: Option<i64>,
+    upper: Option<i64>,
+    range: Option<(i64, i64)>,
+    width: i64,
+    time_buckets: u32,
+) -> Option<Vec<u32>> {
+    /* only NULL timestamps, which are never in a range */
+    let Some((first, last)) = range else {
+        return Some(Vec::new());
+    };
+    let first = lower.map_or(first, |l| first.max(l.div_euclid(width)));
+    let last = upper.map_or(last, |u| last.min(u.div_euclid(width)));
+    if first > last {
+        return Some(Vec::new());
+    }
+    if last - first + 1 >= time_buckets as i64 {
+        return None;
+    }
+    Some(
+        (first..=last)
+            .map(|bucket| get_subgraph(bucket, time_buckets))
+            .collect(),
+    )
+}
+
+/// The subgraphs a scan with `filter` is limited to, None if the index isn't split by time or
+/// the scan has no range condition on the timestamp column.
+pub fn get_scan_buckets(
+    index: &PgRelation,
+    meta_page: &MetaPage,
+    filter: &PayloadFilter,
+) -> Option<Vec<u32>> {
+    let time_buckets = meta_page.get_time_buckets();
+    if time_buckets == 0 {
+        return None;
+    }
+    let (column, _) = find_timestamp_column(index)?;
+    let (lower, upper) = filter.get_bounds(column);
+    if lower.is_none() && upper.is_none() {
+        return None;
+    }
+    get_range_subgraphs(
+        lower,
+        upper,
+        meta_page.get_time_bucket_range(),
+        meta_page.get_time_bucket_width(),
+        time_buckets,
+    )
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    use super::get_range_subgraphs;
+
+    #[pg_test]
+    fn test_range_subgraphs() {
+        let range = Some((100, 200));
+        assert_eq!(
+            get_range_subgraphs(Some(195), None, range, 1, 16),
+            Some(vec![3, 4, 5, 6, 7, 8])
+        );
+        /* the buckets wrap around */
+        assert_eq!(
+            get_range_subgraphs(Some(1560), Some(1619), range, 10, 16),
+            Some(vec![12, 13, 14, 15, 0, 1])
+        );
+        assert_eq!(get_range_subgraphs(None, Some(150), range, 1, 16), None);
+        assert_eq!(
+            get_range_subgraphs(Some(300), None, range, 1, 16),
+            Some(vec![])
+        );
+        assert_eq!(
+            get_range_subgraphs(Some(0), None, None, 1, 16),
+            Some(vec![])
+        );
+        /* negative timestamps are before the epoch */
+        assert_eq!(
+            get_range_subgraphs(Some(-15), Some(-11), Some((-2, 0)), 10, 16),
+            Some(vec![14])
+        );
+    }
+
+    unsafe fn test_time_bucket_scan_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(id int, embedding vector(3), created_at timestamptz);
+
+            INSERT INTO test(id, embedding, created_at)
+            SELECT i, ARRAY[i, i % 7, 1]::vector, now() - i * interval '1 hour'
+            FROM generate_series(1, 500) i;
+            INSERT INTO test(id, embedding, created_at) VALUES (0, '[1, 1, 1]', NULL);
+
+            CREATE INDEX idxtest ON test USING diskann(embedding, created_at)
+                WITH (time_buckets = 8, time_bucket_width = 86400, {index_options});
+
+            INSERT INTO test(id, embedding, created_at)
+            SELECT i, ARRAY[i, i % 7, 1]::vector, now() + (i - 500) * interval '1 hour'
+            FROM generate_series(501, 600) i;
+            SET enable_seqscan = 0;",
+        ))?;
+
+        /* a range scan returns all the rows of the range, and only those */
+        for range in [
+            "created_at > now() - interval '7 days'",
+            "created_at >= now() - interval '2 days' AND created_at < now() - interval '1 day'",
+            "created_at < now() - interval '19 days'",
+            "created_at > now() + interval '1 day'",
+        ] {
+            let (count, expected) = Spi::get_two::<i64, i64>(&format!(
+                "SELECT
+                    (SELECT count(*) FROM (SELECT id FROM test WHERE {range}
+                        ORDER BY embedding <=> '[100, 3, 1]') t),
+                    (SELECT count(*) FROM test WHERE ({range}) IS TRUE)",
+            ))?;
+            assert_eq!(count, expected, "{}", range);
+        }
+
+        /* scans without a range see all the subgraphs */
+        let count = Spi::get_one::<i64>(
+            "SELECT count(*) FROM (SELECT id FROM test ORDER BY embedding <=> '[100, 3, 1]') t",
+        )?;
+        assert_eq!(count, Some(601));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_time_bucket_scan_plain() -> spi::Result<()> {
+        test_time_bucket_scan_scaffold("storage_layout = plain")
+    }
+
+    #[pg_test]
+    unsafe fn test_time_bucket_scan_memory_optimized() -> spi::Result<()> {
+        test_time_bucket_scan_scaffold("storage_layout = memory_optimized")
+    }
+
+    #[pg_test(error = "time_buckets requires a timestamp or timestamptz filter column")]
+    unsafe fn test_time_buckets_without_column() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(3), category int4);
+            CREATE INDEX idxtest ON test USING diskann(embedding, category) WITH (time_buckets = 8);",
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+//! Recommends query settings for an index by measuring recall and latency on sampled queries.
+
+use pgrx::{pg_sys, *};
+
+use super::{
+    benchmark::percentile,
+    guc::get_query_rescore,
+    meta_page::MetaPage,
+    recall::RecallQueries,
+    stats::GreedySearchStats,
+    storage::StorageType,
+    storage_common::open_diskann_index,
+};
+
+const SEARCH_LIST_SIZES: [usize; 9] = [10, 25, 50, 75, 100, 150, 200, 300, 400];
+const RESCORES: [usize; 5] = [25, 50, 100, 200, 400];
+
+struct TuneResult {
+    search_list_size: usize,
+    rescore: usize,
+    recall: f64,
+    p50_ms: f64,
+    p99_ms: f64,
+}
+
+impl TuneResult {
+    fn meets(&self, target_recall: f64, max_latency_ms: Option<f64>) -> bool {
+        self.recall >= target_recall && max_latency_ms.map_or(true, |max| self.p99_ms <= max)
+    }
+}
+
+/// Picks the fastest settings that reach the target recall within the latency budget, or the
+/// settings with the best recall within the budget if none reaches the target.
+fn recommend(
+    results: &[TuneResult],
+    target_recall: f64,
+    max_latency_ms: Option<f64>,
+) -> Option<usize> {
+    let fastest = results
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| r.meets(target_recall, max_latency_ms))
+        .min_by(|(_, a), (_, b)| a.p50_ms.total_cmp(&b.p50_ms))
+        .map(|(i, _)| i);
+    fastest.or_else(|| {
+        results
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| max_latency_ms.map_or(true, |max| r.p99_ms <= max))
+            .max_by(|(_, a), (_, b)| {
+                a.recall
+                    .total_cmp(&b.recall)
+                    .then(b.p50_ms.total_cmp(&a.p50_ms))
+            })
+            .map(|(i, _)| i)
+    })
+}
+
+/// Recommends query_search_list_size and query_rescore settings for an index. Runs a grid of
+/// settings on `num_queries` sampled rows, measures the recall of the first `k` results against
+/// an exact search and the latency of the searches, and returns a row per setting.
+/// `recommended` marks the fastest setting whose recall reaches `target_recall` and whose
+/// p99 latency stays within `max_latency_ms`, or the one with the best recall within the latency
+/// budget if none does.
+///
+/// The rescore is only tuned for indexes that rescore results, i.e. that don't store the full
+/// vectors in the graph.
+#[pg_extern(volatile)]
+pub fn diskann_tune(
+    index: pg_sys::Oid,
+    target_recall: default!(f64, 0.95),
+    max_latency_ms: default!(Option<f64>, "NULL"),
+    num_queries: default!(i32, 50),
+    k: default!(i32, 10),
+) -> TableIterator<
+    'static,
+    (
+        name!(query_search_list_size, i32),
+        name!(query_rescore, i32),
+        name!(recall, f64),
+        name!(p50_ms, f64),
+        name!(p99_ms, f64),
+        name!(recommended, bool),
+    ),
+> {
+    if !(0.0..=1.0).contains(&target_recall) {
+        error!("target_recall must be between 0 and 1");
+    }
+    if num_queries <= 0 {
+        error!("num_queries must be positive");
+    }
+    if k <= 0 {
+        error!("k must be positive");
+    }
+    let index_relation = open_diskann_index(index, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let heap_relation = index_relation
+        .heap_relation()
+        .expect("failed to get heap relation for index");
+    let meta_page = MetaPage::fetch(&index_relation);
+
+    let queries = RecallQueries::sample(
+        &index_relation,
+        &heap_relation,
+        &meta_page,
+        num_queries as _,
+        k as _,
+    );
+
+    let rescores = match meta_page.get_storage_type() {
+        StorageType::Plain
+            if meta_page.get_num_dimensions() == meta_page.get_num_dimensions_to_index() =>
+        {
+            vec![get_query_rescore(&index_relation, &meta_page)]
+        }
+        _ => RESCORES.to_vec(),
+    };
+
+    let mut results = Vec::new();
+    for search_list_size in SEARCH_LIST_SIZES {
+        for rescore in rescores.iter() {
+            let (recall, mut latencies) = queries.run(
+                &index_relation,
+                &heap_relation,
+                &meta_page,
+                search_list_size,
+                *rescore,
+                &mut GreedySearchStats::new(),
+            );
+            latencies.sort_by(|a, b| a.total_cmp(b));
+            results.push(TuneResult {
+                search_list_size,
+                rescore: *rescore,
+                recall,
+                p50_ms: percentile(&latencies, 0.5),
+                p99_ms: percentile(&latencies, 0.99),
+            });
+        }
+    }
+
+    let recommended = recommend(&results, target_recall, max_latency_ms);
+    TableIterator::new(
+        results
+            .into_iter()
+            .enumerate()
+            .map(|(i, r)| {
+                (
+                    r.search_list_size as i32,
+                    r.rescore as i32,
+                    r.recall,
+                    r.p50_ms,
+                    r.p99_ms,
+                    Some(i) == recommended,
+                )
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    #[pg_test]
+    unsafe fn test_tune() -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(embedding vector(16));
+
+            INSERT INTO test(embedding)
+            SELECT v::vector FROM diskann_generate_vectors(500, 16, num_clusters => 5, seed => 42) v;
+
+            CREATE INDEX idxtest ON test USING diskann(embedding);",
+        )?;
+
+        let (num_settings, num_recommended) = Spi::get_two::<i64, i64>(
+            "SELECT count(*), count(*) FILTER (WHERE recommended)
+            FROM diskann_tune('idxtest'::regclass, target_recall => 0.9, num_queries => 10)",
+        )?;
+        assert_eq!(num_settings, Some(45));
+        assert_eq!(num_recommended, Some(1));
+
+        /* the recommended setting reaches the target if any setting does */
+        let (max_recall, recommended_recall) = Spi::get_two::<f64, f64>(
+            "SELECT max(recall), max(recall) FILTER (WHERE recommended)
+            FROM diskann_tune('idxtest'::regclass, target_recall => 0.9, num_queries => 10)",
+        )?;
+        if max_recall.unwrap() >= 0.9 {
+            assert!(recommended_recall.unwrap() >= 0.9);
+        }
+        Ok(())
+    }
+}
@@ -4,7 +4,14 @@ use pgrx::{
 };
 
 use crate::{
-    access_method::{meta_page::MetaPage, plain_storage::PlainStorage, sbq::SbqSpeedupStorage},
+    access_method::{
+        activity,
+        full_vectors::{free_full_vectors, FullVectorStore},
+        meta_page::MetaPage,
+        plain_storage::PlainStorage,
+        result_cache,
+        sbq::SbqSpeedupStorage,
+    },
     util::{
         page::WritablePage,
         ports::{PageGetItem, PageGetItemId, PageGetMaxOffsetNumber},
@@ -38,12 +45,15 @@ pub extern "C" fn ambulkdelete(
     };
 
     let meta_page = MetaPage::fetch(&index_relation);
+    let full_vectors = FullVectorStore::for_index(&index_relation, &meta_page);
+    let tuples_removed = unsafe { (*results).tuples_removed };
     let storage = meta_page.get_storage_type();
     match storage {
         StorageType::SbqSpeedup | StorageType::SbqCompression => {
             bulk_delete_for_storage::<SbqSpeedupStorage>(
                 &index_relation,
                 nblocks,
+                full_vectors,
                 results,
                 callback,
                 callback_state,
@@ -53,28 +63,37 @@ pub extern "C" fn ambulkdelete(
             bulk_delete_for_storage::<PlainStorage>(
                 &index_relation,
                 nblocks,
+                full_vectors,
                 results,
                 callback,
                 callback_state,
             );
         }
     }
+    result_cache::invalidate(&index_relation);
+    let num_deleted = unsafe { (*results).tuples_removed } - tuples_removed;
+    activity::record_deletes(&index_relation, num_deleted as u64);
     results
 }
 
 fn bulk_delete_for_storage<S: Storage>(
     index: &PgRelation,
     nblocks: u32,
+    full_vectors: Option<FullVectorStore>,
     results: *mut IndexBulkDeleteResult,
     callback: pg_sys::IndexBulkDeleteCallback,
     callback_state: *mut ::std::os::raw::c_void,
 ) {
     for block_number in 0..nblocks {
-        let page = unsafe { WritablePage::cleanup(&index, block_number) };
+        let Some(mut page) = (unsafe { WritablePage::cleanup(&index, block_number) }) else {
+            continue;
+        };
         if page.get_type() != S::page_type() {
             continue;
         }
         let mut modified = false;
+        /* the full vectors of the deleted nodes, freed once the page is committed */
+        let mut dead_full_vectors = Vec::new();
 
         unsafe { pg_sys::vacuum_delay_point() };
 
@@ -85,6 +104,7 @@ fn bulk_delete_for_storage<S: Storage>(
                 let item = PageGetItem(*page, item_id) as *mut u8;
                 let len = (*item_id).lp_len();
                 let data = std::slice::from_raw_parts_mut(item, len as _);
+                let full_vector = full_vectors.and_then(|store| store.read_pointer(data));
                 let node = S::ArchivedType::with_data(data);
 
                 if node.is_deleted() {
@@ -100,6 +120,12 @@ fn bulk_delete_for_storage<S: Storage>(
                 let deleted = callback.unwrap()(&mut ctid, callback_state);
                 if deleted {
                     node.delete();
+                    /* the node keeps its slot, which the edges of other nodes still point to,
+                    but nothing else references its full vector */
+                    if let (Some(store), Some(full_vector)) = (full_vectors, full_vector) {
+                        store.clear_pointer(data);
+                        dead_full_vectors.push(full_vector);
+                    }
                     modified = true;
                     (*results).tuples_removed += 1.0;
                 } else {
@@ -110,6 +136,9 @@ fn bulk_delete_for_storage<S: Storage>(
         if modified {
             page.commit();
         }
+        if !dead_full_vectors.is_empty() {
+            unsafe { free_full_vectors(index, dead_full_vectors) };
+        }
     }
 }
 
@@ -125,6 +154,9 @@ pub extern "C" fn amvacuumcleanup(
 
         let index_relation = PgRelation::from_pg((*vinfo).index);
 
+        /* make the space freed by ambulkdelete visible to the searches of the free space map */
+        pg_sys::IndexFreeSpaceMapVacuum(index_relation.as_ptr());
+
         (*stats).num_pages = pg_sys::RelationGetNumberOfBlocksInFork(
             index_relation.as_ptr(),
             pg_sys::ForkNumber_MAIN_FORKNUM,
@@ -355,6 +387,140 @@ pub mod tests {
         client.execute("DROP TABLE test_vac_full", &[]).unwrap();
     }
 
+    #[cfg(test)]
+    static VAC_INSERT_MUTEX: once_cell::sync::Lazy<std::sync::Mutex<()>> =
+        once_cell::sync::Lazy::new(std::sync::Mutex::default);
+
+    #[test]
+    fn test_delete_vacuum_insert() {
+        let _lock = VAC_INSERT_MUTEX.lock().unwrap();
+
+        //bring up the test db by running a fake test on a fake fn
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        let (mut client, _) = pgrx_tests::client().unwrap();
+        client
+            .batch_execute(
+                "CREATE TABLE test_vac_insert(id int, embedding vector(3));
+
+                INSERT INTO test_vac_insert(id, embedding)
+                SELECT i, ARRAY[i, i % 7, 1]::vector FROM generate_series(1, 300) i;
+
+                CREATE INDEX idxtest_vac_insert ON test_vac_insert USING diskann(embedding)
+                    WITH (storage_layout = plain, num_neighbors = 10);
+
+                INSERT INTO test_vac_insert(id, embedding)
+                SELECT i, ARRAY[i, i % 7, 1]::vector FROM generate_series(301, 400) i;
+
+                SET enable_seqscan = 0;",
+            )
+            .unwrap();
+
+        for round in 0..3 {
+            client
+                .execute("DELETE FROM test_vac_insert WHERE id > 300", &[])
+                .unwrap();
+            client.execute("VACUUM test_vac_insert", &[]).unwrap();
+            client
+                .execute(
+                    "INSERT INTO test_vac_insert(id, embedding)
+                    SELECT i, ARRAY[i, i % 7, 1]::vector FROM generate_series(301, 400) i",
+                    &[],
+                )
+                .unwrap();
+
+            /* the edges of the other nodes still point to the deleted nodes, so the new nodes
+            never take their slots */
+            let num_deleted_nodes: i64 = client
+                .query_one(
+                    "SELECT num_deleted_nodes FROM diskann_index_info('idxtest_vac_insert'::regclass)",
+                    &[],
+                )
+                .unwrap()
+                .get(0);
+            assert_eq!(num_deleted_nodes, 100 * (round + 1), "round {}", round);
+
+            let cnt: i64 = client
+                .query_one(
+                    "WITH cte as (SELECT * FROM test_vac_insert ORDER BY embedding <=> '[1,1,1]') SELECT count(*) FROM cte",
+                    &[],
+                )
+                .unwrap()
+                .get(0);
+            assert_eq!(cnt, 400, "round {}", round);
+            let first: i32 = client
+                .query_one(
+                    "SELECT id FROM test_vac_insert ORDER BY embedding <=> '[350, 0, 1]' LIMIT 1",
+                    &[],
+                )
+                .unwrap()
+                .get(0);
+            assert_eq!(first, 350, "round {}", round);
+        }
+
+        client.execute("DROP TABLE test_vac_insert", &[]).unwrap();
+    }
+
+    #[cfg(test)]
+    static KILL_TUPLE_MUTEX: once_cell::sync::Lazy<std::sync::Mutex<()>> =
+        once_cell::sync::Lazy::new(std::sync::Mutex::default);
+
+    #[test]
+    fn test_kill_prior_tuple() {
+        let _lock = KILL_TUPLE_MUTEX.lock().unwrap();
+
+        //bring up the test db by running a fake test on a fake fn
+        pgrx_tests::run_test(
+            "test_delete_mock_fn",
+            None,
+            crate::pg_test::postgresql_conf_options(),
+        )
+        .unwrap();
+
+        let (mut client, _) = pgrx_tests::client().unwrap();
+        client
+            .batch_execute(
+                "CREATE TABLE test_kill(id int, embedding vector(3));
+
+                INSERT INTO test_kill(id, embedding)
+                SELECT i, ARRAY[i, i % 7, 1]::vector FROM generate_series(1, 300) i;
+
+                CREATE INDEX idxtest_kill ON test_kill USING diskann(embedding)
+                    WITH (storage_layout = plain, num_neighbors = 10);
+
+                DELETE FROM test_kill WHERE id > 200;",
+            )
+            .unwrap();
+
+        /* the scan finds the deleted rows dead and only sets a hint on their nodes */
+        client.execute("set enable_seqscan = 0;", &[]).unwrap();
+        let count_query = "WITH cte as (SELECT * FROM test_kill ORDER BY embedding <=> '[1,1,1]') SELECT count(*) FROM cte";
+        let deleted_query =
+            "SELECT num_deleted_nodes FROM diskann_index_info('idxtest_kill'::regclass)";
+        let cnt: i64 = client.query_one(count_query, &[]).unwrap().get(0);
+        assert_eq!(cnt, 200);
+        let num_deleted_nodes: i64 = client.query_one(deleted_query, &[]).unwrap().get(0);
+        assert_eq!(num_deleted_nodes, 0);
+
+        /* the following scans skip the hinted nodes */
+        let cnt: i64 = client.query_one(count_query, &[]).unwrap().get(0);
+        assert_eq!(cnt, 200);
+
+        /* and VACUUM deletes them */
+        client.execute("VACUUM test_kill", &[]).unwrap();
+        let num_deleted_nodes: i64 = client.query_one(deleted_query, &[]).unwrap().get(0);
+        assert_eq!(num_deleted_nodes, 100);
+        let cnt: i64 = client.query_one(count_query, &[]).unwrap().get(0);
+        assert_eq!(cnt, 200);
+
+        client.execute("DROP TABLE test_kill", &[]).unwrap();
+    }
+
     #[pg_test]
     ///This function is only a mock to bring up the test framewokr in test_delete_vacuum
     fn test_delete_mock_fn() -> spi::Result<()> {
@@ -0,0 +1,18 @@
+//! The algorithms of the index that don't depend on Postgres.
+//!
+//! The distance kernels, the pruning of the neighbors of the Vamana graph and the statistical
+//! binary quantizer are plain Rust over slices and traits, and the access method implements
+//! those traits on top of its pages. Built with `--no-default-features`, the crate only
+//! contains this module and links without pgrx or a Postgres installation, so offline tools
+//! and tests can run the exact same code as the index.
+//!
+//! With the `build_offload` feature, `offload` hands the quantizer training and the distances
+//! of the pruning of a build to a pluggable backend.
+
+pub mod distance;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod distance_x86;
+#[cfg(feature = "build_offload")]
+pub mod offload;
+pub mod prune;
+pub mod sbq;
@@ -0,0 +1,223 @@
+//! Offload of the heavy computations of an index build. Only compiled with the `build_offload`
+//! feature.
+//!
+//! A build spends most of its time training the quantizer over the vectors and computing the
+//! distances between the candidate neighbors of the nodes it prunes. `OffloadBackend` computes
+//! both on batches, so that a backend can run them on another device, e.g. a GPU. `CpuBackend`
+//! runs them with the kernels of `distance`, so it gives the same results as a build without
+//! the feature, and is used whenever the requested backend is not compiled in or finds no
+//! device.
+
+use super::{distance::DistanceImpl, sbq::SbqQuantizer};
+
+/// The number of samples handed to the backend at once during the training of the quantizer.
+const TRAINING_BATCH_SIZE: usize = 4096;
+
+/// The distance a backend computes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OffloadDistance {
+    Cosine,
+    L2,
+}
+
+/// The per-dimension statistics of a batch of samples, which the SBQ quantizer is trained on.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SampleMoments {
+    pub count: u64,
+    pub mean: Vec<f32>,
+    /// the sums of the squared deviations from the mean
+    pub m2: Vec<f32>,
+}
+
+pub trait OffloadBackend {
+    /// The name the backend is selected by.
+    fn name(&self) -> &'static str;
+
+    /// Whether the backend can run in this process, e.g. finds its device.
+    fn is_available(&self) -> bool;
+
+    /// The distances from `query` to each of `vectors`, rows of `query.len()` values.
+    fn get_distances(&self, distance: OffloadDistance, query: &[f32], vectors: &[f32]) -> Vec<f32>;
+
+    /// The statistics of `samples`, rows of `num_dimensions` values.
+    fn get_moments(&self, samples: &[f32], num_dimensions: usize) -> SampleMoments;
+}
+
+/// Runs the computations on the CPU of the backend process.
+pub struct CpuBackend {
+    distance_impl: DistanceImpl,
+}
+
+impl CpuBackend {
+    pub fn new(distance_impl: DistanceImpl) -> Self {
+        Self { distance_impl }
+    }
+}
+
+impl OffloadBackend for CpuBackend {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    fn get_distances(&self, distance: OffloadDistance, query: &[f32], vectors: &[f32]) -> Vec<f32> {
+        let distance_fn = match distance {
+            OffloadDistance::Cosine => self.distance_impl.get_cosine(),
+            OffloadDistance::L2 => self.distance_impl.get_l2(),
+        };
+        vectors
+            .chunks_exact(query.len())
+            .map(|vector| distance_fn(query, vector))
+            .collect()
+    }
+
+    fn get_moments(&self, samples: &[f32], num_dimensions: usize) -> SampleMoments {
+        let count = samples.len() / num_dimensions;
+        let mut mean = vec![0.0f64; num_dimensions];
+        for sample in samples.chunks_exact(num_dimensions) {
+            for (m, s) in mean.iter_mut().zip(sample) {
+                *m += *s as f64;
+            }
+        }
+        mean.iter_mut().for_each(|m| *m /= count.max(1) as f64);
+
+        let mut m2 = vec![0.0f64; num_dimensions];
+        for sample in samples.chunks_exact(num_dimensions) {
+            for ((m2, m), s) in m2.iter_mut().zip(&mean).zip(sample) {
+                let delta = *s as f64 - m;
+                *m2 += delta * delta;
+            }
+        }
+
+        SampleMoments {
+            count: count as u64,
+            mean: mean.into_iter().map(|m| m as f32).collect(),
+            m2: m2.into_iter().map(|m2| m2 as f32).collect(),
+        }
+    }
+}
+
+/// The backends compiled in. A backend for a device adds itself here, under the feature of the
+/// crate of its runtime.
+fn get_backends(distance_impl: DistanceImpl) -> Vec<Box<dyn OffloadBackend>> {
+    vec![Box::new(CpuBackend::new(distance_impl))]
+}
+
+/// The backend named `name`, or the CPU backend if that one is not compiled in or not
+/// available. The caller can tell from the name of the backend it gets.
+pub fn select_backend(name: &str, distance_impl: DistanceImpl) -> Box<dyn OffloadBackend> {
+    get_backends(distance_impl)
+        .into_iter()
+        .find(|backend| backend.name() == name && backend.is_available())
+        .unwrap_or_else(|| Box::new(CpuBackend::new(distance_impl)))
+}
+
+/// The training of an SBQ quantizer on batches of samples computed by a backend.
+pub struct BatchedTraining {
+    backend: Box<dyn OffloadBackend>,
+    num_dimensions: usize,
+    samples: Vec<f32>,
+}
+
+impl BatchedTraining {
+    pub fn new(backend: Box<dyn OffloadBackend>, num_dimensions: usize) -> Self {
+        Self {
+            backend,
+            num_dimensions,
+            samples: Vec::with_capacity(TRAINING_BATCH_SIZE * num_dimensions),
+        }
+    }
+
+    pub fn add_sample(&mut self, quantizer: &mut SbqQuantizer, sample: &[f32]) {
+        assert_eq!(sample.len(), self.num_dimensions);
+        self.samples.extend_from_slice(sample);
+        if self.samples.len() >= TRAINING_BATCH_SIZE * self.num_dimensions {
+            self.flush(quantizer);
+        }
+    }
+
+    /// Adds the samples not handed to the backend yet to the quantizer.
+    pub fn flush(&mut self, quantizer: &mut SbqQuantizer) {
+        if self.samples.is_empty() {
+            return;
+        }
+        let moments = self.backend.get_moments(&self.samples, self.num_dimensions);
+        quantizer.add_moments(&moments);
+        self.samples.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        select_backend, BatchedTraining, CpuBackend, OffloadBackend, OffloadDistance,
+        TRAINING_BATCH_SIZE,
+    };
+    use crate::diskann::{
+        distance::{distance_cosine, distance_l2, preprocess_cosine, DistanceImpl},
+        sbq::SbqQuantizer,
+    };
+
+    fn vectors(count: usize, num_dimensions: usize) -> Vec<f32> {
+        (0..count * num_dimensions)
+            .map(|i| ((i * 7919) % 101) as f32 / 50.0 - 1.0)
+            .collect()
+    }
+
+    #[test]
+    fn test_cpu_distances() {
+        let backend = CpuBackend::new(DistanceImpl::Auto);
+        /* the cosine distance is computed on normalized vectors */
+        let query = [0.6, 0.8, 0.0, 0.0];
+        let mut vectors = vectors(10, 4);
+        vectors.chunks_exact_mut(4).for_each(preprocess_cosine);
+        let l2 = backend.get_distances(OffloadDistance::L2, &query, &vectors);
+        let cosine = backend.get_distances(OffloadDistance::Cosine, &query, &vectors);
+        assert_eq!(l2.len(), 10);
+        for (i, vector) in vectors.chunks_exact(4).enumerate() {
+            assert_eq!(l2[i], distance_l2(&query, vector));
+            assert_eq!(cosine[i], distance_cosine(&query, vector));
+        }
+    }
+
+    #[test]
+    fn test_batched_training() {
+        /* more than a batch, so that batches are merged */
+        let num_dimensions = 3;
+        let samples = vectors(TRAINING_BATCH_SIZE + 100, num_dimensions);
+
+        let mut sequential = SbqQuantizer::new(2);
+        sequential.start_training(num_dimensions);
+        for sample in samples.chunks_exact(num_dimensions) {
+            sequential.add_sample(sample);
+        }
+        sequential.finish_training();
+
+        let mut batched = SbqQuantizer::new(2);
+        batched.start_training(num_dimensions);
+        let mut training = BatchedTraining::new(
+            Box::new(CpuBackend::new(DistanceImpl::Auto)),
+            num_dimensions,
+        );
+        for sample in samples.chunks_exact(num_dimensions) {
+            training.add_sample(&mut batched, sample);
+        }
+        training.flush(&mut batched);
+        batched.finish_training();
+
+        assert_eq!(batched.count, sequential.count);
+        for d in 0..num_dimensions {
+            assert!((batched.mean[d] - sequential.mean[d]).abs() < 1e-4);
+            assert!((batched.m2[d] - sequential.m2[d]).abs() / sequential.m2[d] < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_select_backend_falls_back_to_cpu() {
+        assert_eq!(select_backend("cpu", DistanceImpl::Auto).name(), "cpu");
+        assert_eq!(select_backend("gpu", DistanceImpl::Auto).name(), "cpu");
+    }
+}
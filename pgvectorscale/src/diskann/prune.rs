@@ -0,0 +1,163 @@
+//! The robust pruning of the Vamana graph.
+
+/// The distances between the nodes of a graph, as the pruning needs them. The index computes
+/// them from the vectors of its storage, where `N` is the pointer to a node.
+pub trait PruneDistances<N> {
+    /// The distances from the node `from` to each of the nodes `to`, in the same order.
+    fn get_distances(&mut self, from: N, to: &[N]) -> Vec<f32>;
+}
+
+/// Chooses at most `max_neighbors` neighbors of a point among `candidates`, the candidate
+/// neighbors with their distance to the point, sorted by distance. Prefers candidates that are
+/// closer to the point than to the neighbors already chosen: a candidate is skipped while its
+/// distance to the point is more than `alpha` times its distance to one of them, starting with
+/// an alpha of 1 and growing it up to `max_alpha` while there is still room.
+///
+/// Returns the positions in `candidates` of the chosen neighbors, in the order they were chosen.
+pub fn robust_prune<N: Copy, D: PruneDistances<N>>(
+    candidates: &[(N, f32)],
+    max_neighbors: usize,
+    max_alpha: f64,
+    num_dimensions: usize,
+    distances: &mut D,
+) -> Vec<usize> {
+    let mut results = Vec::<usize>::with_capacity(max_neighbors);
+
+    let mut max_factors: Vec<f64> = vec![0.0; candidates.len()];
+
+    let mut alpha = 1.0;
+    let dimension_epsilon = num_dimensions as f32 * f32::EPSILON;
+    //first we add nodes that "pass" a small alpha. Then, if there
+    //is still room we loop again with a larger alpha.
+    while alpha <= max_alpha && results.len() < max_neighbors {
+        for i in 0..candidates.len() {
+            if results.len() >= max_neighbors {
+                return results;
+            }
+            if max_factors[i] > alpha {
+                continue;
+            }
+
+            //don't consider again
+            max_factors[i] = f64::MAX;
+            results.push(i);
+
+            //go thru the other candidates (tail of the list) that haven't been completely excluded
+            let others: Vec<usize> = (i + 1..candidates.len())
+                .filter(|j| max_factors[*j] <= max_alpha)
+                .collect();
+            let to: Vec<N> = others.iter().map(|j| candidates[*j].0).collect();
+            let distances_to_existing_neighbor = distances.get_distances(candidates[i].0, &to);
+
+            for (j, mut distance_between_candidate_and_existing_neighbor) in others
+                .into_iter()
+                .zip(distances_to_existing_neighbor.into_iter())
+            {
+                let mut distance_between_candidate_and_point = candidates[j].1;
+
+                //We need both values to be positive.
+                //Otherwise, the case where distance_between_candidate_and_point > 0 and distance_between_candidate_and_existing_neighbor < 0 is totally wrong.
+                //If we implement inner product distance we'll have to figure something else out.
+                if distance_between_candidate_and_point < 0.0
+                    && distance_between_candidate_and_point >= 0.0 - dimension_epsilon
+                {
+                    distance_between_candidate_and_point = 0.0;
+                }
+
+                if distance_between_candidate_and_existing_neighbor < 0.0
+                    && distance_between_candidate_and_existing_neighbor >= 0.0 - dimension_epsilon
+                {
+                    distance_between_candidate_and_existing_neighbor = 0.0;
+                }
+
+                debug_assert!(
+                    distance_between_candidate_and_point >= 0.0,
+                    "distance_between_candidate_and_point is negative: {}, {}",
+                    distance_between_candidate_and_point,
+                    f32::EPSILON
+                );
+                debug_assert!(distance_between_candidate_and_existing_neighbor >= 0.0);
+
+                //factor is high if the candidate is closer to an existing neighbor than the point it's being considered for
+                let factor =
+                    if distance_between_candidate_and_existing_neighbor < 0.0 + f32::EPSILON {
+                        if distance_between_candidate_and_point < 0.0 + f32::EPSILON {
+                            1.0
+                        } else {
+                            f64::MAX
+                        }
+                    } else {
+                        distance_between_candidate_and_point as f64
+                            / distance_between_candidate_and_existing_neighbor as f64
+                    };
+                max_factors[j] = max_factors[j].max(factor)
+            }
+        }
+        alpha = alpha * 1.2
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{robust_prune, PruneDistances};
+
+    /// Points on a line, identified by their coordinate.
+    struct LineDistances {
+        num_computed: usize,
+    }
+
+    impl PruneDistances<f32> for LineDistances {
+        fn get_distances(&mut self, from: f32, to: &[f32]) -> Vec<f32> {
+            self.num_computed += to.len();
+            to.iter().map(|p| (from - p).abs()).collect()
+        }
+    }
+
+    fn candidates(point: f32, others: &[f32]) -> Vec<(f32, f32)> {
+        let mut candidates: Vec<(f32, f32)> =
+            others.iter().map(|p| (*p, (point - p).abs())).collect();
+        candidates.sort_by(|a, b| a.1.total_cmp(&b.1));
+        candidates
+    }
+
+    #[test]
+    fn test_robust_prune() {
+        let candidates = candidates(0.0, &[1.0, -1.0, 2.0, 3.0]);
+        let mut distances = LineDistances { num_computed: 0 };
+
+        /* 2 and 3 are behind 1 */
+        let chosen = robust_prune(&candidates, 10, 1.2, 1, &mut distances);
+        assert_eq!(chosen, vec![0, 1]);
+        /* the candidates excluded by 1 are not compared to -1 */
+        assert_eq!(distances.num_computed, 3);
+
+        /* a larger alpha lets 3 in, but not 2 */
+        let chosen = robust_prune(&candidates, 10, 2.0, 1, &mut distances);
+        assert_eq!(chosen, vec![0, 1, 3]);
+
+        let chosen = robust_prune(&candidates, 1, 2.0, 1, &mut distances);
+        assert_eq!(chosen, vec![0]);
+    }
+
+    #[test]
+    fn test_robust_prune_duplicates() {
+        /* a duplicate of a neighbor is only kept if the point is a duplicate too */
+        let chosen = robust_prune(
+            &candidates(0.0, &[1.0, 1.0]),
+            10,
+            1.2,
+            1,
+            &mut LineDistances { num_computed: 0 },
+        );
+        assert_eq!(chosen, vec![0]);
+        let chosen = robust_prune(
+            &candidates(0.0, &[0.0, 0.0]),
+            10,
+            1.2,
+            1,
+            &mut LineDistances { num_computed: 0 },
+        );
+        assert_eq!(chosen, vec![0, 1]);
+    }
+}
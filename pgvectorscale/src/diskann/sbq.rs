@@ -0,0 +1,279 @@
+//! The statistical binary quantizer.
+//!
+//! Every dimension of a vector is compared to the mean of the dimension over a sample of the
+//! vectors, and encoded in one bit, or in several bits by its z-score.
+
+pub type SbqVectorElement = u64;
+pub const BITS_STORE_TYPE_SIZE: usize = 64;
+
+#[derive(Clone)]
+pub struct SbqQuantizer {
+    pub use_mean: bool,
+    training: bool,
+    pub count: u64,
+    pub mean: Vec<f32>,
+    pub m2: Vec<f32>,
+    pub num_bits_per_dimension: u8,
+}
+
+impl SbqQuantizer {
+    /// A quantizer with `num_bits_per_dimension` bits per dimension, to train or load.
+    pub fn new(num_bits_per_dimension: u8) -> SbqQuantizer {
+        Self {
+            use_mean: true,
+            training: false,
+            count: 0,
+            mean: vec![],
+            m2: vec![],
+            num_bits_per_dimension,
+        }
+    }
+
+    pub fn load(&mut self, count: u64, mean: Vec<f32>, m2: Vec<f32>) {
+        self.count = count;
+        self.mean = mean;
+        self.m2 = m2
+    }
+
+    pub fn quantized_size(&self, full_vector_size: usize) -> usize {
+        Self::quantized_size_internal(full_vector_size, self.num_bits_per_dimension)
+    }
+
+    pub fn quantized_size_internal(full_vector_size: usize, num_bits_per_dimension: u8) -> usize {
+        let num_bits = full_vector_size * num_bits_per_dimension as usize;
+
+        if num_bits % BITS_STORE_TYPE_SIZE == 0 {
+            num_bits / BITS_STORE_TYPE_SIZE
+        } else {
+            (num_bits / BITS_STORE_TYPE_SIZE) + 1
+        }
+    }
+
+    pub fn quantized_size_bytes(num_dimensions: usize, num_bits_per_dimension: u8) -> usize {
+        Self::quantized_size_internal(num_dimensions, num_bits_per_dimension)
+            * std::mem::size_of::<SbqVectorElement>()
+    }
+
+    /// The codes of a vector: the bits of every dimension, 64 to an element.
+    pub fn quantize(&self, full_vector: &[f32]) -> Vec<SbqVectorElement> {
+        assert!(!self.training);
+        if self.use_mean {
+            let mut res_vector = vec![0; self.quantized_size(full_vector.len())];
+
+            if self.num_bits_per_dimension == 1 {
+                for (i, &v) in full_vector.iter().enumerate() {
+                    if v > self.mean[i] {
+                        res_vector[i / BITS_STORE_TYPE_SIZE] |= 1 << (i % BITS_STORE_TYPE_SIZE);
+                    }
+                }
+            } else {
+                for (i, &v) in full_vector.iter().enumerate() {
+                    let mean = self.mean[i];
+                    let variance = self.m2[i] / self.count as f32;
+                    let std_dev = variance.sqrt();
+                    let ranges = self.num_bits_per_dimension + 1;
+
+                    let v_z_score = (v - mean) / std_dev;
+                    let index = (v_z_score + 2.0) / (4.0 / ranges as f32); //we consider z scores between -2 and 2 and divide them into {ranges} ranges
+
+                    let bit_position = i * self.num_bits_per_dimension as usize;
+                    if index < 1.0 {
+                        //all zeros
+                    } else {
+                        let count_ones =
+                            (index.floor() as usize).min(self.num_bits_per_dimension as usize);
+                        //fill in count_ones bits from the left
+                        // ex count_ones=1: 100
+                        // ex count_ones=2: 110
+                        // ex count_ones=3: 111
+                        for j in 0..count_ones {
+                            res_vector[(bit_position + j) / BITS_STORE_TYPE_SIZE] |=
+                                1 << ((bit_position + j) % BITS_STORE_TYPE_SIZE);
+                        }
+                    }
+                }
+            }
+            res_vector
+        } else {
+            let mut res_vector = vec![0; self.quantized_size(full_vector.len())];
+
+            for (i, &v) in full_vector.iter().enumerate() {
+                if v > 0.0 {
+                    res_vector[i / BITS_STORE_TYPE_SIZE] |= 1 << (i % BITS_STORE_TYPE_SIZE);
+                }
+            }
+
+            res_vector
+        }
+    }
+
+    /// Starts computing the means of vectors of `num_dimensions` dimensions.
+    pub fn start_training(&mut self, num_dimensions: usize) {
+        self.training = true;
+        if self.use_mean {
+            self.count = 0;
+            self.mean = vec![0.0; num_dimensions];
+            if self.num_bits_per_dimension > 1 {
+                self.m2 = vec![0.0; num_dimensions];
+            }
+        }
+    }
+
+    pub fn add_sample(&mut self, sample: &[f32]) {
+        if self.use_mean {
+            self.count += 1;
+            assert!(self.mean.len() == sample.len());
+
+            if self.num_bits_per_dimension > 1 {
+                assert!(self.m2.len() == sample.len());
+                let delta: Vec<_> = self
+                    .mean
+                    .iter()
+                    .zip(sample.iter())
+                    .map(|(m, s)| s - *m)
+                    .collect();
+
+                self.mean
+                    .iter_mut()
+                    .zip(sample.iter())
+                    .for_each(|(m, s)| *m += (s - *m) / self.count as f32);
+
+                let delta2 = self.mean.iter().zip(sample.iter()).map(|(m, s)| s - *m);
+
+                self.m2
+                    .iter_mut()
+                    .zip(delta.iter())
+                    .zip(delta2)
+                    .for_each(|((m2, d), d2)| *m2 += d * d2);
+            } else {
+                self.mean
+                    .iter_mut()
+                    .zip(sample.iter())
+                    .for_each(|(m, s)| *m += (s - *m) / self.count as f32);
+            }
+        }
+    }
+
+    /// Adds the statistics of a batch of samples computed by an offload backend, like adding
+    /// the samples one by one.
+    #[cfg(feature = "build_offload")]
+    pub fn add_moments(&mut self, moments: &super::offload::SampleMoments) {
+        if !self.use_mean || moments.count == 0 {
+            return;
+        }
+        assert!(self.mean.len() == moments.mean.len());
+        let count = self.count + moments.count;
+        let weight = moments.count as f32 / count as f32;
+        if self.num_bits_per_dimension > 1 {
+            /* the sums of squared deviations of both parts, plus the one between their means */
+            let between = self.count as f32 * weight;
+            self.m2
+                .iter_mut()
+                .zip(moments.m2.iter())
+                .zip(self.mean.iter().zip(moments.mean.iter()))
+                .for_each(|((m2, batch_m2), (m, batch_m))| {
+                    let delta = batch_m - m;
+                    *m2 += batch_m2 + delta * delta * between;
+                });
+        }
+        self.mean
+            .iter_mut()
+            .zip(moments.mean.iter())
+            .for_each(|(m, batch_m)| *m += (batch_m - *m) * weight);
+        self.count = count;
+    }
+
+    pub fn finish_training(&mut self) {
+        self.training = false;
+    }
+
+    /// The standard deviation of the dimension `dimension` over the training sample, None with one
+    /// bit per dimension, which only uses the means.
+    pub fn get_std_dev(&self, dimension: usize) -> Option<f32> {
+        if !self.use_mean || self.num_bits_per_dimension == 1 {
+            return None;
+        }
+        Some((self.m2[dimension] / self.count as f32).sqrt())
+    }
+
+    /// The values of the dimension `dimension` the codes set its bits above, one per bit: a
+    /// value above the first threshold sets the first bit, above the second the first two, and
+    /// so on.
+    pub fn get_thresholds(&self, dimension: usize) -> Vec<f32> {
+        if !self.use_mean {
+            return vec![0.0];
+        }
+        let mean = self.mean[dimension];
+        match self.get_std_dev(dimension) {
+            None => vec![mean],
+            Some(std_dev) => {
+                /* the z scores between -2 and 2 are divided into num_bits_per_dimension + 1 ranges */
+                let ranges = self.num_bits_per_dimension + 1;
+                (1..=self.num_bits_per_dimension)
+                    .map(|k| mean + (k as f32 * (4.0 / ranges as f32) - 2.0) * std_dev)
+                    .collect()
+            }
+        }
+    }
+
+    /// Identifies the means and bits per dimension the codes are computed with, so that codes
+    /// computed by a client can be checked against the index. The FNV-1a hash of the quantizer,
+    /// so that it does not change across versions.
+    pub fn get_id(&self) -> i64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut add = |bytes: &[u8]| {
+            for b in bytes {
+                hash = (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+        add(&[self.use_mean as u8, self.num_bits_per_dimension]);
+        add(&self.count.to_le_bytes());
+        for v in self.mean.iter().chain(self.m2.iter()) {
+            add(&v.to_le_bytes());
+        }
+        hash as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SbqQuantizer;
+
+    fn train(num_bits_per_dimension: u8) -> SbqQuantizer {
+        let mut quantizer = SbqQuantizer::new(num_bits_per_dimension);
+        quantizer.start_training(2);
+        for i in 0..100 {
+            quantizer.add_sample(&[i as f32, (i % 10) as f32 * 0.1]);
+        }
+        quantizer.finish_training();
+        quantizer
+    }
+
+    #[test]
+    fn test_thresholds() {
+        for num_bits_per_dimension in [1, 2, 3] {
+            let quantizer = train(num_bits_per_dimension);
+            for dimension in 0..2 {
+                let thresholds = quantizer.get_thresholds(dimension);
+                assert_eq!(thresholds.len(), num_bits_per_dimension as usize);
+                assert!(thresholds.windows(2).all(|t| t[0] < t[1]));
+
+                /* every threshold sets one more bit of the dimension */
+                let ones = |value: f32| {
+                    let mut vector = [0.0, 0.0];
+                    vector[dimension] = value;
+                    let codes = quantizer.quantize(&vector)[0];
+                    let bits = num_bits_per_dimension as usize;
+                    (codes >> (dimension * bits) & ((1 << bits) - 1)).count_ones()
+                };
+                for (k, threshold) in thresholds.iter().enumerate() {
+                    let epsilon = 0.01 * threshold.abs().max(1.0);
+                    assert_eq!(ones(threshold - epsilon), k as u32);
+                    assert_eq!(ones(threshold + epsilon), k as u32 + 1);
+                }
+            }
+        }
+        assert_eq!(train(1).get_std_dev(0), None);
+        assert!((train(2).get_std_dev(0).unwrap() - 28.866).abs() < 0.01);
+    }
+}
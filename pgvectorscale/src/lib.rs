@@ -1,17 +1,31 @@
+//! Without a `pg15` or `pg16` feature, e.g. with `--no-default-features`, the crate only
+//! contains the `diskann` module and doesn't depend on pgrx.
+
+#[cfg(any(feature = "pg15", feature = "pg16"))]
 use pgrx::prelude::*;
 
+#[cfg(any(feature = "pg15", feature = "pg16"))]
 pgrx::pg_module_magic!();
 
+#[cfg(any(feature = "pg15", feature = "pg16"))]
 pub mod access_method;
+pub mod diskann;
+#[cfg(any(feature = "pg15", feature = "pg16"))]
 mod util;
 
+#[cfg(any(feature = "pg15", feature = "pg16"))]
 #[allow(non_snake_case)]
 #[pg_guard]
 pub unsafe extern "C" fn _PG_init() {
     access_method::options::init();
     access_method::guc::init();
+    if pg_sys::process_shared_preload_libraries_in_progress {
+        access_method::result_cache::init();
+        access_method::activity::init();
+    }
 }
 
+#[cfg(any(feature = "pg15", feature = "pg16"))]
 #[allow(non_snake_case)]
 #[pg_guard]
 pub extern "C" fn _PG_fini() {
@@ -20,7 +34,7 @@ pub extern "C" fn _PG_fini() {
 
 /// This module is required by `cargo pgrx test` invocations.
 /// It must be visible at the root of your extension crate.
-#[cfg(test)]
+#[cfg(all(test, any(feature = "pg15", feature = "pg16")))]
 pub mod pg_test {
     pub fn setup(_options: Vec<&str>) {
         //let (mut client, _) = pgrx_tests::client().unwrap();
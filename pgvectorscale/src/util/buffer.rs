@@ -2,8 +2,13 @@
 //! Under the hood, it is just an index into the shared memory array.
 //! To use the slot, certain pins and locks need to be taken
 //! See src/backend/storage/buffer/README in the Postgres src.
+//!
+//! Indexes on temp tables use local buffers instead, which are private to the backend.
+//! `ReadBufferExtended` and the buffer locking functions handle those transparently, only the
+//! relation extension lock has to be skipped, like Postgres does.
 
 use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use pgrx::*;
 
@@ -12,19 +17,58 @@ use pgrx::pg_sys::{
     ReadBufferMode_RBM_NORMAL,
 };
 
+use super::ports::RELATION_IS_LOCAL;
+
+/* The buffers pinned through the types of this module by this backend, and the most pinned at
+once since `reset_peak_pins`. Every backend is its own process, so these are per backend. */
+static NUM_PINS: AtomicUsize = AtomicUsize::new(0);
+static PEAK_PINS: AtomicUsize = AtomicUsize::new(0);
+
+fn record_pin() {
+    let num_pins = NUM_PINS.fetch_add(1, Ordering::Relaxed) + 1;
+    PEAK_PINS.fetch_max(num_pins, Ordering::Relaxed);
+}
+
+fn record_unpin() {
+    NUM_PINS.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// The number of buffers the index currently has pinned in this backend.
+pub fn get_num_pins() -> usize {
+    NUM_PINS.load(Ordering::Relaxed)
+}
+
+/// Starts measuring the peak number of pinned buffers, e.g. of a scan.
+pub fn reset_peak_pins() {
+    PEAK_PINS.store(get_num_pins(), Ordering::Relaxed);
+}
+
+/// The most buffers pinned at once since the last `reset_peak_pins`.
+pub fn get_peak_pins() -> usize {
+    PEAK_PINS.load(Ordering::Relaxed)
+}
+
 pub struct LockRelationForExtension<'a> {
     relation: &'a PgRelation,
+    locked: bool,
 }
 
 impl<'a> LockRelationForExtension<'a> {
     pub fn new(index: &'a PgRelation) -> Self {
-        unsafe {
-            pg_sys::LockRelationForExtension(
-                index.as_ptr(),
-                pg_sys::ExclusiveLock as pg_sys::LOCKMODE,
-            )
+        /* no other backend can extend a local relation */
+        let locked = unsafe { !RELATION_IS_LOCAL(index.as_ptr()) };
+        if locked {
+            unsafe {
+                pg_sys::LockRelationForExtension(
+                    index.as_ptr(),
+                    pg_sys::ExclusiveLock as pg_sys::LOCKMODE,
+                )
+            }
+        }
+        Self {
+            relation: index,
+            locked,
         }
-        Self { relation: index }
     }
 }
 
@@ -34,7 +78,7 @@ impl<'a> Drop for LockRelationForExtension<'a> {
         unsafe {
             // Only unlock while in a transaction state. Should not be unlocking during abort or commit.
             // During abort, the system will unlock stuff itself. During commit, the release should have already happened.
-            if pgrx::pg_sys::IsTransactionState() {
+            if self.locked && pgrx::pg_sys::IsTransactionState() {
                 pg_sys::UnlockRelationForExtension(
                     self.relation.as_ptr(),
                     pg_sys::ExclusiveLock as pg_sys::LOCKMODE,
@@ -83,6 +127,7 @@ impl<'a> LockedBufferExclusive<'a> {
             ReadBufferMode_RBM_NORMAL,
             std::ptr::null_mut(),
         );
+        record_pin();
 
         pg_sys::LockBuffer(buf, pg_sys::BUFFER_LOCK_EXCLUSIVE as i32);
         LockedBufferExclusive {
@@ -104,6 +149,7 @@ impl<'a> LockedBufferExclusive<'a> {
             ReadBufferMode_RBM_NORMAL,
             std::ptr::null_mut(),
         );
+        record_pin();
 
         pg_sys::LockBufferForCleanup(buf);
         LockedBufferExclusive {
@@ -120,6 +166,7 @@ impl<'a> LockedBufferExclusive<'a> {
 impl<'a> Drop for LockedBufferExclusive<'a> {
     /// drop both unlock and unpins the buffer.
     fn drop(&mut self) {
+        record_unpin();
         unsafe {
             // Only unlock while in a transaction state. Should not be unlocking during abort or commit.
             // During abort, the system will unlock stuff itself. During commit, the release should have already happened.
@@ -163,6 +210,7 @@ impl<'a> LockedBufferShare<'a> {
                 ReadBufferMode_RBM_NORMAL,
                 std::ptr::null_mut(),
             );
+            record_pin();
 
             pg_sys::LockBuffer(buf, pg_sys::BUFFER_LOCK_SHARE as i32);
             LockedBufferShare {
@@ -176,6 +224,7 @@ impl<'a> LockedBufferShare<'a> {
 impl<'a> Drop for LockedBufferShare<'a> {
     /// drop both unlock and unpins the buffer.
     fn drop(&mut self) {
+        record_unpin();
         unsafe {
             // Only unlock while in a transaction state. Should not be unlocking during abort or commit.
             // During abort, the system will unlock stuff itself. During commit, the release should have already happened.
@@ -220,6 +269,7 @@ impl PinnedBufferShare {
                 ReadBufferMode_RBM_NORMAL,
                 std::ptr::null_mut(),
             );
+            record_pin();
             PinnedBufferShare { buffer: buf }
         }
     }
@@ -228,6 +278,7 @@ impl PinnedBufferShare {
 impl Drop for PinnedBufferShare {
     /// drop both unlock and unpins the buffer.
     fn drop(&mut self) {
+        record_unpin();
         unsafe {
             // Only unlock while in a transaction state. Should not be unlocking during abort or commit.
             // During abort, the system will unlock stuff itself. During commit, the release should have already happened.
@@ -0,0 +1,232 @@
+//! Fault injection in the write path of the index pages, for the tests of writes that fail
+//! half-way. Only compiled with the `fault_injection` feature.
+//!
+//! `diskann_inject_fault(fault, after_writes)` arms a fault in this backend that fires on the
+//! write of a page following `after_writes` more writes, and only once:
+//!
+//! - `error` fails the write with an error before anything reaches the buffer or the WAL, as if
+//!   the backend died in the middle of a build or an insert.
+//! - `torn_page` zeroes the second half of the page after it was written and WAL-logged, as if
+//!   only the first sectors of the page reached the disk and no full page image restored it.
+//!
+//! Every write of a page, including those of `Tape`, goes through `WritablePage::commit`, which
+//! calls `on_page_write`.
+
+use pgrx::{pg_sys::BLCKSZ, *};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fault {
+    Error,
+    TornPage,
+}
+
+/// The armed fault and the number of writes left before it fires. Backends are single threaded.
+static mut ARMED_FAULT: Option<(Fault, u32)> = None;
+
+/// Arms `fault` in this backend. It fires on the write of a page following `after_writes` more
+/// writes of index pages, and only once.
+#[pg_extern(volatile)]
+pub fn diskann_inject_fault(fault: &str, after_writes: i32) {
+    let fault = match fault {
+        "error" => Fault::Error,
+        "torn_page" => Fault::TornPage,
+        _ => error!(
+            "unknown fault \"{}\", expected \"error\" or \"torn_page\"",
+            fault
+        ),
+    };
+    if after_writes < 0 {
+        error!("after_writes must not be negative");
+    }
+    unsafe { ARMED_FAULT = Some((fault, after_writes as u32)) };
+}
+
+/// Disarms the fault of this backend, if it has not fired yet.
+#[pg_extern(volatile)]
+pub fn diskann_clear_faults() {
+    unsafe { ARMED_FAULT = None };
+}
+
+/// Counts a write of a page and returns the fault that fires on it, if any.
+pub fn on_page_write() -> Option<Fault> {
+    unsafe {
+        match ARMED_FAULT {
+            Some((fault, 0)) => {
+                ARMED_FAULT = None;
+                Some(fault)
+            }
+            Some((fault, writes_left)) => {
+                ARMED_FAULT = Some((fault, writes_left - 1));
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+/// Zeroes the second half of `page`, which holds the items added first and the special space.
+pub unsafe fn tear_page(page: pg_sys::Page) {
+    let half = BLCKSZ as usize / 2;
+    std::ptr::write_bytes(page.cast::<u8>().add(half), 0, half);
+}
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pgrx::pg_schema]
+mod tests {
+    use pgrx::*;
+
+    /// Runs `statement` in a subtransaction and returns the message of its error, if it failed.
+    fn run_and_catch(statement: &str) -> spi::Result<Option<String>> {
+        Spi::run(&format!(
+            "CREATE TEMP TABLE IF NOT EXISTS fault_errors(message text);
+            TRUNCATE fault_errors;
+            DO $$
+            BEGIN
+                {statement};
+            EXCEPTION WHEN OTHERS THEN
+                INSERT INTO fault_errors VALUES (SQLERRM);
+            END $$;",
+        ))?;
+        Spi::get_one::<String>("SELECT max(message) FROM fault_errors")
+    }
+
+    fn count_by_index() -> spi::Result<Option<i64>> {
+        Spi::get_one::<i64>(
+            "SET enable_seqscan = 0;
+            SELECT count(*) FROM (SELECT id FROM test ORDER BY embedding <=> '[1, 2, 3]') t",
+        )
+    }
+
+    unsafe fn test_fault_mid_build_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(
+            "CREATE TABLE test(id int, embedding vector(3));
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 7, i % 13]::vector FROM generate_series(1, 300) i;",
+        )?;
+
+        /* a build that fails half-way leaves no index behind */
+        Spi::run("SELECT diskann_inject_fault('error', 10)")?;
+        let message = run_and_catch(&format!(
+            "CREATE INDEX idxtest ON test USING diskann(embedding) WITH ({index_options})"
+        ))?;
+        assert!(message.unwrap().contains("injected fault"));
+        let indexes =
+            Spi::get_one::<i64>("SELECT count(*) FROM pg_class WHERE relname = 'idxtest'")?;
+        assert_eq!(indexes, Some(0));
+
+        /* and does not get in the way of the next one */
+        Spi::run(&format!(
+            "CREATE INDEX idxtest ON test USING diskann(embedding) WITH ({index_options})"
+        ))?;
+        assert_eq!(count_by_index()?, Some(300));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_fault_mid_build_plain() -> spi::Result<()> {
+        test_fault_mid_build_scaffold("storage_layout = plain")
+    }
+
+    #[pg_test]
+    unsafe fn test_fault_mid_build_memory_optimized() -> spi::Result<()> {
+        test_fault_mid_build_scaffold("storage_layout = memory_optimized")
+    }
+
+    unsafe fn test_fault_mid_insert_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(id int, embedding vector(3));
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 7, i % 13]::vector FROM generate_series(1, 300) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH ({index_options});",
+        ))?;
+
+        /* an insert that fails after writing some of its pages, e.g. its node but not all the
+        back-links of its neighbors, leaves a node of an aborted row that scans skip */
+        for after_writes in 0..3 {
+            Spi::run(&format!(
+                "SELECT diskann_inject_fault('error', {after_writes})"
+            ))?;
+            let message =
+                run_and_catch("INSERT INTO test(id, embedding) VALUES (1000, '[1, 2, 3]')")?;
+            assert!(message.unwrap().contains("injected fault"));
+            assert_eq!(
+                count_by_index()?,
+                Some(300),
+                "after {} writes",
+                after_writes
+            );
+        }
+
+        /* the pages added by the failed inserts and never written are skipped by the functions
+        that go through all the pages */
+        Spi::run(
+            "SELECT count(*) FROM diskann_page_ranges('idxtest'::regclass);
+            SELECT diskann_refresh_entry_points('idxtest'::regclass);",
+        )?;
+
+        /* the index keeps working */
+        Spi::run(
+            "INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 7, i % 13]::vector FROM generate_series(301, 350) i;",
+        )?;
+        assert_eq!(count_by_index()?, Some(350));
+        let first = Spi::get_one::<i32>(
+            "SET enable_seqscan = 0;
+            SELECT id FROM test ORDER BY embedding <=> '[350, 0, 12]' LIMIT 1",
+        )?;
+        assert_eq!(first, Some(350));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_fault_mid_insert_plain() -> spi::Result<()> {
+        test_fault_mid_insert_scaffold("storage_layout = plain")
+    }
+
+    #[pg_test]
+    unsafe fn test_fault_mid_insert_memory_optimized() -> spi::Result<()> {
+        test_fault_mid_insert_scaffold("storage_layout = memory_optimized")
+    }
+
+    unsafe fn test_fault_torn_page_scaffold(index_options: &str) -> spi::Result<()> {
+        Spi::run(&format!(
+            "CREATE TABLE test(id int, embedding vector(3));
+            INSERT INTO test(id, embedding)
+            SELECT i, ARRAY[i, i % 7, i % 13]::vector FROM generate_series(1, 300) i;
+            CREATE INDEX idxtest ON test USING diskann(embedding) WITH ({index_options});",
+        ))?;
+
+        /* the torn page is read back by the rest of the insert or by the next scan, which fail
+        instead of returning the rows of garbage nodes */
+        Spi::run(
+            "SET enable_seqscan = 0;
+            SELECT diskann_inject_fault('torn_page', 0);",
+        )?;
+        let message = match run_and_catch(
+            "INSERT INTO test(id, embedding) VALUES (1000, '[1, 2, 3]')",
+        )? {
+            Some(message) => Some(message),
+            None => run_and_catch(
+                "PERFORM count(*) FROM (SELECT id FROM test ORDER BY embedding <=> '[1, 2, 3]') t",
+            )?,
+        };
+        assert!(message.unwrap().contains("corrupted page"));
+        Ok(())
+    }
+
+    #[pg_test]
+    unsafe fn test_fault_torn_page_plain() -> spi::Result<()> {
+        test_fault_torn_page_scaffold("storage_layout = plain")
+    }
+
+    #[pg_test]
+    unsafe fn test_fault_torn_page_memory_optimized() -> spi::Result<()> {
+        test_fault_torn_page_scaffold("storage_layout = memory_optimized")
+    }
+
+    #[pg_test(error = "unknown fault \"crash\", expected \"error\" or \"torn_page\"")]
+    unsafe fn test_unknown_fault() -> spi::Result<()> {
+        Spi::run("SELECT diskann_inject_fault('crash', 0)")?;
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
 pub mod buffer;
+#[cfg(feature = "fault_injection")]
+pub mod fault;
 pub mod page;
 pub mod ports;
 pub mod table_slot;
@@ -3,14 +3,14 @@
 
 use pg_sys::Page;
 use pgrx::{
-    pg_sys::{BlockNumber, BufferGetPage, OffsetNumber, BLCKSZ},
+    pg_sys::{BlockNumber, BufferGetPage, FirstOffsetNumber, OffsetNumber, BLCKSZ},
     *,
 };
 use std::ops::Deref;
 
 use super::{
     buffer::{LockedBufferExclusive, LockedBufferShare},
-    ports::{PageGetItem, PageGetItemId},
+    ports::{PageGetItem, PageGetItemId, PageGetMaxOffsetNumber, PageIsNew},
     ReadableBuffer,
 };
 pub struct WritablePage<'a> {
@@ -33,9 +33,32 @@ pub enum PageType {
     SbqMeans = 4,
     SbqNode = 5,
     Meta = 6,
+    Pca = 7,
+    FullVector = 8,
 }
 
 impl PageType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PageType::MetaV1 => "meta_v1",
+            PageType::Node => "node",
+            PageType::PqQuantizerDef => "pq_quantizer_def",
+            PageType::PqQuantizerVector => "pq_quantizer_vector",
+            PageType::SbqMeans => "sbq_means",
+            PageType::SbqNode => "sbq_node",
+            PageType::Meta => "meta",
+            PageType::Pca => "pca",
+            PageType::FullVector => "full_vector",
+        }
+    }
+
+    /// Whether new items can take the space of the dead items of pages of this type. The nodes
+    /// are the targets of the edges of the graph, and a deleted node keeps its in-edges, so its
+    /// slot is never reused for another node. A full vector is only referenced by its node.
+    pub fn reuses_dead_items(&self) -> bool {
+        *self == PageType::FullVector
+    }
+
     fn from_u8(value: u8) -> Self {
         match value {
             0 => PageType::MetaV1,
@@ -45,10 +68,21 @@ impl PageType {
             4 => PageType::SbqMeans,
             5 => PageType::SbqNode,
             6 => PageType::Meta,
+            7 => PageType::Pca,
+            8 => PageType::FullVector,
             _ => panic!("Unknown PageType number {}", value),
         }
     }
 }
+/// The number of items of `item_size` bytes that fit on one of our pages.
+pub fn items_per_page(item_size: usize) -> usize {
+    let maxalign = |size: usize| (size + 7) & !7;
+    let usable = BLCKSZ as usize
+        - std::mem::size_of::<pg_sys::PageHeaderData>()
+        - maxalign(std::mem::size_of::<TsvPageOpaqueData>());
+    usable / (maxalign(item_size) + std::mem::size_of::<pg_sys::ItemIdData>())
+}
+
 /// This is the Tsv-specific data that goes on every "diskann-owned" page
 /// It is placed at the end of a page in the "special" area
 
@@ -84,9 +118,12 @@ impl TsvPageOpaqueData {
         }
     }
 
+    /// A page with the wrong magic number was not written by the index, or was torn by a crash
+    /// that the WAL didn't repair. Fail rather than read garbage nodes from it.
     fn verify(&self) {
-        assert_eq!(self.page_id, TSV_PAGE_ID);
-        PageType::from_u8(self.page_type);
+        if self.page_id != TSV_PAGE_ID || self.page_type > PageType::FullVector as u8 {
+            error!("diskann index contains a corrupted page, REINDEX the index");
+        }
     }
 }
 
@@ -151,10 +188,53 @@ impl<'a> WritablePage<'a> {
         offset_number
     }
 
-    /// get a writable page for cleanup(vacuum) operations.
-    pub unsafe fn cleanup(index: &'a PgRelation, block: BlockNumber) -> Self {
+    /// Marks the item at `offset` dead: its space can be reused by an item of the same size.
+    pub unsafe fn mark_item_dead(&mut self, offset: OffsetNumber) {
+        let item_id = PageGetItemId(self.page, offset);
+        (*item_id).set_lp_flags(pg_sys::LP_DEAD);
+    }
+
+    /// Overwrites a dead item of the same size as `data`, if the page has one.
+    pub unsafe fn reuse_dead_item(&mut self, data: &[u8]) -> Option<OffsetNumber> {
+        let max_offset = PageGetMaxOffsetNumber(self.page);
+        for offset in FirstOffsetNumber..(max_offset + 1) as OffsetNumber {
+            let item_id = PageGetItemId(self.page, offset);
+            if (*item_id).lp_flags() != pg_sys::LP_DEAD
+                || (*item_id).lp_len() as usize != data.len()
+            {
+                continue;
+            }
+            let item = PageGetItem(self.page, item_id) as *mut u8;
+            std::ptr::copy_nonoverlapping(data.as_ptr(), item, data.len());
+            (*item_id).set_lp_flags(pg_sys::LP_NORMAL);
+            return Some(offset);
+        }
+        None
+    }
+
+    /// The free space of the page plus the space of its dead items.
+    pub fn get_reusable_space(&self) -> usize {
+        let mut space = self.get_free_space();
+        unsafe {
+            let max_offset = PageGetMaxOffsetNumber(self.page);
+            for offset in FirstOffsetNumber..(max_offset + 1) as OffsetNumber {
+                let item_id = PageGetItemId(self.page, offset);
+                if (*item_id).lp_flags() == pg_sys::LP_DEAD {
+                    space += (*item_id).lp_len() as usize;
+                }
+            }
+        }
+        space
+    }
+
+    /// get a writable page for cleanup(vacuum) operations. None if the page was never
+    /// initialized.
+    pub unsafe fn cleanup(index: &'a PgRelation, block: BlockNumber) -> Option<Self> {
         let buffer = LockedBufferExclusive::read_for_cleanup(index, block);
-        Self::modify_with_buffer(index, buffer)
+        if PageIsNew(BufferGetPage(*buffer)) {
+            return None;
+        }
+        Some(Self::modify_with_buffer(index, buffer))
     }
 
     // Safety: Safe because it verifies the page
@@ -207,10 +287,20 @@ impl<'a> WritablePage<'a> {
     /// commit saves all the changes to the page.
     /// Note that this will consume the page and make it unusable after the call.
     pub fn commit(mut self) {
+        #[cfg(feature = "fault_injection")]
+        let fault = super::fault::on_page_write();
+        #[cfg(feature = "fault_injection")]
+        if fault == Some(super::fault::Fault::Error) {
+            error!("injected fault in the write of a diskann index page");
+        }
         unsafe {
             pg_sys::MarkBufferDirty(*self.buffer);
             pg_sys::GenericXLogFinish(self.state);
         }
+        #[cfg(feature = "fault_injection")]
+        if fault == Some(super::fault::Fault::TornPage) {
+            unsafe { super::fault::tear_page(BufferGetPage(*self.buffer)) };
+        }
         self.committed = true;
     }
 }
@@ -249,6 +339,15 @@ impl<'a> ReadablePage<'a> {
         }
     }
 
+    pub fn get_free_space(&self) -> usize {
+        unsafe { pg_sys::PageGetFreeSpace(self.page) }
+    }
+
+    /// Whether the page was never initialized. It has no type and no items.
+    pub fn is_new(&self) -> bool {
+        unsafe { PageIsNew(self.page) }
+    }
+
     pub fn get_type(&self) -> PageType {
         let opaque_data = TsvPageOpaqueData::read_from_page(&self.page);
         PageType::from_u8((*opaque_data).page_type)
@@ -263,6 +362,8 @@ impl<'a> ReadablePage<'a> {
         self,
         offset: pgrx::pg_sys::OffsetNumber,
     ) -> ReadableBuffer<'a> {
+        //a torn page lost the items of its second half along with the special space
+        _ = TsvPageOpaqueData::read_from_page(&self.page);
         let item_id = PageGetItemId(self.page, offset);
         let item = PageGetItem(self.page, item_id) as *mut u8;
         let len = (*item_id).lp_len();
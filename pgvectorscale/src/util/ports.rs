@@ -35,9 +35,22 @@ pub unsafe fn PageValidateSpecialPointer(page: pgrx::pg_sys::Page) {
     assert!((*header).pd_special >= SizeOfPageHeaderData as u16);
 }
 
+/// A page that was added to the relation but never initialized, e.g. because the backend that
+/// extended the relation failed before its first write of the page.
+#[allow(non_snake_case)]
+pub unsafe fn PageIsNew(page: pgrx::pg_sys::Page) -> bool {
+    let header = page.cast::<pgrx::pg_sys::PageHeaderData>();
+    (*header).pd_upper == 0
+}
+
 #[allow(non_upper_case_globals)]
 const SizeOfPageHeaderData: usize = offset_of!(pgrx::pg_sys::PageHeaderData, pd_linp);
 pub const PROGRESS_CREATE_IDX_SUBPHASE: c_int = 10;
+/// A progress parameter of CREATE INDEX that Postgres doesn't use, `param18` of
+/// `pg_stat_get_progress_info('CREATE INDEX')`.
+pub const PROGRESS_CREATE_IDX_BUILD_MEMORY: c_int = 17;
+/// Another progress parameter of CREATE INDEX that Postgres doesn't use, `param19`.
+pub const PROGRESS_CREATE_IDX_BUILD_WAL_BYTES: c_int = 18;
 
 #[allow(non_snake_case)]
 pub unsafe fn PageGetContents(page: pgrx::pg_sys::Page) -> *mut std::os::raw::c_char {
@@ -119,3 +132,13 @@ pub unsafe fn slot_getattr(
     }
     return Some(*slot.tts_values.add(index));
 }
+
+/// InvalidSubTransactionId is a casted #define, so it isn't in pgrx.
+const INVALID_SUB_TRANSACTION_ID: pg_sys::SubTransactionId = 0;
+
+/// Whether a relation is only visible to the current backend, e.g. a temp table or a relation
+/// created in the current transaction. Pages of temp relations live in local buffers.
+pub unsafe fn RELATION_IS_LOCAL(relation: pg_sys::Relation) -> bool {
+    //return (relation)->rd_islocaltemp || (relation)->rd_createSubid != InvalidSubTransactionId;
+    (*relation).rd_islocaltemp || (*relation).rd_createSubid != INVALID_SUB_TRANSACTION_ID
+}
@@ -12,31 +12,44 @@ pub struct TableSlot {
 }
 
 impl TableSlot {
-    pub unsafe fn new<S: StatsHeapNodeRead>(
-        heap_rel: &PgRelation,
-        heap_pointer: HeapPointer,
-        stats: &mut S,
-    ) -> Self {
+    /// Creates a slot without fetching any tuple into it. Use `fetch` to fill it.
+    pub unsafe fn new(heap_rel: &PgRelation) -> Self {
         let slot = PgBox::from_pg(pg_sys::table_slot_create(
             heap_rel.as_ptr(),
             std::ptr::null_mut(),
         ));
+        Self { slot }
+    }
 
+    /// Fetches the heap tuple at `heap_pointer` into this slot, replacing the previous one.
+    ///
+    /// Reusing one slot for many fetches (e.g. when rescoring candidates) avoids allocating
+    /// and dropping a slot for every heap access. Returns false if the tuple doesn't exist
+    /// anymore, e.g. because it was pruned.
+    pub unsafe fn fetch<S: StatsHeapNodeRead>(
+        &mut self,
+        heap_rel: &PgRelation,
+        heap_pointer: HeapPointer,
+        stats: &mut S,
+    ) -> bool {
         let table_am = heap_rel.rd_tableam;
         let fetch_row_version = (*table_am).tuple_fetch_row_version.unwrap();
         let mut ctid: pg_sys::ItemPointerData = pg_sys::ItemPointerData {
             ..Default::default()
         };
         heap_pointer.to_item_pointer_data(&mut ctid);
-        fetch_row_version(
+        let found = fetch_row_version(
             heap_rel.as_ptr(),
             &mut ctid,
             addr_of_mut!(pg_sys::SnapshotAnyData),
-            slot.as_ptr(),
+            self.slot.as_ptr(),
         );
         stats.record_heap_read();
+        found
+    }
 
-        Self { slot }
+    pub fn as_ptr(&self) -> *mut TupleTableSlot {
+        self.slot.as_ptr()
     }
 
     pub unsafe fn get_attribute(&self, attribute_number: pg_sys::AttrNumber) -> Option<Datum> {
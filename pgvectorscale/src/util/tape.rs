@@ -1,19 +1,106 @@
 //! Tape provides a simple infinite-tape-writing abstraction over postgres pages.
 
-use super::page::{PageType, WritablePage};
+use std::cell::RefCell;
+
+use super::page::{PageType, ReadablePage, WritablePage};
 use pgrx::{
-    pg_sys::{BlockNumber, BLCKSZ},
+    pg_sys::{BlockNumber, InvalidBlockNumber, BLCKSZ},
     *,
 };
 
+/// The physical file of an index: its tablespace, database and relfilenode. Unlike the OID, it
+/// changes when the index is rebuilt or truncated, so a target block is never used on the new
+/// file of the index.
+type RelFileKey = (pg_sys::Oid, pg_sys::Oid, pg_sys::Oid);
+
+thread_local! {
+    /// The page every index extended last for every page type in this backend, where the next
+    /// insert appends first. The free space map only finds a new page after the next VACUUM, so
+    /// without it every insert that doesn't fit in the space freed by VACUUM would extend the
+    /// index again.
+    static TARGET_BLOCKS: RefCell<Vec<(RelFileKey, PageType, BlockNumber)>> =
+        RefCell::new(Vec::new());
+}
+
+/// The number of indexes and page types whose target block is kept.
+const MAX_TARGET_BLOCKS: usize = 64;
+
+#[cfg(feature = "pg15")]
+unsafe fn get_rel_file_key(index: &PgRelation) -> RelFileKey {
+    let node = (*index.as_ptr()).rd_node;
+    (node.spcNode, node.dbNode, node.relNode)
+}
+
+#[cfg(feature = "pg16")]
+unsafe fn get_rel_file_key(index: &PgRelation) -> RelFileKey {
+    let locator = (*index.as_ptr()).rd_locator;
+    (locator.spcOid, locator.dbOid, locator.relNumber)
+}
+
+unsafe fn get_target_block(index: &PgRelation, page_type: PageType) -> BlockNumber {
+    let key = get_rel_file_key(index);
+    let target = TARGET_BLOCKS.with(|targets| {
+        targets
+            .borrow()
+            .iter()
+            .find(|(k, t, _)| *k == key && *t == page_type)
+            .map_or(InvalidBlockNumber, |(_, _, block_number)| *block_number)
+    });
+    /* the relfilenode may have been reused by a new, smaller file since */
+    if target != InvalidBlockNumber
+        && target
+            >= pg_sys::RelationGetNumberOfBlocksInFork(
+                index.as_ptr(),
+                pg_sys::ForkNumber_MAIN_FORKNUM,
+            )
+    {
+        return InvalidBlockNumber;
+    }
+    target
+}
+
+unsafe fn set_target_block(index: &PgRelation, page_type: PageType, block_number: BlockNumber) {
+    let key = get_rel_file_key(index);
+    TARGET_BLOCKS.with(|targets| {
+        let mut targets = targets.borrow_mut();
+        match targets
+            .iter_mut()
+            .find(|(k, t, _)| *k == key && *t == page_type)
+        {
+            Some(target) => target.2 = block_number,
+            None => {
+                if targets.len() >= MAX_TARGET_BLOCKS {
+                    targets.clear();
+                }
+                targets.push((key, page_type, block_number));
+            }
+        }
+    });
+}
+
 pub struct Tape<'a> {
     page_type: PageType,
     index: &'a PgRelation,
     current: BlockNumber,
+    /* whether writes look for space in the pages recorded in the free space map first */
+    reuse_space: bool,
+    /* bytes left free on every page for later inserts, recorded in the free space map */
+    reserved_space: usize,
 }
 
 impl<'a> Tape<'a> {
     pub unsafe fn new(index: &'a PgRelation, page_type: PageType) -> Self {
+        Self::with_fillfactor(index, page_type, 100)
+    }
+
+    /// A tape that fills its pages only to `fillfactor` percent, so that the nodes inserted
+    /// after the build can go to the same pages. The free space of the pages is recorded in the
+    /// free space map when the tape moves on to the next page and by `record_free_space`.
+    pub unsafe fn with_fillfactor(
+        index: &'a PgRelation,
+        page_type: PageType,
+        fillfactor: u8,
+    ) -> Self {
         let page = WritablePage::new(index, page_type);
         let block_number = page.get_block_number();
         page.commit();
@@ -21,6 +108,23 @@ impl<'a> Tape<'a> {
             page_type,
             index: index,
             current: block_number,
+            reuse_space: false,
+            reserved_space: BLCKSZ as usize * (100 - fillfactor.min(100) as usize) / 100,
+        }
+    }
+
+    /// A tape that writes to the pages of `page_type` recorded in the free space map before
+    /// extending the relation: into their free space, or over dead items of the same size as
+    /// the data on the pages whose dead items can be reused. Used by inserts, so that the free
+    /// space of the pages is filled and the full vectors freed by VACUUM are reused. The page
+    /// the last insert of the backend extended the index with is filled first.
+    pub unsafe fn resume(index: &'a PgRelation, page_type: PageType) -> Self {
+        Self {
+            page_type,
+            index: index,
+            current: InvalidBlockNumber,
+            reuse_space: true,
+            reserved_space: 0,
         }
     }
 
@@ -28,13 +132,36 @@ impl<'a> Tape<'a> {
         let size = data.len();
         assert!(size < BLCKSZ as usize);
 
+        if self.reuse_space {
+            let target = get_target_block(self.index, self.page_type);
+            if target != InvalidBlockNumber {
+                if let Ok(item_pointer) = self.write_to_page(target, data) {
+                    return item_pointer;
+                }
+            }
+            if let Some(item_pointer) = self.write_to_free_space(data) {
+                return item_pointer;
+            }
+            let mut page = WritablePage::new(self.index, self.page_type);
+            self.current = page.get_block_number();
+            let offset_number = page.add_item(data);
+            let item_pointer = super::ItemPointer::with_page(&page, offset_number);
+            let free_space = self.get_usable_space(&page);
+            page.commit();
+            pg_sys::RecordPageWithFreeSpace(self.index.as_ptr(), self.current, free_space);
+            set_target_block(self.index, self.page_type, self.current);
+            return item_pointer;
+        }
+
         let mut current_page = WritablePage::modify(self.index, self.current);
 
         //don't split data over pages. Depending on packing,
         //we may have to implement that in the future.
-        if current_page.get_free_space() < size {
+        if current_page.get_free_space() < size + self.reserved_space {
             //TODO update forward pointer;
 
+            std::mem::drop(current_page);
+            self.record_free_space();
             current_page = WritablePage::new(self.index, self.page_type);
             self.current = current_page.get_block_number();
             if current_page.get_free_space() < size {
@@ -48,6 +175,78 @@ impl<'a> Tape<'a> {
         item_pointer
     }
 
+    /// Writes `data` to a page from the free space map, or returns None if no page has room.
+    /// The free space map is corrected for the pages that turn out to be full.
+    unsafe fn write_to_free_space(&mut self, data: &[u8]) -> Option<super::ItemPointer> {
+        let mut block_number = pg_sys::GetPageWithFreeSpace(self.index.as_ptr(), data.len());
+        while block_number != InvalidBlockNumber {
+            let free_space = match self.write_to_page(block_number, data) {
+                Ok(item_pointer) => return Some(item_pointer),
+                Err(free_space) => free_space,
+            };
+            /* the page has dead items of another size, or less space than recorded */
+            block_number = pg_sys::RecordAndGetPageWithFreeSpace(
+                self.index.as_ptr(),
+                block_number,
+                free_space.min(data.len() - 1),
+                data.len(),
+            );
+        }
+        None
+    }
+
+    /// Writes `data` to the page `block_number` if it is a page of the tape with room for it,
+    /// over a dead item of the same size if the page type reuses them or into its free space,
+    /// and records its remaining space in the free space map. Otherwise returns the usable
+    /// space of the page.
+    unsafe fn write_to_page(
+        &mut self,
+        block_number: BlockNumber,
+        data: &[u8],
+    ) -> Result<super::ItemPointer, usize> {
+        let mut page = WritablePage::modify(self.index, block_number);
+        if page.get_type() != self.page_type {
+            return Err(0);
+        }
+        let reused = if self.page_type.reuses_dead_items() {
+            page.reuse_dead_item(data)
+        } else {
+            None
+        };
+        let offset_number = match reused {
+            Some(offset_number) => offset_number,
+            None if page.get_free_space() >= data.len() => page.add_item_unchecked(data),
+            None => return Err(self.get_usable_space(&page)),
+        };
+        let item_pointer = super::ItemPointer::with_page(&page, offset_number);
+        let free_space = self.get_usable_space(&page);
+        page.commit();
+        self.current = block_number;
+        pg_sys::RecordPageWithFreeSpace(self.index.as_ptr(), block_number, free_space);
+        Ok(item_pointer)
+    }
+
+    /// The space of `page` that the items of the tape can take.
+    fn get_usable_space(&self, page: &WritablePage) -> usize {
+        if self.page_type.reuses_dead_items() {
+            page.get_reusable_space()
+        } else {
+            page.get_free_space()
+        }
+    }
+
+    /// Records the free space of the current page in the free space map if the tape leaves
+    /// space on its pages.
+    pub unsafe fn record_free_space(&self) {
+        if self.reserved_space == 0 {
+            return;
+        }
+        let page = ReadablePage::read(self.index, self.current);
+        let free_space = page.get_free_space();
+        std::mem::drop(page);
+        pg_sys::RecordPageWithFreeSpace(self.index.as_ptr(), self.current, free_space);
+    }
+
     pub fn close(self) {
         std::mem::drop(self)
     }